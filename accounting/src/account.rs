@@ -0,0 +1,401 @@
+use crate::price::Price;
+use alloc::{format, string::String};
+use core::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// lifts the lock of an account, see [`TransactionType::is_admin`]
+    Unlock,
+    /// back-office correction with a signed amount and a reason code
+    Adjustment,
+    /// moves the account of another client into the one of the event
+    Merge,
+    /// repayment of a negative balance, see [`Account::recover`]
+    Recovery,
+}
+
+impl TransactionType {
+    pub const ALL: [TransactionType; 9] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Unlock,
+        TransactionType::Adjustment,
+        TransactionType::Merge,
+        TransactionType::Recovery,
+    ];
+
+    /// Back-office events, only applied when they are authenticated.
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock | TransactionType::Adjustment | TransactionType::Merge
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Adjustment => "adjustment",
+            TransactionType::Merge => "merge",
+            TransactionType::Recovery => "recovery",
+        }
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TransactionType::ALL
+            .into_iter()
+            .find(|ty| ty.as_str() == s)
+            .ok_or_else(|| format!("unknown transaction type `{s}`"))
+    }
+}
+
+impl Display for TransactionType {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionFlags {
+    None,
+    Disputed,
+    Resolved,
+    Chargeback,
+    /// stored only so the tx id can not be reused, e.g. of an adjustment, can
+    /// not be disputed
+    Final,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionError {
+    Overflow,
+    Duplicate,
+    NotFound,
+    InvalidDispute,
+    InsufficientFunds,
+    Locked,
+    ClientMismatch,
+    /// an admin event that is not authenticated
+    Unauthorized,
+    /// an adjustment without a reason code
+    MissingReason,
+    /// a merge without another client to merge
+    InvalidMerge,
+    /// a recovery exceeding the negative balance of the account
+    InvalidRecovery,
+    /// rejected by a validation rule before it was applied
+    RuleViolation,
+    /// an event of a client that is not registered
+    UnknownClient,
+    /// an event of the client reserved as suspense account
+    ReservedClient,
+    /// the amount of the input is beyond the range of a price
+    AmountOutOfRange,
+}
+
+impl TransactionError {
+    pub const ALL: [TransactionError; 15] = [
+        TransactionError::Overflow,
+        TransactionError::Duplicate,
+        TransactionError::NotFound,
+        TransactionError::InvalidDispute,
+        TransactionError::InsufficientFunds,
+        TransactionError::Locked,
+        TransactionError::ClientMismatch,
+        TransactionError::Unauthorized,
+        TransactionError::MissingReason,
+        TransactionError::InvalidMerge,
+        TransactionError::InvalidRecovery,
+        TransactionError::RuleViolation,
+        TransactionError::UnknownClient,
+        TransactionError::ReservedClient,
+        TransactionError::AmountOutOfRange,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionError::Overflow => "overflow",
+            TransactionError::Duplicate => "duplicate",
+            TransactionError::NotFound => "not_found",
+            TransactionError::InvalidDispute => "invalid_dispute",
+            TransactionError::InsufficientFunds => "insufficient_funds",
+            TransactionError::Locked => "locked",
+            TransactionError::ClientMismatch => "client_mismatch",
+            TransactionError::Unauthorized => "unauthorized",
+            TransactionError::MissingReason => "missing_reason",
+            TransactionError::InvalidMerge => "invalid_merge",
+            TransactionError::InvalidRecovery => "invalid_recovery",
+            TransactionError::RuleViolation => "rule_violation",
+            TransactionError::UnknownClient => "unknown_client",
+            TransactionError::ReservedClient => "reserved_client",
+            TransactionError::AmountOutOfRange => "amount_out_of_range",
+        }
+    }
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a chargeback does to the account of the client.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LockPolicy {
+    /// freeze the account, further deposits and withdrawals are rejected
+    #[default]
+    Lock,
+    /// only reject further withdrawals
+    Withdrawals,
+    /// leave the account usable
+    None,
+}
+
+impl LockPolicy {
+    pub const ALL: [LockPolicy; 3] = [LockPolicy::Lock, LockPolicy::Withdrawals, LockPolicy::None];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockPolicy::Lock => "lock",
+            LockPolicy::Withdrawals => "withdrawals",
+            LockPolicy::None => "none",
+        }
+    }
+}
+
+impl FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LockPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| format!("unknown lock policy `{s}`, expected lock, withdrawals or none"))
+    }
+}
+
+impl Display for LockPolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What a dispute holds when it exceeds the available funds, e.g. when the
+/// disputed deposit was already withdrawn.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HoldPolicy {
+    /// hold the full amount, the available funds go negative
+    #[default]
+    Full,
+    /// hold at most the available funds, the rest is tracked as shortfall
+    Cap,
+    /// hold the full amount, and flag the account once it goes negative
+    Flag,
+}
+
+impl HoldPolicy {
+    pub const ALL: [HoldPolicy; 3] = [HoldPolicy::Full, HoldPolicy::Cap, HoldPolicy::Flag];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HoldPolicy::Full => "full",
+            HoldPolicy::Cap => "cap",
+            HoldPolicy::Flag => "flag",
+        }
+    }
+}
+
+impl FromStr for HoldPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HoldPolicy::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| format!("unknown hold policy `{s}`, expected full, cap or flag"))
+    }
+}
+
+impl Display for HoldPolicy {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Audit information of an account, populated while processing. Only used for
+/// reporting, it has no influence on the balances.
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct AccountMetadata {
+    /// number of applied deposits and withdrawals
+    pub tx_count: u32,
+    pub disputes: u32,
+    pub chargebacks: u32,
+    /// unix timestamp (seconds) of the chargeback that locked the account, if
+    /// it had one
+    pub locked_at: Option<u64>,
+    /// the chargeback that caused the lock
+    pub lock_tx: Option<u32>,
+    /// a dispute took the available funds below zero, see [`HoldPolicy::Flag`]
+    pub overdrawn: bool,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Account {
+    pub total: Price,
+    pub held: Price,
+    pub locked: bool,
+    /// only withdrawals are rejected, see [`LockPolicy::Withdrawals`]
+    pub withdrawals_locked: bool,
+    /// disputed funds that are not held, see [`HoldPolicy::Cap`]
+    pub shortfall: Price,
+    pub meta: AccountMetadata,
+}
+
+impl Account {
+    pub fn withdraw(&mut self, amount: Price) -> Result<(), TransactionError> {
+        if self.locked || self.withdrawals_locked {
+            return Err(TransactionError::Locked);
+        }
+
+        if amount > self.available() {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        if !self.total.try_sub(amount) {
+            return Err(TransactionError::Overflow);
+        }
+
+        Ok(())
+    }
+
+    pub fn deposit(&mut self, amount: Price) -> Result<(), TransactionError> {
+        if self.locked {
+            return Err(TransactionError::Locked);
+        }
+
+        if !self.total.try_add(amount) {
+            return Err(TransactionError::Overflow);
+        }
+        Ok(())
+    }
+
+    pub fn dispute(&mut self, amount: Price, policy: HoldPolicy) {
+        let hold = match policy {
+            HoldPolicy::Cap => Price(amount.0.min(self.available().0.max(0)).max(0)),
+            HoldPolicy::Full | HoldPolicy::Flag => amount,
+        };
+        self.held.try_add(hold);
+        self.shortfall.try_add(Price(amount.0 - hold.0));
+        if policy == HoldPolicy::Flag && self.available().0 < 0 {
+            self.meta.overdrawn = true;
+        }
+    }
+
+    /// Releases the disputed amount. The shortfall is settled first, so the
+    /// held funds stay held as long as disputes are open.
+    pub fn resolve(&mut self, amount: Price) {
+        let amount = self.settle_shortfall(amount);
+        self.held.try_sub(amount);
+    }
+
+    fn settle_shortfall(&mut self, amount: Price) -> Price {
+        let settled = amount.0.min(self.shortfall.0).max(0);
+        self.shortfall.try_sub(Price(settled));
+        Price(amount.0 - settled)
+    }
+
+    pub fn chargeback(&mut self, amount: Price, policy: LockPolicy) {
+        let held = self.settle_shortfall(amount);
+        self.held.try_sub(held);
+        self.total.try_sub(amount);
+        match policy {
+            LockPolicy::Lock => self.locked = true,
+            LockPolicy::Withdrawals => self.withdrawals_locked = true,
+            LockPolicy::None => {}
+        }
+    }
+
+    /// Lifts a lock of any [`LockPolicy`], the chargeback that caused it is
+    /// forgotten.
+    pub fn unlock(&mut self) {
+        self.locked = false;
+        self.withdrawals_locked = false;
+        self.meta.locked_at = None;
+        self.meta.lock_tx = None;
+    }
+
+    /// Applies a signed correction, also to a locked account. A debit can not
+    /// take more than the available funds.
+    pub fn adjust(&mut self, amount: Price) -> Result<(), TransactionError> {
+        if amount.0 < 0 && amount.0.saturating_neg() > self.available().0 {
+            return Err(TransactionError::InsufficientFunds);
+        }
+
+        if !self.total.try_add(amount) {
+            return Err(TransactionError::Overflow);
+        }
+        Ok(())
+    }
+
+    /// Repays a negative balance, also of a locked account. A recovery can not
+    /// take the available funds above zero, the rest is a deposit.
+    pub fn recover(&mut self, amount: Price) -> Result<(), TransactionError> {
+        if amount.0 <= 0 || amount.0 > self.available().0.saturating_neg() {
+            return Err(TransactionError::InvalidRecovery);
+        }
+
+        if !self.total.try_add(amount) {
+            return Err(TransactionError::Overflow);
+        }
+        Ok(())
+    }
+
+    /// Takes over the funds, locks and counters of another account. Nothing
+    /// changes when the funds overflow.
+    pub fn merge(&mut self, other: &Account) -> Result<(), TransactionError> {
+        let (mut total, mut held, mut shortfall) = (self.total, self.held, self.shortfall);
+        if !total.try_add(other.total)
+            || !held.try_add(other.held)
+            || !shortfall.try_add(other.shortfall)
+        {
+            return Err(TransactionError::Overflow);
+        }
+        self.total = total;
+        self.held = held;
+        self.shortfall = shortfall;
+        self.locked |= other.locked;
+        self.withdrawals_locked |= other.withdrawals_locked;
+        self.meta.tx_count = self.meta.tx_count.saturating_add(other.meta.tx_count);
+        self.meta.disputes = self.meta.disputes.saturating_add(other.meta.disputes);
+        self.meta.chargebacks = self.meta.chargebacks.saturating_add(other.meta.chargebacks);
+        self.meta.locked_at = self.meta.locked_at.or(other.meta.locked_at);
+        self.meta.lock_tx = self.meta.lock_tx.or(other.meta.lock_tx);
+        self.meta.overdrawn |= other.meta.overdrawn;
+        Ok(())
+    }
+
+    #[inline]
+    pub fn available(&self) -> Price {
+        let scaled = self.total.0 - self.held.0;
+        Price(scaled)
+    }
+}
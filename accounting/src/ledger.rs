@@ -0,0 +1,319 @@
+use crate::{
+    account::{
+        Account, HoldPolicy, LockPolicy, TransactionError, TransactionFlags, TransactionType,
+    },
+    price::Price,
+};
+use alloc::collections::BTreeMap;
+
+/// Amount, state and owning client of a stored transaction.
+pub type StoredTransaction = (Price, TransactionFlags, u16);
+
+/// Where the accounts and transactions live, the rules only need these
+/// lookups.
+pub trait Ledger {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction>;
+
+    /// Inserts or replaces a transaction.
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction);
+
+    /// Applies `update` to the account of the client, created when missing,
+    /// and returns the result.
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account;
+}
+
+/// Applies a deposit or withdrawal to the account of the client and returns
+/// the updated account. Only transactions that are stored can be disputed.
+pub fn apply_transaction<L: Ledger + ?Sized>(
+    ledger: &mut L,
+    client_id: u16,
+    tx: u32,
+    amount: Price,
+    action: impl Fn(&mut Account, Price) -> Result<(), TransactionError>,
+    store_transaction: bool,
+) -> Result<Account, TransactionError> {
+    if ledger.transaction(tx).is_some() {
+        return Err(TransactionError::Duplicate);
+    }
+
+    // the account is created even when the action fails
+    let mut result = Ok(());
+    let account = ledger.update_account(client_id, &mut |account| {
+        result = action(account, amount);
+        if result.is_ok() {
+            account.meta.tx_count += 1;
+        }
+    });
+    result?;
+
+    if store_transaction {
+        ledger.put_transaction(tx, (amount, TransactionFlags::None, client_id));
+    }
+    Ok(account)
+}
+
+/// Applies a dispute, resolve or chargeback to the referenced transaction,
+/// following [`TRANSITIONS`]. Returns the updated account and the amount of
+/// the transaction. A dispute holds funds according to `hold`, a chargeback
+/// locks the account according to `policy`, stamped with the unix
+/// `timestamp` of the event.
+pub fn apply_dispute<L: Ledger + ?Sized>(
+    ledger: &mut L,
+    ty: TransactionType,
+    client_id: u16,
+    tx: u32,
+    policy: LockPolicy,
+    hold: HoldPolicy,
+    timestamp: Option<u64>,
+) -> Result<(Account, Price), TransactionError> {
+    let Some((amount, flags, owner)) = ledger.transaction(tx) else {
+        return Err(TransactionError::NotFound);
+    };
+
+    if owner != client_id {
+        return Err(TransactionError::ClientMismatch);
+    }
+
+    let Some(transition) = TRANSITIONS[flags as usize][ty as usize] else {
+        return Err(TransactionError::InvalidDispute);
+    };
+
+    // transactions are stored after their account, so it exists
+    let account = ledger.update_account(client_id, &mut |account| {
+        (transition.action)(account, amount, tx, (policy, hold), timestamp)
+    });
+    ledger.put_transaction(tx, (amount, transition.to, owner));
+    Ok((account, amount))
+}
+
+/// The policies a transition applies under.
+type Policies = (LockPolicy, HoldPolicy);
+
+/// State change of a disputed transaction and what it does to the account.
+#[derive(Clone, Copy)]
+struct Transition {
+    to: TransactionFlags,
+    action: fn(&mut Account, Price, u32, Policies, Option<u64>),
+}
+
+const DISPUTE: Option<Transition> = Some(Transition {
+    to: TransactionFlags::Disputed,
+    action: |account, amount, _, (_, hold), _| {
+        account.dispute(amount, hold);
+        account.meta.disputes += 1;
+    },
+});
+const RESOLVE: Option<Transition> = Some(Transition {
+    to: TransactionFlags::Resolved,
+    action: |account, amount, _, _, _| account.resolve(amount),
+});
+const CHARGEBACK: Option<Transition> = Some(Transition {
+    to: TransactionFlags::Chargeback,
+    action: |account, amount, tx, (policy, _), timestamp| {
+        account.chargeback(amount, policy);
+        account.meta.chargebacks += 1;
+        if (account.locked || account.withdrawals_locked) && account.meta.lock_tx.is_none() {
+            account.meta.lock_tx = Some(tx);
+            account.meta.locked_at = timestamp;
+        }
+    },
+});
+
+/// Dispute state machine, indexed by the state of the transaction and the
+/// type of the event, in declaration order, with a column for every type.
+/// Missing transitions are invalid disputes. The rows are as wide as
+/// [`TransactionType::ALL`], so a new type does not compile without a column.
+static TRANSITIONS: [[Option<Transition>; TransactionType::ALL.len()]; 5] = [
+    // deposit, withdrawal, dispute, resolve, chargeback, unlock, adjustment,
+    // merge, recovery
+    [None, None, DISPUTE, None, None, None, None, None, None], // none
+    [
+        None, None, None, RESOLVE, CHARGEBACK, None, None, None, None,
+    ], // disputed
+    [None, None, None, None, None, None, None, None, None],    // resolved
+    [None, None, None, None, None, None, None, None, None],    // chargeback
+    [None, None, None, None, None, None, None, None, None],    // final
+];
+
+/// [`Ledger`] on top of `alloc` collections.
+#[derive(Debug, Default, Clone)]
+pub struct BTreeLedger {
+    pub accounts: BTreeMap<u16, Account>,
+    pub transactions: BTreeMap<u32, StoredTransaction>,
+}
+
+impl Ledger for BTreeLedger {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        self.transactions.get(&tx).copied()
+    }
+
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        self.transactions.insert(tx, transaction);
+    }
+
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account {
+        let account = self.accounts.entry(client_id).or_default();
+        update(account);
+        *account
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_btree_ledger() {
+        let mut ledger = BTreeLedger::default();
+        let now = Some(1_700_000_000);
+        apply_transaction(&mut ledger, 1, 1, Price(10), Account::deposit, true).unwrap();
+        assert_eq!(
+            apply_transaction(&mut ledger, 2, 1, Price(10), Account::deposit, true),
+            Err(TransactionError::Duplicate)
+        );
+        assert_eq!(
+            apply_dispute(
+                &mut ledger,
+                TransactionType::Dispute,
+                2,
+                1,
+                LockPolicy::Lock,
+                HoldPolicy::Full,
+                now
+            ),
+            Err(TransactionError::ClientMismatch)
+        );
+        for ty in [
+            TransactionType::Resolve,
+            TransactionType::Merge,
+            TransactionType::Recovery,
+        ] {
+            assert_eq!(
+                apply_dispute(
+                    &mut ledger,
+                    ty,
+                    1,
+                    1,
+                    LockPolicy::Lock,
+                    HoldPolicy::Full,
+                    now
+                ),
+                Err(TransactionError::InvalidDispute)
+            );
+        }
+        apply_dispute(
+            &mut ledger,
+            TransactionType::Dispute,
+            1,
+            1,
+            LockPolicy::Lock,
+            HoldPolicy::Full,
+            now,
+        )
+        .unwrap();
+        let (account, amount) = apply_dispute(
+            &mut ledger,
+            TransactionType::Chargeback,
+            1,
+            1,
+            LockPolicy::Lock,
+            HoldPolicy::Full,
+            now,
+        )
+        .unwrap();
+
+        assert_eq!(amount, Price(10));
+        assert_eq!(account.total, Price(0));
+        assert!(account.locked);
+        assert_eq!(account.meta.lock_tx, Some(1));
+        assert_eq!(account.meta.locked_at, Some(1_700_000_000));
+        assert_eq!(
+            ledger.transaction(1),
+            Some((Price(10), TransactionFlags::Chargeback, 1))
+        );
+    }
+
+    #[test]
+    fn test_lock_policies() {
+        let now = Some(1_700_000_000);
+        let chargeback = |policy| {
+            let mut ledger = BTreeLedger::default();
+            apply_transaction(&mut ledger, 1, 1, Price(10), Account::deposit, true).unwrap();
+            apply_transaction(&mut ledger, 1, 2, Price(10), Account::deposit, true).unwrap();
+            apply_dispute(
+                &mut ledger,
+                TransactionType::Dispute,
+                1,
+                1,
+                policy,
+                HoldPolicy::Full,
+                now,
+            )
+            .unwrap();
+            apply_dispute(
+                &mut ledger,
+                TransactionType::Chargeback,
+                1,
+                1,
+                policy,
+                HoldPolicy::Full,
+                now,
+            )
+            .unwrap();
+            let deposit = apply_transaction(&mut ledger, 1, 3, Price(1), Account::deposit, true);
+            let withdrawal =
+                apply_transaction(&mut ledger, 1, 4, Price(1), Account::withdraw, false);
+            (deposit.is_ok(), withdrawal.err())
+        };
+
+        assert_eq!(
+            chargeback(LockPolicy::Lock),
+            (false, Some(TransactionError::Locked))
+        );
+        assert_eq!(
+            chargeback(LockPolicy::Withdrawals),
+            (true, Some(TransactionError::Locked))
+        );
+        assert_eq!(chargeback(LockPolicy::None), (true, None));
+    }
+
+    #[test]
+    fn test_hold_policies() {
+        let now = Some(1_700_000_000);
+        // the deposit is mostly withdrawn before it gets disputed
+        let dispute = |hold| {
+            let mut ledger = BTreeLedger::default();
+            apply_transaction(&mut ledger, 1, 1, Price(10), Account::deposit, true).unwrap();
+            apply_transaction(&mut ledger, 1, 2, Price(7), Account::withdraw, false).unwrap();
+            let dispute = |ledger: &mut BTreeLedger, ty| {
+                apply_dispute(ledger, ty, 1, 1, LockPolicy::None, hold, now)
+                    .unwrap()
+                    .0
+            };
+            let disputed = dispute(&mut ledger, TransactionType::Dispute);
+            let charged_back = dispute(&mut ledger, TransactionType::Chargeback);
+            (disputed, charged_back)
+        };
+
+        let (disputed, charged_back) = dispute(HoldPolicy::Full);
+        assert_eq!(
+            (disputed.available(), disputed.shortfall),
+            (Price(-7), Price(0))
+        );
+        assert!(!disputed.meta.overdrawn);
+        assert_eq!(charged_back.total, Price(-7));
+
+        let (disputed, charged_back) = dispute(HoldPolicy::Cap);
+        assert_eq!((disputed.available(), disputed.held), (Price(0), Price(3)));
+        assert_eq!(disputed.shortfall, Price(7));
+        assert_eq!(
+            (charged_back.held, charged_back.shortfall),
+            (Price(0), Price(0))
+        );
+        assert_eq!(charged_back.total, Price(-7));
+
+        let (disputed, _) = dispute(HoldPolicy::Flag);
+        assert_eq!(disputed.available(), Price(-7));
+        assert!(disputed.meta.overdrawn);
+    }
+}
@@ -0,0 +1,21 @@
+//! The accounting rules of the toy transaction engine: fixed point prices,
+//! accounts and the dispute state machine.
+//!
+//! The crate is `no_std` and only needs `alloc`, so the rules can be reused
+//! where the standard library is not available, e.g. in an enclave. Where the
+//! state is kept is up to the [`Ledger`] implementation, [`BTreeLedger`] is a
+//! simple one on top of `alloc` collections.
+#![no_std]
+
+extern crate alloc;
+
+mod account;
+mod ledger;
+mod price;
+
+pub use account::{
+    Account, AccountMetadata, HoldPolicy, LockPolicy, TransactionError, TransactionFlags,
+    TransactionType,
+};
+pub use ledger::{apply_dispute, apply_transaction, BTreeLedger, Ledger, StoredTransaction};
+pub use price::{Float2PriceError, ParseDecimalError, Price, Rounding, PRICE_SCALAR};
@@ -0,0 +1,374 @@
+use alloc::{format, string::String};
+use core::{
+    fmt::{Debug, Display},
+    str::FromStr,
+};
+
+pub const PRICE_SCALAR: i64 = 10000;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Price(pub i64);
+
+impl Price {
+    pub fn make_absolute(&mut self) {
+        self.0 = self.0.abs();
+    }
+
+    pub fn try_add(&mut self, other: Price) -> bool {
+        let Some(val) = self.0.checked_add(other.0) else {
+            return false;
+        };
+        self.0 = val;
+        true
+    }
+
+    pub fn try_sub(&mut self, other: Price) -> bool {
+        let Some(val) = self.0.checked_sub(other.0) else {
+            return false;
+        };
+        self.0 = val;
+        true
+    }
+}
+
+impl Display for Price {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // the sign is written separately, -0.5 has no sign in its integral part
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let abs = self.0.unsigned_abs();
+        let integral = abs / PRICE_SCALAR as u64;
+        let mut fractional = abs % PRICE_SCALAR as u64;
+        if fractional == 0 {
+            return write!(f, "{}{}.0", sign, integral);
+        }
+        let mut width = 4;
+        while fractional.is_multiple_of(10) {
+            fractional /= 10;
+            width -= 1;
+        }
+        write!(f, "{}{}.{:0width$}", sign, integral, fractional)
+    }
+}
+
+#[derive(Debug)]
+pub struct Float2PriceError;
+
+/// We want to be conservative converting prices here and reject any
+/// over/underflow while converting.
+/// * Infinite and NaN values are rejected and result in an Error.
+/// * The conversion uses the overall price scalar to provide the appropriate
+///   decimal precision (See [`PRICE_SCALAR`]).
+/// * subnormal numbers are not handled.
+impl TryFrom<f64> for Price {
+    type Error = Float2PriceError;
+
+    fn try_from(value: f64) -> Result<Self, Self::Error> {
+        if value.is_infinite() || value.is_nan() {
+            return Err(Float2PriceError);
+        }
+
+        let value = value * PRICE_SCALAR as f64;
+        if !(value <= i64::MAX as f64 && value >= i64::MIN as f64) {
+            return Err(Float2PriceError);
+        }
+        // rounds half away from zero like `f64::round`, which is not
+        // available without std. The cast saturates and truncates.
+        let truncated = value as i64;
+        let fraction = value - truncated as f64;
+        Ok(Price(if fraction >= 0.5 {
+            truncated + 1
+        } else if fraction <= -0.5 {
+            truncated - 1
+        } else {
+            truncated
+        }))
+    }
+}
+
+/// How an amount with more than four decimals is brought to the precision of
+/// a [`Price`], see [`Price::parse_decimal`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Rounding {
+    /// fail on the extra decimals
+    Reject,
+    /// drop the extra decimals, rounding towards zero
+    Truncate,
+    /// round to the nearest, ties to the even neighbour
+    HalfEven,
+    /// round to the nearest, ties away from zero
+    #[default]
+    HalfUp,
+}
+
+impl Rounding {
+    pub const ALL: [Rounding; 4] = [
+        Rounding::Reject,
+        Rounding::Truncate,
+        Rounding::HalfEven,
+        Rounding::HalfUp,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rounding::Reject => "reject",
+            Rounding::Truncate => "truncate",
+            Rounding::HalfEven => "half-even",
+            Rounding::HalfUp => "half-up",
+        }
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Rounding::ALL
+            .into_iter()
+            .find(|rounding| rounding.as_str() == s)
+            .ok_or_else(|| {
+                format!("unknown rounding `{s}`, expected reject, truncate, half-even or half-up")
+            })
+    }
+}
+
+impl Display for Rounding {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why [`Price::parse_decimal`] failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParseDecimalError {
+    /// not a decimal number
+    Invalid,
+    /// more than four decimals with [`Rounding::Reject`]
+    Inexact,
+    /// beyond the range of a price, with the bound it can be clamped to
+    OutOfRange(Price),
+}
+
+impl Price {
+    pub const MIN: Price = Price(i64::MIN);
+    pub const MAX: Price = Price(i64::MAX);
+
+    /// Parses a decimal amount like `-12.34567` or `1.5e-3` exactly, without
+    /// going through a float, and rounds it to four decimals.
+    pub fn parse_decimal(s: &str, rounding: Rounding) -> Result<Price, ParseDecimalError> {
+        let s = s.trim();
+        let (negative, s) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (mantissa, exponent) = match s.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa, Some(exponent)),
+            None => (s, None),
+        };
+        let (integral, fractional) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+        if (integral.is_empty() && fractional.is_empty())
+            || !is_digits(integral)
+            || !is_digits(fractional)
+        {
+            return Err(ParseDecimalError::Invalid);
+        }
+        // beyond this the exponent makes no difference anymore
+        const MAX_EXPONENT: i64 = 1_000_000;
+        let exponent = match exponent {
+            Some(exponent) => {
+                let (sign, digits) = match exponent.as_bytes().first() {
+                    Some(b'-') => (-1, &exponent[1..]),
+                    Some(b'+') => (1, &exponent[1..]),
+                    _ => (1, exponent),
+                };
+                if digits.is_empty() || !is_digits(digits) {
+                    return Err(ParseDecimalError::Invalid);
+                }
+                let value = digits.bytes().fold(0i64, |value, b| {
+                    (value * 10 + (b - b'0') as i64).min(MAX_EXPONENT)
+                });
+                sign * value
+            }
+            None => 0,
+        };
+
+        // the digits without leading zeros, and the position of the decimal
+        // point in them
+        let digits = [integral.as_bytes(), fractional.as_bytes()].concat();
+        let zeros = digits.iter().take_while(|b| **b == b'0').count();
+        let digits = &digits[zeros..];
+        let point = integral.len() as i64 - zeros as i64 + exponent;
+        let digit = |i: i64| match usize::try_from(i) {
+            Ok(i) if i < digits.len() => digits[i] - b'0',
+            _ => 0,
+        };
+
+        let bound = if negative { Price::MIN } else { Price::MAX };
+        // at most one more than i64::MAX, for i64::MIN
+        let limit = i64::MAX as u64 + 1;
+        let kept = point + PRICE_SCALAR.ilog10() as i64;
+        let mut value: u64 = 0;
+        for i in 0..kept.max(0) {
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_add(digit(i) as u64))
+                .filter(|value| *value <= limit)
+                .ok_or(ParseDecimalError::OutOfRange(bound))?;
+        }
+
+        let first = digit(kept);
+        let rest = (kept.max(-1) + 1..digits.len() as i64).any(|i| digit(i) != 0);
+        let round_up = match rounding {
+            Rounding::Reject if first != 0 || rest => return Err(ParseDecimalError::Inexact),
+            Rounding::Reject | Rounding::Truncate => false,
+            Rounding::HalfUp => first >= 5,
+            Rounding::HalfEven => first > 5 || (first == 5 && (rest || value % 2 == 1)),
+        };
+        if round_up {
+            value += 1;
+        }
+
+        if negative {
+            0i64.checked_sub_unsigned(value)
+        } else {
+            i64::try_from(value).ok()
+        }
+        .map(Price)
+        .ok_or(ParseDecimalError::OutOfRange(bound))
+    }
+}
+
+impl FromStr for Price {
+    type Err = Float2PriceError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value: f64 = s.trim().parse().map_err(|_| Float2PriceError)?;
+        Price::try_from(value)
+    }
+}
+
+/// Prices are serialized as decimal strings, to not lose any precision.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Price {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Price {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let opt_float: Option<f64> = serde::Deserialize::deserialize(deserializer)?;
+        let value = opt_float.unwrap_or_default();
+        Price::try_from(value).map_err(|_| serde::de::Error::custom("Invalid amount"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn test_price_display() {
+        assert_eq!(Price(10000).to_string(), "1.0");
+        assert_eq!(Price(15000).to_string(), "1.5");
+        assert_eq!(Price(500).to_string(), "0.05");
+        assert_eq!(Price(1).to_string(), "0.0001");
+        assert_eq!(Price(-5000).to_string(), "-0.5");
+        assert_eq!(Price(-12345).to_string(), "-1.2345");
+        assert_eq!(Price(0).to_string(), "0.0");
+        assert_eq!(Price(i64::MIN).to_string(), "-922337203685477.5808");
+    }
+
+    #[test]
+    fn test_price_display_keeps_leading_fraction_zeros() {
+        // the fraction used to be printed without its leading zeros, 1.05 as
+        // "1.500"
+        assert_eq!(Price(10500).to_string(), "1.05");
+        assert_eq!(Price(10050).to_string(), "1.005");
+        assert_eq!(Price(10005).to_string(), "1.0005");
+        assert_eq!(Price(-500).to_string(), "-0.05");
+        assert_eq!("1.05".parse::<Price>().unwrap().to_string(), "1.05");
+    }
+
+    #[test]
+    fn test_price_from_f64() {
+        assert_eq!(Price::try_from(1.5).unwrap(), Price(15000));
+        assert_eq!(Price::try_from(0.00005).unwrap(), Price(1));
+        assert_eq!(Price::try_from(-0.00005).unwrap(), Price(-1));
+        assert_eq!(Price::try_from(0.00004).unwrap(), Price(0));
+        // same rounding as f64::round
+        for value in [2.00015, -2.00015, 0.12345, 1e14 + 0.5, -7.77775] {
+            let rounded = (value * PRICE_SCALAR as f64).round() as i64;
+            assert_eq!(Price::try_from(value).unwrap(), Price(rounded));
+        }
+        assert!(Price::try_from(f64::NAN).is_err());
+        assert!(Price::try_from(f64::INFINITY).is_err());
+        assert!(Price::try_from(1e300).is_err());
+    }
+
+    #[test]
+    fn test_price_parse_decimal() {
+        let parse = |s, rounding| Price::parse_decimal(s, rounding).ok();
+        use Rounding::*;
+        assert_eq!(parse("1.5", Reject), Some(Price(15000)));
+        assert_eq!(parse(" -.25 ", Reject), Some(Price(-2500)));
+        assert_eq!(parse("3", Reject), Some(Price(30000)));
+        assert_eq!(parse("1.00000", Reject), Some(Price(10000)));
+        assert_eq!(parse("1.00001", Reject), None);
+
+        let cases = [
+            // value, truncate, half-even, half-up
+            ("2.00015", 20001, 20002, 20002),
+            ("2.00025", 20002, 20002, 20003),
+            ("2.000250001", 20002, 20003, 20003),
+            ("2.00024", 20002, 20002, 20002),
+            ("-2.00025", -20002, -20002, -20003),
+            ("-7.77779", -77777, -77778, -77778),
+        ];
+        for (value, truncated, half_even, half_up) in cases {
+            assert_eq!(parse(value, Truncate), Some(Price(truncated)), "{value}");
+            assert_eq!(parse(value, HalfEven), Some(Price(half_even)), "{value}");
+            assert_eq!(parse(value, HalfUp), Some(Price(half_up)), "{value}");
+        }
+
+        assert_eq!(parse("1e3", Reject), Some(Price(10_000_000)));
+        assert_eq!(parse("-1.5E-2", Reject), Some(Price(-150)));
+        assert_eq!(parse("0.000123e2", Reject), Some(Price(123)));
+        assert_eq!(parse("0e99999999999", Reject), Some(Price(0)));
+        assert_eq!(parse("5e-5", HalfUp), Some(Price(1)));
+        assert_eq!(parse("5e-5", HalfEven), Some(Price(0)));
+        assert_eq!(parse("1e-99999999999", Truncate), Some(Price(0)));
+        assert_eq!(parse("1e-99999999999", Reject), None);
+
+        assert_eq!(parse("-922337203685477.5808", Reject), Some(Price::MIN));
+        let out_of_range = |s, rounding| Price::parse_decimal(s, rounding).err();
+        let positive = Some(ParseDecimalError::OutOfRange(Price::MAX));
+        let negative = Some(ParseDecimalError::OutOfRange(Price::MIN));
+        assert_eq!(out_of_range("922337203685477.5808", Reject), positive);
+        assert_eq!(out_of_range("922337203685477.58075", HalfUp), positive);
+        assert_eq!(out_of_range("9999999999999999.9999", HalfUp), positive);
+        assert_eq!(out_of_range("1e300", HalfUp), positive);
+        assert_eq!(out_of_range("-1E99999999999", HalfUp), negative);
+        for invalid in [
+            "", "-", ".", "e3", "1e", "1e+", "1,5", "1.2.3", "--1", "nan", "inf",
+        ] {
+            assert_eq!(
+                out_of_range(invalid, HalfUp),
+                Some(ParseDecimalError::Invalid),
+                "{invalid}"
+            );
+        }
+        assert_eq!("half-even".parse(), Ok(HalfEven));
+        assert!("bankers".parse::<Rounding>().is_err());
+    }
+}
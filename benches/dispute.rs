@@ -0,0 +1,53 @@
+//! Dispute heavy workload: every deposit is disputed, then resolved or charged
+//! back, and disputed again, which is rejected.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use toy_transaction_engine::{
+    data_types::{Account, Price, TransactionEvent, TransactionType},
+    transaction_context::TransactionContext,
+};
+
+const DEPOSITS: u32 = 10_000;
+const CLIENTS: u32 = 100;
+
+fn event(ty: TransactionType, tx: u32) -> TransactionEvent {
+    TransactionEvent::new(ty, (tx % CLIENTS) as u16, tx, Price(10_000))
+}
+
+fn deposited() -> TransactionContext {
+    let mut context = TransactionContext::new();
+    for tx in 0..DEPOSITS {
+        context
+            .handle_transaction(&event(TransactionType::Deposit, tx), Account::deposit, true)
+            .unwrap();
+    }
+    context
+}
+
+fn dispute(context: &mut TransactionContext, ty: TransactionType, tx: u32) {
+    let _ = context.handle_dispute(&event(ty, tx));
+}
+
+fn disputes(c: &mut Criterion) {
+    c.bench_function("disputes", |b| {
+        b.iter_batched(
+            deposited,
+            |mut context| {
+                for tx in 0..DEPOSITS {
+                    dispute(&mut context, TransactionType::Dispute, tx);
+                    let settle = if tx % 2 == 0 {
+                        TransactionType::Resolve
+                    } else {
+                        TransactionType::Chargeback
+                    };
+                    dispute(&mut context, settle, tx);
+                    dispute(&mut context, TransactionType::Dispute, tx);
+                }
+                context
+            },
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, disputes);
+criterion_main!(benches);
@@ -0,0 +1,71 @@
+//! Compares the state store backends on the hot path of the engine: deposits
+//! followed by disputes and resolves of a part of them.
+//!
+//! Run with `cargo bench --features sled,rocksdb` to include the persistent
+//! backends.
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use toy_transaction_engine::{
+    data_types::{Account, Price, TransactionEvent, TransactionType},
+    state_store::{MemoryStore, StateStore},
+    transaction_context::TransactionContext,
+};
+
+const EVENTS: u32 = 10_000;
+const CLIENTS: u32 = 100;
+
+fn event(ty: TransactionType, tx: u32) -> TransactionEvent {
+    TransactionEvent::new(ty, (tx % CLIENTS) as u16, tx, Price(10_000))
+}
+
+fn process(mut context: TransactionContext) -> TransactionContext {
+    for tx in 0..EVENTS {
+        context
+            .handle_transaction(&event(TransactionType::Deposit, tx), Account::deposit, true)
+            .unwrap();
+    }
+    // every tenth deposit is disputed and resolved
+    for tx in (0..EVENTS).step_by(10) {
+        context
+            .handle_dispute(&event(TransactionType::Dispute, tx))
+            .unwrap();
+        context
+            .handle_dispute(&event(TransactionType::Resolve, tx))
+            .unwrap();
+    }
+    context.flush().unwrap();
+    context
+}
+
+fn bench_store(c: &mut Criterion, name: &str, store: impl Fn() -> Box<dyn StateStore>) {
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || TransactionContext::with_store(store()),
+            process,
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+fn empty_dir(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(name);
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
+
+fn state_store(c: &mut Criterion) {
+    bench_store(c, "memory", || Box::<MemoryStore>::default());
+    #[cfg(feature = "sled")]
+    bench_store(c, "sled", || {
+        let path = empty_dir("txe_bench_sled");
+        Box::new(toy_transaction_engine::sled_store::SledStore::open(&path).unwrap())
+    });
+    #[cfg(feature = "rocksdb")]
+    bench_store(c, "rocksdb", || {
+        let path = empty_dir("txe_bench_rocksdb");
+        Box::new(toy_transaction_engine::rocksdb_store::RocksStore::open(&path).unwrap())
+    });
+}
+
+criterion_group!(benches, state_store);
+criterion_main!(benches);
@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the gRPC bindings are generated from the proto definitions, requires
+    // `protoc` to be installed.
+    #[cfg(feature = "grpc")]
+    tonic_prost_build::compile_protos("proto/engine.proto")?;
+    Ok(())
+}
@@ -0,0 +1,104 @@
+#ifndef TXE_H
+#define TXE_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define TXE_DEPOSIT 0
+
+#define TXE_WITHDRAWAL 1
+
+#define TXE_DISPUTE 2
+
+#define TXE_RESOLVE 3
+
+#define TXE_CHARGEBACK 4
+
+// Amounts are expressed in units of `1 / TXE_PRICE_SCALAR`.
+#define TXE_PRICE_SCALAR 10000
+
+// Outcome of [`engine_push_event`].
+typedef enum TxeStatus {
+  TXE_STATUS_OK = 0,
+  TXE_STATUS_OVERFLOW,
+  TXE_STATUS_DUPLICATE,
+  TXE_STATUS_NOT_FOUND,
+  TXE_STATUS_INVALID_DISPUTE,
+  TXE_STATUS_INSUFFICIENT_FUNDS,
+  TXE_STATUS_LOCKED,
+  TXE_STATUS_CLIENT_MISMATCH,
+  // a null engine or an unknown transaction type
+  TXE_STATUS_INVALID_ARGUMENT,
+  // an admin event, they can not be pushed through the C API
+  TXE_STATUS_UNAUTHORIZED,
+} TxeStatus;
+
+// Accounts of a finished engine, ordered by client.
+typedef struct TxeAccounts TxeAccounts;
+
+// Engine created by [`engine_new`].
+typedef struct TxeEngine TxeEngine;
+
+// An account as returned by [`accounts_next`].
+typedef struct TxeAccount {
+  uint16_t client;
+  int64_t available;
+  int64_t held;
+  int64_t total;
+  bool locked;
+} TxeAccount;
+
+// Creates an engine with an empty, in memory state. Free it with
+// [`engine_finish`] or [`engine_free`].
+struct TxeEngine *engine_new(void);
+
+// Applies an event, `amount` is ignored for disputes, resolves and
+// chargebacks.
+//
+// # Safety
+//
+// `engine` must be null or returned by [`engine_new`] and not yet freed.
+enum TxeStatus engine_push_event(struct TxeEngine *engine,
+                                 uint8_t ty,
+                                 uint16_t client,
+                                 uint32_t tx,
+                                 int64_t amount);
+
+// Frees the engine and returns its accounts, iterate them with
+// [`accounts_next`] and free them with [`accounts_free`]. Returns null for a
+// null engine.
+//
+// # Safety
+//
+// `engine` must be null or returned by [`engine_new`] and not yet freed.
+struct TxeAccounts *engine_finish(struct TxeEngine *engine);
+
+// Frees the engine without reading its accounts.
+//
+// # Safety
+//
+// `engine` must be null or returned by [`engine_new`] and not yet freed.
+void engine_free(struct TxeEngine *engine);
+
+// Writes the next account to `account`, returns false when all accounts were
+// read.
+//
+// # Safety
+//
+// `accounts` must be null or returned by [`engine_finish`] and not yet freed,
+// `account` must be null or valid for writes.
+bool accounts_next(struct TxeAccounts *accounts, struct TxeAccount *account);
+
+// Frees the accounts returned by [`engine_finish`].
+//
+// # Safety
+//
+// `accounts` must be null or returned by [`engine_finish`] and not yet freed.
+void accounts_free(struct TxeAccounts *accounts);
+
+#endif  /* TXE_H */
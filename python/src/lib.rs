@@ -0,0 +1,161 @@
+//! Python bindings of the engine, the `toy_txn_engine` module. Build and
+//! install it into the active environment with `maturin develop` in this
+//! directory.
+//!
+//! Accounts are returned as a list of dicts, one per client ordered by client,
+//! which `pandas.DataFrame` takes as is. Amounts are `decimal.Decimal`, so no
+//! precision is lost on the way.
+use pyo3::{
+    exceptions::{PyOSError, PyValueError},
+    prelude::*,
+    types::{PyDict, PyList},
+};
+use std::path::PathBuf;
+use toy_transaction_engine::{
+    csv_source::CsvSource,
+    data_types::{Account, Price, TransactionEvent},
+    pipeline::Message,
+    transaction_context::TransactionContext,
+};
+
+#[pymodule]
+fn toy_txn_engine(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(process_file, m)?)?;
+    m.add_class::<Engine>()?;
+    Ok(())
+}
+
+/// Processes a csv file like the binary does and returns the accounts.
+#[pyfunction]
+fn process_file(py: Python<'_>, path: PathBuf) -> PyResult<Bound<'_, PyList>> {
+    let source =
+        CsvSource::open(&path, None).map_err(|error| PyOSError::new_err(format!("{error:#}")))?;
+    let mut context = TransactionContext::new();
+    // rejected events are skipped, like the binary does
+    py.detach(|| {
+        for event in source.filter_map(Message::into_event) {
+            let _ = context.apply(&event);
+        }
+    });
+    accounts_to_py(py, context.iter_accounts())
+}
+
+/// State that events are pushed into one by one, e.g. for what-if analysis.
+// the store is not `Sync`, an engine stays on the thread that created it
+#[pyclass(unsendable)]
+#[derive(Debug, Default)]
+struct Engine {
+    context: TransactionContext,
+}
+
+#[pymethods]
+impl Engine {
+    #[new]
+    fn new() -> Self {
+        Engine::default()
+    }
+
+    /// Applies an event given as a dict with the keys of the csv columns:
+    /// `type`, `client`, `tx` and, for deposits and withdrawals, `amount`.
+    /// Returns None when the event was applied, the reason when it was
+    /// rejected. Raises `ValueError` for a malformed event.
+    fn push(&mut self, event: &Bound<'_, PyDict>) -> PyResult<Option<&'static str>> {
+        let event = parse_event(event)?;
+        Ok(self.context.apply(&event).err().map(|error| error.as_str()))
+    }
+
+    /// The accounts so far.
+    fn accounts<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyList>> {
+        accounts_to_py(py, self.context.iter_accounts())
+    }
+}
+
+fn parse_event(event: &Bound<'_, PyDict>) -> PyResult<TransactionEvent> {
+    let field = |key: &str| -> PyResult<Bound<'_, PyAny>> {
+        event
+            .get_item(key)?
+            .ok_or_else(|| PyValueError::new_err(format!("event misses `{key}`")))
+    };
+    let ty: String = field("type")?.extract()?;
+    // amounts are parsed from their text, a float or Decimal is taken as it
+    // prints
+    let amount = match event.get_item("amount")? {
+        Some(amount) if !amount.is_none() => {
+            let text = amount.str()?.to_string();
+            text.parse()
+                .map_err(|_| PyValueError::new_err(format!("invalid amount `{text}`")))?
+        }
+        _ => Price::default(),
+    };
+    Ok(TransactionEvent::new(
+        ty.trim().parse().map_err(PyValueError::new_err)?,
+        field("client")?.extract()?,
+        field("tx")?.extract()?,
+        amount,
+    ))
+}
+
+fn accounts_to_py<'py, 'a>(
+    py: Python<'py>,
+    accounts: impl Iterator<Item = (u16, &'a Account)>,
+) -> PyResult<Bound<'py, PyList>> {
+    let decimal = py.import("decimal")?.getattr("Decimal")?;
+    let mut accounts: Vec<_> = accounts.collect();
+    accounts.sort_by_key(|(client, _)| *client);
+
+    let list = PyList::empty(py);
+    for (client, account) in accounts {
+        let row = PyDict::new(py);
+        row.set_item("client", client)?;
+        for (key, amount) in [
+            ("available", account.available()),
+            ("held", account.held),
+            ("total", account.total),
+        ] {
+            row.set_item(key, decimal.call1((amount.to_string(),))?)?;
+        }
+        row.set_item("locked", account.locked)?;
+        list.append(row)?;
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engine_push() {
+        Python::attach(|py| {
+            let mut engine = Engine::new();
+            let event = |ty: &str, tx: u32, amount: Option<f64>| {
+                let event = PyDict::new(py);
+                event.set_item("type", ty).unwrap();
+                event.set_item("client", 1).unwrap();
+                event.set_item("tx", tx).unwrap();
+                event.set_item("amount", amount).unwrap();
+                event
+            };
+            assert_eq!(engine.push(&event("deposit", 1, Some(1.5))).unwrap(), None);
+            assert_eq!(
+                engine.push(&event("withdrawal", 2, Some(2.0))).unwrap(),
+                Some("insufficient_funds")
+            );
+            assert_eq!(engine.push(&event("dispute", 1, None)).unwrap(), None);
+            assert!(engine.push(&event("refund", 3, None)).is_err());
+
+            let accounts = engine.accounts(py).unwrap();
+            assert_eq!(accounts.len(), 1);
+            let account = accounts.get_item(0).unwrap();
+            assert_eq!(
+                account.get_item("held").unwrap().str().unwrap().to_string(),
+                "1.5"
+            );
+            assert!(!account
+                .get_item("locked")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+        });
+    }
+}
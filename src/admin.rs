@@ -0,0 +1,166 @@
+//! Authentication of admin events, e.g. `unlock`, see
+//! [`TransactionType::is_admin`]. Data files are written by many parties, so
+//! an admin event in one is only applied when its `signature` column holds
+//! the HMAC-SHA256 of the event under the admin key. Without a key every
+//! admin event from a file is rejected.
+//!
+//! The signed message is `<type>,<client>,<tx>,<amount>` with the amount as
+//! the engine prints it, e.g. `unlock,1,100,0.0`, followed by `,<reason code>`
+//! when the event has one, e.g. `adjustment,1,101,-2.5,fee_refund`. Merges
+//! append both the reason code, empty when missing, and the merged client,
+//! e.g. `merge,1,102,0.0,,2`. The key is read from
+//! [`KEY_ENV`] (hex) or from the file named by [`KEY_FILE_ENV`] (hex or raw
+//! bytes). Events built in code, e.g. through the library, are trusted and
+//! mark themselves as authenticated.
+use crate::{
+    data_types::{TransactionEvent, TransactionType},
+    seal::decode_hex,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Environment variable holding the key as hex.
+pub const KEY_ENV: &str = "TXE_ADMIN_KEY";
+/// Environment variable naming a file that holds the key.
+pub const KEY_FILE_ENV: &str = "TXE_ADMIN_KEY_FILE";
+
+/// Name of the column holding the signature.
+pub const SIGNATURE_COLUMN: &str = "signature";
+
+/// The key admin events are signed with.
+#[derive(Clone)]
+pub struct AdminKey {
+    mac: Hmac<Sha256>,
+}
+
+impl std::fmt::Debug for AdminKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AdminKey(..)")
+    }
+}
+
+impl AdminKey {
+    pub fn new(key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() < 32 {
+            anyhow::bail!("the admin key needs at least 32 bytes, got {}", key.len());
+        }
+        let mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+        Ok(AdminKey { mac })
+    }
+
+    /// The key from [`KEY_ENV`] or [`KEY_FILE_ENV`], `None` when neither is
+    /// set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        if let Ok(hex) = std::env::var(KEY_ENV) {
+            let key = decode_hex(hex.trim())
+                .ok_or_else(|| anyhow::anyhow!("{KEY_ENV} is not a hex string"))?;
+            return Self::new(&key).map(Some);
+        }
+        let Some(path) = std::env::var_os(KEY_FILE_ENV) else {
+            return Ok(None);
+        };
+        let content = std::fs::read(&path)?;
+        let key = std::str::from_utf8(&content)
+            .ok()
+            .and_then(|text| decode_hex(text.trim()))
+            .unwrap_or(content);
+        Self::new(&key).map(Some)
+    }
+
+    fn mac(&self, event: &TransactionEvent) -> Hmac<Sha256> {
+        let mut mac = self.mac.clone();
+        let mut message = format!(
+            "{},{},{},{}",
+            event.ty, event.client_id, event.tx, event.amount
+        );
+        if let Some(merged) = event.merged_client {
+            message = format!("{message},{},{merged}", event.reason);
+        } else if !event.reason.is_empty() {
+            message = format!("{message},{}", event.reason);
+        }
+        mac.update(message.as_bytes());
+        mac
+    }
+
+    /// The signature of the event, as hex.
+    pub fn sign(&self, event: &TransactionEvent) -> String {
+        self.mac(event)
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Whether the hex signature belongs to the event.
+    pub fn verify(&self, event: &TransactionEvent, signature: &str) -> bool {
+        decode_hex(signature)
+            .is_some_and(|signature| self.mac(event).verify_slice(&signature).is_ok())
+    }
+}
+
+/// Marks an admin event as authenticated when its signature is valid. Other
+/// events are left alone.
+pub fn authenticate(event: &mut TransactionEvent, signature: Option<&str>, key: Option<&AdminKey>) {
+    if !event.ty.is_admin() {
+        return;
+    }
+    event.authenticated = match (signature, key) {
+        (Some(signature), Some(key)) => key.verify(event, signature),
+        _ => false,
+    };
+}
+
+/// An authenticated admin event, for callers issuing them in code.
+pub fn admin_event(ty: TransactionType, client_id: u16, tx: u32) -> TransactionEvent {
+    debug_assert!(ty.is_admin());
+    TransactionEvent {
+        authenticated: true,
+        ..TransactionEvent::new(ty, client_id, tx, Default::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::Account, transaction_context::TransactionContext};
+
+    #[test]
+    fn test_admin_events() {
+        let key = AdminKey::new(&[3; 32]).unwrap();
+        let mut context = TransactionContext::new();
+        context.insert_account(
+            1,
+            Account {
+                locked: true,
+                ..Default::default()
+            },
+        );
+
+        let mut unlock = admin_event(TransactionType::Unlock, 1, 100);
+        let signature = key.sign(&unlock);
+        unlock.authenticated = false;
+        assert_eq!(
+            context.apply(&unlock).err(),
+            Some(crate::data_types::TransactionError::Unauthorized)
+        );
+
+        for (signature, key, authenticated) in [
+            (None, Some(&key), false),
+            (Some(signature.as_str()), None, false),
+            (Some("00ff"), Some(&key), false),
+            (Some(signature.as_str()), Some(&key), true),
+        ] {
+            authenticate(&mut unlock, signature, key);
+            assert_eq!(unlock.authenticated, authenticated);
+        }
+        // the signature does not cover another client
+        let mut other = admin_event(TransactionType::Unlock, 2, 100);
+        authenticate(&mut other, Some(&signature), Some(&key));
+        assert!(!other.authenticated);
+
+        let account = context.apply(&unlock).unwrap();
+        assert!(!account.locked);
+        assert!(AdminKey::new(&[3; 16]).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//! Alerts when a run looks anomalous, see `--alerts`: too many rejected
+//! events, too many unparsable rows or a state digest that differs from
+//! `--expect-digest`. Bad input files are noticed that way before anyone
+//! consumes the report. At the end of the run an alert is POSTed to every
+//! target, either as the run summary in JSON or as a Slack message.
+//!
+//! ```toml
+//! # fraction of the processed events that may be rejected
+//! max_reject_rate = 0.05
+//! # fraction of the rows read that may fail to parse
+//! max_parse_failure_rate = 0.01
+//! # alert when the state digest differs from --expect-digest, on by default
+//! digest_mismatch = true
+//! # delivery attempts per target and the wait before the first retry
+//! attempts = 3
+//! backoff = "1s"
+//!
+//! [[alert]]
+//! url = "https://hooks.slack.com/services/T000/B000/XXXX"
+//! # `json` (the default) or `slack`
+//! format = "slack"
+//! # signs the body, sent as `X-Txe-Signature: sha256=<hex>`
+//! secret = "s3cret"
+//! ```
+use crate::{
+    run_status::RunStatus,
+    webhooks::{deserialize_period, sign, REQUEST_TIMEOUT},
+};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, path::Path, time::Duration};
+use tracing::{info, warn};
+use ureq::Agent;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertFormat {
+    /// the anomalies and the `--status-json` summary
+    #[default]
+    Json,
+    /// a message for a Slack incoming webhook, `{"text": ...}`
+    Slack,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertTarget {
+    pub url: String,
+    #[serde(default)]
+    pub format: AlertFormat,
+    pub secret: Option<String>,
+}
+
+/// The contents of an `--alerts` file, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    pub max_reject_rate: Option<f64>,
+    pub max_parse_failure_rate: Option<f64>,
+    #[serde(default = "enabled")]
+    pub digest_mismatch: bool,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_backoff", deserialize_with = "deserialize_period")]
+    pub backoff: Duration,
+    #[serde(default, rename = "alert")]
+    pub targets: Vec<AlertTarget>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// A threshold the run exceeded.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Anomaly {
+    RejectRate { rate: f64, threshold: f64 },
+    ParseFailureRate { rate: f64, threshold: f64 },
+    DigestMismatch { expected: String, actual: String },
+}
+
+impl Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::RejectRate { rate, threshold } => write!(
+                f,
+                "reject rate {:.2}% exceeds {:.2}%",
+                rate * 100.0,
+                threshold * 100.0
+            ),
+            Anomaly::ParseFailureRate { rate, threshold } => write!(
+                f,
+                "parse failure rate {:.2}% exceeds {:.2}%",
+                rate * 100.0,
+                threshold * 100.0
+            ),
+            Anomaly::DigestMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "state digest {actual} differs from the expected {expected}"
+                )
+            }
+        }
+    }
+}
+
+/// The body of a `json` alert.
+#[derive(Debug, Serialize)]
+struct Alert<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input: Option<String>,
+    anomalies: &'a [Anomaly],
+    run: &'a RunStatus,
+}
+
+impl AlertConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let config: AlertConfig =
+            toml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        if config.attempts == 0 {
+            anyhow::bail!("{}: attempts needs to be at least 1", path.display());
+        }
+        for rate in [config.max_reject_rate, config.max_parse_failure_rate]
+            .into_iter()
+            .flatten()
+        {
+            if !(0.0..=1.0).contains(&rate) {
+                anyhow::bail!("{}: rates need to be between 0 and 1", path.display());
+            }
+        }
+        Ok(config)
+    }
+
+    /// The thresholds the run exceeded. A rate is only checked when there was
+    /// something to count, the digest only when both digests are known.
+    pub fn anomalies(&self, status: &RunStatus, expected_digest: Option<&str>) -> Vec<Anomaly> {
+        let rate = |count: u64, total: u64| (total > 0).then(|| count as f64 / total as f64);
+        let mut anomalies = Vec::new();
+        if let (Some(threshold), Some(rate)) = (
+            self.max_reject_rate,
+            rate(status.rejects, status.events_processed),
+        ) {
+            if rate > threshold {
+                anomalies.push(Anomaly::RejectRate { rate, threshold });
+            }
+        }
+        if let (Some(threshold), Some(rate)) = (
+            self.max_parse_failure_rate,
+            rate(status.parse_failures, status.rows_read),
+        ) {
+            if rate > threshold {
+                anomalies.push(Anomaly::ParseFailureRate { rate, threshold });
+            }
+        }
+        if let (true, Some(expected), Some(actual)) =
+            (self.digest_mismatch, expected_digest, &status.state_digest)
+        {
+            if !expected.eq_ignore_ascii_case(actual) {
+                anomalies.push(Anomaly::DigestMismatch {
+                    expected: expected.to_string(),
+                    actual: actual.clone(),
+                });
+            }
+        }
+        anomalies
+    }
+
+    /// Alerts every target when the run exceeded a threshold, returns the
+    /// anomalies. Fails when a target did not accept the alert.
+    pub fn notify(
+        &self,
+        status: &RunStatus,
+        input: Option<&Path>,
+        expected_digest: Option<&str>,
+    ) -> anyhow::Result<Vec<Anomaly>> {
+        let anomalies = self.anomalies(status, expected_digest);
+        if anomalies.is_empty() {
+            return Ok(anomalies);
+        }
+        let input = input.map(|path| path.display().to_string());
+        let json = serde_json::to_vec(&Alert {
+            input: input.clone(),
+            anomalies: &anomalies,
+            run: status,
+        })?;
+        let slack = serde_json::to_vec(&serde_json::json!({
+            "text": slack_text(&anomalies, status, input.as_deref()),
+        }))?;
+
+        let agent = Agent::new_with_config(
+            Agent::config_builder()
+                .timeout_global(Some(REQUEST_TIMEOUT))
+                .http_status_as_error(false)
+                .build(),
+        );
+        let mut failed = 0;
+        for target in &self.targets {
+            let body = match target.format {
+                AlertFormat::Json => &json,
+                AlertFormat::Slack => &slack,
+            };
+            if let Err(error) = self.deliver(&agent, target, body) {
+                warn!(url = target.url, %error, "failed to send the run alert");
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            anyhow::bail!("{failed} alert targets did not accept the run alert");
+        }
+        info!(anomalies = anomalies.len(), "sent the run alert");
+        Ok(anomalies)
+    }
+
+    /// Posts the body until it is accepted or the attempts run out.
+    fn deliver(&self, agent: &Agent, target: &AlertTarget, body: &[u8]) -> anyhow::Result<()> {
+        let signature = target
+            .secret
+            .as_ref()
+            .map(|secret| format!("sha256={}", sign(secret, body)));
+        let mut backoff = self.backoff;
+        for attempt in 1..=self.attempts {
+            let mut request = agent
+                .post(&target.url)
+                .header("Content-Type", "application/json");
+            if let Some(signature) = &signature {
+                request = request.header("X-Txe-Signature", signature);
+            }
+            let error = match request.send(body) {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => format!("status {}", response.status().as_u16()),
+                Err(error) => error.to_string(),
+            };
+            if attempt == self.attempts {
+                anyhow::bail!("{error} after {attempt} attempts");
+            }
+            warn!(url = target.url, %error, attempt, "retrying the run alert");
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+        unreachable!("attempts is at least 1")
+    }
+}
+
+fn slack_text(anomalies: &[Anomaly], status: &RunStatus, input: Option<&str>) -> String {
+    let mut text = format!(
+        "Anomalous run{}: exit code {}, {} events, {} rejects, {} parse failures",
+        input
+            .map(|input| format!(" of {input}"))
+            .unwrap_or_default(),
+        status.exit_code,
+        status.events_processed,
+        status.rejects,
+        status.parse_failures
+    );
+    for anomaly in anomalies {
+        text.push_str(&format!("\n• {anomaly}"));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_status::Outcome;
+
+    #[test]
+    fn test_alerts() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let config: AlertConfig = toml::from_str(&format!(
+            r#"
+            max_reject_rate = 0.1
+            max_parse_failure_rate = 0.5
+            attempts = 2
+            backoff = "10ms"
+
+            [[alert]]
+            url = "http://{addr}/json"
+            secret = "s3cret"
+
+            [[alert]]
+            url = "http://{addr}/slack"
+            format = "slack"
+            "#
+        ))
+        .unwrap();
+
+        let mut status = RunStatus::new(Outcome::SuccessWithRejects, Duration::ZERO, None);
+        status.rows_read = 100;
+        status.events_processed = 90;
+        status.rejects = 9;
+        status.parse_failures = 10;
+        status.state_digest = Some("ab12".to_string());
+        assert!(config.anomalies(&status, Some("AB12")).is_empty());
+
+        status.rejects = 18;
+        let thread = std::thread::spawn(move || {
+            config.notify(&status, Some(Path::new("input.csv")), Some("cd34"))
+        });
+        let mut bodies = Vec::new();
+        // the json alert is retried once
+        for status in [500, 200, 200] {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            if request.url() == "/json" {
+                let signature = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("X-Txe-Signature"))
+                    .map(|header| header.value.to_string());
+                assert_eq!(
+                    signature,
+                    Some(format!("sha256={}", sign("s3cret", body.as_bytes())))
+                );
+            }
+            request.respond(tiny_http::Response::empty(status)).unwrap();
+            if status == 200 {
+                bodies.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+            }
+        }
+        let anomalies = thread.join().unwrap().unwrap();
+        assert_eq!(
+            anomalies,
+            [
+                Anomaly::RejectRate {
+                    rate: 0.2,
+                    threshold: 0.1
+                },
+                Anomaly::DigestMismatch {
+                    expected: "cd34".to_string(),
+                    actual: "ab12".to_string()
+                }
+            ]
+        );
+
+        assert_eq!(bodies[0]["input"], "input.csv");
+        assert_eq!(bodies[0]["anomalies"][0]["kind"], "reject_rate");
+        assert_eq!(bodies[0]["run"]["rejects"], 18);
+        assert_eq!(
+            bodies[1]["text"],
+            "Anomalous run of input.csv: exit code 3, 90 events, 18 rejects, 10 parse failures\n\
+             • reject rate 20.00% exceeds 10.00%\n\
+             • state digest ab12 differs from the expected cd34"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+//! Aggregates over a processed run for dashboards, see the `analyze`
+//! subcommand: the top clients by volume and by charged back amount, the
+//! distribution of the transaction sizes and the dispute ratio per decile of
+//! the clients ranked by volume.
+//!
+//! The aggregates are taken from the transaction history, so only applied
+//! events count. Volume is the sum of the deposits and withdrawals of a
+//! client.
+use crate::{
+    data_types::{Price, TransactionType, PRICE_SCALAR},
+    pseudonym::{self, DisplayClient},
+    transaction_context::TransactionContext,
+};
+use csv::Writer;
+use serde::Serialize;
+use std::{io::Write, str::FromStr};
+
+/// Upper bounds of the transaction size buckets, the last bucket is open.
+const SIZE_BOUNDS: [i64; 5] = [1, 10, 100, 1_000, 10_000];
+
+/// Output format of the analysis.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AnalysisFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for AnalysisFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(AnalysisFormat::Csv),
+            "json" => Ok(AnalysisFormat::Json),
+            _ => Err(format!(
+                "unknown analysis format `{s}`, expected csv or json"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct ClientAmount {
+    pub client: DisplayClient,
+    pub amount: Price,
+}
+
+/// Deposits and withdrawals with an amount in `[from, to)`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SizeBucket {
+    pub from: Price,
+    pub to: Option<Price>,
+    pub count: u64,
+}
+
+/// Clients ranked by volume, decile 1 has the lowest volume.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Decile {
+    pub decile: u8,
+    pub clients: usize,
+    pub transactions: u64,
+    pub disputes: u64,
+    /// disputes per deposit and withdrawal
+    pub dispute_ratio: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Analysis {
+    pub top_volume: Vec<ClientAmount>,
+    pub top_chargebacks: Vec<ClientAmount>,
+    pub sizes: Vec<SizeBucket>,
+    pub deciles: Vec<Decile>,
+}
+
+#[derive(Debug, Default)]
+struct ClientStats {
+    volume: i64,
+    chargebacks: i64,
+    transactions: u64,
+    disputes: u64,
+}
+
+/// Aggregates the history of the context, history tracking needs to be
+/// enabled. The top lists hold at most `top` clients.
+pub fn analyze(context: &TransactionContext, top: usize) -> Analysis {
+    let mut sizes = [0; SIZE_BOUNDS.len() + 1];
+    let mut clients: Vec<(u16, ClientStats)> = context
+        .iter_accounts()
+        .map(|(client_id, _)| {
+            let mut stats = ClientStats::default();
+            for record in context.history(client_id) {
+                match record.ty {
+                    TransactionType::Deposit | TransactionType::Withdrawal => {
+                        stats.volume = stats.volume.saturating_add(record.amount.0);
+                        stats.transactions += 1;
+                        let bucket = SIZE_BOUNDS
+                            .iter()
+                            .position(|bound| record.amount.0 < bound * PRICE_SCALAR)
+                            .unwrap_or(SIZE_BOUNDS.len());
+                        sizes[bucket] += 1;
+                    }
+                    TransactionType::Dispute => stats.disputes += 1,
+                    TransactionType::Chargeback => {
+                        stats.chargebacks = stats.chargebacks.saturating_add(record.amount.0);
+                    }
+                    _ => {}
+                }
+            }
+            (client_id, stats)
+        })
+        .collect();
+
+    let top_by = |clients: &mut Vec<(u16, ClientStats)>, amount: fn(&ClientStats) -> i64| {
+        clients.sort_unstable_by_key(|(client_id, stats)| (-amount(stats), *client_id));
+        clients
+            .iter()
+            .filter(|(_, stats)| amount(stats) > 0)
+            .take(top)
+            .map(|(client_id, stats)| ClientAmount {
+                client: pseudonym::client(*client_id),
+                amount: Price(amount(stats)),
+            })
+            .collect()
+    };
+    let top_chargebacks = top_by(&mut clients, |stats| stats.chargebacks);
+    let top_volume = top_by(&mut clients, |stats| stats.volume);
+
+    // sorted by descending volume by now
+    let n = clients.len();
+    let mut deciles: Vec<Decile> = (1..=10)
+        .map(|decile| Decile {
+            decile,
+            clients: 0,
+            transactions: 0,
+            disputes: 0,
+            dispute_ratio: 0.0,
+        })
+        .collect();
+    for (rank, (_, stats)) in clients.iter().rev().enumerate() {
+        let decile = &mut deciles[rank * 10 / n];
+        decile.clients += 1;
+        decile.transactions += stats.transactions;
+        decile.disputes += stats.disputes;
+    }
+    deciles.retain(|decile| decile.clients > 0);
+    for decile in &mut deciles {
+        if decile.transactions > 0 {
+            decile.dispute_ratio = decile.disputes as f64 / decile.transactions as f64;
+        }
+    }
+
+    let bound = |i: usize| SIZE_BOUNDS.get(i).map(|bound| Price(bound * PRICE_SCALAR));
+    let sizes = sizes
+        .iter()
+        .enumerate()
+        .map(|(i, count)| SizeBucket {
+            from: i.checked_sub(1).and_then(bound).unwrap_or_default(),
+            to: bound(i),
+            count: *count,
+        })
+        .collect();
+
+    Analysis {
+        top_volume,
+        top_chargebacks,
+        sizes,
+        deciles,
+    }
+}
+
+/// Writes the analysis. The csv holds a row per value, with the section it
+/// belongs to and its key: the client, the size bucket or the decile.
+pub fn write_analysis(
+    writer: impl Write,
+    analysis: &Analysis,
+    format: AnalysisFormat,
+) -> anyhow::Result<()> {
+    if format == AnalysisFormat::Json {
+        let mut writer = writer;
+        serde_json::to_writer_pretty(&mut writer, analysis)?;
+        writeln!(writer)?;
+        return Ok(());
+    }
+
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(["section", "key", "value"])?;
+    for (section, top) in [
+        ("top_volume", &analysis.top_volume),
+        ("top_chargebacks", &analysis.top_chargebacks),
+    ] {
+        for entry in top {
+            writer.write_record([
+                section,
+                &entry.client.to_string(),
+                &entry.amount.to_string(),
+            ])?;
+        }
+    }
+    for bucket in &analysis.sizes {
+        let to = bucket.to.map(|to| to.to_string()).unwrap_or_default();
+        let key = format!("{}-{to}", bucket.from);
+        writer.write_record(["size", &key, &bucket.count.to_string()])?;
+    }
+    for decile in &analysis.deciles {
+        let (key, ratio) = (decile.decile.to_string(), decile.dispute_ratio.to_string());
+        writer.write_record(["dispute_ratio", &key, &ratio])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionEvent;
+
+    #[test]
+    fn test_analyze() {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        let mut context = TransactionContext::new();
+        context.track_history();
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 5_000),
+            event(TransactionType::Deposit, 2, 2, 500_000),
+            event(TransactionType::Withdrawal, 2, 3, 200_000),
+            event(TransactionType::Deposit, 3, 4, 150_000_000),
+            event(TransactionType::Deposit, 3, 5, 20_000),
+            event(TransactionType::Dispute, 3, 5, 0),
+            event(TransactionType::Chargeback, 3, 5, 0),
+            // rejected, not counted
+            event(TransactionType::Withdrawal, 1, 6, 10_000),
+        ] {
+            let _ = context.apply(&event);
+        }
+
+        let analysis = analyze(&context, 2);
+        let clients = |top: &[ClientAmount]| {
+            top.iter()
+                .map(|e| (e.client.0, e.amount.0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            clients(&analysis.top_volume),
+            [(3, 150_020_000), (2, 700_000)]
+        );
+        assert_eq!(clients(&analysis.top_chargebacks), [(3, 20_000)]);
+        let counts: Vec<_> = analysis.sizes.iter().map(|b| b.count).collect();
+        assert_eq!(counts, [1, 1, 2, 0, 0, 1]);
+        assert_eq!(analysis.sizes[5].to, None);
+
+        assert_eq!(analysis.deciles.len(), 3);
+        let top = analysis.deciles.last().unwrap();
+        assert_eq!((top.transactions, top.disputes), (2, 1));
+        assert_eq!(top.dispute_ratio, 0.5);
+
+        let mut csv = Vec::new();
+        write_analysis(&mut csv, &analysis, AnalysisFormat::Csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.contains("top_volume,3,15002.0\n"));
+        assert!(csv.contains("size,10000.0-,1\n"));
+    }
+}
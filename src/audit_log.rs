@@ -0,0 +1,394 @@
+use crate::{
+    data_types::{Account, ReasonCode, TransactionError, TransactionEvent, TransactionFlags},
+    pseudonym::{self, DisplayClient},
+    transaction_context::PrunedAccount,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use tracing::info;
+
+/// Records between two anchors.
+pub const ANCHOR_EVERY: u64 = 10_000;
+
+/// The hash the first record links to.
+const GENESIS: [u8; 32] = [0; 32];
+
+/// A record linked to the one before it.
+#[derive(Debug, Serialize)]
+struct Chained<'a, T> {
+    #[serde(flatten)]
+    record: &'a T,
+    prev: String,
+}
+
+/// The head of the chain after the given number of records, to be kept
+/// outside of the log.
+#[derive(Debug, Serialize)]
+struct AnchorRecord {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    records: u64,
+    digest: String,
+}
+
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    tx: u32,
+    client: DisplayClient,
+    amount: String,
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "ReasonCode::is_empty")]
+    reason_code: ReasonCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    merged_client: Option<DisplayClient>,
+    #[serde(flatten)]
+    balances: Option<Balances>,
+}
+
+#[derive(Debug, Serialize)]
+struct Balances {
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+/// A pruned account with everything needed to restore it.
+#[derive(Debug, Serialize)]
+struct PruneRecord {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    client: DisplayClient,
+    tx_count: u32,
+    disputes: u32,
+    transactions: Vec<PrunedTransaction>,
+}
+
+#[derive(Debug, Serialize)]
+struct PrunedTransaction {
+    tx: u32,
+    amount: String,
+    state: &'static str,
+}
+
+/// Writes one JSON object per line for every applied or rejected event,
+/// including the balances of the account after the event.
+///
+/// The records form a hash chain: every record carries the SHA-256 of the line
+/// before it in `prev`, so changing, removing or reordering records breaks the
+/// chain, see [`verify`]. Every [`ANCHOR_EVERY`] records and at the end, an
+/// anchor record holds the digest of the chain so far. Anchors kept outside of
+/// the log also reveal a log that was rewritten as a whole.
+///
+/// Writing is done on the hot path, so io errors do not interrupt processing.
+/// The first error is kept and returned by [`AuditLog::finish`].
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    error: Option<std::io::Error>,
+    line: Vec<u8>,
+    head: [u8; 32],
+    records: u64,
+    anchored: u64,
+}
+
+impl AuditLog {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(File::create(path)?),
+            error: None,
+            line: Vec::new(),
+            head: GENESIS,
+            records: 0,
+            anchored: 0,
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let record = AuditRecord {
+            ty: event.ty.as_str(),
+            tx: event.tx,
+            client: pseudonym::client(event.client_id),
+            amount: event.amount.to_string(),
+            outcome: if result.is_ok() {
+                "applied"
+            } else {
+                "rejected"
+            },
+            reason: result.err().map(|e| e.as_str()),
+            reason_code: event.reason,
+            merged_client: event.merged_client.map(pseudonym::client),
+            balances: account.map(|account| Balances {
+                available: account.available().to_string(),
+                held: account.held.to_string(),
+                total: account.total.to_string(),
+                locked: account.locked,
+            }),
+        };
+
+        self.write(&record);
+    }
+
+    /// Records a pruned account (`"type": "prune"`) with its stored
+    /// transactions, so it can be recovered from the log.
+    pub fn record_prune(&mut self, pruned: &PrunedAccount) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let record = PruneRecord {
+            ty: "prune",
+            client: pseudonym::client(pruned.client_id),
+            tx_count: pruned.account.meta.tx_count,
+            disputes: pruned.account.meta.disputes,
+            transactions: pruned
+                .transactions
+                .iter()
+                .map(|(tx, (amount, flags, _))| PrunedTransaction {
+                    tx: *tx,
+                    amount: amount.to_string(),
+                    state: match flags {
+                        TransactionFlags::None => "none",
+                        TransactionFlags::Disputed => "disputed",
+                        TransactionFlags::Resolved => "resolved",
+                        TransactionFlags::Chargeback => "chargeback",
+                        TransactionFlags::Final => "final",
+                    },
+                })
+                .collect(),
+        };
+        self.write(&record);
+    }
+
+    fn write(&mut self, record: &impl Serialize) {
+        self.append(record);
+        self.records += 1;
+        if self.records - self.anchored >= ANCHOR_EVERY {
+            self.anchor();
+        }
+    }
+
+    fn append(&mut self, record: &impl Serialize) {
+        let record = Chained {
+            record,
+            prev: hex(&self.head),
+        };
+        self.line.clear();
+        let res = serde_json::to_writer(&mut self.line, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(&self.line))
+            .and_then(|_| self.writer.write_all(b"\n"));
+        match res {
+            Ok(()) => self.head = Sha256::digest(&self.line).into(),
+            Err(e) => self.error = Some(e),
+        }
+    }
+
+    /// Writes an anchor with the digest of the chain so far, unless nothing
+    /// was recorded since the last one.
+    pub fn anchor(&mut self) {
+        if self.error.is_some() || self.records == self.anchored {
+            return;
+        }
+        self.anchored = self.records;
+        let digest = hex(&self.head);
+        info!(records = self.records, %digest, "audit log anchor");
+        self.append(&AnchorRecord {
+            ty: "anchor",
+            records: self.records,
+            digest,
+        });
+    }
+
+    /// Anchors and flushes the log, returns the first error that occurred
+    /// while writing.
+    pub fn finish(mut self) -> std::io::Result<()> {
+        self.anchor();
+        self.flush()
+    }
+
+    /// Finishes the log and moves it to `<path>.1`, shifting earlier rotated
+    /// logs to `<path>.2`, .. The new log is a chain of its own.
+    pub fn rotate(&mut self) -> std::io::Result<()> {
+        self.anchor();
+        self.flush()?;
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{n}"));
+            PathBuf::from(path)
+        };
+        let mut last = 0;
+        while rotated(last + 1).exists() {
+            last += 1;
+        }
+        for n in (1..=last).rev() {
+            std::fs::rename(rotated(n), rotated(n + 1))?;
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        info!(path = %self.path.display(), records = self.records, "rotated audit log");
+        *self = AuditLog::create(&self.path)?;
+        Ok(())
+    }
+
+    /// Like [`AuditLog::finish`], but keeps the log open for further records.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.writer.flush()
+    }
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Outcome of [`verify`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verification {
+    /// records, excluding anchors
+    pub records: u64,
+    pub anchors: u64,
+    /// digest of the last anchor
+    pub digest: Option<String>,
+}
+
+/// Verifies the hash chain and the anchors of an audit log. With `anchor`,
+/// the log must also hold an anchor with the given digest.
+pub fn verify(reader: impl BufRead, anchor: Option<&str>) -> anyhow::Result<Verification> {
+    let mut head = GENESIS;
+    let mut verification = Verification {
+        records: 0,
+        anchors: 0,
+        digest: None,
+    };
+    let mut anchor_found = false;
+    let mut anchored = true;
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        let number = index + 1;
+        let value: serde_json::Value = serde_json::from_str(&line)
+            .map_err(|e| anyhow::anyhow!("line {number}: not a JSON record: {e}"))?;
+        if value["prev"].as_str() != Some(hex(&head).as_str()) {
+            anyhow::bail!("line {number}: does not link to the record before it");
+        }
+        if value["type"] == "anchor" {
+            let digest = hex(&head);
+            if value["digest"].as_str() != Some(digest.as_str())
+                || value["records"].as_u64() != Some(verification.records)
+            {
+                anyhow::bail!("line {number}: the anchor does not match the records before it");
+            }
+            anchor_found |= anchor.is_some_and(|anchor| anchor.eq_ignore_ascii_case(&digest));
+            verification.anchors += 1;
+            verification.digest = Some(digest);
+            anchored = true;
+        } else {
+            verification.records += 1;
+            anchored = false;
+        }
+        head = Sha256::digest(line.as_bytes()).into();
+    }
+    if !anchored {
+        anyhow::bail!("the log does not end with an anchor, it may be truncated");
+    }
+    if let (Some(anchor), false) = (anchor, anchor_found) {
+        anyhow::bail!("the log holds no anchor with digest {anchor}");
+    }
+    Ok(verification)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionType;
+
+    #[test]
+    fn test_audit_record() {
+        let path = std::env::temp_dir().join("txe_test_audit_record.jsonl");
+        let mut log = AuditLog::create(&path).unwrap();
+
+        let event =
+            TransactionEvent::new(TransactionType::Withdrawal, 3, 7, 2.5.try_into().unwrap());
+        let account = Account {
+            total: 10.0.try_into().unwrap(),
+            ..Default::default()
+        };
+        log.record(&event, Ok(()), Some(&account));
+        log.record(&event, Err(TransactionError::Duplicate), Some(&account));
+        log.record_prune(&PrunedAccount {
+            client_id: 3,
+            account: Account::default(),
+            transactions: vec![(1, (2.5.try_into().unwrap(), TransactionFlags::Resolved, 3))],
+        });
+        log.finish().unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0]["type"], "withdrawal");
+        assert_eq!(lines[0]["outcome"], "applied");
+        assert_eq!(lines[0]["available"], "10.0");
+        assert!(lines[0].get("reason").is_none());
+        assert_eq!(lines[1]["outcome"], "rejected");
+        assert_eq!(lines[1]["reason"], "duplicate");
+        assert_eq!(lines[2]["type"], "prune");
+        assert_eq!(lines[2]["transactions"][0]["state"], "resolved");
+        assert_eq!(lines[3]["type"], "anchor");
+        assert_eq!(lines[3]["records"], 3);
+        let _ = std::fs::remove_file(&path);
+
+        let digest = lines[3]["digest"].as_str().unwrap();
+        let verification = verify(content.as_bytes(), Some(digest)).unwrap();
+        assert_eq!(verification.records, 3);
+        assert_eq!(verification.digest.as_deref(), Some(digest));
+        assert!(verify(content.as_bytes(), Some(&"0".repeat(64))).is_err());
+
+        let tampered = content.replacen("\"2.5\"", "\"0.5\"", 1);
+        assert!(verify(tampered.as_bytes(), None).is_err());
+        let truncated: String = content.lines().take(2).map(|l| format!("{l}\n")).collect();
+        assert!(verify(truncated.as_bytes(), None).is_err());
+        let removed: String = content
+            .lines()
+            .enumerate()
+            .filter(|(i, _)| *i != 1)
+            .map(|(_, l)| format!("{l}\n"))
+            .collect();
+        assert!(verify(removed.as_bytes(), None).is_err());
+
+        let mut log = AuditLog::create(&path).unwrap();
+        log.record(&event, Ok(()), Some(&account));
+        log.rotate().unwrap();
+        log.record(&event, Ok(()), Some(&account));
+        log.finish().unwrap();
+        let rotated = path.with_extension("jsonl.1");
+        for path in [&path, &rotated] {
+            let content = std::fs::read(path).unwrap();
+            assert_eq!(verify(content.as_slice(), None).unwrap().records, 1);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+//! Parallel replay of files that are complete already, see the `replay`
+//! subcommand. The rows are parsed in parallel and the events grouped by
+//! client. Every rayon thread runs a worker, which takes the next client
+//! whenever it is done with one, largest client first, and applies its events
+//! with an [`Engine`] of its own. A client with a lot of events keeps one
+//! worker busy while the others share the remaining clients, instead of
+//! holding up the clients that would share a fixed partition with it. The
+//! accounts are merged afterwards.
+//!
+//! Only the order of the events of a client is kept. Clients interact through
+//! their tx ids, which are unique across all clients, so a first pass builds a
+//! [`TxIndex`] of the stored transactions of all clients, which the tx ids of
+//! the events are checked against: a reused tx id is rejected as a duplicate
+//! and a dispute of the transaction of another client as `client_mismatch`,
+//! like in a sequential run. The index takes the first deposit, adjustment or
+//! recovery of a tx id as its owner, the result differs from a sequential run
+//! only when that event is rejected itself, e.g. as its account is locked.
+//!
+//! Without the index tx ids are not checked across clients at all. Merges
+//! move funds between clients and are not supported, the replay fails on
+//! them.
+use crate::{
+    csv_source::{CsvSource, Row},
+    data_types::{Account, Rejected, TransactionError, TransactionEvent, TransactionType},
+    engine::{Engine, Outcome},
+    pipeline::Message,
+};
+use rayon::prelude::*;
+use std::{collections::HashMap, path::Path, sync::Mutex};
+use tracing::info;
+
+#[derive(Debug, Default)]
+pub struct Replay {
+    /// ordered by client
+    pub accounts: Vec<(u16, Account)>,
+    /// in the order of the input
+    pub rejects: Vec<Rejected>,
+}
+
+/// The client and position in the input of the first deposit, adjustment or
+/// recovery of every tx id.
+#[derive(Debug, Default)]
+pub struct TxIndex(HashMap<u32, (usize, u16)>);
+
+impl TxIndex {
+    /// Indexes the deposits, adjustments and recoveries, on the threads of the
+    /// current rayon pool.
+    pub fn build(events: &[TransactionEvent]) -> Self {
+        let index = events
+            .par_iter()
+            .enumerate()
+            .filter(|(_, event)| {
+                matches!(
+                    event.ty,
+                    TransactionType::Deposit
+                        | TransactionType::Adjustment
+                        | TransactionType::Recovery
+                )
+            })
+            .fold(HashMap::new, |mut index, (idx, event)| {
+                index.entry(event.tx).or_insert((idx, event.client_id));
+                index
+            })
+            .reduce(HashMap::new, |mut a, b| {
+                for (tx, first) in b {
+                    a.entry(tx)
+                        .and_modify(|known: &mut (usize, u16)| *known = (*known).min(first))
+                        .or_insert(first);
+                }
+                a
+            });
+        TxIndex(index)
+    }
+
+    /// The reject of the event at `idx` when it uses the tx id of a deposit,
+    /// adjustment or recovery of another client before it.
+    fn check(&self, idx: usize, event: &TransactionEvent) -> Option<TransactionError> {
+        let (first, owner) = *self.0.get(&event.tx)?;
+        if first >= idx || owner == event.client_id {
+            return None;
+        }
+        match event.ty {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Adjustment
+            | TransactionType::Recovery => Some(TransactionError::Duplicate),
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                Some(TransactionError::ClientMismatch)
+            }
+            TransactionType::Unlock | TransactionType::Merge => None,
+        }
+    }
+}
+
+/// Reads and replays the file on `threads` threads, all available cores when
+/// 0. With `two_pass` the tx ids are indexed first, see [`TxIndex`].
+pub fn replay_file(path: &Path, threads: usize, two_pass: bool) -> anyhow::Result<Replay> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    pool.install(|| {
+        let mut source = CsvSource::open(path, None)?;
+        let parser = source.parser();
+        let mut rows = Vec::new();
+        while let Some(row) = source.read_row() {
+            if let Row::Record(record, row) = row {
+                rows.push((record, row));
+            }
+        }
+        if let Some(error) = source.take_error() {
+            return Err(error);
+        }
+        let messages: Vec<_> = rows
+            .into_par_iter()
+            .filter_map(|(record, row)| parser.parse(record, row))
+            .collect();
+        let mut events = Vec::with_capacity(messages.len());
+        let mut rejected = Vec::new();
+        for message in messages {
+            match message {
+                Message::Event(event) => events.push(event),
+                Message::Rejected(reject) => rejected.push((events.len(), reject)),
+                Message::Flush | Message::EndOfStream => {}
+            }
+        }
+        let index = two_pass.then(|| TxIndex::build(&events));
+        replay_with_rejects(events, index.as_ref(), rejected)
+    })
+}
+
+/// Applies the events grouped by client, on the threads of the current rayon
+/// pool. Fails on merges, see the module documentation.
+pub fn replay(events: Vec<TransactionEvent>, index: Option<&TxIndex>) -> anyhow::Result<Replay> {
+    replay_with_rejects(events, index, Vec::new())
+}
+
+/// Like [`replay`], with events rejected while parsing. Each comes with the
+/// number of events in front of it, to keep the rejects in the order of the
+/// input.
+fn replay_with_rejects(
+    events: Vec<TransactionEvent>,
+    index: Option<&TxIndex>,
+    mut rejects: Vec<(usize, Rejected)>,
+) -> anyhow::Result<Replay> {
+    if let Some(merge) = events.iter().find(|e| e.ty == TransactionType::Merge) {
+        anyhow::bail!(
+            "merges can not be replayed in parallel, tx {} merges client {:?} into {}",
+            merge.tx,
+            merge.merged_client,
+            merge.client_id
+        );
+    }
+
+    let mut clients: HashMap<u16, Vec<(usize, TransactionEvent)>> = HashMap::new();
+    for (idx, event) in events.into_iter().enumerate() {
+        clients
+            .entry(event.client_id)
+            .or_default()
+            .push((idx, event));
+    }
+    let mut clients: Vec<_> = clients.into_values().collect();
+    // the largest first, so no worker picks up a large client at the end
+    clients.sort_unstable_by_key(|events| std::cmp::Reverse(events.len()));
+    let workers = rayon::current_num_threads().min(clients.len()).max(1);
+    info!(clients = clients.len(), workers, "replaying in parallel");
+
+    let queue = Mutex::new(clients.into_iter());
+    let next_client = || queue.lock().expect("client queue is poisoned").next();
+    let replays: Vec<_> = (0..workers)
+        .into_par_iter()
+        .map(|_| -> anyhow::Result<_> {
+            let mut accounts = Vec::new();
+            let mut rejects = Vec::new();
+            while let Some(events) = next_client() {
+                // other clients are only seen through the index, a shared
+                // engine would see tx ids they store later in the input
+                let mut engine = Engine::new();
+                for (idx, event) in events {
+                    let error = match index.and_then(|index| index.check(idx, &event)) {
+                        Some(error) => error,
+                        None => match engine.push(event) {
+                            Outcome::Applied(_) => continue,
+                            Outcome::Rejected(error) => error,
+                        },
+                    };
+                    rejects.push((idx, Rejected { event, error }));
+                }
+                accounts.extend(engine.finish()?);
+            }
+            Ok((accounts, rejects))
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let mut replay = Replay::default();
+    for (accounts, worker_rejects) in replays {
+        replay.accounts.extend(accounts);
+        rejects.extend(worker_rejects);
+    }
+    replay.accounts.sort_by_key(|(client_id, _)| *client_id);
+    // stable, the rejects of the parser come before the event they precede
+    rejects.sort_by_key(|(idx, _)| *idx);
+    replay.rejects = rejects.into_iter().map(|(_, reject)| reject).collect();
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::Price, transaction_context::TransactionContext};
+
+    fn event(ty: TransactionType, client_id: u16, tx: u32, amount: i64) -> TransactionEvent {
+        TransactionEvent {
+            timestamp: Some(1_700_000_000),
+            ..TransactionEvent::new(ty, client_id, tx, Price(amount))
+        }
+    }
+
+    /// The result of a sequential run.
+    fn sequential(events: &[TransactionEvent]) -> Replay {
+        let mut context = TransactionContext::new();
+        let mut rejects = Vec::new();
+        for event in events {
+            if let Err(error) = context.apply(event) {
+                rejects.push(Rejected {
+                    event: *event,
+                    error,
+                });
+            }
+        }
+        let mut accounts: Vec<_> = context.into_iter_accounts().collect();
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        Replay { accounts, rejects }
+    }
+
+    fn rejected_txs(replay: &Replay) -> Vec<(u32, TransactionError)> {
+        replay
+            .rejects
+            .iter()
+            .map(|reject| (reject.event.tx, reject.error))
+            .collect()
+    }
+
+    #[test]
+    fn test_replay() {
+        use TransactionType::*;
+        let mut events = Vec::new();
+        for tx in 0..1000u32 {
+            let client_id = (tx % 37) as u16;
+            events.push(event(Deposit, client_id, tx * 4, 10_000 + tx as i64));
+            events.push(event(Withdrawal, client_id, tx * 4 + 1, 15_000));
+            if tx % 5 == 0 {
+                events.push(event(Dispute, client_id, tx * 4, 0));
+                events.push(event(Chargeback, client_id, tx * 4, 0));
+            }
+            // a hot client with as many events as all the others together
+            events.push(event(Deposit, 100, tx * 4 + 2, 1));
+            events.push(event(Withdrawal, 100, tx * 4 + 3, 1));
+        }
+        let expected = sequential(&events);
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let replayed = pool.install(|| replay(events.clone(), None)).unwrap();
+        assert_eq!(rejected_txs(&replayed), rejected_txs(&expected));
+        assert_eq!(replayed.accounts, expected.accounts);
+
+        let mut merge = event(Merge, 1, 5000, 0);
+        merge.merged_client = Some(2);
+        events.push(merge);
+        assert!(pool.install(|| replay(events, None)).is_err());
+    }
+
+    #[test]
+    fn test_two_pass_matches_sequential() {
+        use TransactionType::*;
+        let mut events = Vec::new();
+        for tx in 0..300u32 {
+            let client_id = (tx % 7) as u16;
+            let other = |offset| (client_id + offset) % 7;
+            let id = tx * 3;
+            events.push(event(Deposit, client_id, id, 50_000));
+            // the deposit of another client, and the one of the next round
+            events.push(event(Deposit, other(1), id, 10_000));
+            events.push(event(Withdrawal, other(2), id + 3, 1_000));
+            events.push(event(Dispute, other(3), id, 0));
+            events.push(event(Dispute, other(4), id + 3, 0));
+            if tx % 4 == 0 {
+                events.push(event(Dispute, client_id, id, 0));
+                events.push(event(Resolve, client_id, id, 0));
+            }
+            let mut adjustment = event(Adjustment, client_id, id + 1, 500);
+            adjustment.reason = "fix".parse().unwrap();
+            adjustment.authenticated = true;
+            events.push(adjustment);
+            events.push(event(Withdrawal, other(5), id + 1, 1_000));
+        }
+        let expected = sequential(&events);
+        for error in [
+            TransactionError::Duplicate,
+            TransactionError::ClientMismatch,
+            TransactionError::NotFound,
+        ] {
+            assert!(expected.rejects.iter().any(|reject| reject.error == error));
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(3)
+            .build()
+            .unwrap();
+        let index = pool.install(|| TxIndex::build(&events));
+        let replayed = pool.install(|| replay(events, Some(&index))).unwrap();
+        assert_eq!(rejected_txs(&replayed), rejected_txs(&expected));
+        assert_eq!(replayed.accounts, expected.accounts);
+    }
+
+    #[test]
+    fn test_two_pass() {
+        let event = |ty, client_id, tx| TransactionEvent::new(ty, client_id, tx, Price(10_000));
+        use TransactionType::*;
+        let events = vec![
+            event(Deposit, 1, 1),
+            event(Deposit, 2, 1),
+            event(Withdrawal, 2, 1),
+            event(Dispute, 2, 1),
+            event(Deposit, 2, 2),
+            event(Dispute, 1, 2),
+            event(Dispute, 1, 1),
+        ];
+        let expected = [
+            (2, TransactionError::Duplicate),
+            (2, TransactionError::Duplicate),
+            (2, TransactionError::ClientMismatch),
+            (1, TransactionError::ClientMismatch),
+        ];
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let index = pool.install(|| TxIndex::build(&events));
+        let replayed = pool.install(|| replay(events, Some(&index))).unwrap();
+        let rejects: Vec<_> = replayed
+            .rejects
+            .iter()
+            .map(|reject| (reject.event.client_id, reject.error))
+            .collect();
+        assert_eq!(rejects, expected);
+        assert_eq!(replayed.accounts[0].1.held, Price(10_000));
+        assert_eq!(replayed.accounts[1].1.total, Price(10_000));
+    }
+}
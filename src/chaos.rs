@@ -0,0 +1,245 @@
+//! Fault injection for robustness testing, only built with the `chaos`
+//! feature. [`ChaosSource`] wraps a source and, driven by a seed, drops rows as
+//! if they did not parse, duplicates events, moves disputes, resolves and
+//! chargebacks behind the next event and lets the source fail mid-run.
+//! [`check_invariants`] verifies the state is still consistent afterwards.
+use crate::{
+    data_types::{Price, TransactionFlags, TransactionType},
+    metrics::metrics,
+    pipeline::Message,
+    transaction_context::TransactionContext,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/// What a [`ChaosSource`] injected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosReport {
+    pub parse_errors: u64,
+    pub duplicates: u64,
+    pub reordered: u64,
+    /// the amount of messages read before the source failed
+    pub failed_at: Option<u64>,
+}
+
+impl Display for ChaosReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "parse errors: {}, duplicates: {}, reordered: {}, source failure: ",
+            self.parse_errors, self.duplicates, self.reordered
+        )?;
+        match self.failed_at {
+            Some(position) => write!(f, "after {position} messages"),
+            None => write!(f, "none"),
+        }
+    }
+}
+
+/// Wraps a source and injects faults into its messages. Each event is
+/// corrupted in one of the ways with probability `rate`, the source fails with
+/// probability `rate / 100` per event.
+#[derive(Debug)]
+pub struct ChaosSource<I> {
+    inner: I,
+    rng: u64,
+    rate: f64,
+    pending: VecDeque<Message>,
+    /// a dispute, resolve or chargeback that is emitted after the next event
+    held_back: Option<Message>,
+    position: u64,
+    failed: bool,
+    report: Arc<Mutex<ChaosReport>>,
+}
+
+impl<I: Iterator<Item = Message>> ChaosSource<I> {
+    pub fn new(inner: impl IntoIterator<IntoIter = I>, seed: u64, rate: f64) -> Self {
+        ChaosSource {
+            inner: inner.into_iter(),
+            rng: seed,
+            rate,
+            pending: VecDeque::new(),
+            held_back: None,
+            position: 0,
+            failed: false,
+            report: Arc::default(),
+        }
+    }
+
+    /// Handle to the report, it is updated while the source is read.
+    pub fn report(&self) -> Arc<Mutex<ChaosReport>> {
+        self.report.clone()
+    }
+
+    /// splitmix64, a seed gives the same faults on every platform
+    fn next_f64(&mut self) -> f64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn roll(&mut self, rate: f64) -> bool {
+        self.next_f64() < rate
+    }
+
+    fn update(&self, update: impl FnOnce(&mut ChaosReport)) {
+        update(&mut self.report.lock().expect("chaos report poisoned"));
+    }
+}
+
+impl<I: Iterator<Item = Message>> Iterator for ChaosSource<I> {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        if let Some(message) = self.pending.pop_front() {
+            return Some(message);
+        }
+        if self.failed {
+            return None;
+        }
+
+        loop {
+            let Some(message) = self.inner.next() else {
+                return self.held_back.take();
+            };
+            self.position += 1;
+            let Message::Event(event) = message else {
+                return Some(message);
+            };
+
+            if self.roll(self.rate / 100.0) {
+                let position = self.position;
+                warn!(position, "chaos: failing the source");
+                self.update(|report| report.failed_at = Some(position));
+                self.failed = true;
+                return self.held_back.take();
+            }
+            if self.roll(self.rate) {
+                // what the csv source does with a malformed row
+                metrics().record_parse_failure();
+                self.update(|report| report.parse_errors += 1);
+                continue;
+            }
+            if self.roll(self.rate) {
+                self.pending.push_back(message);
+                self.update(|report| report.duplicates += 1);
+            }
+            let is_dispute = matches!(
+                event.ty,
+                TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+            );
+            if is_dispute && self.held_back.is_none() && self.roll(self.rate) {
+                self.held_back = Some(message);
+                self.update(|report| report.reordered += 1);
+                continue;
+            }
+            if let Some(held_back) = self.held_back.take() {
+                self.pending.push_back(held_back);
+            }
+            return Some(message);
+        }
+    }
+}
+
+/// Checks that the state is consistent: every account holds exactly the
+/// amounts of its disputed transactions, only charged back accounts are locked
+/// and every stored transaction belongs to an existing account.
+pub fn check_invariants(context: &TransactionContext) -> Result<(), String> {
+    let mut held: HashMap<u16, Price> = HashMap::new();
+    for (tx, (amount, flags, client_id)) in context.iter_transactions() {
+        if context.account(client_id).is_none() {
+            return Err(format!("transaction {tx} of missing client {client_id}"));
+        }
+        if flags == TransactionFlags::Disputed {
+            held.entry(client_id).or_default().0 += amount.0;
+        }
+    }
+    for (client_id, account) in context.iter_accounts() {
+        let expected = held.get(&client_id).copied().unwrap_or_default();
+        if account.held != expected {
+            return Err(format!(
+                "client {client_id} holds {}, its disputes add up to {expected}",
+                account.held
+            ));
+        }
+        if account.locked != (account.meta.chargebacks > 0) {
+            return Err(format!(
+                "client {client_id} is locked: {}, after {} chargebacks",
+                account.locked, account.meta.chargebacks
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionEvent;
+
+    /// Deposits, withdrawals and dispute chains over a few clients.
+    fn workload() -> Vec<Message> {
+        let mut events = Vec::new();
+        for tx in 1..=2000u32 {
+            let client_id = (tx % 7) as u16;
+            let event = |ty, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+            // disputes refer to the deposit seven transactions back
+            events.push(match tx % 5 {
+                3 => event(TransactionType::Withdrawal, tx, 5_000),
+                4 if tx > 7 => event(TransactionType::Dispute, tx - 7, 0),
+                _ => event(TransactionType::Deposit, tx, tx as i64 * 100),
+            });
+            if tx > 7 && tx % 35 == 4 {
+                events.push(event(TransactionType::Resolve, tx - 7, 0));
+            }
+            if tx % 70 == 9 {
+                events.push(event(TransactionType::Chargeback, tx - 7, 0));
+            }
+        }
+        events.into_iter().map(Message::Event).collect()
+    }
+
+    #[test]
+    fn test_chaos_keeps_invariants() {
+        let workload = workload();
+        let mut total = ChaosReport::default();
+        for seed in 0..50 {
+            let source = ChaosSource::new(workload.clone(), seed, 0.05);
+            let report = source.report();
+            let mut context = TransactionContext::new();
+            for event in source.filter_map(Message::into_event) {
+                let _ = context.apply(&event);
+            }
+            check_invariants(&context).unwrap_or_else(|e| panic!("seed {seed}: {e}"));
+
+            let report = report.lock().unwrap().clone();
+            total.parse_errors += report.parse_errors;
+            total.duplicates += report.duplicates;
+            total.reordered += report.reordered;
+            total.failed_at = total.failed_at.or(report.failed_at);
+        }
+        // every kind of fault was exercised
+        assert!(total.parse_errors > 0 && total.duplicates > 0 && total.reordered > 0);
+        assert!(total.failed_at.is_some());
+    }
+
+    #[test]
+    fn test_seed_is_deterministic() {
+        let run = |seed| {
+            let source = ChaosSource::new(workload(), seed, 0.05);
+            let report = source.report();
+            let messages = source.count();
+            let report = report.lock().unwrap().clone();
+            (messages, report)
+        };
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+}
@@ -0,0 +1,944 @@
+use clap::{Args, Parser, Subcommand};
+use encoding_rs::Encoding;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, time::Duration};
+use toy_transaction_engine::{
+    analytics::AnalysisFormat,
+    data_types::{parse_timestamp, HoldPolicy, LockPolicy, Price, Rounding},
+    encoding::parse_encoding,
+    filter::{ClientSet, TypeSet},
+    memory_budget::{ByteSize, MemoryPolicy},
+    pipeline::{CorePins, FlushInterval},
+    pruning::parse_period,
+    push_source::QueueMode,
+    risk::Heuristic,
+    schema::{Locale, OutOfRange, Schema},
+    seal::Protection,
+    snapshot::SnapshotFormat,
+    tenants::TenantInput,
+};
+
+/// Processes transactions from a csv file and dumps the resulting accounts to stdout.
+#[derive(Debug, Parser)]
+#[command(
+    name = env!("CARGO_PKG_NAME"),
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// csv file containing the transactions to process
+    #[cfg_attr(
+        feature = "grpc",
+        arg(required_unless_present_any = ["grpc_addr", "udp_addr", "tenant", "follow"])
+    )]
+    #[cfg_attr(
+        not(feature = "grpc"),
+        arg(required_unless_present_any = ["udp_addr", "tenant", "follow"])
+    )]
+    pub file_path: Option<PathBuf>,
+
+    /// multi-tenant mode: process the file into an isolated context of the
+    /// named tenant. Can be repeated, the output gets a leading `tenant` column.
+    #[arg(
+        long,
+        value_name = "NAME=PATH",
+        conflicts_with_all = ["file_path", "watch", "snapshot", "statement", "audit_log"]
+    )]
+    pub tenant: Vec<TenantInput>,
+
+    /// in multi-tenant mode, write the accounts of every tenant to
+    /// `<DIR>/<tenant>.csv` instead of stdout
+    #[arg(long, value_name = "DIR", requires = "tenant")]
+    pub output_dir: Option<PathBuf>,
+
+    /// in multi-tenant mode, write the transaction ids that appear in more
+    /// than one file of a tenant to the given path
+    #[arg(long, value_name = "PATH", requires = "tenant")]
+    pub cross_file_duplicates: Option<PathBuf>,
+
+    /// serve the gRPC ingest and query API on the given address instead of
+    /// reading a file. Runs until SIGINT/SIGTERM is received.
+    #[cfg(feature = "grpc")]
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = [
+            "file_path",
+            "udp_addr",
+            "tenant",
+            "follow",
+            "clients",
+            "clients_file",
+            "ignore",
+            "from",
+            "to",
+            "schema",
+            "columns",
+            "delimiter",
+            "quote",
+            "comment",
+            "no_headers",
+            "encoding",
+            "rounding",
+            "locale",
+            "out_of_range",
+            "snapshot_every",
+            "pin_cores",
+            "parse_threads",
+            "rules",
+            "max_rate",
+            "settings"
+        ]
+    )]
+    pub grpc_addr: Option<SocketAddr>,
+
+    /// receive events as UDP datagrams on the given address instead of
+    /// reading a file, delivery is best effort. Runs until SIGINT/SIGTERM is
+    /// received.
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = [
+            "file_path",
+            "tenant",
+            "schema",
+            "columns",
+            "delimiter",
+            "quote",
+            "comment",
+            "no_headers",
+            "encoding",
+            "rounding",
+            "locale",
+            "out_of_range",
+            "window_deltas",
+            "watch",
+            "parse_threads"
+        ]
+    )]
+    pub udp_addr: Option<SocketAddr>,
+
+    /// queue between the UDP listener and the pipeline: `block:N`, `drop:N`
+    /// or `unbounded`
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "block:65536",
+        requires = "udp_addr"
+    )]
+    pub udp_queue: QueueMode,
+
+    /// toml file mapping the column names and type spellings of the input onto
+    /// the canonical format, see the readme
+    #[arg(long, value_name = "PATH")]
+    pub schema: Option<PathBuf>,
+
+    /// map input columns onto the canonical columns, e.g.
+    /// `type=txn_type,client=customer`. Takes precedence over `--schema`.
+    #[arg(long, value_name = "MAPPING", value_parser = Schema::parse_columns)]
+    pub columns: Option<HashMap<String, String>>,
+
+    /// toml file with row-level validation rules, events violating them are
+    /// rejected with `rule_violation`, see the readme
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub rules: Option<PathBuf>,
+
+    /// WebAssembly module with custom rules, see the readme. Can be repeated.
+    #[cfg(feature = "plugins")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tenant", "follow"])]
+    #[cfg_attr(feature = "grpc", arg(conflicts_with = "grpc_addr"))]
+    pub plugin: Vec<PathBuf>,
+
+    /// rhai script with custom rules, see the readme. Can be repeated.
+    #[cfg(feature = "scripting")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tenant", "follow"])]
+    #[cfg_attr(feature = "grpc", arg(conflicts_with = "grpc_addr"))]
+    pub script: Vec<PathBuf>,
+
+    /// field delimiter of the input, e.g. `;` or `\t`
+    #[arg(long, value_name = "CHAR", value_parser = parse_ascii_char)]
+    pub delimiter: Option<char>,
+
+    /// quote character of the input
+    #[arg(long, value_name = "CHAR", value_parser = parse_ascii_char)]
+    pub quote: Option<char>,
+
+    /// skip input lines starting with the given character
+    #[arg(long, value_name = "CHAR", value_parser = parse_ascii_char)]
+    pub comment: Option<char>,
+
+    /// text encoding of the input, e.g. `windows-1252` or `utf-16le`.
+    /// Detected from the start of the file when not given
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    pub encoding: Option<&'static Encoding>,
+
+    /// how amounts with more than four decimals are rounded: `half-up`,
+    /// `half-even`, `truncate` or `reject` to skip the row. Without it, or any
+    /// other amount option, the amount is parsed as a float and rounded half
+    /// away from zero
+    #[arg(long, value_name = "MODE")]
+    pub rounding: Option<Rounding>,
+
+    /// separators of the amounts: `en` (1,234.56), `de` (1.234,56), `fr`
+    /// (1 234,56) or `ch` (1'234.56)
+    #[arg(long, value_name = "LOCALE")]
+    pub locale: Option<Locale>,
+
+    /// what happens to amounts beyond the range of a price: `reject` the
+    /// event as `amount_out_of_range`, or `clamp` it to the largest price
+    #[arg(long, value_name = "POLICY")]
+    pub out_of_range: Option<OutOfRange>,
+
+    /// the input has no header, columns are expected in the order `type`,
+    /// `client`, `tx`, `amount`, `timestamp`
+    #[arg(long)]
+    pub no_headers: bool,
+
+    /// only process the events of the given clients, e.g. `1,2,500-600`.
+    /// Events of other clients are counted, but not processed.
+    #[arg(long, value_name = "IDS", conflicts_with = "clients_file")]
+    pub clients: Option<ClientSet>,
+
+    /// like `--clients`, reading the ids and ranges from a file, one or more
+    /// per line
+    #[arg(long, value_name = "PATH")]
+    pub clients_file: Option<PathBuf>,
+
+    /// drop all events of the given types, e.g. `chargeback,dispute`, to see
+    /// what the balances would be without them
+    #[arg(long, value_name = "TYPES")]
+    pub ignore: Option<TypeSet>,
+
+    /// only apply events with a timestamp at or after the given time, as unix
+    /// seconds, `2024-01-01` or `2024-01-01T00:00:00Z`
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub from: Option<u64>,
+
+    /// only apply events with a timestamp before the given time
+    #[arg(long, value_name = "TIME", value_parser = parse_timestamp)]
+    pub to: Option<u64>,
+
+    /// apply the events before `--from` to build the opening state, and
+    /// output the balance deltas per client over the window instead of the
+    /// accounts. Reads the input twice.
+    #[arg(long, requires = "from", conflicts_with_all = ["watch", "tenant"])]
+    pub window_deltas: bool,
+
+    /// service mode: keep following the input file for appended rows until
+    /// SIGINT/SIGTERM is received
+    #[arg(long)]
+    pub watch: bool,
+
+    /// write a snapshot of the account state to the given path at the end of
+    /// the run, or on shutdown in service mode
+    #[arg(long, value_name = "PATH")]
+    pub snapshot: Option<PathBuf>,
+
+    /// also write the snapshot periodically, every given number of events or
+    /// duration (`60s`, `5m`, `1h`)
+    #[arg(long, value_name = "INTERVAL", requires = "snapshot")]
+    pub snapshot_every: Option<FlushInterval>,
+
+    /// amount of previous snapshots to keep as `<PATH>.1`, `<PATH>.2`, ..
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 3,
+        requires = "snapshot_every"
+    )]
+    pub snapshot_keep: usize,
+
+    /// format of the snapshot: `csv` holds the accounts, `binary` the
+    /// accounts and transactions so a later run can `--restore` it
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "csv",
+        requires = "snapshot"
+    )]
+    pub snapshot_format: SnapshotFormat,
+
+    /// protect the snapshot with the key in `TXE_SNAPSHOT_KEY` or the file
+    /// named by `TXE_SNAPSHOT_KEY_FILE`: `encrypt` (AES-256-GCM), `sign`
+    /// (HMAC-SHA256) or `encrypt,sign`
+    #[arg(long, value_name = "PROTECTION", requires = "snapshot")]
+    pub snapshot_protection: Option<Protection>,
+
+    /// continue from a binary snapshot, the events of the input are applied
+    /// on top of it
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub restore: Option<PathBuf>,
+
+    /// seed the accounts from an account output or snapshot, e.g. the closing
+    /// balances of the previous day. Transactions are not carried over.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["tenant", "restore"]
+    )]
+    pub opening_balances: Option<PathBuf>,
+
+    /// hand at most the given amount of events per second to the processor,
+    /// to not starve other workloads on the host in service mode
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        conflicts_with = "tenant"
+    )]
+    pub max_rate: Option<u64>,
+
+    /// toml file with the max rate, reloaded on SIGHUP in service mode along
+    /// with the `--rules` and `--segments`, see the readme
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = ["tenant", "max_rate"]
+    )]
+    pub settings: Option<PathBuf>,
+
+    /// keep the memory held by the state within the given size, e.g. `512M`
+    /// or `2G`, see `--memory-policy`
+    #[arg(long, value_name = "SIZE", conflicts_with = "tenant")]
+    pub max_memory: Option<ByteSize>,
+
+    /// what to do when the state approaches `--max-memory`: `abort` the run,
+    /// `evict-finalized` transactions (resolved and charged back ones) or
+    /// `spill` the state to `--spill-dir` and continue on disk
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "abort",
+        requires = "max_memory"
+    )]
+    pub memory_policy: MemoryPolicy,
+
+    /// empty directory the `spill` memory policy moves the state to
+    #[cfg(feature = "sled")]
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "max_memory",
+        required_if_eq("memory_policy", "spill")
+    )]
+    pub spill_dir: Option<PathBuf>,
+
+    /// drop accounts that hold nothing, are not locked and had no events for
+    /// the given period (`12h`, `30d`), by the timestamps of the events. The
+    /// audit log records what was dropped.
+    #[arg(
+        long,
+        value_name = "PERIOD",
+        value_parser = parse_period,
+        conflicts_with = "tenant"
+    )]
+    pub prune_after: Option<Duration>,
+
+    /// resolve disputes that are neither resolved nor charged back within the
+    /// given period (`30d`), by the timestamps of the events. The resolves are
+    /// flagged with the `dispute_expired` reason code
+    #[arg(
+        long,
+        value_name = "PERIOD",
+        value_parser = parse_period,
+        conflicts_with = "tenant"
+    )]
+    pub dispute_timeout: Option<Duration>,
+
+    /// log events that take longer than the given duration (`50ms`) to
+    /// process, with the time spent in the state store and the sinks
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = parse_period,
+        conflicts_with = "tenant"
+    )]
+    pub event_deadline: Option<Duration>,
+
+    /// pin the thread reading the input and the processor thread to the given
+    /// cores, e.g. `2,3`
+    #[arg(long, value_name = "SOURCE,PROCESSOR", conflicts_with = "tenant")]
+    pub pin_cores: Option<CorePins>,
+
+    /// amount of threads parsing the input rows, the order of the events is
+    /// kept regardless
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "tenant")]
+    pub parse_threads: u16,
+
+    /// persist the accounts and transactions in a database in the given
+    /// directory, continuing with the state it already holds
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[arg(long, value_name = "DIR", conflicts_with = "tenant")]
+    pub state_dir: Option<PathBuf>,
+
+    /// database used for `--state-dir`
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[arg(long, value_enum, default_value = StateBackend::DEFAULT, requires = "state_dir")]
+    pub state_backend: StateBackend,
+
+    /// commit the state together with the position in the input, in one
+    /// atomic step at every `--snapshot-every` batch and at the end. A run
+    /// restarted on the same input skips the events committed before.
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[arg(
+        long,
+        requires = "state_dir",
+        conflicts_with_all = ["udp_addr", "window_deltas"]
+    )]
+    #[cfg_attr(feature = "grpc", arg(conflicts_with = "grpc_addr"))]
+    pub exactly_once: bool,
+
+    /// only process while holding the given lock, e.g.
+    /// `file:/shared/txe.lock`, standing by until it is free and taking over
+    /// the `--state-dir`, see the readme
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[arg(long, value_name = "LOCK", requires = "state_dir")]
+    pub leader_lock: Option<toy_transaction_engine::leader::LockSpec>,
+
+    /// ship the applied events to followers connecting on the given address,
+    /// see the readme
+    #[arg(long, value_name = "ADDR", conflicts_with = "tenant")]
+    pub replication_addr: Option<SocketAddr>,
+
+    /// follow the primary on the given `--replication-addr` instead of reading
+    /// an input, keeping a replica of its state until SIGINT/SIGTERM is
+    /// received
+    #[arg(
+        long,
+        value_name = "ADDR",
+        conflicts_with_all = [
+            "file_path",
+            "udp_addr",
+            "tenant",
+            "replication_addr",
+            "restore",
+            "opening_balances",
+            "postings",
+            "open_disputes",
+            "locked_accounts",
+            "negative_balances",
+            "risk_score",
+            "check_conservation",
+            "snapshot_every",
+            "rules",
+            "max_rate",
+            "settings"
+        ]
+    )]
+    pub follow: Option<SocketAddr>,
+
+    /// inject faults into the input, driven by the given seed, and verify the
+    /// state is still consistent at the end
+    #[cfg(feature = "chaos")]
+    #[arg(long, value_name = "SEED", conflicts_with_all = ["tenant", "udp_addr"])]
+    pub chaos_seed: Option<u64>,
+
+    /// probability per event of every kind of injected fault
+    #[cfg(feature = "chaos")]
+    #[arg(
+        long,
+        value_name = "P",
+        default_value_t = 0.01,
+        requires = "chaos_seed"
+    )]
+    pub chaos_rate: f64,
+
+    /// fail the run (exit code 4) when rows could not be parsed, instead of
+    /// skipping them
+    #[arg(long)]
+    pub strict: bool,
+
+    /// write a machine readable summary of the run to the given path
+    #[arg(long, value_name = "PATH")]
+    pub status_json: Option<PathBuf>,
+
+    /// fail the run (exit code 7) when the digest of the final state differs
+    /// from the given one, see `state_digest` in the `--status-json` summary
+    #[arg(long, value_name = "DIGEST", conflicts_with = "tenant")]
+    pub expect_digest: Option<String>,
+
+    /// include the per-account audit columns in the output (for compliance review)
+    #[arg(long)]
+    pub extended: bool,
+
+    /// show a keyed hash of the client ids in all outputs and logs, keyed by
+    /// `TXE_PSEUDONYM_KEY` or a random key
+    #[arg(long, global = true)]
+    pub pseudonymize: bool,
+
+    /// write the pseudonym of every client to the given path, readable by the
+    /// owner only
+    #[arg(long, value_name = "PATH", requires = "pseudonymize")]
+    pub pseudonym_map: Option<PathBuf>,
+
+    /// keep an index of applied transactions per client. Costs memory
+    /// proportional to the amount of transactions.
+    #[arg(long)]
+    pub track_history: bool,
+
+    /// write a statement of all applied transactions per client to the given path
+    #[arg(long, requires = "track_history")]
+    pub statement: Option<PathBuf>,
+
+    /// add the available, held and total balance after every transaction to
+    /// the statement. Costs memory proportional to the amount of transactions.
+    #[arg(long, requires = "statement")]
+    pub running_balances: bool,
+
+    /// write a JSON line per applied or rejected event to the given path
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// credit charged back amounts to the account of the given client id, so
+    /// the funds in the system are conserved. Events of the client itself are
+    /// rejected as `reserved_client`.
+    #[arg(long, value_name = "CLIENT", conflicts_with = "tenant")]
+    pub suspense_account: Option<u16>,
+
+    /// strict onboarding: reject all events of clients that are not in the
+    /// given file of ids and ranges, as `unknown_client`, instead of creating
+    /// their accounts
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub client_registry: Option<PathBuf>,
+
+    /// what a chargeback does to the account: `lock` it altogether, only
+    /// lock further `withdrawals`, or `none`
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "lock",
+        conflicts_with = "tenant"
+    )]
+    pub chargeback_lock: LockPolicy,
+
+    /// override `--chargeback-lock` for some clients, e.g.
+    /// `500-599=withdrawals`. Can be repeated, the last matching one wins.
+    #[arg(
+        long,
+        value_name = "IDS=POLICY",
+        value_parser = parse_client_lock_policy,
+        conflicts_with = "tenant"
+    )]
+    pub chargeback_lock_for: Vec<(ClientSet, LockPolicy)>,
+
+    /// what a dispute exceeding the available funds holds: the `full` amount,
+    /// at most the available funds with the rest tracked as shortfall (`cap`),
+    /// or the full amount while flagging the overdrawn account (`flag`)
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "full",
+        conflicts_with = "tenant"
+    )]
+    pub dispute_hold: HoldPolicy,
+
+    /// csv file assigning clients to segments, with the columns
+    /// `client,segment`. The segment is added to the extended output.
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub segments: Option<PathBuf>,
+
+    /// override `--chargeback-lock` for the clients of a segment, e.g.
+    /// `business=withdrawals`. `--chargeback-lock-for` takes precedence.
+    #[arg(
+        long,
+        value_name = "SEGMENT=POLICY",
+        value_parser = parse_segment_lock_policy,
+        requires = "segments"
+    )]
+    pub segment_chargeback_lock: Vec<(String, LockPolicy)>,
+
+    /// write the number of accounts, balances, disputes and chargebacks per
+    /// segment to the given path
+    #[arg(long, value_name = "PATH", requires = "segments")]
+    pub segment_summary: Option<PathBuf>,
+
+    /// fail the run (exit code 6) when the totals of the accounts do not add
+    /// up to the applied deposits, withdrawals and chargebacks
+    #[arg(long, conflicts_with = "tenant")]
+    pub check_conservation: bool,
+
+    /// write a double-entry posting per applied event to the given path, for
+    /// reconciliation against the general ledger
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub postings: Option<PathBuf>,
+
+    /// publish every applied event with the resulting balances to
+    /// `--kafka-topic` on the given brokers, e.g. `localhost:9092`
+    #[cfg(feature = "kafka")]
+    #[arg(
+        long,
+        value_name = "BROKERS",
+        requires = "kafka_topic",
+        conflicts_with_all = ["tenant", "follow"]
+    )]
+    pub kafka_brokers: Option<String>,
+
+    /// topic the applied events are published to, keyed by client
+    #[cfg(feature = "kafka")]
+    #[arg(long, value_name = "TOPIC", requires = "kafka_brokers")]
+    pub kafka_topic: Option<String>,
+
+    /// librdkafka producer property, e.g. `security.protocol=ssl`. Can be
+    /// repeated.
+    #[cfg(feature = "kafka")]
+    #[arg(
+        long,
+        value_name = "KEY=VALUE",
+        value_parser = parse_kafka_property,
+        requires = "kafka_brokers"
+    )]
+    pub kafka_config: Vec<(String, String)>,
+
+    /// toml file of webhooks notified of locked accounts, large movements and
+    /// repeated rejects, see the readme
+    #[cfg(feature = "webhooks")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["tenant", "follow"])]
+    pub webhooks: Option<PathBuf>,
+
+    /// toml file of thresholds for the reject rate, the parse failure rate and
+    /// the state digest, a run exceeding them alerts HTTP or Slack webhooks at
+    /// the end, see the readme
+    #[cfg(feature = "webhooks")]
+    #[arg(long, value_name = "PATH")]
+    pub alerts: Option<PathBuf>,
+
+    /// enrich the postings with the annotations of the given csv file, looked
+    /// up by tx id prefix, e.g. `prefix,merchant,category`
+    #[arg(long, value_name = "PATH", requires_all = ["file_path", "postings"])]
+    pub annotations: Option<PathBuf>,
+
+    /// write the disputes still open at the end of the run, per client and
+    /// with their age, to the given path
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub open_disputes: Option<PathBuf>,
+
+    /// write the locked accounts with the chargeback that locked them, their
+    /// balance at that moment and the events rejected since, to the given path
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub locked_accounts: Option<PathBuf>,
+
+    /// write the accounts ending with a negative available or total balance,
+    /// with the recoveries they received, to the given path
+    #[arg(long, value_name = "PATH", conflicts_with = "tenant")]
+    pub negative_balances: Option<PathBuf>,
+
+    /// add a `risk_score` column to the output, the highest score of the given
+    /// heuristics: `chargebacks`, `dispute-ratio`, `velocity`
+    #[arg(
+        long,
+        value_name = "HEURISTICS",
+        value_delimiter = ',',
+        conflicts_with_all = ["tenant", "window_deltas"]
+    )]
+    pub risk_score: Option<Vec<Heuristic>>,
+
+    /// serve prometheus metrics on `http://<addr>/metrics` and the account
+    /// state on `http://<addr>/accounts[/<client_id>]` while running
+    #[arg(long, value_name = "ADDR", alias = "metrics-addr")]
+    pub http_addr: Option<SocketAddr>,
+
+    /// periodically report progress of the run to stderr
+    #[arg(long)]
+    pub progress: bool,
+
+    /// export traces and metrics to the given OTLP http endpoint, e.g. `http://localhost:4318`
+    #[cfg(feature = "otel")]
+    #[arg(long, value_name = "URL")]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum StateBackend {
+    #[cfg(feature = "sled")]
+    Sled,
+    #[cfg(feature = "rocksdb")]
+    Rocksdb,
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+impl StateBackend {
+    const DEFAULT: &'static str = if cfg!(feature = "sled") {
+        "sled"
+    } else {
+        "rocksdb"
+    };
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// process a file or load a snapshot, and inspect the result interactively
+    Shell(ShellArgs),
+    /// compare two account outputs or snapshots, reporting balance deltas per
+    /// client and newly locked accounts
+    Diff(DiffArgs),
+    /// process a file and reconstruct the account of a client as it was at a
+    /// given transaction or event offset, e.g. to investigate a dispute
+    StateAt(StateAtArgs),
+    /// process a file and compare the resulting balances with an external
+    /// balance file, reporting the discrepancies per client
+    Reconcile(ReconcileArgs),
+    /// apply hypothetical events on top of a snapshot and report the balance
+    /// deltas, without persisting anything
+    Simulate(SimulateArgs),
+    /// verify the hash chain and the anchors of an audit log
+    VerifyAudit(VerifyAuditArgs),
+    /// process a file and write aggregates for dashboards: top clients,
+    /// transaction sizes and dispute ratios
+    Analyze(AnalyzeArgs),
+    /// sample a file of unknown format, report its encoding, delimiter,
+    /// columns and rows per transaction type, and suggest a schema for it
+    Inspect(InspectArgs),
+    /// process a complete file with the clients partitioned over all cores,
+    /// faster on big machines but without merges and cross-client checks
+    Replay(ReplayArgs),
+    /// continue from the snapshot of the previous day with the file of the
+    /// day, write the new snapshot and report the balance deltas
+    Incremental(IncrementalArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ShellArgs {
+    /// csv file containing the transactions to process
+    #[arg(required_unless_present = "snapshot", conflicts_with = "snapshot")]
+    pub file_path: Option<PathBuf>,
+
+    /// load the accounts from a snapshot instead of processing a file
+    #[arg(long, value_name = "PATH")]
+    pub snapshot: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct DiffArgs {
+    /// the earlier account output or snapshot
+    pub snapshot_a: PathBuf,
+
+    /// the later account output or snapshot
+    pub snapshot_b: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyAuditArgs {
+    /// the audit log written with `--audit-log`
+    pub path: PathBuf,
+
+    /// digest of an anchor kept outside of the log, which the log must hold
+    #[arg(long, value_name = "DIGEST")]
+    pub anchor: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ReconcileArgs {
+    /// csv file containing the transactions to process
+    pub file_path: PathBuf,
+
+    /// the expected balances, an account output or snapshot
+    #[arg(long, value_name = "PATH")]
+    pub expected: PathBuf,
+
+    /// accept differences up to the given amount, e.g. `0.01`
+    #[arg(long, value_name = "AMOUNT", default_value = "0", value_parser = parse_amount)]
+    pub tolerance: Price,
+
+    /// accept differences up to the given fraction of the expected balance,
+    /// e.g. `0.001`
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.0)]
+    pub relative_tolerance: f64,
+
+    /// do not report clients that are only in one of both
+    #[arg(long)]
+    pub ignore_missing: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct SimulateArgs {
+    /// the snapshot or account output to start from
+    pub snapshot: PathBuf,
+
+    /// csv file containing the hypothetical transactions
+    pub file_path: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct IncrementalArgs {
+    /// the snapshot or account output of the previous day
+    pub snapshot: PathBuf,
+
+    /// csv file containing the transactions of the day
+    pub file_path: PathBuf,
+
+    /// where to write the new snapshot
+    #[arg(long, value_name = "PATH")]
+    pub out: PathBuf,
+
+    /// format of the new snapshot, the one of the previous day by default
+    #[arg(long, value_name = "FORMAT")]
+    pub snapshot_format: Option<SnapshotFormat>,
+
+    /// protect the new snapshot, see the option of the same name of a normal
+    /// run
+    #[arg(long, value_name = "PROTECTION")]
+    pub snapshot_protection: Option<Protection>,
+}
+
+#[derive(Debug, Args)]
+pub struct AnalyzeArgs {
+    /// csv file containing the transactions to process
+    pub file_path: PathBuf,
+
+    /// length of the top client lists
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    pub top: usize,
+
+    /// `csv` or `json`
+    #[arg(long, value_name = "FORMAT", default_value = "csv")]
+    pub format: AnalysisFormat,
+}
+
+#[derive(Debug, Args)]
+pub struct InspectArgs {
+    /// csv file to inspect
+    pub file_path: PathBuf,
+
+    /// amount of rows the column types are guessed from
+    #[arg(long, value_name = "N", default_value_t = 1000)]
+    pub sample: usize,
+}
+
+#[derive(Debug, Args)]
+pub struct ReplayArgs {
+    /// csv file containing the transactions to process
+    pub file_path: PathBuf,
+
+    /// threads to replay on, all cores when 0
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub threads: usize,
+
+    /// skip indexing the tx ids of all clients first, tx ids reused by or
+    /// disputed from another client are then not rejected like in a normal run
+    #[arg(long)]
+    pub one_pass: bool,
+
+    /// append the audit metadata of each account as extra columns
+    #[arg(long)]
+    pub extended: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct StateAtArgs {
+    /// csv file containing the transactions to process
+    pub file_path: PathBuf,
+
+    /// the client to reconstruct the account of
+    #[arg(long)]
+    pub client: u16,
+
+    /// reconstruct up to and including the first applied event of this transaction
+    #[arg(long, required_unless_present = "offset", conflicts_with = "offset")]
+    pub tx: Option<u32>,
+
+    /// reconstruct up to and including the n-th (zero based) applied event of
+    /// the client
+    #[arg(long)]
+    pub offset: Option<usize>,
+
+    /// include the per-account audit columns in the output
+    #[arg(long)]
+    pub extended: bool,
+}
+
+fn parse_client_lock_policy(s: &str) -> Result<(ClientSet, LockPolicy), String> {
+    let Some((clients, policy)) = s.split_once('=') else {
+        return Err(format!("expected IDS=POLICY, got `{s}`"));
+    };
+    Ok((clients.parse()?, policy.parse()?))
+}
+
+fn parse_segment_lock_policy(s: &str) -> Result<(String, LockPolicy), String> {
+    let Some((segment, policy)) = s.split_once('=') else {
+        return Err(format!("expected SEGMENT=POLICY, got `{s}`"));
+    };
+    Ok((segment.to_string(), policy.parse()?))
+}
+
+#[cfg(feature = "kafka")]
+fn parse_kafka_property(s: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err(format!("expected KEY=VALUE, got `{s}`"));
+    };
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_amount(s: &str) -> Result<Price, String> {
+    s.parse()
+        .map_err(|_| format!("expected an amount like `0.01`, got `{s}`"))
+}
+
+/// Parses a single ascii character, `\t` is accepted for a tab.
+fn parse_ascii_char(s: &str) -> Result<char, String> {
+    let c = match s {
+        "\\t" => '\t',
+        _ => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(format!("expected a single character, got `{s}`")),
+            }
+        }
+    };
+    if !c.is_ascii() {
+        return Err(format!("`{c}` is not an ascii character"));
+    }
+    Ok(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{error::ErrorKind, CommandFactory};
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_follow_conflicts() {
+        let follow = ["txe", "--follow", "127.0.0.1:7000"];
+        assert!(Cli::try_parse_from(follow).is_ok());
+        assert!(Cli::try_parse_from(follow.into_iter().chain(["--snapshot", "s.csv"])).is_ok());
+        for args in [
+            &["--postings", "postings.csv"][..],
+            &["--open-disputes", "disputes.csv"],
+            &["--locked-accounts", "locked.csv"],
+            &["--negative-balances", "negative.csv"],
+            &["--risk-score", "velocity"],
+            &["--check-conservation"],
+            &["--snapshot", "s.csv", "--snapshot-every", "10s"],
+            &["--rules", "rules.toml"],
+            &["--max-rate", "10"],
+            &["--settings", "settings.toml"],
+        ] {
+            let error = Cli::try_parse_from(follow.iter().chain(args)).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ArgumentConflict, "{args:?}");
+        }
+    }
+
+    #[cfg(feature = "grpc")]
+    #[test]
+    fn test_grpc_conflicts() {
+        let grpc = ["txe", "--grpc-addr", "127.0.0.1:50051"];
+        assert!(Cli::try_parse_from(grpc).is_ok());
+        for args in [
+            ["--settings", "settings.toml"],
+            ["--rules", "rules.toml"],
+            ["--max-rate", "10"],
+        ] {
+            let error = Cli::try_parse_from(grpc.into_iter().chain(args)).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::ArgumentConflict, "{args:?}");
+        }
+    }
+}
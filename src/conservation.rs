@@ -0,0 +1,223 @@
+//! End of run check that no funds appeared or vanished: for every client the
+//! total at the end must equal the total at the start plus the applied
+//! deposits, recoveries and adjustments, minus the applied withdrawals and chargebacks
+//! (plus the chargebacks it received as suspense account).
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent, TransactionType},
+    pipeline::Sink,
+    postings::{LedgerAccount, Posting},
+    pseudonym,
+    transaction_context::TransactionContext,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+};
+
+/// Clients listed in a [`ConservationError`] at most.
+const MAX_REPORTED: usize = 10;
+
+/// A client whose total does not match its applied events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Discrepancy {
+    pub client_id: u16,
+    pub expected: Price,
+    pub actual: Price,
+}
+
+/// Funds were not conserved, see [`ConservationCheck`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConservationError {
+    pub deposits: Price,
+    pub withdrawals: Price,
+    pub chargebacks: Price,
+    /// sum of the signed adjustments
+    pub adjustments: Price,
+    pub recoveries: Price,
+    /// change of the sum of all totals
+    pub net: Price,
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Display for ConservationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "funds are not conserved: deposits {} - withdrawals {} - chargebacks {} + adjustments {} + recoveries {}, totals changed by {}",
+            self.deposits, self.withdrawals, self.chargebacks, self.adjustments, self.recoveries, self.net
+        )?;
+        for d in self.discrepancies.iter().take(MAX_REPORTED) {
+            write!(
+                f,
+                "; client {} expected {} is {}",
+                pseudonym::client(d.client_id),
+                d.expected,
+                d.actual
+            )?;
+        }
+        if self.discrepancies.len() > MAX_REPORTED {
+            write!(
+                f,
+                "; and {} more clients",
+                self.discrepancies.len() - MAX_REPORTED
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConservationError {}
+
+/// [`Sink`] summing the applied events per client, and checking them against
+/// the totals of the accounts once all events are processed.
+#[derive(Debug)]
+pub struct ConservationCheck {
+    opening: HashMap<u16, Price>,
+    /// expected change of the total per client
+    flows: HashMap<u16, i64>,
+    /// applied amount per transaction type
+    sums: [i64; TransactionType::ALL.len()],
+    /// last known account per client, see [`Posting::of`]
+    accounts: HashMap<u16, Account>,
+    suspense: Option<u16>,
+}
+
+impl ConservationCheck {
+    /// Starts from the accounts in the context, e.g. after restoring a
+    /// snapshot.
+    pub fn new(context: &TransactionContext) -> Self {
+        ConservationCheck {
+            opening: context
+                .iter_accounts()
+                .map(|(client, account)| (client, account.total))
+                .collect(),
+            flows: HashMap::new(),
+            sums: [0; TransactionType::ALL.len()],
+            accounts: context
+                .iter_accounts()
+                .map(|(client, account)| (client, *account))
+                .collect(),
+            suspense: context.suspense_account(),
+        }
+    }
+
+    /// Compares the totals in the context with the applied events.
+    pub fn check(&self, context: &TransactionContext) -> Result<(), ConservationError> {
+        let mut discrepancies = Vec::new();
+        let mut net = 0;
+        let clients: BTreeSet<_> = self.opening.keys().chain(self.flows.keys()).collect();
+        for client_id in clients {
+            let opening = self.opening.get(client_id).copied().unwrap_or_default().0;
+            // pruned accounts held nothing
+            let actual = context
+                .account(*client_id)
+                .map_or(0, |account| account.total.0);
+            let expected = opening + self.flows.get(client_id).copied().unwrap_or_default();
+            net += actual - opening;
+            if actual != expected {
+                discrepancies.push(Discrepancy {
+                    client_id: *client_id,
+                    expected: Price(expected),
+                    actual: Price(actual),
+                });
+            }
+        }
+        if discrepancies.is_empty() {
+            return Ok(());
+        }
+        let sum = |ty: TransactionType| Price(self.sums[ty as usize]);
+        Err(ConservationError {
+            deposits: sum(TransactionType::Deposit),
+            withdrawals: sum(TransactionType::Withdrawal),
+            chargebacks: sum(TransactionType::Chargeback),
+            adjustments: sum(TransactionType::Adjustment),
+            recoveries: sum(TransactionType::Recovery),
+            net: Price(net),
+            discrepancies,
+        })
+    }
+}
+
+impl Sink for ConservationCheck {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let (Ok(()), Some(account)) = (result, account) else {
+            return;
+        };
+        let before = self
+            .accounts
+            .insert(event.client_id, *account)
+            .unwrap_or_default();
+        if let (TransactionType::Merge, Some(merged)) = (event.ty, event.merged_client) {
+            self.accounts.remove(&merged);
+        }
+
+        for posting in Posting::of(event, &before, account, self.suspense) {
+            self.sums[event.ty as usize] += match event.ty {
+                // summed with their sign
+                TransactionType::Adjustment => event.amount.0,
+                _ => posting.amount.0,
+            };
+            // credits raise the balance of a client, debits lower it
+            for (ledger_account, sign) in [(posting.credit, 1), (posting.debit, -1)] {
+                if let LedgerAccount::Available(client) | LedgerAccount::Held(client) =
+                    ledger_account
+                {
+                    *self.flows.entry(client).or_default() += sign * posting.amount.0;
+                }
+            }
+        }
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        Ok(self.check(context)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conservation() {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        let mut context = TransactionContext::new();
+        context
+            .apply(&event(TransactionType::Deposit, 3, 9, 70_000))
+            .unwrap();
+        let mut check = ConservationCheck::new(&context);
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 30_000),
+            event(TransactionType::Withdrawal, 1, 2, 10_000),
+            event(TransactionType::Dispute, 1, 1, 0),
+            event(TransactionType::Chargeback, 1, 1, 0),
+            event(TransactionType::Deposit, 2, 3, 10_000),
+            event(TransactionType::Withdrawal, 3, 4, 20_000),
+        ] {
+            let result = context.apply(&event);
+            check.record(&event, result.map(|_| ()), result.ok().as_ref());
+        }
+        check.check(&context).unwrap();
+
+        // funds appearing out of nowhere
+        let mut account = *context.account(2).unwrap();
+        account.total = Price(15_000);
+        context.insert_account(2, account);
+        let error = check.check(&context).unwrap_err();
+        assert_eq!(
+            error.discrepancies,
+            vec![Discrepancy {
+                client_id: 2,
+                expected: Price(10_000),
+                actual: Price(15_000),
+            }]
+        );
+        assert_eq!(error.chargebacks, Price(30_000));
+        assert_eq!(error.net, Price(-15_000));
+    }
+}
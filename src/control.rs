@@ -0,0 +1,203 @@
+//! Operational actions on a running instance, behind the admin endpoints of
+//! the HTTP server: refresh the snapshot, rotate the audit log, pause and
+//! resume ingestion and unlock an account. The actions run on the processor
+//! thread in between events, the HTTP thread waits for their outcome.
+//!
+//! The endpoints are only served when a token is set in [`TOKEN_ENV`], and
+//! requests need to carry it as `Authorization: Bearer <token>`.
+use crate::{data_types::TransactionError, metrics::metrics, shutdown::Shutdown};
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Environment variable holding the token of the admin endpoints.
+pub const TOKEN_ENV: &str = "TXE_ADMIN_TOKEN";
+
+/// How long a request waits for the processor to run the command.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// flush the sinks, refreshing the snapshot
+    Snapshot,
+    /// move the audit log aside and start a new one
+    RotateAuditLog,
+    /// unlock the account of the client
+    Unlock(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// the accounting rules rejected the command, e.g. an unknown client
+    Rejected(TransactionError),
+    /// the command failed, e.g. writing a file
+    Failed(String),
+    /// the processor did not run the command in time
+    Unavailable,
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::Rejected(error) => write!(f, "rejected: {}", error.as_str()),
+            CommandError::Failed(error) => write!(f, "failed: {error}"),
+            CommandError::Unavailable => f.write_str("the processor is not responding"),
+        }
+    }
+}
+
+type Pending = (Command, mpsc::Sender<Result<(), CommandError>>);
+
+#[derive(Default)]
+struct Shared {
+    token: String,
+    commands: Mutex<Vec<Pending>>,
+    /// set when there are commands
+    pending: AtomicBool,
+    paused: AtomicBool,
+    shutdown: Option<Shutdown>,
+}
+
+/// Hands commands from the admin endpoints to the processor. Cloning shares
+/// the queue.
+#[derive(Clone)]
+pub struct Control(Arc<Shared>);
+
+impl std::fmt::Debug for Control {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Control")
+            .field("paused", &self.0.paused)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Control {
+    /// Accepts the requests carrying the given token. Once a shutdown is
+    /// requested ingestion is no longer paused, so the queued events drain.
+    pub fn new(token: String, shutdown: Option<Shutdown>) -> Self {
+        Control(Arc::new(Shared {
+            token,
+            shutdown,
+            ..Default::default()
+        }))
+    }
+
+    /// Whether the `Authorization` header carries the token. The comparison
+    /// takes the same time for every wrong token of the same length.
+    pub fn authorize(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = authorization.and_then(|value| value.strip_prefix("Bearer ")) else {
+            return false;
+        };
+        let expected = self.0.token.as_bytes();
+        !expected.is_empty()
+            && token.len() == expected.len()
+            && token
+                .bytes()
+                .zip(expected)
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Queues the command for the processor and waits for its outcome.
+    pub fn run(&self, command: Command) -> Result<(), CommandError> {
+        let (reply, outcome) = mpsc::channel();
+        self.0
+            .commands
+            .lock()
+            .expect("control lock is poisoned")
+            .push((command, reply));
+        self.0.pending.store(true, Ordering::Release);
+        outcome
+            .recv_timeout(REPLY_TIMEOUT)
+            .unwrap_or(Err(CommandError::Unavailable))
+    }
+
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Relaxed);
+        metrics().set_paused(true);
+    }
+
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Relaxed);
+        metrics().set_paused(false);
+    }
+
+    /// Whether the processor should hold off taking events.
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+            && !self.0.shutdown.as_ref().is_some_and(Shutdown::is_requested)
+    }
+
+    /// Whether commands are waiting for the processor.
+    pub fn has_pending(&self) -> bool {
+        self.0.pending.load(Ordering::Acquire)
+    }
+
+    /// Takes the waiting commands, the outcome of each is sent back with
+    /// [`Reply::send`].
+    pub fn take(&self) -> Vec<(Command, Reply)> {
+        let mut commands = self.0.commands.lock().expect("control lock is poisoned");
+        self.0.pending.store(false, Ordering::Release);
+        commands
+            .drain(..)
+            .map(|(command, reply)| (command, Reply(reply)))
+            .collect()
+    }
+}
+
+/// Where the outcome of a command goes.
+#[derive(Debug)]
+pub struct Reply(mpsc::Sender<Result<(), CommandError>>);
+
+impl Reply {
+    pub fn send(self, outcome: Result<(), CommandError>) {
+        // the request may have timed out
+        let _ = self.0.send(outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control() {
+        let control = Control::new("secret".to_string(), None);
+        assert!(control.authorize(Some("Bearer secret")));
+        assert!(!control.authorize(Some("Bearer secreT")));
+        assert!(!control.authorize(Some("secret")));
+        assert!(!control.authorize(None));
+        assert!(!Control::new(String::new(), None).authorize(Some("Bearer ")));
+
+        let processor = control.clone();
+        let thread = std::thread::spawn(move || {
+            while !processor.has_pending() {
+                std::thread::yield_now();
+            }
+            for (command, reply) in processor.take() {
+                match command {
+                    Command::Unlock(2) => {
+                        reply.send(Err(CommandError::Rejected(TransactionError::NotFound)))
+                    }
+                    _ => reply.send(Ok(())),
+                }
+            }
+        });
+        assert_eq!(
+            control.run(Command::Unlock(2)),
+            Err(CommandError::Rejected(TransactionError::NotFound))
+        );
+        thread.join().unwrap();
+        assert!(!control.has_pending());
+
+        control.pause();
+        assert!(control.is_paused());
+        control.resume();
+        assert!(!control.is_paused());
+    }
+}
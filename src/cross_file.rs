@@ -0,0 +1,187 @@
+//! Report of the transaction ids that appear in more than one input file of a
+//! tenant, see `--cross-file-duplicates`. Such duplicates usually come from a
+//! faulty export upstream, e.g. overlapping date ranges, rather than from
+//! fraud, so they are listed even when the events are identical. The engine
+//! still rejects all but the first of them.
+//!
+//! Disputes, resolves and chargebacks refer to an earlier transaction by its
+//! id, they are not duplicates.
+use crate::{
+    data_types::{Price, TransactionEvent, TransactionType},
+    tenants::TenantInput,
+};
+use csv::Writer;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// A transaction id found in several files of a tenant.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossFileDuplicate {
+    pub tenant: String,
+    pub tx: u32,
+    /// in the order they were processed
+    pub files: Vec<PathBuf>,
+    /// whether all events with the id have the same type, client and amount
+    pub identical: bool,
+}
+
+type Fingerprint = (TransactionType, u16, Price);
+
+#[derive(Debug, Default)]
+struct Tracker {
+    files: Vec<PathBuf>,
+    /// the first event with the id, and the file it is in
+    first: HashMap<u32, (usize, Fingerprint)>,
+    /// indices of the files and whether the events are identical
+    duplicates: BTreeMap<u32, (Vec<usize>, bool)>,
+}
+
+impl Tracker {
+    fn observe(&mut self, file: usize, event: &TransactionEvent) {
+        if matches!(
+            event.ty,
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback
+        ) {
+            return;
+        }
+        let fingerprint = (event.ty, event.client_id, event.amount);
+        let Some(&(first_file, first)) = self.first.get(&event.tx) else {
+            self.first.insert(event.tx, (file, fingerprint));
+            return;
+        };
+        // repeated within a file, a plain duplicate
+        if first_file == file && !self.duplicates.contains_key(&event.tx) {
+            return;
+        }
+        let (files, identical) = self
+            .duplicates
+            .entry(event.tx)
+            .or_insert_with(|| (vec![first_file], true));
+        if !files.contains(&file) {
+            files.push(file);
+        }
+        *identical &= fingerprint == first;
+    }
+}
+
+/// Collects the cross-file duplicates of every tenant while their files are
+/// processed. The tenants are processed in parallel, each has its own lock.
+#[derive(Debug)]
+pub struct CrossFileDuplicates {
+    tenants: BTreeMap<String, Mutex<Tracker>>,
+}
+
+impl CrossFileDuplicates {
+    pub fn new(inputs: &[TenantInput]) -> Self {
+        let mut tenants: BTreeMap<String, Tracker> = BTreeMap::new();
+        for input in inputs {
+            let tracker = tenants.entry(input.name.clone()).or_default();
+            tracker.files.push(input.path.clone());
+        }
+        CrossFileDuplicates {
+            tenants: tenants
+                .into_iter()
+                .map(|(name, tracker)| (name, Mutex::new(tracker)))
+                .collect(),
+        }
+    }
+
+    /// Registers an event of the `file`-th input of the tenant.
+    pub fn observe(&self, tenant: &str, file: usize, event: &TransactionEvent) {
+        if let Some(tracker) = self.tenants.get(tenant) {
+            tracker
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .observe(file, event);
+        }
+    }
+
+    /// The duplicates ordered by tenant and transaction id.
+    pub fn duplicates(&self) -> Vec<CrossFileDuplicate> {
+        let mut duplicates = Vec::new();
+        for (tenant, tracker) in &self.tenants {
+            let tracker = tracker.lock().unwrap_or_else(|e| e.into_inner());
+            for (tx, (files, identical)) in &tracker.duplicates {
+                duplicates.push(CrossFileDuplicate {
+                    tenant: tenant.clone(),
+                    tx: *tx,
+                    files: files.iter().map(|i| tracker.files[*i].clone()).collect(),
+                    identical: *identical,
+                });
+            }
+        }
+        duplicates
+    }
+}
+
+/// Writes the duplicates as csv, the files are separated by spaces.
+pub fn write_cross_file_duplicates(
+    writer: impl Write,
+    duplicates: &[CrossFileDuplicate],
+) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(["tenant", "tx", "identical", "files"])?;
+    for duplicate in duplicates {
+        let files: Vec<_> = duplicate
+            .files
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect();
+        writer.write_record([
+            duplicate.tenant.as_str(),
+            &duplicate.tx.to_string(),
+            &duplicate.identical.to_string(),
+            &files.join(" "),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cross_file_duplicates() {
+        let input = |name: &str, path: &str| TenantInput {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        };
+        let event = |ty, tx, amount| TransactionEvent::new(ty, 1, tx, Price(amount));
+        let duplicates = CrossFileDuplicates::new(&[
+            input("acme", "jan.csv"),
+            input("acme", "feb.csv"),
+            input("acme", "mar.csv"),
+            input("globex", "all.csv"),
+        ]);
+        for (tenant, file, event) in [
+            ("acme", 0, event(TransactionType::Deposit, 1, 10_000)),
+            ("acme", 0, event(TransactionType::Deposit, 2, 10_000)),
+            ("acme", 0, event(TransactionType::Deposit, 2, 10_000)),
+            ("acme", 1, event(TransactionType::Deposit, 1, 10_000)),
+            ("acme", 1, event(TransactionType::Dispute, 2, 0)),
+            ("acme", 2, event(TransactionType::Withdrawal, 1, 5_000)),
+            ("acme", 2, event(TransactionType::Deposit, 3, 10_000)),
+            // other tenants have their own ids
+            ("globex", 0, event(TransactionType::Deposit, 3, 10_000)),
+        ] {
+            duplicates.observe(tenant, file, &event);
+        }
+
+        assert_eq!(
+            duplicates.duplicates(),
+            [CrossFileDuplicate {
+                tenant: "acme".to_string(),
+                tx: 1,
+                files: ["jan.csv", "feb.csv", "mar.csv"]
+                    .map(PathBuf::from)
+                    .to_vec(),
+                identical: false,
+            }]
+        );
+    }
+}
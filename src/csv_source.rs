@@ -1,45 +1,1133 @@
-use crate::data_types::{Account, TransactionEvent};
-use anyhow::bail;
-use csv::{ReaderBuilder, Writer};
-use rtrb::Producer;
-use std::env;
-
-/// non-blocking task that reads csv data on a separate thread and sends it over a channel
-pub fn run_csv_source(mut producer: Producer<TransactionEvent>) -> anyhow::Result<()> {
-    let Some(file_path) = env::args().nth(1) else {
-        bail!("Usage: {} <file_path>", env!("CARGO_PKG_NAME"))
-    };
+use crate::{
+    admin::{self, AdminKey},
+    data_types::{
+        Account, AccountMetadata, Price, Rejected, TransactionError, TransactionEvent,
+        TransactionType,
+    },
+    encoding,
+    metrics::metrics,
+    pipeline::{Message, Sink},
+    pseudonym,
+    schema::{suggest_columns, AmountError, AmountFormat, OutOfRange, Schema, COLUMNS},
+    seal::{self, Protection, SnapshotKey},
+    shutdown::Shutdown,
+    snapshot::{self, SnapshotFormat},
+    transaction_context::TransactionContext,
+};
+use csv::{ReaderBuilder, StringRecord, StringRecordsIntoIter, Writer};
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tracing::{debug, error, info, info_span, trace_span, warn};
 
-    let mut rdr = ReaderBuilder::new()
-        .flexible(true)
-        .trim(csv::Trim::All)
-        .from_path(file_path)?;
+/// How often a followed file is checked for appended data.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Iterator over the events in a csv file. Rows that cannot be parsed are
+/// skipped. The source stops at an I/O error, or when the input ends in the
+/// middle of a row that can not be parsed, see [`CsvSource::take_error`].
+///
+/// When `follow` is given the source does not stop at the end of the file, but
+/// waits for rows to be appended until shutdown is requested. Every time it
+/// caught up with the end of the file a [`Message::Flush`] is handed out.
+///
+/// Added to a pipeline with [`PipelineBuilder::csv_source`] the rows are read
+/// and parsed by separate stages.
+///
+/// [`PipelineBuilder::csv_source`]: crate::pipeline::PipelineBuilder::csv_source
+pub struct CsvSource {
+    path: PathBuf,
+    records: StringRecordsIntoIter<Box<dyn Read + Send>>,
+    parser: Arc<RecordParser>,
+    /// in follow mode, set once the followed file really ended, the builder
+    /// to continue reading with after a pause and the shutdown it waits for
+    follow: Option<(Arc<AtomicBool>, ReaderBuilder, Shutdown)>,
+    /// end of the input read so far, not tracked for a followed file as it
+    /// only hands out complete rows
+    tail: Option<Arc<Tail>>,
+    /// the error the source stopped at
+    error: Option<anyhow::Error>,
+    rows: u64,
+    /// bytes read by the readers before the current one
+    base: u64,
+    offset: u64,
+}
+
+impl CsvSource {
+    /// Opens the file and reads its header, in follow mode this waits for the
+    /// header to be written.
+    pub fn open(file_path: &Path, follow: Option<Shutdown>) -> anyhow::Result<Self> {
+        Self::open_with_schema(file_path, follow, &Schema::default())
+    }
+
+    /// Like [`CsvSource::open`], reading the file in the format described by
+    /// the schema and mapping its columns and type spellings. Fails when a
+    /// mapped column is not part of the file.
+    ///
+    /// Files that are not UTF-8 are transcoded, unless the schema names the
+    /// encoding it is detected from the start of the file.
+    pub fn open_with_schema(
+        file_path: &Path,
+        follow: Option<Shutdown>,
+        schema: &Schema,
+    ) -> anyhow::Result<Self> {
+        let options = &schema.csv;
+        let encoding = match options.encoding {
+            Some(encoding) => encoding,
+            None => encoding::detect_file(file_path)?,
+        };
+        info!(path = %file_path.display(), encoding = encoding.name(), "input encoding");
+        metrics().record_input_encoding(file_path, encoding.name());
+
+        let reader = encoding::decode(File::open(file_path)?, encoding);
+        let mut builder = ReaderBuilder::new();
+        builder
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .delimiter(options.delimiter as u8)
+            .quote(options.quote as u8)
+            .comment(options.comment.map(|c| c as u8))
+            .has_headers(options.headers);
+        let (mut rdr, follow, tail) = match follow {
+            Some(shutdown) => {
+                let reader = FollowReader::new(reader, shutdown.clone());
+                let ended = reader.ended.clone();
+                let rdr = builder.from_reader(Box::new(reader) as Box<dyn Read + Send>);
+                // after a pause reading continues in the middle of the file
+                builder.has_headers(false);
+                (rdr, Some((ended, builder, shutdown)), None)
+            }
+            None => {
+                let tail = Arc::new(Tail::default());
+                let reader = TailReader {
+                    inner: reader,
+                    tail: tail.clone(),
+                };
+                let rdr = builder.from_reader(Box::new(reader) as Box<dyn Read + Send>);
+                (rdr, None, Some(tail))
+            }
+        };
+
+        let headers = if options.headers {
+            let headers = schema.map_headers(rdr.headers()?)?;
+            let suggestions = suggest_columns(&headers);
+            for suggestion in &suggestions {
+                warn!(path = %file_path.display(), "{suggestion}");
+            }
+            if !suggestions.is_empty() {
+                metrics().record_column_suggestions(file_path, suggestions);
+            }
+            headers
+        } else {
+            // columns are in the canonical order
+            StringRecord::from(COLUMNS.to_vec())
+        };
+        let offset = rdr.position().byte();
+        metrics().record_source_progress(0, offset);
+
+        let types = schema
+            .maps_types()
+            .then(|| headers.iter().position(|h| h == "type"))
+            .flatten()
+            .map(|idx| (idx, schema.clone()));
+
+        Ok(CsvSource {
+            path: file_path.to_path_buf(),
+            records: rdr.into_records(),
+            parser: Arc::new(RecordParser {
+                signature: match options.headers {
+                    true => headers.iter().position(|h| h == admin::SIGNATURE_COLUMN),
+                    // the signature follows the canonical columns
+                    false => Some(COLUMNS.len()),
+                },
+                admin_key: AdminKey::from_env()?,
+                amount: options
+                    .amount_format()
+                    .and_then(|format| Some((headers.iter().position(|h| h == "amount")?, format))),
+                headers,
+                types,
+            }),
+            follow,
+            tail,
+            error: None,
+            rows: 0,
+            base: 0,
+            offset,
+        })
+    }
+
+    /// Continues reading a followed file after the csv reader stopped at a
+    /// pause. The reader consumed all data up to the pause, so nothing is lost
+    /// by replacing it.
+    fn resume(&mut self, builder: &ReaderBuilder) {
+        let empty: Box<dyn Read + Send> = Box::new(std::io::empty());
+        let records =
+            std::mem::replace(&mut self.records, builder.from_reader(empty).into_records());
+        let rdr = records.into_reader();
+        self.base += rdr.position().byte();
+        self.records = builder.from_reader(rdr.into_inner()).into_records();
+    }
+
+    /// The parser of the rows read by [`CsvSource::read_row`].
+    pub fn parser(&self) -> Arc<RecordParser> {
+        self.parser.clone()
+    }
+
+    /// Reads the next row without parsing it, `None` once the file ended.
+    pub fn read_row(&mut self) -> Option<Row> {
+        if self.error.is_some() {
+            return None;
+        }
+        let Some(record) = self.records.next() else {
+            if let Some((ended, builder, shutdown)) = self.follow.take() {
+                if !ended.load(Ordering::Relaxed) {
+                    debug!(rows = self.rows, "caught up with the followed file");
+                    self.resume(&builder);
+                    self.follow = Some((ended, builder, shutdown));
+                    return Some(Row::Pause);
+                }
+            }
+            info!(rows = self.rows, "source exhausted");
+            return None;
+        };
+        let record = match record {
+            Err(error) if error.is_io_error() => {
+                let row = self.rows + 1;
+                return self
+                    .fail(anyhow::Error::from(error).context(format!("failed to read row {row}")));
+            }
+            record => record,
+        };
+        if let Ok(fields) = &record {
+            if self.ends_mid_row() && !self.parser.is_complete(fields) {
+                let error = std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    format!("input ends in the middle of row {}", self.rows + 1),
+                );
+                return self.fail(error.into());
+            }
+        }
+        self.rows += 1;
+        let position = self.base + self.records.reader().position().byte();
+        metrics().record_source_progress(1, position - self.offset);
+        self.offset = position;
+        Some(Row::Record(record, self.rows))
+    }
+
+    /// The error the source stopped at, if any. It is logged and counted in
+    /// the [`metrics`] as well, so iterating the source does not hide it.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+
+    /// The shutdown a followed file is read until.
+    pub fn follows(&self) -> Option<&Shutdown> {
+        self.follow.as_ref().map(|(_, _, shutdown)| shutdown)
+    }
+
+    fn fail(&mut self, error: anyhow::Error) -> Option<Row> {
+        let error = error.context(format!("failed to read {}", self.path.display()));
+        error!(rows = self.rows, "source failed: {error:#}");
+        metrics().record_source_error();
+        self.error = Some(error);
+        None
+    }
+
+    /// Whether the last row read ends where the input ends, without a line
+    /// terminator.
+    fn ends_mid_row(&self) -> bool {
+        let Some(tail) = &self.tail else {
+            return false;
+        };
+        self.records.reader().position().byte() == tail.bytes.load(Ordering::Relaxed)
+            && !matches!(tail.last.load(Ordering::Relaxed), b'\n' | b'\r')
+    }
+}
+
+impl Iterator for CsvSource {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        loop {
+            match self.read_row()? {
+                Row::Record(record, row) => {
+                    if let Some(message) = self.parser.parse(record, row) {
+                        return Some(message);
+                    }
+                }
+                Row::Pause => return Some(Message::Flush),
+            }
+        }
+    }
+}
+
+/// A row read by [`CsvSource::read_row`].
+#[derive(Debug)]
+pub enum Row {
+    /// the row and its (one based) number
+    Record(csv::Result<StringRecord>, u64),
+    /// caught up with a followed file
+    Pause,
+}
+
+/// Parses the rows of a [`CsvSource`], can be shared by several threads.
+#[derive(Debug, Clone)]
+pub struct RecordParser {
+    headers: StringRecord,
+    /// index of the type column and the schema to map its spellings with
+    types: Option<(usize, Schema)>,
+    /// index of the signature column of admin events
+    signature: Option<usize>,
+    admin_key: Option<AdminKey>,
+    /// index of the amount column and how to parse it, when it is not a
+    /// plain float
+    amount: Option<(usize, AmountFormat)>,
+}
+
+impl RecordParser {
+    /// Parses a row into an event, or into a rejected event when its amount
+    /// is out of range. Returns `None` for rows of a passthrough type and rows
+    /// that can not be parsed.
+    pub fn parse(&self, record: csv::Result<StringRecord>, row: u64) -> Option<Message> {
+        let _span = trace_span!("parse", row).entered();
+        match record.and_then(|record| self.try_parse(record)) {
+            Ok(Some(message)) => Some(message),
+            Ok(None) => {
+                metrics().record_passthrough_row();
+                debug!(row, "skipping row of passthrough type");
+                None
+            }
+            Err(error) => {
+                // malformed rows are skipped, they do not affect any account
+                metrics().record_parse_failure();
+                warn!(%error, row, "skipping unparsable row");
+                None
+            }
+        }
+    }
 
-    std::thread::Builder::new()
-        .name("CSV source".to_string())
-        .spawn(move || {
-            for res in rdr.deserialize() {
-                let transaction: TransactionEvent = res.expect("hoedan");
-                producer.push(transaction).expect("CSV source died");
+    /// Whether the row parses and has an amount when its type needs one, to
+    /// tell a truncated row from a row that leaves out optional columns.
+    fn is_complete(&self, record: &StringRecord) -> bool {
+        match self.try_parse(record.clone()) {
+            Ok(Some(Message::Event(event) | Message::Rejected(Rejected { event, .. }))) => {
+                let has_amount = || {
+                    self.headers
+                        .iter()
+                        .position(|header| header == "amount")
+                        .is_some_and(|idx| record.get(idx).is_some_and(|amount| !amount.is_empty()))
+                };
+                !matches!(
+                    event.ty,
+                    TransactionType::Deposit | TransactionType::Withdrawal
+                ) || has_amount()
             }
-        })?;
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// Returns `None` for rows of a passthrough type.
+    fn try_parse(&self, mut record: StringRecord) -> csv::Result<Option<Message>> {
+        if let Some((idx, schema)) = &self.types {
+            let ty = record.get(*idx).unwrap_or_default();
+            if schema.is_passthrough(ty) {
+                return Ok(None);
+            }
+            if let Some(ty) = schema.map_type(ty) {
+                record = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == *idx { ty } else { field })
+                    .collect();
+            }
+        }
+        // parsed before the rest of the row, which would fail on separators
+        let (mut amount, mut out_of_range) = (None, false);
+        if let Some((idx, format)) = &self.amount {
+            if let Some(field) = record.get(*idx).filter(|field| !field.is_empty()) {
+                amount = Some(match format.parse(field) {
+                    Ok(amount) => amount,
+                    Err(AmountError::OutOfRange(bound)) => {
+                        match format.out_of_range {
+                            OutOfRange::Reject => out_of_range = true,
+                            OutOfRange::Clamp => {
+                                warn!(amount = field, clamped = %bound, "amount out of range")
+                            }
+                        }
+                        bound
+                    }
+                    Err(AmountError::Invalid(error)) => {
+                        return Err(
+                            std::io::Error::new(std::io::ErrorKind::InvalidData, error).into()
+                        )
+                    }
+                });
+                record = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == *idx { "" } else { field })
+                    .collect();
+            }
+        }
+        let mut event: TransactionEvent = record.deserialize(Some(&self.headers))?;
+        if let Some(amount) = amount {
+            event.amount = amount;
+        }
+        if out_of_range {
+            // carries the bound as its amount
+            let error = TransactionError::AmountOutOfRange;
+            return Ok(Some(Message::Rejected(Rejected { event, error })));
+        }
+        let signature = self.signature.and_then(|idx| record.get(idx));
+        admin::authenticate(&mut event, signature, self.admin_key.as_ref());
+        Ok(Some(Message::Event(event)))
+    }
+}
+
+/// The amount of bytes read and the last of them.
+#[derive(Debug, Default)]
+struct Tail {
+    bytes: AtomicU64,
+    last: AtomicU8,
+}
+
+struct TailReader<R> {
+    inner: R,
+    tail: Arc<Tail>,
+}
+
+impl<R: Read> Read for TailReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.tail.bytes.fetch_add(n as u64, Ordering::Relaxed);
+            self.tail.last.store(buf[n - 1], Ordering::Relaxed);
+        }
+        Ok(n)
+    }
+}
+
+/// Reader that treats the end of the file as "no data yet". Only complete rows
+/// are handed out, an incomplete row that is still pending at shutdown is
+/// discarded.
+///
+/// The first time it catches up with the end of the file after handing out
+/// rows, it reports a pause as end of input once. Only at shutdown `ended` is
+/// set.
+struct FollowReader<R> {
+    inner: BufReader<R>,
+    line: Vec<u8>,
+    pos: usize,
+    shutdown: Shutdown,
+    /// whether rows were handed out since the last pause
+    unpaused: bool,
+    ended: Arc<AtomicBool>,
+}
+
+impl<R: Read> FollowReader<R> {
+    fn new(inner: R, shutdown: Shutdown) -> Self {
+        FollowReader {
+            inner: BufReader::new(inner),
+            line: Vec::new(),
+            pos: 0,
+            shutdown,
+            unpaused: false,
+            ended: Arc::default(),
+        }
+    }
+}
+
+impl<R: Read> Read for FollowReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while !self.line.ends_with(b"\n") {
+            if self.inner.read_until(b'\n', &mut self.line)? > 0 {
+                continue;
+            }
+
+            if self.shutdown.is_requested() {
+                if !self.line.is_empty() {
+                    warn!(bytes = self.line.len(), "discarding incomplete row");
+                    metrics().record_discarded_row();
+                    self.line.clear();
+                }
+                self.ended.store(true, Ordering::Relaxed);
+                return Ok(0);
+            }
+
+            if self.unpaused {
+                self.unpaused = false;
+                return Ok(0);
+            }
+
+            std::thread::sleep(FOLLOW_POLL_INTERVAL);
+        }
+        self.unpaused = true;
+
+        let n = buf.len().min(self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        if self.pos == self.line.len() {
+            self.line.clear();
+            self.pos = 0;
+        }
+        Ok(n)
+    }
+}
+
+/// Writes the accounts as csv to stdout. When `extended` is set, the audit
+/// metadata of each account is appended as extra columns.
+pub fn write_accounts_to_csv(
+    accounts: impl Iterator<Item = (u16, Account)>,
+    extended: bool,
+) -> anyhow::Result<()> {
+    let _span = info_span!("output").entered();
+    write_accounts(std::io::stdout(), accounts, extended, true)
+}
+
+/// Column appended to the account output, see
+/// [`write_accounts_with_columns_to_csv`].
+pub struct Column<'a> {
+    pub name: &'static str,
+    pub value: ColumnValue<'a>,
+}
+
+/// Formats the value of a [`Column`] for the account of a client.
+pub type ColumnValue<'a> = Box<dyn Fn(u16, &Account) -> String + 'a>;
+
+/// Writes the accounts as csv to stdout, like [`write_accounts_to_csv`], with
+/// the given columns appended.
+pub fn write_accounts_with_columns_to_csv(
+    accounts: impl Iterator<Item = (u16, Account)>,
+    extended: bool,
+    columns: &[Column],
+) -> anyhow::Result<()> {
+    let _span = info_span!("output").entered();
+    let mut writer = Writer::from_writer(std::io::stdout());
+    let mut header = account_header(extended);
+    header.extend(columns.iter().map(|column| column.name));
+    writer.write_record(header)?;
+    for (client_id, account) in accounts {
+        let mut record =
+            account_record(pseudonym::client(client_id).to_string(), &account, extended);
+        record.extend(
+            columns
+                .iter()
+                .map(|column| (column.value)(client_id, &account)),
+        );
+        writer.write_record(record)?;
+    }
+    Ok(writer.flush()?)
+}
+
+/// Writes the accounts as csv to the given path.
+pub fn write_accounts_to_file(
+    accounts: impl Iterator<Item = (u16, Account)>,
+    path: &Path,
+    extended: bool,
+) -> anyhow::Result<()> {
+    let _span = info_span!("output", path = %path.display()).entered();
+    write_accounts(File::create(path)?, accounts, extended, true)
+}
+
+/// [`Sink`] writing the final accounts as csv, like [`write_accounts_to_csv`].
+#[derive(Debug)]
+pub struct AccountsCsvSink<W> {
+    writer: W,
+    extended: bool,
+}
+
+impl<W: Write> AccountsCsvSink<W> {
+    pub fn new(writer: W, extended: bool) -> Self {
+        AccountsCsvSink { writer, extended }
+    }
+}
 
-    Ok(())
+impl<W: Write> Sink for AccountsCsvSink<W> {
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        let accounts = context.iter_accounts().map(|(id, account)| (id, *account));
+        write_accounts(&mut self.writer, accounts, self.extended, true)
+    }
 }
 
-pub fn write_accounts_to_csv(accounts: impl Iterator<Item = (u16, Account)>) -> anyhow::Result<()> {
+/// Writes the accounts of multiple tenants as csv to stdout, prefixing every
+/// row with the name of the tenant.
+pub fn write_tenant_accounts_to_csv<'a>(
+    tenants: impl Iterator<Item = (&'a str, &'a TransactionContext)>,
+    extended: bool,
+) -> anyhow::Result<()> {
+    let _span = info_span!("output").entered();
     let mut writer = Writer::from_writer(std::io::stdout());
-    writer.write_record(["client", "available", "held", "total", "locked"])?;
+    let mut header = vec!["tenant"];
+    header.extend(account_header(extended));
+    writer.write_record(header)?;
+
+    for (tenant, context) in tenants {
+        for (client_id, account) in context.iter_accounts() {
+            let mut record = vec![tenant.to_string()];
+            record.extend(account_record(
+                pseudonym::client(client_id).to_string(),
+                account,
+                extended,
+            ));
+            writer.write_record(&record)?;
+        }
+    }
+
+    Ok(writer.flush()?)
+}
+
+/// Writes a snapshot of the current account state as csv to the given path.
+pub fn write_snapshot(context: &TransactionContext, path: &Path) -> anyhow::Result<()> {
+    let _span = info_span!("snapshot", path = %path.display()).entered();
+    let accounts = context.iter_accounts().map(|(id, account)| (id, *account));
+    write_accounts(File::create(path)?, accounts, true, false)
+}
+
+/// [`Sink`] writing a snapshot (see [`write_snapshot`]) at every batch
+/// boundary with changes, and once all events are processed. Snapshots are
+/// replaced atomically, so readers never see a partially written one.
+#[derive(Debug)]
+pub struct SnapshotSink {
+    path: PathBuf,
+    format: SnapshotFormat,
+    protection: Option<(Protection, Arc<SnapshotKey>)>,
+    keep: usize,
+    /// whether events were processed since the last snapshot
+    dirty: bool,
+}
+
+impl SnapshotSink {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SnapshotSink {
+            path: path.into(),
+            format: SnapshotFormat::Csv,
+            protection: None,
+            keep: 0,
+            dirty: false,
+        }
+    }
+
+    pub fn format(mut self, format: SnapshotFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Encrypt or sign the snapshots with the given key, see [`crate::seal`].
+    pub fn protect(mut self, protection: Protection, key: Arc<SnapshotKey>) -> Self {
+        self.protection = Some((protection, key));
+        self
+    }
+
+    /// Keep the given amount of previous snapshots, rotated like log files:
+    /// `<path>.1` is the most recent one before `<path>`.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    fn with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(suffix);
+        path.into()
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        self.with_suffix(&format!(".{n}"))
+    }
+
+    fn write(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        let tmp = self.with_suffix(".tmp");
+        match (&self.protection, self.format) {
+            (None, SnapshotFormat::Csv) => write_snapshot(context, &tmp)?,
+            (None, SnapshotFormat::Binary) => {
+                snapshot::write_binary_snapshot_to_file(context, &tmp)?
+            }
+            (Some((protection, key)), format) => {
+                let _span = info_span!("snapshot", path = %tmp.display()).entered();
+                let mut plain = Vec::new();
+                match format {
+                    SnapshotFormat::Csv => {
+                        let accounts = context.iter_accounts().map(|(id, account)| (id, *account));
+                        write_accounts(&mut plain, accounts, true, false)?
+                    }
+                    SnapshotFormat::Binary => snapshot::write_binary_snapshot(context, &mut plain)?,
+                }
+                std::fs::write(&tmp, seal::seal(&plain, *protection, key)?)?;
+            }
+        }
+
+        if self.keep > 0 && self.path.exists() {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        std::fs::rename(tmp, &self.path)?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Sink for SnapshotSink {
+    fn record(
+        &mut self,
+        _event: &TransactionEvent,
+        _result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+        self.dirty = true;
+    }
+
+    fn flush(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        if self.dirty {
+            self.write(context)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        self.write(context)
+    }
+}
 
+/// Row of an account output file, the audit columns are only present in the
+/// extended output. `available` is derived from `total` and `held`.
+#[derive(Debug, Deserialize)]
+struct AccountRow {
+    client: u16,
+    held: Price,
+    total: Price,
+    locked: bool,
+    #[serde(default)]
+    tx_count: u32,
+    #[serde(default)]
+    disputes: u32,
+    #[serde(default)]
+    chargebacks: u32,
+    #[serde(default)]
+    locked_at: Option<u64>,
+    #[serde(default)]
+    lock_tx: Option<u32>,
+    #[serde(default)]
+    withdrawals_locked: bool,
+    #[serde(default)]
+    shortfall: Price,
+    #[serde(default)]
+    overdrawn: bool,
+}
+
+/// Reads accounts from an account output file or snapshot, as written by
+/// [`write_accounts_to_csv`], [`write_snapshot`] or
+/// [`snapshot::write_binary_snapshot`].
+pub fn read_accounts(path: &Path) -> anyhow::Result<Vec<(u16, Account)>> {
+    if snapshot::is_binary_snapshot(path)? {
+        let mut context = TransactionContext::new();
+        snapshot::restore(&mut context, path)?;
+        return Ok(context.into_iter_accounts().collect());
+    }
+    let data = seal::read_file(path)?;
+    let mut rdr = ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(data.as_slice());
+    let mut accounts = Vec::new();
+    for row in rdr.deserialize() {
+        let row: AccountRow = row?;
+        let account = Account {
+            total: row.total,
+            held: row.held,
+            locked: row.locked,
+            withdrawals_locked: row.withdrawals_locked,
+            shortfall: row.shortfall,
+            meta: AccountMetadata {
+                tx_count: row.tx_count,
+                disputes: row.disputes,
+                chargebacks: row.chargebacks,
+                locked_at: row.locked_at,
+                lock_tx: row.lock_tx,
+                overdrawn: row.overdrawn,
+            },
+        };
+        accounts.push((row.client, account));
+    }
+    Ok(accounts)
+}
+
+/// Snapshots are read back by the engine, so they keep the real client ids
+/// when the outputs are pseudonymized.
+fn write_accounts(
+    writer: impl Write,
+    accounts: impl Iterator<Item = (u16, Account)>,
+    extended: bool,
+    pseudonymize: bool,
+) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(account_header(extended))?;
     for (client_id, account) in accounts {
-        writer.write_record(&[
-            client_id.to_string(),
-            account.available().to_string(),
-            account.held.to_string(),
-            account.total.to_string(),
-            account.locked.to_string(),
-        ])?;
+        let client = match pseudonymize {
+            true => pseudonym::client(client_id).to_string(),
+            false => client_id.to_string(),
+        };
+        writer.write_record(account_record(client, &account, extended))?;
     }
 
     Ok(writer.flush()?)
 }
+
+fn account_header(extended: bool) -> Vec<&'static str> {
+    let mut header = vec!["client", "available", "held", "total", "locked"];
+    if extended {
+        header.extend([
+            "tx_count",
+            "disputes",
+            "chargebacks",
+            "locked_at",
+            "lock_tx",
+            "withdrawals_locked",
+            "shortfall",
+            "overdrawn",
+        ]);
+    }
+    header
+}
+
+fn account_record(client: String, account: &Account, extended: bool) -> Vec<String> {
+    let mut record = vec![
+        client,
+        account.available().to_string(),
+        account.held.to_string(),
+        account.total.to_string(),
+        account.locked.to_string(),
+    ];
+
+    if extended {
+        let meta = &account.meta;
+        record.extend([
+            meta.tx_count.to_string(),
+            meta.disputes.to_string(),
+            meta.chargebacks.to_string(),
+            meta.locked_at.map(|t| t.to_string()).unwrap_or_default(),
+            meta.lock_tx.map(|t| t.to_string()).unwrap_or_default(),
+            account.withdrawals_locked.to_string(),
+            account.shortfall.to_string(),
+            meta.overdrawn.to_string(),
+        ]);
+    }
+    record
+}
+
+/// Writes the transaction history of every client as csv to the given path,
+/// ordered by client id. History tracking needs to be enabled on the context.
+/// When it tracks balances, the running balances follow every transaction.
+pub fn write_statement_to_csv(context: &TransactionContext, path: &Path) -> anyhow::Result<()> {
+    let _span = info_span!("statement", path = %path.display()).entered();
+    let mut writer = Writer::from_path(path)?;
+    let running = context.tracks_balances();
+    let mut header = vec!["client", "type", "tx", "amount", "reference"];
+    if running {
+        header.extend(["available", "held", "total"]);
+    }
+    writer.write_record(header)?;
+
+    let mut clients: Vec<u16> = context.iter_accounts().map(|(id, _)| id).collect();
+    clients.sort_unstable();
+
+    for client_id in clients {
+        let mut balances = context.balances(client_id);
+        for record in context.history(client_id) {
+            let mut row = vec![
+                pseudonym::client(client_id).to_string(),
+                record.ty.to_string(),
+                record.tx.to_string(),
+                record.amount.to_string(),
+                record
+                    .reference
+                    .map(|reference| reference.to_string())
+                    .unwrap_or_default(),
+            ];
+            if running {
+                let balance = balances.next().unwrap_or_default();
+                row.extend([
+                    balance.available().to_string(),
+                    balance.held.to_string(),
+                    balance.total.to_string(),
+                ]);
+            }
+            writer.write_record(&row)?;
+        }
+    }
+
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::TransactionType,
+        schema::{CsvOptions, Locale},
+    };
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut context = TransactionContext::new();
+        let account = Account {
+            total: Price(125_000),
+            held: Price(5_000),
+            locked: true,
+            withdrawals_locked: false,
+            shortfall: Price(2_500),
+            meta: AccountMetadata {
+                tx_count: 3,
+                disputes: 1,
+                chargebacks: 1,
+                locked_at: Some(1_700_000_000),
+                lock_tx: Some(42),
+                overdrawn: true,
+            },
+        };
+        context.insert_account(7, account);
+        context.insert_account(
+            8,
+            Account {
+                withdrawals_locked: true,
+                ..Default::default()
+            },
+        );
+
+        let path = std::env::temp_dir().join("txe_test_snapshot_roundtrip.csv");
+        write_snapshot(&context, &path).unwrap();
+        let mut accounts = read_accounts(&path).unwrap();
+        accounts.sort_by_key(|(id, _)| *id);
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[0].0, 7);
+        assert_eq!(accounts[0].1.total, account.total);
+        assert_eq!(accounts[0].1.held, account.held);
+        assert_eq!(accounts[0].1.shortfall, account.shortfall);
+        assert!(accounts[0].1.locked);
+        assert_eq!(accounts[0].1.meta, account.meta);
+        assert_eq!(accounts[1].1.meta, AccountMetadata::default());
+        assert!(!accounts[1].1.locked && accounts[1].1.withdrawals_locked);
+    }
+
+    #[test]
+    fn test_snapshot_sink_rotates() {
+        let dir = std::env::temp_dir().join("txe_test_snapshot_sink_rotates");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.csv");
+        let mut sink = SnapshotSink::new(&path).keep(2);
+        let mut context = TransactionContext::new();
+
+        // nothing changed, nothing to write
+        sink.flush(&context).unwrap();
+        assert!(!path.exists());
+
+        for client in 1..=4 {
+            context.insert_account(client, Account::default());
+            sink.dirty = true;
+            sink.flush(&context).unwrap();
+        }
+        let count = |path: PathBuf| read_accounts(&path).unwrap().len();
+        assert_eq!(count(path.clone()), 4);
+        assert_eq!(count(dir.join("state.csv.1")), 3);
+        assert_eq!(count(dir.join("state.csv.2")), 2);
+        assert!(!dir.join("state.csv.3").exists());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_csv_source_with_schema() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_schema.csv");
+        std::fs::write(
+            &path,
+            "txn_type,customer,txid,value\nwithdraw,1,2,1.5\nfee,1,3,0.1\ndeposit,2,3,2\n",
+        )
+        .unwrap();
+        let schema = Schema {
+            columns: [
+                ("type", "txn_type"),
+                ("client", "customer"),
+                ("tx", "txid"),
+                ("amount", "value"),
+            ]
+            .into_iter()
+            .map(|(c, i)| (c.to_string(), i.to_string()))
+            .collect(),
+            types: [("withdraw".to_string(), TransactionType::Withdrawal)].into(),
+            passthrough: ["fee".to_string()].into(),
+            ..Default::default()
+        };
+        let events: Vec<_> = CsvSource::open_with_schema(&path, None, &schema)
+            .unwrap()
+            .filter_map(Message::into_event)
+            .collect();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].ty, TransactionType::Withdrawal);
+        assert_eq!(events[0].client_id, 1);
+        assert_eq!(events[0].tx, 2);
+        assert_eq!(events[0].amount, Price(15_000));
+        assert_eq!(events[1].ty, TransactionType::Deposit);
+    }
+
+    #[test]
+    fn test_csv_source_options() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_options.csv");
+        std::fs::write(
+            &path,
+            "# exported by bank\ndeposit;1;1;'1,5'\nwithdrawal;1;2;0.5\n",
+        )
+        .unwrap();
+        let schema = Schema {
+            csv: CsvOptions {
+                delimiter: ';',
+                quote: '\'',
+                comment: Some('#'),
+                headers: false,
+                encoding: None,
+                rounding: None,
+                locale: None,
+                out_of_range: None,
+            },
+            ..Default::default()
+        };
+        let events: Vec<_> = CsvSource::open_with_schema(&path, None, &schema)
+            .unwrap()
+            .filter_map(Message::into_event)
+            .collect();
+        let _ = std::fs::remove_file(path);
+
+        // the amount fails to parse, a decimal comma is not supported
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].ty, TransactionType::Withdrawal);
+        assert_eq!(events[0].amount, Price(5_000));
+    }
+
+    #[test]
+    fn test_csv_source_amounts() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_amounts.csv");
+        std::fs::write(
+            &path,
+            "type;client;tx;amount\ndeposit;1;1;1.234,5\ndeposit;1;2;1e300\ndeposit;1;3;1,2,3\n",
+        )
+        .unwrap();
+        let mut schema = Schema {
+            csv: CsvOptions {
+                delimiter: ';',
+                locale: Some(Locale::De),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let messages = |schema: &Schema| -> Vec<_> {
+            CsvSource::open_with_schema(&path, None, schema)
+                .unwrap()
+                .collect()
+        };
+        let rejected = messages(&schema);
+        schema.csv.out_of_range = Some(OutOfRange::Clamp);
+        let clamped = messages(&schema);
+        let _ = std::fs::remove_file(&path);
+
+        // the last row fails to parse
+        assert_eq!(rejected.len(), 2);
+        assert!(matches!(rejected[0], Message::Event(event) if event.amount == Price(12_345_000)));
+        assert!(matches!(
+            rejected[1],
+            Message::Rejected(Rejected { event, error: TransactionError::AmountOutOfRange })
+                if event.tx == 2
+        ));
+        assert!(matches!(clamped[1], Message::Event(event) if event.amount == Price::MAX));
+    }
+
+    #[test]
+    fn test_csv_source_utf16() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_utf16.csv");
+        let content = "type,client,tx,amount\r\ndeposit,1,1,1.5\r\n";
+        let mut bytes = vec![0xff, 0xfe];
+        bytes.extend(content.encode_utf16().flat_map(u16::to_le_bytes));
+        std::fs::write(&path, bytes).unwrap();
+        let events: Vec<_> = CsvSource::open(&path, None)
+            .unwrap()
+            .filter_map(Message::into_event)
+            .collect();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].amount, Price(15_000));
+    }
+
+    #[test]
+    fn test_csv_source_truncated() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_truncated.csv");
+        let events = |content: &str| {
+            std::fs::write(&path, content).unwrap();
+            let mut source = CsvSource::open(&path, None).unwrap();
+            let events: Vec<_> = source.by_ref().filter_map(Message::into_event).collect();
+            (events.len(), source.take_error())
+        };
+
+        let (read, error) = events("type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2");
+        assert_eq!(read, 1);
+        let error = error.unwrap();
+        assert!(error.chain().any(|cause| cause
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::UnexpectedEof)));
+
+        // complete rows do not need a line terminator
+        let (read, error) = events("type,client,tx,amount\ndeposit,1,1,1.0\ndispute,1,1");
+        assert_eq!(read, 2);
+        assert!(error.is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_csv_source_follow_flushes() {
+        let path = std::env::temp_dir().join("txe_test_csv_source_follow.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let shutdown = Shutdown::default();
+        let mut source = CsvSource::open(&path, Some(shutdown.clone())).unwrap();
+
+        assert!(matches!(source.next(), Some(Message::Event(e)) if e.tx == 1));
+        assert!(matches!(source.next(), Some(Message::Flush)));
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"deposit,1,2,1.0\n").unwrap();
+        assert!(matches!(source.next(), Some(Message::Event(e)) if e.tx == 2));
+        assert!(matches!(source.next(), Some(Message::Flush)));
+
+        shutdown.request();
+        assert!(source.next().is_none());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_follow_reader_discards_incomplete_row() {
+        let shutdown = Shutdown::default();
+        shutdown.request();
+        let input: &[u8] = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,12";
+        let mut reader = FollowReader::new(input, shutdown);
+
+        let mut out = String::new();
+        reader.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "type,client,tx,amount\ndeposit,1,1,1.0\n");
+    }
+}
@@ -1,169 +1,287 @@
-use serde::{de, Deserialize, Deserializer};
-use std::fmt::{Debug, Display};
+use crate::reference::Reference;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt::Display, str::FromStr};
 
-pub const PRICE_SCALAR: i64 = 10000;
+pub use txe_accounting::{
+    Account, AccountMetadata, Float2PriceError, HoldPolicy, LockPolicy, ParseDecimalError, Price,
+    Rounding, TransactionError, TransactionFlags, TransactionType, PRICE_SCALAR,
+};
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
-pub struct Price(pub i64);
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct TransactionEvent {
+    #[serde(rename = "type")]
+    pub ty: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: u16,
+    pub tx: u32,
+    pub amount: Price,
+    /// unix timestamp in seconds, from the optional `timestamp` column
+    #[serde(default, deserialize_with = "deserialize_timestamp")]
+    pub timestamp: Option<u64>,
+    /// why an adjustment was made, from the optional `reason_code` column
+    #[serde(
+        default,
+        rename = "reason_code",
+        deserialize_with = "deserialize_reason"
+    )]
+    pub reason: ReasonCode,
+    /// the client a merge moves into `client_id`, from the optional
+    /// `merged_client` column
+    #[serde(default)]
+    pub merged_client: Option<u16>,
+    /// external reference, from the optional `reference` column
+    #[serde(default, deserialize_with = "deserialize_reference")]
+    pub reference: Option<Reference>,
+    /// index in the annotations of an [`crate::enrichment::Enrichment`] stage
+    #[serde(skip)]
+    pub annotation: Option<u32>,
+    /// set by the source when an admin event carries a valid signature, see
+    /// [`crate::admin`]
+    #[serde(skip)]
+    pub authenticated: bool,
+}
 
-impl Price {
-    pub fn make_absolute(&mut self) {
-        self.0 = self.0.abs();
+impl TransactionEvent {
+    /// An event without any of the optional columns.
+    pub fn new(ty: TransactionType, client_id: u16, tx: u32, amount: Price) -> Self {
+        TransactionEvent {
+            ty,
+            client_id,
+            tx,
+            amount,
+            timestamp: None,
+            reason: Default::default(),
+            merged_client: None,
+            reference: None,
+            annotation: None,
+            authenticated: false,
+        }
     }
+}
 
-    pub fn try_add(&mut self, other: Price) -> bool {
-        let Some(val) = self.0.checked_add(other.0) else {
-            return false;
-        };
-        self.0 = val;
-        true
+fn deserialize_timestamp<'de, D>(deserializer: D) -> Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    if value.is_empty() {
+        return Ok(None);
     }
+    parse_timestamp(&value).map(Some).map_err(de::Error::custom)
+}
+
+fn deserialize_reference<'de, D>(deserializer: D) -> Result<Option<Reference>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?;
+    Ok(value.as_deref().and_then(Reference::intern))
+}
+
+fn deserialize_reason<'de, D>(deserializer: D) -> Result<ReasonCode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = Option::<String>::deserialize(deserializer)?.unwrap_or_default();
+    value.parse().map_err(de::Error::custom)
+}
 
-    pub fn try_sub(&mut self, other: Price) -> bool {
-        let Some(val) = self.0.checked_sub(other.0) else {
-            return false;
+/// A short code telling why an adjustment was made, e.g. `fee_refund`. Stored
+/// inline so events stay `Copy`, empty when not given.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReasonCode {
+    len: u8,
+    bytes: [u8; ReasonCode::MAX_LEN],
+}
+
+impl ReasonCode {
+    pub const MAX_LEN: usize = 15;
+
+    pub fn as_str(&self) -> &str {
+        // only built from ascii
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl FromStr for ReasonCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() > Self::MAX_LEN {
+            return Err(format!(
+                "reason code `{s}` is longer than {} characters",
+                Self::MAX_LEN
+            ));
+        }
+        if !s
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+        {
+            return Err(format!(
+                "reason code `{s}` may only hold letters, digits, `_` and `-`"
+            ));
+        }
+        let mut code = ReasonCode {
+            len: s.len() as u8,
+            ..Default::default()
         };
-        self.0 = val;
-        true
+        code.bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Ok(code)
+    }
+}
+
+impl Display for ReasonCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
     }
 }
 
-impl Display for Price {
+impl std::fmt::Debug for ReasonCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let integral = self.0 / PRICE_SCALAR;
-        let fractional = self.0.abs() % PRICE_SCALAR;
-        write!(f, "{}.{}", integral, fractional)
+        write!(f, "{:?}", self.as_str())
     }
 }
 
-#[derive(Debug)]
-pub struct Float2PriceError;
+impl Serialize for ReasonCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
 
-/// We want to be conservative converting prices here and reject any
-/// over/underflow while converting.
-/// * Infinite and NaN values are rejected and result in an Error.
-/// * The conversion uses the overall price scalar to provide the appropriate
-///   decimal precision (See [`PRICE_SCALAR`]).
-/// * subnormal numbers are not handled.
-impl TryFrom<f64> for Price {
-    type Error = Float2PriceError;
+/// Parses a timestamp given as unix seconds, as a date (`2024-01-31`) or as a
+/// UTC date and time (`2024-01-31T23:59:59Z`) into unix seconds.
+pub fn parse_timestamp(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid timestamp `{value}`");
+    if value.bytes().all(|b| b.is_ascii_digit()) {
+        return value.parse().map_err(|_| invalid());
+    }
 
-    fn try_from(mut value: f64) -> Result<Self, Self::Error> {
-        if value.is_infinite() || value.is_nan() {
-            return Err(Float2PriceError);
-        }
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time.strip_suffix('Z').unwrap_or(time))),
+        None => (value, None),
+    };
+    let number = |s: &str| s.parse::<u32>().map_err(|_| invalid());
 
-        value = (value * PRICE_SCALAR as f64).round();
-        if value <= i64::MAX as f64 && value >= i64::MIN as f64 {
-            Ok(Price(value as i64))
-        } else {
-            Err(Float2PriceError)
-        }
+    let mut parts = date.splitn(3, '-');
+    let (Some(year), Some(month), Some(day)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    let (year, month, day) = (number(year)?, number(month)?, number(day)?);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
     }
-}
 
-impl<'de> Deserialize<'de> for Price {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        let opt_float: Option<f64> = Deserialize::deserialize(deserializer)?;
-        let value = opt_float.unwrap_or_default();
-        Price::try_from(value).map_err(|_| de::Error::custom("Invalid amount"))
+    let (hour, minute, second) = match time {
+        Some(time) => {
+            let mut parts = time.splitn(3, ':');
+            let (Some(hour), Some(minute), Some(second)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(invalid());
+            };
+            (number(hour)?, number(minute)?, number(second)?)
+        }
+        None => (0, 0, 0),
+    };
+    if hour > 23 || minute > 59 || second > 60 {
+        return Err(invalid());
     }
+
+    let days = days_since_epoch(year, month, day);
+    Ok(days * 86400 + hour as u64 * 3600 + minute as u64 * 60 + second as u64)
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+/// Days between 1970-01-01 and the given date of the proleptic gregorian
+/// calendar, see <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+fn days_since_epoch(year: u32, month: u32, day: u32) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as u64 + 9) % 12) + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
 }
 
-#[derive(Debug, Deserialize)]
-pub struct TransactionEvent {
-    #[serde(rename = "type")]
+/// An applied transaction as recorded in the history of a client. For
+/// disputes, resolves and chargebacks `amount` is the amount of the referenced
+/// transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TxRecord {
     pub ty: TransactionType,
-    #[serde(rename = "client")]
-    pub client_id: u16,
     pub tx: u32,
     pub amount: Price,
+    pub reference: Option<Reference>,
 }
 
-#[derive(Debug, PartialEq)]
-pub enum TransactionFlags {
-    None,
-    Disputed,
-    Resolved,
-    Chargeback,
+/// The balances of an account right after an applied event, see
+/// [`crate::transaction_context::TransactionContext::track_balances`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Balance {
+    pub held: Price,
+    pub total: Price,
 }
 
-#[derive(Debug)]
-pub enum TransactionError {
-    Overflow,
-    Duplicate,
-    NotFound,
-    InvalidDispute,
-    InsufficientFunds,
-    Locked,
-    ClientMismatch,
+impl Balance {
+    pub fn available(&self) -> Price {
+        Price(self.total.0 - self.held.0)
+    }
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-pub struct Account {
-    pub total: Price,
-    pub held: Price,
-    pub locked: bool,
+/// An event that was not applied, together with the reason.
+#[derive(Debug, Clone, Copy)]
+pub struct Rejected {
+    pub event: TransactionEvent,
+    pub error: TransactionError,
 }
 
-impl Account {
-    pub fn withdraw(&mut self, amount: Price) -> Result<(), TransactionError> {
-        if self.locked {
-            return Err(TransactionError::Locked);
-        }
-
-        if amount > self.available() {
-            return Err(TransactionError::InsufficientFunds);
-        }
-
-        if !self.total.try_sub(amount) {
-            return Err(TransactionError::Overflow);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1700000000"), Ok(1_700_000_000));
+        assert_eq!(parse_timestamp("1970-01-01"), Ok(0));
+        assert_eq!(parse_timestamp("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_timestamp("2024-03-01T12:30:15Z"), Ok(1_709_296_215));
+        assert_eq!(parse_timestamp("2024-03-01T12:30:15"), Ok(1_709_296_215));
+        assert!(parse_timestamp("2024-13-01").is_err());
+        assert!(parse_timestamp("2024-01").is_err());
+        assert!(parse_timestamp("yesterday").is_err());
     }
 
-    pub fn deposit(&mut self, amount: Price) -> Result<(), TransactionError> {
-        if self.locked {
-            return Err(TransactionError::Locked);
-        }
+    #[test]
+    fn test_deserialize_timestamp_column() {
+        let input =
+            "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,2024-01-01\ndeposit,1,2,1.0,\n";
+        let mut rdr = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let events: Vec<TransactionEvent> = rdr.deserialize().map(Result::unwrap).collect();
+        assert_eq!(events[0].timestamp, Some(1_704_067_200));
+        assert_eq!(events[1].timestamp, None);
 
-        if !self.total.try_add(amount) {
-            return Err(TransactionError::Overflow);
-        }
-        Ok(())
-    }
-
-    pub fn dispute(&mut self, amount: Price) {
-        self.held.try_add(amount);
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut rdr = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let event: TransactionEvent = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(event.timestamp, None);
     }
 
-    pub fn resolve(&mut self, amount: Price) {
-        self.held.try_sub(amount);
-    }
+    #[test]
+    fn test_reason_code() {
+        let input =
+            "type,client,tx,amount,timestamp,reason_code\nadjustment,1,1,-1.5,,fee_refund\n";
+        let mut rdr = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+        let event: TransactionEvent = rdr.deserialize().next().unwrap().unwrap();
+        assert_eq!(event.reason.as_str(), "fee_refund");
+        assert!(!event.amount.0.is_positive());
 
-    pub fn chargeback(&mut self, amount: Price) {
-        self.held.try_sub(amount);
-        self.total.try_sub(amount);
-        self.locked = true;
-    }
-
-    #[inline]
-    pub fn available(&self) -> Price {
-        let scaled = self.total.0 - self.held.0;
-        Price(scaled)
+        assert!(ReasonCode::default().is_empty());
+        assert!("a_much_too_long_reason".parse::<ReasonCode>().is_err());
+        assert!("fee refund".parse::<ReasonCode>().is_err());
     }
 }
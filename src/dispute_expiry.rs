@@ -0,0 +1,145 @@
+//! Automatic resolution of disputes that stay open for too long, like the
+//! time limits of the card schemes, so funds are not held forever. A dispute
+//! that is neither resolved nor charged back within the configured period is
+//! resolved by the engine, with the [`EXPIRED`] reason code flagging the
+//! resolve in the audit log and the open disputes report.
+//!
+//! Time is taken from the timestamps of the events, a replay expires the same
+//! disputes at the same point. Disputes without a timestamp never expire.
+use crate::{
+    data_types::{ReasonCode, TransactionError, TransactionEvent, TransactionType},
+    transaction_context::PrunedAccount,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+/// Reason code of the resolves issued for expired disputes.
+pub const EXPIRED: &str = "dispute_expired";
+
+#[derive(Debug, Clone)]
+pub struct DisputeExpiry {
+    after: u64,
+    /// latest timestamp seen on any event
+    now: u64,
+    /// open disputes by the time they were opened, with their client
+    deadlines: BTreeMap<(u64, u32), u16>,
+    opened: HashMap<u32, u64>,
+    reason: ReasonCode,
+}
+
+impl DisputeExpiry {
+    /// Resolves disputes that stay open for the given period.
+    pub fn new(after: Duration) -> Self {
+        DisputeExpiry {
+            after: after.as_secs(),
+            now: 0,
+            deadlines: BTreeMap::new(),
+            opened: HashMap::new(),
+            reason: EXPIRED.parse().expect("valid reason code"),
+        }
+    }
+
+    /// Whether the event is a resolve issued for an expired dispute.
+    pub fn is_expiry(event: &TransactionEvent) -> bool {
+        event.ty == TransactionType::Resolve && event.reason.as_str() == EXPIRED
+    }
+
+    /// Registers the outcome of an event.
+    pub fn observe(&mut self, event: &TransactionEvent, result: Result<(), TransactionError>) {
+        if result.is_err() {
+            return;
+        }
+        match event.ty {
+            TransactionType::Dispute => {
+                if let Some(timestamp) = event.timestamp {
+                    self.opened.insert(event.tx, timestamp);
+                    self.deadlines
+                        .insert((timestamp, event.tx), event.client_id);
+                }
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => self.close(event.tx),
+            // the disputes of the merged client now belong to the other one
+            TransactionType::Merge => {
+                for client_id in self.deadlines.values_mut() {
+                    if Some(*client_id) == event.merged_client {
+                        *client_id = event.client_id;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn close(&mut self, tx: u32) {
+        if let Some(timestamp) = self.opened.remove(&tx) {
+            self.deadlines.remove(&(timestamp, tx));
+        }
+    }
+
+    /// Forgets the disputes of a pruned account.
+    pub fn prune(&mut self, pruned: &PrunedAccount) {
+        for (tx, _) in &pruned.transactions {
+            self.close(*tx);
+        }
+    }
+
+    /// Moves time forward to the timestamp, and returns the resolves of the
+    /// disputes that expired by then, oldest first.
+    pub fn advance(&mut self, timestamp: Option<u64>) -> Vec<TransactionEvent> {
+        self.now = self.now.max(timestamp.unwrap_or_default());
+        let mut expired = Vec::new();
+        while let Some((&(opened, tx), &client_id)) = self.deadlines.first_key_value() {
+            if self.now.saturating_sub(opened) < self.after {
+                break;
+            }
+            self.close(tx);
+            expired.push(TransactionEvent {
+                timestamp: Some(self.now),
+                reason: self.reason,
+                ..TransactionEvent::new(TransactionType::Resolve, client_id, tx, Default::default())
+            });
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::Price, transaction_context::TransactionContext};
+
+    #[test]
+    fn test_dispute_expiry() {
+        let event = |ty, tx, timestamp| TransactionEvent {
+            timestamp: Some(timestamp),
+            ..TransactionEvent::new(ty, 1, tx, Price(10_000))
+        };
+        let day = 24 * 60 * 60;
+        let mut expiry = DisputeExpiry::new(Duration::from_secs(30 * day));
+        let mut context = TransactionContext::new();
+        let mut apply = |expiry: &mut DisputeExpiry, event: TransactionEvent| {
+            let result = context.apply(&event).map(|_| ());
+            expiry.observe(&event, result);
+        };
+        for event in [
+            event(TransactionType::Deposit, 1, 0),
+            event(TransactionType::Deposit, 2, 0),
+            event(TransactionType::Dispute, 1, day),
+            event(TransactionType::Dispute, 2, 2 * day),
+            event(TransactionType::Resolve, 2, 3 * day),
+        ] {
+            apply(&mut expiry, event);
+        }
+        assert!(expiry.advance(Some(30 * day)).is_empty());
+
+        let expired = expiry.advance(Some(31 * day));
+        assert_eq!(expired.len(), 1);
+        assert_eq!((expired[0].tx, expired[0].client_id), (1, 1));
+        assert!(DisputeExpiry::is_expiry(&expired[0]));
+        apply(&mut expiry, expired[0]);
+        assert_eq!(context.account(1).unwrap().held, Price(0));
+        assert!(expiry.advance(Some(100 * day)).is_empty());
+    }
+}
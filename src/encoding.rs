@@ -0,0 +1,183 @@
+//! Detection of the text encoding of input files, so exports that are not
+//! UTF-8 are transcoded instead of failing to parse.
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+use std::{
+    fs::File,
+    io::{Read, Result},
+    path::Path,
+};
+
+/// Amount of bytes inspected to detect the encoding.
+const SNIFF_LEN: usize = 8 * 1024;
+
+/// Looks up an encoding by its label, e.g. `windows-1252` or `latin1`.
+pub fn parse_encoding(label: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes())
+        .ok_or_else(|| format!("unknown encoding `{label}`"))
+}
+
+/// Detects the encoding from the start of a file. A byte order mark wins,
+/// otherwise UTF-16 is recognized by its zero bytes and anything that is not
+/// valid UTF-8 is assumed to be Windows-1252.
+pub fn detect(prefix: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(prefix) {
+        return encoding;
+    }
+
+    // ascii text in UTF-16 has every other byte zeroed
+    let zeroes = |offset: usize| {
+        prefix
+            .iter()
+            .skip(offset)
+            .step_by(2)
+            .filter(|b| **b == 0)
+            .count()
+    };
+    let half = prefix.len() / 2;
+    if half > 0 && zeroes(1) * 2 > half && zeroes(0) == 0 {
+        return UTF_16LE;
+    }
+    if half > 0 && zeroes(0) * 2 > half && zeroes(1) == 0 {
+        return UTF_16BE;
+    }
+
+    match std::str::from_utf8(prefix) {
+        Ok(_) => UTF_8,
+        // the prefix can end in the middle of a character
+        Err(e) if e.error_len().is_none() => UTF_8,
+        Err(_) => WINDOWS_1252,
+    }
+}
+
+/// Detects the encoding of the file at `path` from its first bytes.
+pub fn detect_file(path: &Path) -> Result<&'static Encoding> {
+    let mut prefix = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut prefix)?;
+    Ok(detect(&prefix))
+}
+
+/// Wraps the reader so it yields UTF-8. UTF-8 input is passed through as is,
+/// a byte order mark is skipped.
+pub fn decode<R: Read + Send + 'static>(
+    reader: R,
+    encoding: &'static Encoding,
+) -> Box<dyn Read + Send> {
+    if encoding == UTF_8 {
+        return Box::new(reader);
+    }
+    Box::new(Transcoder {
+        inner: reader,
+        decoder: encoding.new_decoder(),
+        input: vec![0; SNIFF_LEN],
+        output: Vec::new(),
+        pos: 0,
+    })
+}
+
+/// Transcodes the inner reader to UTF-8. Reaching the end of the inner reader
+/// does not finish the decoder, so reading can continue when data is appended
+/// to a followed file, even when the end fell in the middle of a character.
+struct Transcoder<R> {
+    inner: R,
+    decoder: Decoder,
+    input: Vec<u8>,
+    /// decoded data that is not handed out yet
+    output: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Read for Transcoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.pos == self.output.len() {
+            let n = self.inner.read(&mut self.input)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let len = self
+                .decoder
+                .max_utf8_buffer_length(n)
+                .expect("decode buffer length overflows");
+            self.output.resize(len, 0);
+            let (_, read, written, _) =
+                self.decoder
+                    .decode_to_utf8(&self.input[..n], &mut self.output, false);
+            debug_assert_eq!(read, n, "output is large enough for all input");
+            self.output.truncate(written);
+            self.pos = 0;
+        }
+
+        let n = buf.len().min(self.output.len() - self.pos);
+        buf[..n].copy_from_slice(&self.output[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16le(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(detect(b"type,client\ndeposit,1"), UTF_8);
+        assert_eq!(detect(b""), UTF_8);
+        assert_eq!(detect("caf\u{e9}".as_bytes()), UTF_8);
+        // truncated in the middle of a character
+        assert_eq!(detect(&"caf\u{e9}".as_bytes()[..4]), UTF_8);
+        assert_eq!(detect(b"caf\xe9,1"), WINDOWS_1252);
+        assert_eq!(detect(b"\xef\xbb\xbftype"), UTF_8);
+        assert_eq!(detect(b"\xff\xfet\0y\0"), UTF_16LE);
+        assert_eq!(detect(&utf16le("type,client")), UTF_16LE);
+        let be: Vec<u8> = "type".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(detect(&be), UTF_16BE);
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut input = vec![0xff, 0xfe];
+        input.extend(utf16le("deposit,1"));
+        let mut decoded = String::new();
+        decode(std::io::Cursor::new(input), UTF_16LE)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "deposit,1");
+
+        let mut decoded = String::new();
+        decode(&b"caf\xe9"[..], WINDOWS_1252)
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, "caf\u{e9}");
+    }
+
+    #[test]
+    fn test_decode_resumes_after_end() {
+        /// Hands out one chunk per read, an empty chunk is an end of input.
+        struct Chunks(Vec<Vec<u8>>);
+        impl Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                if self.0.is_empty() {
+                    return Ok(0);
+                }
+                let chunk = self.0.remove(0);
+                buf[..chunk.len()].copy_from_slice(&chunk);
+                Ok(chunk.len())
+            }
+        }
+
+        // the end of input falls in the middle of a character
+        let input = utf16le("ab");
+        let chunks = Chunks(vec![input[..3].to_vec(), vec![], input[3..].to_vec()]);
+        let mut reader = decode(chunks, UTF_16LE);
+        let mut decoded = String::new();
+        reader.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "a");
+        reader.read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, "ab");
+    }
+}
@@ -0,0 +1,247 @@
+//! Synchronous engine for embedding, without the threads and queues of the
+//! [`crate::pipeline`]. The caller pushes events one by one and gets the
+//! outcome back right away, [`Validator`]s and [`Sink`]s are called in
+//! between like in a pipeline.
+//!
+//! ```
+//! use toy_transaction_engine::{
+//!     data_types::{Price, TransactionError, TransactionEvent, TransactionType},
+//!     engine::{Engine, Outcome},
+//! };
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let event = |ty, tx, amount| TransactionEvent::new(ty, 1, tx, Price(amount));
+//! let mut engine = Engine::new();
+//! assert!(engine.push(event(TransactionType::Deposit, 1, 15_000)).is_applied());
+//! assert_eq!(
+//!     engine.push(event(TransactionType::Withdrawal, 2, 20_000)),
+//!     Outcome::Rejected(TransactionError::InsufficientFunds)
+//! );
+//! let accounts = engine.finish()?;
+//! assert_eq!(accounts.get(1).unwrap().total, Price(15_000));
+//! # Ok(())
+//! # }
+//! ```
+use crate::{
+    data_types::{Account, TransactionError, TransactionEvent, TransactionType},
+    pipeline::{Sink, Validator},
+    pseudonym,
+    transaction_context::TransactionContext,
+};
+use tracing::{debug, trace};
+
+/// Outcome of [`Engine::push`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// the event was applied, with the account of the client afterwards
+    Applied(Account),
+    Rejected(TransactionError),
+}
+
+impl Outcome {
+    pub fn is_applied(&self) -> bool {
+        matches!(self, Outcome::Applied(_))
+    }
+}
+
+/// Accounts of a finished engine, ordered by client.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Accounts(Vec<(u16, Account)>);
+
+impl Accounts {
+    pub fn get(&self, client_id: u16) -> Option<&Account> {
+        let idx = self
+            .0
+            .binary_search_by_key(&client_id, |(id, _)| *id)
+            .ok()?;
+        Some(&self.0[idx].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &Account)> {
+        self.0.iter().map(|(id, account)| (*id, account))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl IntoIterator for Accounts {
+    type Item = (u16, Account);
+    type IntoIter = std::vec::IntoIter<(u16, Account)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+/// Applies pushed events on the calling thread.
+#[derive(Default)]
+pub struct Engine<'a> {
+    context: TransactionContext,
+    validators: Vec<Box<dyn Validator + 'a>>,
+    sinks: Vec<Box<dyn Sink + 'a>>,
+}
+
+impl std::fmt::Debug for Engine<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Engine")
+            .field("context", &self.context)
+            .field("validators", &self.validators.len())
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+impl<'a> Engine<'a> {
+    /// An engine with an empty, in memory state.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An engine continuing from the given state, e.g. one with a persistent
+    /// store or policies set.
+    pub fn with_context(context: TransactionContext) -> Self {
+        Engine {
+            context,
+            ..Default::default()
+        }
+    }
+
+    /// Checks every event before it is applied, in the order the validators
+    /// are added.
+    pub fn with_validator(mut self, validator: impl Validator + 'a) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Passes the outcome of every event to the sink.
+    pub fn with_sink(mut self, sink: impl Sink + 'a) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    pub fn context(&self) -> &TransactionContext {
+        &self.context
+    }
+
+    /// Validates and applies the event, and passes the outcome to the sinks.
+    pub fn push(&mut self, mut event: TransactionEvent) -> Outcome {
+        // only adjustments carry a signed amount, as in the processor
+        if event.ty != TransactionType::Adjustment {
+            event.amount.make_absolute();
+        }
+        let checked = self
+            .validators
+            .iter_mut()
+            .try_for_each(|validator| validator.validate(&event));
+        let (outcome, account) = match checked {
+            Err(reason) => {
+                debug!(%reason, ty = %event.ty, event.tx, "event failed validation");
+                let error = TransactionError::RuleViolation;
+                let account = self.context.account(event.client_id).copied();
+                (Outcome::Rejected(error), account)
+            }
+            Ok(()) => match self.context.apply(&event) {
+                Ok(account) => (Outcome::Applied(account), Some(account)),
+                // a rejected event can still have created the account
+                Err(error) => (
+                    Outcome::Rejected(error),
+                    self.context.account(event.client_id).copied(),
+                ),
+            },
+        };
+
+        let result = match outcome {
+            Outcome::Applied(_) => {
+                trace!(ty = %event.ty, client = %pseudonym::client(event.client_id), event.tx, %event.amount, "applied");
+                Ok(())
+            }
+            Outcome::Rejected(error) => {
+                debug!(%error, ty = %event.ty, client = %pseudonym::client(event.client_id), event.tx, %event.amount, "rejected");
+                Err(error)
+            }
+        };
+        for sink in &mut self.sinks {
+            sink.record(&event, result, account.as_ref());
+        }
+        outcome
+    }
+
+    /// Flushes the sinks and the state, e.g. at the end of a batch.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        for sink in &mut self.sinks {
+            sink.flush(&self.context)?;
+        }
+        self.context.flush()
+    }
+
+    /// Finishes the sinks and returns the accounts.
+    pub fn finish(mut self) -> anyhow::Result<Accounts> {
+        for sink in &mut self.sinks {
+            sink.finish(&self.context)?;
+        }
+        self.context.flush()?;
+        let mut accounts: Vec<_> = self.context.into_iter_accounts().collect();
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        Ok(Accounts(accounts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, Rejected};
+
+    #[test]
+    fn test_engine() {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        struct NoClientZero;
+        impl Validator for NoClientZero {
+            fn validate(&mut self, event: &TransactionEvent) -> Result<(), String> {
+                match event.client_id {
+                    0 => Err("client 0".to_string()),
+                    _ => Ok(()),
+                }
+            }
+        }
+
+        let mut rejects: Vec<Rejected> = Vec::new();
+        let mut engine = Engine::new()
+            .with_validator(NoClientZero)
+            .with_sink(&mut rejects);
+        use TransactionType::*;
+        // the amount is made absolute
+        let Outcome::Applied(account) = engine.push(event(Deposit, 2, 1, -10_000)) else {
+            panic!("deposit is rejected");
+        };
+        assert_eq!(account.total, Price(10_000));
+        assert!(engine.push(event(Deposit, 1, 2, 5_000)).is_applied());
+        assert_eq!(
+            engine.push(event(Deposit, 0, 3, 5_000)),
+            Outcome::Rejected(TransactionError::RuleViolation)
+        );
+        assert_eq!(
+            engine.push(event(Deposit, 1, 2, 5_000)),
+            Outcome::Rejected(TransactionError::Duplicate)
+        );
+        let accounts = engine.finish().unwrap();
+
+        let clients: Vec<_> = accounts.iter().map(|(client_id, _)| client_id).collect();
+        assert_eq!(clients, [1, 2]);
+        assert_eq!(accounts.get(1).unwrap().total, Price(5_000));
+        assert_eq!(accounts.get(0), None);
+        assert_eq!(
+            rejects
+                .iter()
+                .map(|reject| reject.error)
+                .collect::<Vec<_>>(),
+            [TransactionError::RuleViolation, TransactionError::Duplicate]
+        );
+    }
+}
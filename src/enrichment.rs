@@ -0,0 +1,134 @@
+//! Enrichment of events with annotations from a sidecar file, e.g. the
+//! merchant or category of a transaction, looked up by the prefix of its tx
+//! id. The events only carry the index of their annotation through the
+//! pipeline, sinks resolve it with [`Annotations::get`].
+use crate::{data_types::TransactionEvent, pipeline::Transform};
+use csv::ReaderBuilder;
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+/// Digits of the longest possible tx id.
+const MAX_PREFIX: usize = 10;
+
+/// Annotations per tx id prefix, read from a csv file with a `prefix` column
+/// followed by the annotation columns, e.g. `prefix,merchant,category`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Annotations {
+    columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+    by_prefix: HashMap<String, u32>,
+}
+
+impl Annotations {
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(path)?;
+        let headers = rdr.headers()?.clone();
+        if headers.get(0) != Some("prefix") || headers.len() < 2 {
+            anyhow::bail!(
+                "{}: expected a `prefix` column followed by annotation columns",
+                path.display()
+            );
+        }
+        let mut annotations = Annotations {
+            columns: headers.iter().skip(1).map(str::to_string).collect(),
+            ..Default::default()
+        };
+        for record in rdr.records() {
+            let record = record?;
+            let prefix = &record[0];
+            if prefix.is_empty()
+                || prefix.len() > MAX_PREFIX
+                || !prefix.bytes().all(|b| b.is_ascii_digit())
+            {
+                anyhow::bail!("{}: invalid tx id prefix `{prefix}`", path.display());
+            }
+            let index = annotations.rows.len() as u32;
+            if annotations
+                .by_prefix
+                .insert(prefix.to_string(), index)
+                .is_some()
+            {
+                anyhow::bail!("{}: prefix `{prefix}` is listed twice", path.display());
+            }
+            annotations
+                .rows
+                .push(record.iter().skip(1).map(str::to_string).collect());
+        }
+        Ok(annotations)
+    }
+
+    /// Names of the annotation columns.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// The annotation with the longest prefix of the tx id.
+    pub fn lookup(&self, tx: u32) -> Option<u32> {
+        let digits = tx.to_string();
+        (1..=digits.len())
+            .rev()
+            .find_map(|len| self.by_prefix.get(&digits[..len]).copied())
+    }
+
+    /// The values of an annotation, in the order of [`Annotations::columns`].
+    pub fn get(&self, index: u32) -> Option<&[String]> {
+        self.rows.get(index as usize).map(Vec::as_slice)
+    }
+}
+
+/// [`Transform`] attaching the matching annotation to every event. Disputes,
+/// resolves and chargebacks reference a tx, so they get the annotation of the
+/// transaction they refer to.
+#[derive(Debug, Clone)]
+pub struct Enrichment {
+    annotations: Arc<Annotations>,
+}
+
+impl Enrichment {
+    pub fn new(annotations: Arc<Annotations>) -> Self {
+        Enrichment { annotations }
+    }
+}
+
+impl Transform for Enrichment {
+    fn apply(&mut self, mut event: TransactionEvent) -> Option<TransactionEvent> {
+        event.annotation = self.annotations.lookup(event.tx);
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionType};
+
+    #[test]
+    fn test_enrichment() {
+        let path = std::env::temp_dir().join("txe_test_enrichment.csv");
+        std::fs::write(
+            &path,
+            "prefix,merchant,category\n1,acme,retail\n12,globex,travel\n# unused\n9,initech,\n",
+        )
+        .unwrap();
+        let annotations = Arc::new(Annotations::from_file(&path).unwrap());
+        let _ = std::fs::remove_file(path);
+        assert_eq!(annotations.columns(), ["merchant", "category"]);
+
+        let mut enrichment = Enrichment::new(annotations.clone());
+        let mut annotate = |tx| {
+            let event = TransactionEvent::new(TransactionType::Deposit, 1, tx, Price(10_000));
+            let event = enrichment.apply(event).unwrap();
+            event
+                .annotation
+                .and_then(|index| annotations.get(index))
+                .map(|values| values.join("/"))
+        };
+        assert_eq!(annotate(1), Some("acme/retail".to_string()));
+        assert_eq!(annotate(1999), Some("acme/retail".to_string()));
+        assert_eq!(annotate(1234), Some("globex/travel".to_string()));
+        assert_eq!(annotate(90), Some("initech/".to_string()));
+        assert_eq!(annotate(42), None);
+    }
+}
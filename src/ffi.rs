@@ -0,0 +1,240 @@
+//! C ABI of the engine, for embedding it in C or C++. The header is
+//! `include/txe.h`, regenerate it with `cbindgen --output include/txe.h` after
+//! changing this module.
+//!
+//! Amounts are passed as integers in ten-thousandths, see [`TXE_PRICE_SCALAR`].
+//! The engine is not thread safe, calls on the same engine must not overlap.
+use crate::{
+    data_types::{Price, TransactionError, TransactionEvent, TransactionType},
+    engine::{Engine, Outcome},
+};
+
+pub const TXE_DEPOSIT: u8 = 0;
+pub const TXE_WITHDRAWAL: u8 = 1;
+pub const TXE_DISPUTE: u8 = 2;
+pub const TXE_RESOLVE: u8 = 3;
+pub const TXE_CHARGEBACK: u8 = 4;
+
+/// Amounts are expressed in units of `1 / TXE_PRICE_SCALAR`.
+// a literal, cbindgen does not resolve constants of other crates
+pub const TXE_PRICE_SCALAR: i64 = 10_000;
+
+/// Outcome of [`engine_push_event`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxeStatus {
+    Ok = 0,
+    Overflow,
+    Duplicate,
+    NotFound,
+    InvalidDispute,
+    InsufficientFunds,
+    Locked,
+    ClientMismatch,
+    /// a null engine or an unknown transaction type
+    InvalidArgument,
+    /// an admin event, they can not be pushed through the C API
+    Unauthorized,
+}
+
+impl From<TransactionError> for TxeStatus {
+    fn from(error: TransactionError) -> Self {
+        match error {
+            TransactionError::Overflow => TxeStatus::Overflow,
+            TransactionError::Duplicate => TxeStatus::Duplicate,
+            TransactionError::NotFound | TransactionError::UnknownClient => TxeStatus::NotFound,
+            TransactionError::ReservedClient => TxeStatus::ClientMismatch,
+            TransactionError::InvalidDispute => TxeStatus::InvalidDispute,
+            TransactionError::InsufficientFunds => TxeStatus::InsufficientFunds,
+            TransactionError::Locked => TxeStatus::Locked,
+            TransactionError::ClientMismatch => TxeStatus::ClientMismatch,
+            TransactionError::Unauthorized => TxeStatus::Unauthorized,
+            // only admin events carry a reason, they are unauthorized first
+            TransactionError::MissingReason
+            | TransactionError::InvalidMerge
+            | TransactionError::InvalidRecovery
+            | TransactionError::RuleViolation
+            | TransactionError::AmountOutOfRange => TxeStatus::InvalidArgument,
+        }
+    }
+}
+
+/// An account as returned by [`accounts_next`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TxeAccount {
+    pub client: u16,
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    pub locked: bool,
+}
+
+/// Engine created by [`engine_new`].
+#[derive(Debug, Default)]
+pub struct TxeEngine {
+    engine: Engine<'static>,
+}
+
+/// Accounts of a finished engine, ordered by client.
+#[derive(Debug)]
+pub struct TxeAccounts {
+    accounts: std::vec::IntoIter<TxeAccount>,
+}
+
+/// Creates an engine with an empty, in memory state. Free it with
+/// [`engine_finish`] or [`engine_free`].
+#[no_mangle]
+pub extern "C" fn engine_new() -> *mut TxeEngine {
+    Box::into_raw(Box::default())
+}
+
+/// Applies an event, `amount` is ignored for disputes, resolves and
+/// chargebacks.
+///
+/// # Safety
+///
+/// `engine` must be null or returned by [`engine_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn engine_push_event(
+    engine: *mut TxeEngine,
+    ty: u8,
+    client: u16,
+    tx: u32,
+    amount: i64,
+) -> TxeStatus {
+    let (Some(engine), Some(ty)) = (engine.as_mut(), TransactionType::ALL.get(ty as usize)) else {
+        return TxeStatus::InvalidArgument;
+    };
+    let event = TransactionEvent::new(*ty, client, tx, Price(amount));
+    match engine.engine.push(event) {
+        Outcome::Applied(_) => TxeStatus::Ok,
+        Outcome::Rejected(error) => error.into(),
+    }
+}
+
+/// Frees the engine and returns its accounts, iterate them with
+/// [`accounts_next`] and free them with [`accounts_free`]. Returns null for a
+/// null engine, or when finishing its state fails.
+///
+/// # Safety
+///
+/// `engine` must be null or returned by [`engine_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn engine_finish(engine: *mut TxeEngine) -> *mut TxeAccounts {
+    if engine.is_null() {
+        return std::ptr::null_mut();
+    }
+    let engine = Box::from_raw(engine);
+    let Ok(accounts) = engine.engine.finish() else {
+        return std::ptr::null_mut();
+    };
+    let accounts: Vec<_> = accounts
+        .into_iter()
+        .map(|(client, account)| TxeAccount {
+            client,
+            available: account.available().0,
+            held: account.held.0,
+            total: account.total.0,
+            locked: account.locked,
+        })
+        .collect();
+    Box::into_raw(Box::new(TxeAccounts {
+        accounts: accounts.into_iter(),
+    }))
+}
+
+/// Frees the engine without reading its accounts.
+///
+/// # Safety
+///
+/// `engine` must be null or returned by [`engine_new`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn engine_free(engine: *mut TxeEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Writes the next account to `account`, returns false when all accounts were
+/// read.
+///
+/// # Safety
+///
+/// `accounts` must be null or returned by [`engine_finish`] and not yet freed,
+/// `account` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_next(
+    accounts: *mut TxeAccounts,
+    account: *mut TxeAccount,
+) -> bool {
+    let (Some(accounts), false) = (accounts.as_mut(), account.is_null()) else {
+        return false;
+    };
+    match accounts.accounts.next() {
+        Some(next) => {
+            account.write(next);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Frees the accounts returned by [`engine_finish`].
+///
+/// # Safety
+///
+/// `accounts` must be null or returned by [`engine_finish`] and not yet freed.
+#[no_mangle]
+pub unsafe extern "C" fn accounts_free(accounts: *mut TxeAccounts) {
+    if !accounts.is_null() {
+        drop(Box::from_raw(accounts));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_engine_roundtrip() {
+        assert_eq!(TXE_PRICE_SCALAR, crate::data_types::PRICE_SCALAR);
+        unsafe {
+            let engine = engine_new();
+            let push = |ty, client, tx, amount| engine_push_event(engine, ty, client, tx, amount);
+            assert_eq!(push(TXE_DEPOSIT, 2, 1, 20_000), TxeStatus::Ok);
+            assert_eq!(push(TXE_DEPOSIT, 1, 2, 10_000), TxeStatus::Ok);
+            assert_eq!(push(TXE_DEPOSIT, 1, 2, 10_000), TxeStatus::Duplicate);
+            assert_eq!(
+                push(TXE_WITHDRAWAL, 1, 3, 50_000),
+                TxeStatus::InsufficientFunds
+            );
+            assert_eq!(push(TXE_DISPUTE, 1, 2, 0), TxeStatus::Ok);
+            assert_eq!(push(9, 1, 4, 0), TxeStatus::InvalidArgument);
+            assert_eq!(
+                engine_push_event(std::ptr::null_mut(), TXE_DEPOSIT, 1, 5, 1),
+                TxeStatus::InvalidArgument
+            );
+
+            let accounts = engine_finish(engine);
+            let mut account = TxeAccount::default();
+            assert!(accounts_next(accounts, &mut account));
+            assert_eq!(
+                account,
+                TxeAccount {
+                    client: 1,
+                    available: 0,
+                    held: 10_000,
+                    total: 10_000,
+                    locked: false,
+                }
+            );
+            assert!(accounts_next(accounts, &mut account));
+            assert_eq!(account.client, 2);
+            assert!(!accounts_next(accounts, &mut account));
+            accounts_free(accounts);
+
+            assert!(engine_finish(std::ptr::null_mut()).is_null());
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use crate::{
+    data_types::{TransactionEvent, TransactionType},
+    pipeline::{PipelineBuilder, Transform},
+};
+use std::{path::Path, str::FromStr};
+
+/// Filters applied to the events right after ingest.
+#[derive(Debug, Clone, Default)]
+pub struct Filters {
+    /// only process the events of these clients
+    pub clients: Option<ClientSet>,
+    /// drop all events of these types
+    pub ignore: Option<TypeSet>,
+    /// only process the events within this time window
+    pub window: Option<TimeWindow>,
+}
+
+impl Filters {
+    /// Adds the configured filters as stages to the pipeline.
+    pub fn apply<'a>(&self, mut pipeline: PipelineBuilder<'a>) -> PipelineBuilder<'a> {
+        if let Some(clients) = &self.clients {
+            pipeline = pipeline.transform("clients", clients.clone());
+        }
+        if let Some(ignore) = self.ignore {
+            pipeline = pipeline.transform("ignore", ignore);
+        }
+        if let Some(window) = self.window {
+            pipeline = pipeline.transform("window", window);
+        }
+        pipeline
+    }
+}
+
+/// Time window in unix seconds, `from` is inclusive and `to` exclusive. An
+/// open bound is unbounded. Used as [`Transform`] it drops all events outside
+/// of the window, including events without a timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimeWindow {
+    pub from: Option<u64>,
+    pub to: Option<u64>,
+}
+
+impl TimeWindow {
+    pub fn contains(&self, timestamp: u64) -> bool {
+        self.from.is_none_or(|from| timestamp >= from) && self.to.is_none_or(|to| timestamp < to)
+    }
+}
+
+impl Transform for TimeWindow {
+    fn apply(&mut self, event: TransactionEvent) -> Option<TransactionEvent> {
+        event
+            .timestamp
+            .is_some_and(|timestamp| self.contains(timestamp))
+            .then_some(event)
+    }
+}
+
+/// Set of transaction types, parsed from a comma separated list, e.g.
+/// `chargeback,dispute`. Used as [`Transform`] it drops all events of the
+/// types in the set.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TypeSet([bool; TransactionType::ALL.len()]);
+
+impl TypeSet {
+    pub fn insert(&mut self, ty: TransactionType) {
+        self.0[ty as usize] = true;
+    }
+
+    pub fn contains(&self, ty: TransactionType) -> bool {
+        self.0[ty as usize]
+    }
+}
+
+impl FromStr for TypeSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = TypeSet::default();
+        for ty in s.split(',').map(str::trim).filter(|ty| !ty.is_empty()) {
+            set.insert(ty.parse()?);
+        }
+        Ok(set)
+    }
+}
+
+impl Transform for TypeSet {
+    fn apply(&mut self, event: TransactionEvent) -> Option<TransactionEvent> {
+        (!self.contains(event.ty)).then_some(event)
+    }
+}
+
+/// Set of client ids, parsed from a comma separated list of ids and inclusive
+/// ranges, e.g. `1,2,500-600`. Used as [`Transform`] it drops the events of
+/// all clients outside of the set.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSet {
+    // one bit per possible client id
+    bits: Box<[u64]>,
+}
+
+impl ClientSet {
+    pub fn new() -> Self {
+        ClientSet {
+            bits: vec![0; (u16::MAX as usize + 1) / 64].into_boxed_slice(),
+        }
+    }
+
+    /// Reads a set from a file containing ids and ranges separated by commas
+    /// or newlines. Everything after a `#` on a line is ignored.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let mut set = ClientSet::new();
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            set.extend_from_spec(line)
+                .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        }
+        Ok(set)
+    }
+
+    pub fn insert(&mut self, client_id: u16) {
+        self.bits[client_id as usize / 64] |= 1 << (client_id % 64);
+    }
+
+    pub fn contains(&self, client_id: u16) -> bool {
+        self.bits[client_id as usize / 64] & (1 << (client_id % 64)) != 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    fn extend_from_spec(&mut self, spec: &str) -> Result<(), String> {
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let parse = |id: &str| {
+                id.trim()
+                    .parse::<u16>()
+                    .map_err(|_| format!("invalid client id `{id}`"))
+            };
+            match item.split_once('-') {
+                Some((start, end)) => {
+                    let (start, end) = (parse(start)?, parse(end)?);
+                    if start > end {
+                        return Err(format!("invalid client range `{item}`"));
+                    }
+                    (start..=end).for_each(|id| self.insert(id));
+                }
+                None => self.insert(parse(item)?),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for ClientSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromStr for ClientSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut set = ClientSet::new();
+        set.extend_from_spec(s)?;
+        Ok(set)
+    }
+}
+
+impl Transform for ClientSet {
+    fn apply(&mut self, event: TransactionEvent) -> Option<TransactionEvent> {
+        self.contains(event.client_id).then_some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_client_set() {
+        let set: ClientSet = "1, 2,500-600,65535".parse().unwrap();
+        assert_eq!(set.len(), 104);
+        assert!(set.contains(1));
+        assert!(set.contains(500));
+        assert!(set.contains(600));
+        assert!(set.contains(u16::MAX));
+        assert!(!set.contains(0));
+        assert!(!set.contains(601));
+
+        assert!("".parse::<ClientSet>().unwrap().is_empty());
+        assert!("1,x".parse::<ClientSet>().is_err());
+        assert!("600-500".parse::<ClientSet>().is_err());
+        assert!("70000".parse::<ClientSet>().is_err());
+    }
+
+    #[test]
+    fn test_parse_type_set() {
+        let set: TypeSet = "chargeback, dispute".parse().unwrap();
+        assert!(set.contains(TransactionType::Chargeback));
+        assert!(set.contains(TransactionType::Dispute));
+        assert!(!set.contains(TransactionType::Deposit));
+        assert!("chargebacks".parse::<TypeSet>().is_err());
+    }
+
+    #[test]
+    fn test_time_window() {
+        let window = TimeWindow {
+            from: Some(100),
+            to: Some(200),
+        };
+        assert!(!window.contains(99));
+        assert!(window.contains(100));
+        assert!(window.contains(199));
+        assert!(!window.contains(200));
+        assert!(TimeWindow::default().contains(0));
+
+        let event = TransactionEvent::new(TransactionType::Deposit, 1, 1, Default::default());
+        assert!(TimeWindow::default().clone().apply(event).is_none());
+    }
+
+    #[test]
+    fn test_client_set_from_file() {
+        let path = std::env::temp_dir().join("txe_test_client_set.txt");
+        std::fs::write(&path, "# investigation 42\n7\n10-12, 20 # vip\n\n").unwrap();
+        let set = ClientSet::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(set.len(), 5);
+        assert!(set.contains(7) && set.contains(11) && set.contains(20));
+    }
+}
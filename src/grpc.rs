@@ -0,0 +1,272 @@
+//! gRPC ingest and query server, see `proto/engine.proto`.
+use crate::{
+    data_types::{Account, TransactionEvent, TransactionType},
+    pipeline::{push_message, Message},
+    shutdown::Shutdown,
+    state_view::StateView,
+};
+use rtrb::Producer;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tonic::{transport::Server, Request, Response, Status, Streaming};
+use tracing::{info, info_span};
+
+pub mod proto {
+    tonic::include_proto!("toy_transaction_engine.v1");
+}
+
+use proto::transaction_engine_server::{TransactionEngine, TransactionEngineServer};
+
+/// Serves the gRPC API on a separate thread. The server acts as the source of
+/// the processor: submitted transactions are pushed onto the ring buffer. The
+/// server stops once shutdown is requested, after which the end of the stream
+/// is signalled so the processor can drain the remaining events. Every
+/// completed stream is followed by a [`Message::Flush`].
+pub fn run_grpc_source(
+    addr: SocketAddr,
+    producer: Producer<Message>,
+    state_view: StateView,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let producer = Arc::new(Mutex::new(producer));
+    let service = EngineService {
+        producer: producer.clone(),
+        state_view,
+    };
+    let span = info_span!("ingest", %addr);
+
+    std::thread::Builder::new()
+        .name("gRPC source".to_string())
+        .spawn(move || {
+            let _span = span.entered();
+            info!(%addr, "serving gRPC");
+            let server = Server::builder()
+                .add_service(TransactionEngineServer::new(service))
+                .serve_with_shutdown(addr, async move {
+                    while !shutdown.is_requested() {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                });
+            if let Err(error) = runtime.block_on(server) {
+                tracing::error!(%error, "gRPC server failed");
+            }
+            info!("gRPC source stopped");
+            if let Ok(mut producer) = producer.lock() {
+                push_message(&mut *producer, Message::EndOfStream);
+            }
+        })?;
+
+    Ok(())
+}
+
+struct EngineService {
+    producer: Arc<Mutex<Producer<Message>>>,
+    state_view: StateView,
+}
+
+impl EngineService {
+    fn push(&self, message: Message) -> Result<(), Status> {
+        self.producer
+            .lock()
+            .map_err(|_| Status::internal("producer poisoned"))?
+            .push(message)
+            .map_err(|_| Status::resource_exhausted("processor queue is full"))
+    }
+}
+
+#[tonic::async_trait]
+impl TransactionEngine for EngineService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::SubmitResponse>, Status> {
+        let event = TransactionEvent::try_from(request.into_inner())?;
+        self.push(Message::Event(event))?;
+        Ok(Response::new(proto::SubmitResponse {}))
+    }
+
+    async fn submit_stream(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<proto::SubmitStreamResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut accepted = 0;
+        while let Some(transaction) = stream.message().await? {
+            self.push(Message::Event(TransactionEvent::try_from(transaction)?))?;
+            accepted += 1;
+        }
+        // a completed stream is a batch
+        self.push(Message::Flush)?;
+        Ok(Response::new(proto::SubmitStreamResponse { accepted }))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client = request.into_inner().client;
+        let client_id =
+            u16::try_from(client).map_err(|_| Status::invalid_argument("invalid client id"))?;
+        let account = self
+            .state_view
+            .account(client_id)
+            .ok_or_else(|| Status::not_found("unknown client"))?;
+        Ok(Response::new(to_proto(client_id, &account)))
+    }
+
+    async fn list_accounts(
+        &self,
+        _request: Request<proto::ListAccountsRequest>,
+    ) -> Result<Response<proto::ListAccountsResponse>, Status> {
+        let accounts = self
+            .state_view
+            .accounts()
+            .iter()
+            .map(|(id, account)| to_proto(*id, account))
+            .collect();
+        Ok(Response::new(proto::ListAccountsResponse { accounts }))
+    }
+}
+
+impl TryFrom<proto::Transaction> for TransactionEvent {
+    type Error = Status;
+
+    fn try_from(transaction: proto::Transaction) -> Result<Self, Self::Error> {
+        let ty = match transaction.r#type() {
+            proto::TransactionType::Deposit => TransactionType::Deposit,
+            proto::TransactionType::Withdrawal => TransactionType::Withdrawal,
+            proto::TransactionType::Dispute => TransactionType::Dispute,
+            proto::TransactionType::Resolve => TransactionType::Resolve,
+            proto::TransactionType::Chargeback => TransactionType::Chargeback,
+            proto::TransactionType::Unspecified => {
+                return Err(Status::invalid_argument("transaction type is required"))
+            }
+        };
+        let client_id = u16::try_from(transaction.client)
+            .map_err(|_| Status::invalid_argument("invalid client id"))?;
+        let amount = if transaction.amount.is_empty() {
+            Default::default()
+        } else {
+            transaction
+                .amount
+                .parse()
+                .map_err(|_| Status::invalid_argument("invalid amount"))?
+        };
+
+        Ok(TransactionEvent {
+            timestamp: (transaction.timestamp > 0).then_some(transaction.timestamp),
+            ..TransactionEvent::new(ty, client_id, transaction.tx, amount)
+        })
+    }
+}
+
+fn to_proto(client_id: u16, account: &Account) -> proto::Account {
+    proto::Account {
+        client: client_id.into(),
+        available: account.available().to_string(),
+        held: account.held.to_string(),
+        total: account.total.to_string(),
+        locked: account.locked,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::Price;
+    use tonic::Code;
+
+    fn transaction(ty: proto::TransactionType, client: u32, amount: &str) -> proto::Transaction {
+        proto::Transaction {
+            r#type: ty.into(),
+            client,
+            tx: 1,
+            amount: amount.to_string(),
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn test_try_from() {
+        let event =
+            TransactionEvent::try_from(transaction(proto::TransactionType::Deposit, 1, "1.5"))
+                .unwrap();
+        assert_eq!(event.ty, TransactionType::Deposit);
+        assert_eq!(event.amount, Price(15_000));
+        assert_eq!(event.timestamp, None);
+
+        for invalid in [
+            transaction(proto::TransactionType::Unspecified, 1, "1.5"),
+            transaction(proto::TransactionType::Deposit, 70_000, "1.5"),
+            transaction(proto::TransactionType::Deposit, 1, "abc"),
+        ] {
+            let status = TransactionEvent::try_from(invalid).unwrap_err();
+            assert_eq!(status.code(), Code::InvalidArgument);
+        }
+    }
+
+    #[test]
+    fn test_service() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (producer, mut consumer) = rtrb::RingBuffer::new(16);
+        let state_view = StateView::new();
+        let service = EngineService {
+            producer: Arc::new(Mutex::new(producer)),
+            state_view: state_view.clone(),
+        };
+
+        let submit = Request::new(transaction(proto::TransactionType::Deposit, 1, "1.5"));
+        runtime
+            .block_on(service.submit_transaction(submit))
+            .unwrap();
+        let Ok(Message::Event(event)) = consumer.pop() else {
+            panic!("the transaction is queued");
+        };
+        assert_eq!(event.client_id, 1);
+
+        let get = |client| {
+            runtime.block_on(service.get_account(Request::new(proto::GetAccountRequest { client })))
+        };
+        assert_eq!(get(1).unwrap_err().code(), Code::NotFound);
+        assert_eq!(get(70_000).unwrap_err().code(), Code::InvalidArgument);
+        let account = Account {
+            total: Price(15_000),
+            ..Default::default()
+        };
+        state_view.update(1, account);
+        assert_eq!(get(1).unwrap().into_inner(), to_proto(1, &account));
+    }
+
+    #[test]
+    fn test_shutdown() {
+        // the port is free once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (producer, mut consumer) = rtrb::RingBuffer::new(16);
+        let shutdown = Shutdown::default();
+        run_grpc_source(addr, producer, StateView::new(), shutdown.clone()).unwrap();
+
+        shutdown.request();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        loop {
+            match consumer.pop() {
+                Ok(Message::EndOfStream) => break,
+                Ok(_) => panic!("no events were submitted"),
+                Err(_) if std::time::Instant::now() < deadline => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(_) => panic!("the end of the stream is not signalled"),
+            }
+        }
+    }
+}
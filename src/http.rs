@@ -0,0 +1,237 @@
+use crate::{
+    control::{Command, CommandError, Control},
+    data_types::{Account, Price, TransactionError},
+    metrics::metrics,
+    state_view::StateView,
+};
+use serde::Serialize;
+use std::net::SocketAddr;
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{info, warn};
+
+#[derive(Debug, Serialize)]
+struct AccountResponse {
+    client: u16,
+    available: Price,
+    held: Price,
+    total: Price,
+    locked: bool,
+}
+
+impl AccountResponse {
+    fn new(client: u16, account: &Account) -> Self {
+        AccountResponse {
+            client,
+            available: account.available(),
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        }
+    }
+}
+
+/// Starts a HTTP server on a separate thread. It exposes:
+/// * `GET /metrics`: the metrics in the prometheus text format.
+/// * `GET /accounts` and `GET /accounts/{client_id}`: the current balances,
+///   when a [`StateView`] is given.
+/// * `POST /admin/snapshot`, `POST /admin/audit-log/rotate`,
+///   `POST /admin/pause`, `POST /admin/resume` and
+///   `POST /admin/accounts/{client_id}/unlock`: operational actions, when a
+///   [`Control`] is given.
+pub fn serve(
+    addr: SocketAddr,
+    state_view: Option<StateView>,
+    control: Option<Control>,
+) -> anyhow::Result<()> {
+    let server = Server::http(addr).map_err(|e| anyhow::anyhow!(e))?;
+    info!(%addr, "serving HTTP");
+
+    std::thread::Builder::new()
+        .name("HTTP server".to_string())
+        .spawn(move || {
+            for request in server.incoming_requests() {
+                if let Err(error) = handle_request(request, state_view.as_ref(), control.as_ref()) {
+                    warn!(%error, "failed to respond to HTTP request");
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_request(
+    request: Request,
+    state_view: Option<&StateView>,
+    control: Option<&Control>,
+) -> std::io::Result<()> {
+    let url = request.url().to_string();
+    let path: Vec<&str> = url.trim_matches('/').split('/').collect();
+    if let ["admin", action @ ..] = path.as_slice() {
+        return handle_admin(request, action, control);
+    }
+    if request.method() != &Method::Get {
+        return request.respond(Response::empty(405));
+    }
+
+    match (path.as_slice(), state_view) {
+        (["metrics"], _) => {
+            let content_type =
+                Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap();
+            let response =
+                Response::from_string(metrics().render_prometheus()).with_header(content_type);
+            request.respond(response)
+        }
+        (["accounts"], Some(view)) => {
+            let accounts: Vec<_> = view
+                .accounts()
+                .iter()
+                .map(|(id, account)| AccountResponse::new(*id, account))
+                .collect();
+            respond_json(request, &accounts)
+        }
+        (["accounts", client_id], Some(view)) => {
+            let Ok(client_id) = client_id.parse::<u16>() else {
+                return request.respond(Response::empty(400));
+            };
+            match view.account(client_id) {
+                Some(account) => respond_json(request, &AccountResponse::new(client_id, &account)),
+                None => request.respond(Response::empty(404)),
+            }
+        }
+        _ => request.respond(Response::empty(404)),
+    }
+}
+
+fn handle_admin(
+    request: Request,
+    action: &[&str],
+    control: Option<&Control>,
+) -> std::io::Result<()> {
+    let Some(control) = control else {
+        return request.respond(Response::empty(404));
+    };
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str());
+    if !control.authorize(authorization) {
+        return request.respond(Response::empty(401));
+    }
+    if request.method() != &Method::Post {
+        return request.respond(Response::empty(405));
+    }
+
+    info!(action = action.join("/"), "admin request");
+    let outcome = match action {
+        ["snapshot"] => control.run(Command::Snapshot),
+        ["audit-log", "rotate"] => control.run(Command::RotateAuditLog),
+        ["pause"] => {
+            control.pause();
+            Ok(())
+        }
+        ["resume"] => {
+            control.resume();
+            Ok(())
+        }
+        ["accounts", client_id, "unlock"] => match client_id.parse::<u16>() {
+            Ok(client_id) => control.run(Command::Unlock(client_id)),
+            Err(_) => return request.respond(Response::empty(400)),
+        },
+        _ => return request.respond(Response::empty(404)),
+    };
+    match outcome {
+        Ok(()) => request.respond(Response::empty(204)),
+        Err(error) => {
+            warn!(%error, "admin request failed");
+            let status = match error {
+                CommandError::Rejected(TransactionError::NotFound) => 404,
+                CommandError::Rejected(_) => 409,
+                CommandError::Failed(_) => 500,
+                CommandError::Unavailable => 503,
+            };
+            request.respond(Response::from_string(error.to_string()).with_status_code(status))
+        }
+    }
+}
+
+fn respond_json(request: Request, body: &impl Serialize) -> std::io::Result<()> {
+    let body = serde_json::to_string(body)?;
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    request.respond(Response::from_string(body).with_header(content_type))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    /// Sends a request and returns the status code and the body of the
+    /// response.
+    fn send(addr: SocketAddr, method: &str, path: &str, token: Option<&str>) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {token}\r\n"))
+            .unwrap_or_default();
+        write!(
+            stream,
+            "{method} {path} HTTP/1.1\r\nHost: {addr}\r\n{authorization}\
+             Content-Length: 0\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    #[test]
+    fn test_requests() {
+        let server = Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let view = StateView::new();
+        let account = Account {
+            total: Price(15_000),
+            held: Price(5_000),
+            ..Default::default()
+        };
+        view.update(1, account);
+        let control = Control::new("secret".to_string(), None);
+        {
+            let view = view.clone();
+            let control = control.clone();
+            std::thread::spawn(move || {
+                for request in server.incoming_requests() {
+                    handle_request(request, Some(&view), Some(&control)).unwrap();
+                }
+            });
+        }
+
+        let (status, body) = send(addr, "GET", "/accounts/1", None);
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let expected = serde_json::to_value(AccountResponse::new(1, &account)).unwrap();
+        assert_eq!(body, expected);
+        let (status, body) = send(addr, "GET", "/accounts", None);
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body, serde_json::json!([expected]));
+        assert_eq!(send(addr, "GET", "/accounts/2", None).0, 404);
+        assert_eq!(send(addr, "GET", "/accounts/x", None).0, 400);
+        assert_eq!(send(addr, "POST", "/accounts", None).0, 405);
+
+        // the admin endpoints need the token
+        assert_eq!(send(addr, "POST", "/admin/pause", None).0, 401);
+        assert_eq!(send(addr, "POST", "/admin/pause", Some("wrong")).0, 401);
+        assert!(!control.is_paused());
+        assert_eq!(send(addr, "GET", "/admin/pause", Some("secret")).0, 405);
+        assert_eq!(send(addr, "POST", "/admin/pause", Some("secret")).0, 204);
+        assert!(control.is_paused());
+        assert_eq!(send(addr, "POST", "/admin/resume", Some("secret")).0, 204);
+        assert!(!control.is_paused());
+    }
+}
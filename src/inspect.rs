@@ -0,0 +1,471 @@
+//! Schema inference for input files of unknown format, see the `inspect`
+//! subcommand. The encoding, delimiter and header are detected, the types of
+//! the columns guessed from a sample of the rows, and a [`Schema`] suggested
+//! that maps the columns and type spellings onto the canonical format.
+//!
+//! The suggestion is a starting point for onboarding a new data source, the
+//! columns are matched by their names and the type spellings by a list of
+//! common synonyms, so it is worth a review before use.
+use crate::{
+    data_types::{parse_timestamp, TransactionType},
+    encoding,
+    schema::{Schema, COLUMNS},
+};
+use csv::{ReaderBuilder, StringRecord};
+use encoding_rs::{Encoding, UTF_8};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+/// Delimiters that are tried, in order of preference.
+const DELIMITERS: [char; 4] = [',', ';', '\t', '|'];
+
+/// Amount of lines the delimiter is detected from.
+const SNIFF_LINES: usize = 20;
+
+/// Column names, lowercased and stripped of anything but letters and
+/// digits, that are recognized as one of the canonical columns.
+const COLUMN_SYNONYMS: [(&str, &[&str]); 6] = [
+    (
+        "type",
+        &["type", "txtype", "txntype", "transactiontype", "kind"],
+    ),
+    (
+        "client",
+        &[
+            "client",
+            "clientid",
+            "customer",
+            "customerid",
+            "account",
+            "accountid",
+        ],
+    ),
+    (
+        "tx",
+        &[
+            "tx",
+            "txid",
+            "txn",
+            "txnid",
+            "transaction",
+            "transactionid",
+            "id",
+        ],
+    ),
+    ("amount", &["amount", "value", "sum"]),
+    (
+        "timestamp",
+        &[
+            "timestamp",
+            "time",
+            "date",
+            "datetime",
+            "createdat",
+            "bookedat",
+        ],
+    ),
+    ("reason_code", &["reasoncode", "reason"]),
+];
+
+/// Lowercased spellings of other systems for the transaction types.
+const TYPE_SYNONYMS: [(&str, TransactionType); 6] = [
+    ("withdraw", TransactionType::Withdrawal),
+    ("debit", TransactionType::Withdrawal),
+    ("payout", TransactionType::Withdrawal),
+    ("credit", TransactionType::Deposit),
+    ("topup", TransactionType::Deposit),
+    ("reversal", TransactionType::Chargeback),
+];
+
+/// Type guessed from the sampled values of a column.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    /// no values in the sample
+    Empty,
+    Integer,
+    Decimal,
+    Timestamp,
+    Text,
+}
+
+impl ColumnKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ColumnKind::Empty => "empty",
+            ColumnKind::Integer => "integer",
+            ColumnKind::Decimal => "decimal",
+            ColumnKind::Timestamp => "timestamp",
+            ColumnKind::Text => "text",
+        }
+    }
+
+    fn of(value: &str) -> Self {
+        if value.parse::<u64>().is_ok() {
+            ColumnKind::Integer
+        } else if value.parse::<f64>().is_ok() {
+            ColumnKind::Decimal
+        } else if parse_timestamp(value).is_ok() {
+            ColumnKind::Timestamp
+        } else {
+            ColumnKind::Text
+        }
+    }
+
+    /// The kind that fits values of both kinds.
+    fn widen(self, other: ColumnKind) -> Self {
+        use ColumnKind::*;
+        match (self, other) {
+            (Empty, kind) | (kind, Empty) => kind,
+            (a, b) if a == b => a,
+            (Integer, Decimal) | (Decimal, Integer) => Decimal,
+            (Integer, Timestamp) | (Timestamp, Integer) => Timestamp,
+            _ => Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnReport {
+    /// the header, or the position for files without one
+    pub name: String,
+    pub kind: ColumnKind,
+    /// sampled rows without a value
+    pub empty: u64,
+    /// the canonical column it is mapped to
+    pub maps_to: Option<&'static str>,
+}
+
+/// Rows with a spelling of the type column.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeCount {
+    pub spelling: String,
+    /// the canonical type, when the spelling is recognized
+    pub ty: Option<TransactionType>,
+    pub rows: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Inspection {
+    pub encoding: &'static Encoding,
+    pub delimiter: char,
+    pub headers: bool,
+    pub columns: Vec<ColumnReport>,
+    /// data rows in the file, without the header
+    pub rows: u64,
+    /// rows the column types are guessed from
+    pub sampled: u64,
+    /// counted over the whole file, empty when there is no type column
+    pub types: Vec<TypeCount>,
+    pub schema: Schema,
+}
+
+/// Inspects the file, guessing the column types from the first `sample`
+/// rows. The rows are counted over the whole file.
+pub fn inspect(path: &Path, sample: usize) -> anyhow::Result<Inspection> {
+    let encoding = encoding::detect_file(path)?;
+    let open = || -> anyhow::Result<_> { Ok(encoding::decode(File::open(path)?, encoding)) };
+
+    let mut lines = Vec::new();
+    for line in BufReader::new(open()?).lines().take(SNIFF_LINES) {
+        lines.push(line?);
+    }
+    let delimiter = detect_delimiter(&lines);
+
+    let mut rdr = ReaderBuilder::new()
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .delimiter(delimiter as u8)
+        .has_headers(false)
+        .from_reader(open()?);
+    let mut records = rdr.records();
+    let first = records.next().transpose()?.unwrap_or_default();
+    // a header does not hold numbers, the first row of data does
+    let headers = !first.is_empty()
+        && first
+            .iter()
+            .all(|field| !field.is_empty() && field.parse::<f64>().is_err());
+
+    let (names, maps_to, pending) = if headers {
+        let names: Vec<String> = first.iter().map(str::to_string).collect();
+        let maps_to = map_columns(&names);
+        (names, maps_to, None)
+    } else {
+        // columns are in the canonical order
+        let names = (1..=first.len()).map(|i| format!("column {i}")).collect();
+        let maps_to = (0..first.len()).map(|i| COLUMNS.get(i).copied()).collect();
+        (names, maps_to, Some(first))
+    };
+    let mut columns: Vec<ColumnReport> = names
+        .into_iter()
+        .zip(maps_to)
+        .map(|(name, maps_to)| ColumnReport {
+            name,
+            kind: ColumnKind::Empty,
+            empty: 0,
+            maps_to,
+        })
+        .collect();
+    let type_idx = columns.iter().position(|c| c.maps_to == Some("type"));
+
+    let (mut rows, mut sampled) = (0, 0);
+    let mut types: BTreeMap<String, u64> = BTreeMap::new();
+    let mut observe = |record: &StringRecord| {
+        rows += 1;
+        if let Some(ty) = type_idx.and_then(|idx| record.get(idx)) {
+            *types.entry(ty.to_string()).or_default() += 1;
+        }
+        if sampled < sample as u64 {
+            sampled += 1;
+            for (idx, column) in columns.iter_mut().enumerate() {
+                match record.get(idx).filter(|value| !value.is_empty()) {
+                    Some(value) => column.kind = column.kind.widen(ColumnKind::of(value)),
+                    None => column.empty += 1,
+                }
+            }
+        }
+    };
+    if let Some(record) = pending {
+        observe(&record);
+    }
+    for record in records {
+        observe(&record?);
+    }
+
+    let types: Vec<TypeCount> = types
+        .into_iter()
+        .map(|(spelling, rows)| TypeCount {
+            ty: recognize_type(&spelling),
+            spelling,
+            rows,
+        })
+        .collect();
+
+    let mut schema = Schema::default();
+    schema.csv.delimiter = delimiter;
+    schema.csv.headers = headers;
+    if encoding != UTF_8 {
+        schema.csv.encoding = Some(encoding);
+    }
+    for column in &columns {
+        match column.maps_to {
+            Some(canonical) if headers && canonical != column.name => {
+                schema
+                    .columns
+                    .insert(canonical.to_string(), column.name.clone());
+            }
+            _ => {}
+        }
+    }
+    for count in &types {
+        match count.ty {
+            Some(ty) if ty.as_str() != count.spelling => {
+                schema.types.insert(count.spelling.clone(), ty);
+            }
+            Some(_) => {}
+            None => {
+                schema.passthrough.insert(count.spelling.clone());
+            }
+        }
+    }
+
+    Ok(Inspection {
+        encoding,
+        delimiter,
+        headers,
+        columns,
+        rows,
+        sampled,
+        types,
+        schema,
+    })
+}
+
+/// Picks the delimiter that occurs the same, non-zero, amount of times on
+/// every line, preferring the one that splits the lines into most columns.
+fn detect_delimiter(lines: &[String]) -> char {
+    let lines: Vec<&String> = lines.iter().filter(|l| !l.trim().is_empty()).collect();
+    DELIMITERS
+        .into_iter()
+        .filter_map(|delimiter| {
+            let counts: Vec<usize> = lines.iter().map(|l| l.matches(delimiter).count()).collect();
+            let min = counts.iter().copied().min()?;
+            let consistent = counts.iter().all(|count| *count == min);
+            (min > 0).then_some(((consistent, min), delimiter))
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, delimiter)| delimiter)
+        .unwrap_or(',')
+}
+
+/// Matches the headers with the canonical columns by name, each header is
+/// mapped at most once.
+fn map_columns(headers: &[String]) -> Vec<Option<&'static str>> {
+    let normalized: Vec<String> = headers
+        .iter()
+        .map(|h| {
+            h.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+                .to_ascii_lowercase()
+        })
+        .collect();
+    let mut maps_to = vec![None; headers.len()];
+    for (canonical, synonyms) in COLUMN_SYNONYMS {
+        // an exact match wins over a synonym
+        let found = headers.iter().position(|h| h == canonical).or_else(|| {
+            synonyms.iter().find_map(|synonym| {
+                (0..headers.len()).find(|i| maps_to[*i].is_none() && normalized[*i] == *synonym)
+            })
+        });
+        if let Some(idx) = found.filter(|idx| maps_to[*idx].is_none()) {
+            maps_to[idx] = Some(canonical);
+        }
+    }
+    maps_to
+}
+
+fn recognize_type(spelling: &str) -> Option<TransactionType> {
+    let lower = spelling.to_ascii_lowercase();
+    lower.parse().ok().or_else(|| {
+        TYPE_SYNONYMS
+            .iter()
+            .find(|(synonym, _)| *synonym == lower)
+            .map(|(_, ty)| *ty)
+    })
+}
+
+/// Writes the report, followed by the suggested schema in the format of
+/// `--schema`.
+pub fn write_inspection(mut writer: impl Write, inspection: &Inspection) -> anyhow::Result<()> {
+    writeln!(writer, "encoding: {}", inspection.encoding.name())?;
+    writeln!(writer, "delimiter: {:?}", inspection.delimiter)?;
+    writeln!(writer, "headers: {}", inspection.headers)?;
+    writeln!(
+        writer,
+        "rows: {} ({} sampled)",
+        inspection.rows, inspection.sampled
+    )?;
+
+    writeln!(writer, "\ncolumns:")?;
+    for column in &inspection.columns {
+        let maps_to = column.maps_to.unwrap_or("-");
+        writeln!(
+            writer,
+            "  {} {} empty={} maps_to={maps_to}",
+            column.name,
+            column.kind.as_str(),
+            column.empty
+        )?;
+    }
+
+    if !inspection.types.is_empty() {
+        writeln!(writer, "\nrows per type:")?;
+        for count in &inspection.types {
+            let ty = count.ty.map(|ty| ty.as_str()).unwrap_or("unknown");
+            writeln!(writer, "  {} ({ty}) {}", count.spelling, count.rows)?;
+        }
+    }
+
+    writeln!(writer, "\n# suggested schema, pass it with --schema")?;
+    write_schema(writer, &inspection.schema)
+}
+
+/// Writes the schema as toml, leaving out what matches the defaults.
+fn write_schema(mut writer: impl Write, schema: &Schema) -> anyhow::Result<()> {
+    let quote = |s: &str| format!("{s:?}");
+    if !schema.passthrough.is_empty() {
+        let mut passthrough: Vec<_> = schema.passthrough.iter().map(|s| quote(s)).collect();
+        passthrough.sort();
+        writeln!(writer, "# unrecognized types, counted and skipped")?;
+        writeln!(writer, "passthrough = [{}]", passthrough.join(", "))?;
+    }
+    if !schema.columns.is_empty() {
+        writeln!(writer, "\n[columns]")?;
+        for canonical in COLUMNS {
+            if let Some(input) = schema.columns.get(canonical) {
+                writeln!(writer, "{canonical} = {}", quote(input))?;
+            }
+        }
+    }
+    let csv = &schema.csv;
+    writeln!(writer, "\n[csv]")?;
+    writeln!(writer, "delimiter = {}", quote(&csv.delimiter.to_string()))?;
+    writeln!(writer, "headers = {}", csv.headers)?;
+    if let Some(encoding) = csv.encoding {
+        writeln!(writer, "encoding = {}", quote(encoding.name()))?;
+    }
+    if !schema.types.is_empty() {
+        writeln!(writer, "\n[types]")?;
+        let types: BTreeMap<_, _> = schema.types.iter().collect();
+        for (spelling, ty) in types {
+            writeln!(writer, "{} = {}", quote(spelling), quote(ty.as_str()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inspect() {
+        let path = std::env::temp_dir().join("txe_test_inspect.csv");
+        std::fs::write(
+            &path,
+            "Txn Type;Customer;TxId;Value;booked_at;note\n\
+             Credit;1;1;10.5;2024-01-01;\n\
+             withdraw;1;2;2;2024-01-02T10:00:00Z;\n\
+             deposit;2;3;1;2024-01-03;late\n\
+             fee;2;4;0.5;2024-01-03;\n",
+        )
+        .unwrap();
+        let inspection = inspect(&path, 2).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(inspection.delimiter, ';');
+        assert!(inspection.headers);
+        assert_eq!((inspection.rows, inspection.sampled), (4, 2));
+        let columns: Vec<_> = inspection
+            .columns
+            .iter()
+            .map(|c| (c.kind, c.maps_to))
+            .collect();
+        assert_eq!(
+            columns,
+            [
+                (ColumnKind::Text, Some("type")),
+                (ColumnKind::Integer, Some("client")),
+                (ColumnKind::Integer, Some("tx")),
+                (ColumnKind::Decimal, Some("amount")),
+                (ColumnKind::Timestamp, Some("timestamp")),
+                (ColumnKind::Empty, None),
+            ]
+        );
+        let types: Vec<_> = inspection
+            .types
+            .iter()
+            .map(|c| (c.spelling.as_str(), c.ty, c.rows))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                ("Credit", Some(TransactionType::Deposit), 1),
+                ("deposit", Some(TransactionType::Deposit), 1),
+                ("fee", None, 1),
+                ("withdraw", Some(TransactionType::Withdrawal), 1),
+            ]
+        );
+
+        // the suggestion is a valid schema
+        let mut toml = Vec::new();
+        write_schema(&mut toml, &inspection.schema).unwrap();
+        let schema: Schema = toml::from_str(&String::from_utf8(toml).unwrap()).unwrap();
+        schema.validate().unwrap();
+        assert_eq!(schema, inspection.schema);
+    }
+}
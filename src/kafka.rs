@@ -0,0 +1,183 @@
+//! Publishes every applied event with the resulting balances of the client to
+//! a Kafka topic, see `--kafka-brokers`. Messages are keyed by the client, so
+//! the events of a client land on the same partition in order and consumers
+//! like notification or fraud systems see the balance changes as they happen.
+//!
+//! Delivery is at least once: the producer is idempotent and retries, a
+//! message that still fails is logged and counted in
+//! `txe_kafka_delivery_failures_total`. At every batch boundary the sink waits
+//! for the outstanding messages, so a snapshot or committed position is never
+//! ahead of the topic.
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent},
+    metrics::metrics,
+    pipeline::Sink,
+    pseudonym::{self, DisplayClient},
+    reference::Reference,
+    transaction_context::TransactionContext,
+};
+use rdkafka::{
+    error::{KafkaError, RDKafkaErrorCode},
+    producer::{BaseRecord, DeliveryResult, Producer, ProducerContext, ThreadedProducer},
+    ClientConfig, ClientContext,
+};
+use serde::Serialize;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How long a batch boundary waits for the outstanding messages.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait for room when the producer queue is full.
+const QUEUE_FULL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The message of an applied event.
+#[derive(Debug, Serialize)]
+struct AppliedEvent {
+    #[serde(rename = "type")]
+    ty: &'static str,
+    client: DisplayClient,
+    tx: u32,
+    amount: Price,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<Reference>,
+    available: Price,
+    held: Price,
+    total: Price,
+    locked: bool,
+}
+
+/// Counts the messages that could not be delivered.
+struct Deliveries;
+
+impl ClientContext for Deliveries {}
+
+impl ProducerContext for Deliveries {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult<'_>, _: ()) {
+        if let Err((error, _)) = delivery_result {
+            metrics().record_kafka_delivery_failure();
+            warn!(%error, "failed to deliver an event to Kafka");
+        }
+    }
+}
+
+/// [`Sink`] publishing the applied events, see the module documentation.
+pub struct KafkaSink {
+    producer: ThreadedProducer<Deliveries>,
+    topic: String,
+}
+
+impl std::fmt::Debug for KafkaSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KafkaSink")
+            .field("topic", &self.topic)
+            .finish_non_exhaustive()
+    }
+}
+
+impl KafkaSink {
+    /// Publishes to the topic on the given brokers. `properties` are passed
+    /// to librdkafka as they are, e.g. `security.protocol=ssl`.
+    pub fn new(
+        brokers: &str,
+        topic: &str,
+        properties: &[(String, String)],
+    ) -> anyhow::Result<Self> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", brokers)
+            .set("enable.idempotence", "true");
+        for (key, value) in properties {
+            config.set(key, value);
+        }
+        let producer = config.create_with_context(Deliveries)?;
+        info!(brokers, topic, "publishing applied events to Kafka");
+        Ok(KafkaSink {
+            producer,
+            topic: topic.to_string(),
+        })
+    }
+
+    /// The key and the JSON payload of an applied event.
+    fn message(event: &TransactionEvent, account: &Account) -> (String, Vec<u8>) {
+        let message = AppliedEvent {
+            ty: event.ty.as_str(),
+            client: pseudonym::client(event.client_id),
+            tx: event.tx,
+            amount: event.amount,
+            timestamp: event.timestamp,
+            reference: event.reference,
+            available: account.available(),
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        };
+        let payload = serde_json::to_vec(&message).expect("applied event serializes");
+        (pseudonym::client(event.client_id).to_string(), payload)
+    }
+}
+
+impl Sink for KafkaSink {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let (Ok(()), Some(account)) = (result, account) else {
+            return;
+        };
+        let (key, payload) = Self::message(event, account);
+        let mut record = BaseRecord::to(&self.topic).key(&key).payload(&payload);
+        loop {
+            match self.producer.send(record) {
+                Ok(()) => return,
+                Err((KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull), returned)) => {
+                    self.producer.poll(QUEUE_FULL_BACKOFF);
+                    record = returned;
+                }
+                Err((error, _)) => {
+                    metrics().record_kafka_delivery_failure();
+                    warn!(%error, "failed to publish an event to Kafka");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        self.producer
+            .flush(FLUSH_TIMEOUT)
+            .map_err(|e| anyhow::anyhow!("failed to publish the events to Kafka: {e}"))
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        self.flush(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionType;
+
+    #[test]
+    fn test_message() {
+        let event = TransactionEvent::new(TransactionType::Deposit, 7, 3, Price(25_000));
+        let account = Account {
+            total: Price(40_000),
+            held: Price(10_000),
+            ..Default::default()
+        };
+        let (key, payload) = KafkaSink::message(&event, &account);
+        assert_eq!(key, "7");
+        assert_eq!(
+            String::from_utf8(payload).unwrap(),
+            r#"{"type":"deposit","client":7,"tx":3,"amount":"2.5","available":"3.0","held":"1.0","total":"4.0","locked":false}"#
+        );
+    }
+}
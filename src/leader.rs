@@ -0,0 +1,239 @@
+//! Leader election for HA deployments, see `--leader-lock`. Only the instance
+//! holding the lock applies events, the others stand by until it is free and
+//! then take over with the state the leader left in the shared `--state-dir`.
+//!
+//! The lock is pluggable through [`LeaderLock`], the engine ships a lock on a
+//! file ([`FileLock`]). Locks backed by e.g. a Postgres advisory lock or an
+//! etcd lease implement the trait, they report a lost lease through
+//! [`LeaderLock::is_held`].
+use crate::{metrics::metrics, shutdown::Shutdown};
+use std::{
+    fmt::Display,
+    fs::{File, OpenOptions, TryLockError},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// Exit code of an instance that lost the lock, see
+/// [`LeaderElection::acquire`].
+pub const LOST_LEADERSHIP_EXIT_CODE: i32 = 9;
+
+/// A lock that at most one instance holds at a time.
+pub trait LeaderLock: Send {
+    /// Takes the lock if it is free, returns whether it is held now.
+    fn try_acquire(&mut self) -> anyhow::Result<bool>;
+
+    /// Whether the lock is still held, e.g. the lease did not expire. Checked
+    /// periodically by the leader.
+    fn is_held(&mut self) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    fn release(&mut self);
+}
+
+/// The `--leader-lock` to use.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockSpec {
+    /// `file:<path>`, see [`FileLock`]
+    File(PathBuf),
+}
+
+impl LockSpec {
+    pub fn open(&self) -> Box<dyn LeaderLock> {
+        match self {
+            LockSpec::File(path) => Box::new(FileLock::new(path.clone())),
+        }
+    }
+}
+
+impl FromStr for LockSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("file", path)) if !path.is_empty() => Ok(LockSpec::File(path.into())),
+            _ => Err(format!("unknown lock `{s}`, expected file:<path>")),
+        }
+    }
+}
+
+impl Display for LockSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockSpec::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+/// An advisory lock on a file, e.g. on storage shared by the instances. The
+/// operating system releases it when the process dies. The holder writes its
+/// process id into the file.
+#[derive(Debug)]
+pub struct FileLock {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl FileLock {
+    pub fn new(path: PathBuf) -> Self {
+        FileLock { path, file: None }
+    }
+}
+
+impl LeaderLock for FileLock {
+    fn try_acquire(&mut self) -> anyhow::Result<bool> {
+        if self.file.is_some() {
+            return Ok(true);
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)?;
+        match file.try_lock() {
+            Ok(()) => {
+                file.set_len(0)?;
+                writeln!(file, "{}", std::process::id())?;
+                self.file = Some(file);
+                Ok(true)
+            }
+            Err(TryLockError::WouldBlock) => Ok(false),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    fn release(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = file.unlock();
+        }
+    }
+}
+
+/// Waits for a [`LeaderLock`].
+pub struct LeaderElection {
+    lock: Box<dyn LeaderLock>,
+    interval: Duration,
+}
+
+impl LeaderElection {
+    pub fn new(lock: Box<dyn LeaderLock>) -> Self {
+        LeaderElection {
+            lock,
+            interval: Duration::from_secs(1),
+        }
+    }
+
+    /// How often a standby tries to take the lock and the leader checks it
+    /// still holds it, every second by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Stands by until the lock is acquired, `None` when a shutdown is
+    /// requested first. `on_lost` is called when the lock is lost afterwards,
+    /// the leader has to stop applying events then.
+    pub fn acquire(
+        mut self,
+        shutdown: &Shutdown,
+        on_lost: impl FnOnce() + Send + 'static,
+    ) -> anyhow::Result<Option<Leadership>> {
+        metrics().set_leader(false);
+        let mut standing_by = false;
+        while !self.lock.try_acquire()? {
+            if !standing_by {
+                info!("standing by for the leader lock");
+                standing_by = true;
+            }
+            if shutdown.is_requested() {
+                return Ok(None);
+            }
+            std::thread::sleep(self.interval);
+        }
+        info!("acquired the leader lock");
+        metrics().set_leader(true);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("leader lock".to_string())
+            .spawn({
+                let stop = stop.clone();
+                move || self.hold(&stop, on_lost)
+            })?;
+        Ok(Some(Leadership {
+            stop,
+            thread: Some(thread),
+        }))
+    }
+
+    fn hold(mut self, stop: &AtomicBool, on_lost: impl FnOnce()) {
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::park_timeout(self.interval);
+            match self.lock.is_held() {
+                Ok(true) => {}
+                held => {
+                    error!(error = ?held.err(), "lost the leader lock");
+                    metrics().set_leader(false);
+                    return on_lost();
+                }
+            }
+        }
+        self.lock.release();
+        metrics().set_leader(false);
+    }
+}
+
+/// Holds the leader lock, it is released when dropped.
+#[derive(Debug)]
+pub struct Leadership {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Leadership {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_lock() {
+        let path = std::env::temp_dir().join("txe_test_leader.lock");
+        let spec: LockSpec = format!("file:{}", path.display()).parse().unwrap();
+        assert!("etcd://localhost:2379".parse::<LockSpec>().is_err());
+
+        let leader = LeaderElection::new(spec.open())
+            .interval(Duration::from_millis(10))
+            .acquire(&Shutdown::default(), || {})
+            .unwrap()
+            .unwrap();
+        let mut standby = spec.open();
+        assert!(!standby.try_acquire().unwrap());
+        let shutdown = Shutdown::default();
+        shutdown.request();
+        let election = LeaderElection::new(spec.open()).interval(Duration::from_millis(10));
+        assert!(election.acquire(&shutdown, || {}).unwrap().is_none());
+
+        drop(leader);
+        assert!(standby.try_acquire().unwrap());
+        standby.release();
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,74 @@
+//! Engine that processes a stream of transactions into client accounts. The
+//! binary in `main.rs` wires a csv source to the processor, but the modules can
+//! be embedded on their own.
+
+pub mod admin;
+#[cfg(feature = "webhooks")]
+pub mod alerts;
+pub mod analytics;
+pub mod audit_log;
+pub mod batch;
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod conservation;
+pub mod control;
+pub mod cross_file;
+pub mod csv_source;
+pub mod data_types;
+pub mod dispute_expiry;
+pub mod encoding;
+pub mod engine;
+pub mod enrichment;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod http;
+pub mod inspect;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod leader;
+pub mod locked_accounts;
+pub mod memory_budget;
+pub mod metrics;
+pub mod negative_balances;
+pub mod open_disputes;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod pipeline;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+pub mod postings;
+pub mod progress;
+pub mod pruning;
+pub mod pseudonym;
+pub mod push_source;
+pub mod reconcile;
+pub mod reference;
+pub mod replication;
+pub mod risk;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb_store;
+pub mod rules;
+pub mod run_status;
+pub mod schema;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod seal;
+pub mod segments;
+pub mod settings;
+pub mod shutdown;
+#[cfg(feature = "sled")]
+pub mod sled_store;
+pub mod snapshot;
+pub mod snapshot_diff;
+pub mod state_store;
+pub mod state_view;
+pub mod tenants;
+pub mod transaction_context;
+pub mod transaction_processor;
+pub mod udp_source;
+pub mod watchdog;
+#[cfg(feature = "webhooks")]
+pub mod webhooks;
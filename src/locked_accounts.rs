@@ -0,0 +1,202 @@
+//! Report of the locked accounts for compliance review: the chargeback that
+//! locked each account, its balance at that moment and the events rejected
+//! afterwards. Accounts with only their withdrawals locked are included.
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent},
+    pipeline::Sink,
+    pseudonym,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use csv::Writer;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockedAccount {
+    pub client_id: u16,
+    /// only withdrawals are locked, see [`crate::data_types::LockPolicy::Withdrawals`]
+    pub withdrawals_only: bool,
+    pub lock_tx: Option<u32>,
+    pub locked_at: Option<u64>,
+    /// amount of the chargeback that caused the lock
+    pub amount: Option<Price>,
+    /// the account right after the chargeback, unknown when it got locked
+    /// before a restored snapshot
+    pub at_lock: Option<Account>,
+    /// transaction ids of the events rejected after the lock
+    pub rejected: Vec<u32>,
+}
+
+#[derive(Debug, Default)]
+struct Lock {
+    amount: Option<Price>,
+    at_lock: Option<Account>,
+    rejected: Vec<u32>,
+}
+
+/// [`Sink`] following the locks as they happen, and writing every locked
+/// account as csv once all events are processed, ordered by client.
+#[derive(Debug)]
+pub struct LockedAccountsReport {
+    path: PathBuf,
+    /// held funds per client, to derive the amount of the chargeback
+    held: HashMap<u16, Price>,
+    locks: HashMap<u16, Lock>,
+}
+
+impl LockedAccountsReport {
+    /// Writes the report to the given path. The held funds of the accounts
+    /// in the context are the opening state, e.g. after restoring a snapshot.
+    pub fn new(path: &Path, context: &TransactionContext) -> Self {
+        LockedAccountsReport {
+            path: path.to_path_buf(),
+            held: context
+                .iter_accounts()
+                .map(|(client, account)| (client, account.held))
+                .collect(),
+            locks: HashMap::new(),
+        }
+    }
+
+    /// The locked accounts of the context.
+    pub fn locked_accounts(&self, context: &TransactionContext) -> Vec<LockedAccount> {
+        let mut locked: Vec<_> = context
+            .iter_accounts()
+            .filter(|(_, account)| account.locked || account.withdrawals_locked)
+            .map(|(client_id, account)| {
+                let lock = self.locks.get(&client_id);
+                LockedAccount {
+                    client_id,
+                    withdrawals_only: !account.locked,
+                    lock_tx: account.meta.lock_tx,
+                    locked_at: account.meta.locked_at,
+                    amount: lock.and_then(|lock| lock.amount),
+                    at_lock: lock.and_then(|lock| lock.at_lock),
+                    rejected: lock.map(|lock| lock.rejected.clone()).unwrap_or_default(),
+                }
+            })
+            .collect();
+        locked.sort_unstable_by_key(|locked| locked.client_id);
+        locked
+    }
+}
+
+impl Sink for LockedAccountsReport {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let Some(account) = account else {
+            return;
+        };
+        let held = self.held.entry(event.client_id).or_default();
+        let held_before = std::mem::replace(held, account.held);
+        if !account.locked && !account.withdrawals_locked {
+            return;
+        }
+
+        let lock = self.locks.entry(event.client_id).or_default();
+        match result {
+            // later chargebacks do not move the lock
+            Ok(()) if account.meta.lock_tx == Some(event.tx) && lock.at_lock.is_none() => {
+                lock.amount = Some(Price(held_before.0 - account.held.0));
+                lock.at_lock = Some(*account);
+            }
+            Ok(()) => {}
+            Err(_) => lock.rejected.push(event.tx),
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.held.remove(&pruned.client_id);
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        let file = std::fs::File::create(&self.path)?;
+        write_locked_accounts(file, &self.locked_accounts(context))
+    }
+}
+
+/// Writes the locked accounts as csv, the ids of the rejected events are
+/// separated by spaces.
+pub fn write_locked_accounts(writer: impl Write, locked: &[LockedAccount]) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record([
+        "client",
+        "withdrawals_only",
+        "lock_tx",
+        "locked_at",
+        "amount",
+        "available_at_lock",
+        "held_at_lock",
+        "total_at_lock",
+        "rejected",
+        "rejected_txs",
+    ])?;
+    let optional = |value: Option<String>| value.unwrap_or_default();
+    for locked in locked {
+        let at_lock = |f: fn(&Account) -> Price| locked.at_lock.as_ref().map(|a| f(a).to_string());
+        let rejected_txs: Vec<_> = locked.rejected.iter().map(u32::to_string).collect();
+        writer.write_record(&[
+            pseudonym::client(locked.client_id).to_string(),
+            locked.withdrawals_only.to_string(),
+            optional(locked.lock_tx.map(|tx| tx.to_string())),
+            optional(locked.locked_at.map(|at| at.to_string())),
+            optional(locked.amount.map(|amount| amount.to_string())),
+            optional(at_lock(Account::available)),
+            optional(at_lock(|account| account.held)),
+            optional(at_lock(|account| account.total)),
+            locked.rejected.len().to_string(),
+            rejected_txs.join(" "),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionType;
+
+    #[test]
+    fn test_locked_accounts() {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        let mut context = TransactionContext::new();
+        let mut report = LockedAccountsReport::new(Path::new("unused.csv"), &context);
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 30_000),
+            event(TransactionType::Deposit, 1, 2, 20_000),
+            event(TransactionType::Dispute, 1, 2, 0),
+            event(TransactionType::Dispute, 1, 1, 0),
+            event(TransactionType::Chargeback, 1, 1, 0),
+            event(TransactionType::Deposit, 1, 3, 10_000),
+            // disputes are still processed after the lock
+            event(TransactionType::Resolve, 1, 2, 0),
+            event(TransactionType::Withdrawal, 1, 4, 10_000),
+            event(TransactionType::Deposit, 2, 5, 10_000),
+            event(TransactionType::Withdrawal, 2, 6, 20_000),
+        ] {
+            let result = context.apply(&event).map(|_| ());
+            report.record(&event, result, context.account(event.client_id));
+        }
+
+        let locked = report.locked_accounts(&context);
+        assert_eq!(locked.len(), 1);
+        let locked = &locked[0];
+        assert_eq!(locked.client_id, 1);
+        assert_eq!(locked.lock_tx, Some(1));
+        assert_eq!(locked.amount, Some(Price(30_000)));
+        let at_lock = locked.at_lock.unwrap();
+        assert_eq!(
+            (at_lock.total, at_lock.held),
+            (Price(20_000), Price(20_000))
+        );
+        assert_eq!(locked.rejected, vec![3, 4]);
+    }
+}
@@ -1,20 +1,1189 @@
-use csv_source::{run_csv_source, write_accounts_to_csv};
-use rtrb::RingBuffer;
-use transaction_processor::TransactionProcessor;
+use anyhow::Context;
+use clap::Parser;
+use cli::{
+    AnalyzeArgs, Cli, Command, DiffArgs, IncrementalArgs, InspectArgs, ReconcileArgs, ReplayArgs,
+    SimulateArgs, StateAtArgs, VerifyAuditArgs,
+};
+use std::{
+    net::UdpSocket,
+    path::Path,
+    process::ExitCode,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use toy_transaction_engine::{
+    analytics::{analyze, write_analysis},
+    audit_log::{self, AuditLog},
+    batch,
+    conservation::ConservationCheck,
+    control::{self, Control},
+    cross_file::{write_cross_file_duplicates, CrossFileDuplicates},
+    csv_source::{
+        read_accounts, write_accounts_to_csv, write_accounts_to_file,
+        write_accounts_with_columns_to_csv, write_statement_to_csv, write_tenant_accounts_to_csv,
+        Column, CsvSource, SnapshotSink,
+    },
+    data_types::Rejected,
+    dispute_expiry::DisputeExpiry,
+    enrichment::{Annotations, Enrichment},
+    filter::{ClientSet, Filters, TimeWindow},
+    http,
+    inspect::{inspect, write_inspection},
+    locked_accounts::LockedAccountsReport,
+    memory_budget::MemoryBudget,
+    metrics::metrics,
+    negative_balances::NegativeBalancesReport,
+    open_disputes::OpenDisputesReport,
+    pipeline::{Pipeline, PipelineBuilder, Sink},
+    postings::PostingsSink,
+    progress::ProgressReporter,
+    pruning::AccountPruner,
+    pseudonym::{self, Pseudonymizer},
+    reconcile::{self, write_discrepancies, ReconciliationError, Tolerance},
+    replication::{Follower, Replication},
+    risk::RiskScoring,
+    run_status::{Outcome, RunStatus},
+    schema::Schema,
+    seal::{Protection, SnapshotKey, KEY_ENV, KEY_FILE_ENV},
+    segments::write_segment_summary,
+    settings::{LiveSettings, SettingsFiles},
+    shutdown::Shutdown,
+    snapshot::{is_binary_snapshot, restore, SnapshotFormat},
+    snapshot_diff::{diff_accounts, write_diff},
+    state_store::{MemoryStore, StateStore},
+    state_view::StateView,
+    tenants::process_tenants,
+    transaction_context::{HistoryPoint, TransactionContext},
+    udp_source::run_udp_source,
+    watchdog::{StoreTimings, TimedStore, Watchdog},
+};
+use tracing::{error, info, warn};
+use tracing_subscriber::{
+    fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter,
+};
 
-mod csv_source;
-mod data_types;
-mod transaction_context;
-mod transaction_processor;
+#[cfg(feature = "webhooks")]
+use toy_transaction_engine::alerts::AlertConfig;
+#[cfg(feature = "chaos")]
+use toy_transaction_engine::chaos::{check_invariants, ChaosSource};
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+use toy_transaction_engine::leader::{LeaderElection, LOST_LEADERSHIP_EXIT_CODE};
 
-fn main() -> anyhow::Result<()> {
-    // number is arbitrary guesstimate depending on incoming volume
-    let (producer, consumer) = RingBuffer::new(1024 * 1024);
+mod cli;
+mod shell;
+
+/// Keeps the tracing exporters alive, they are flushed when dropped.
+#[cfg(feature = "otel")]
+type TracingGuard = Option<toy_transaction_engine::otel::OtelGuard>;
+#[cfg(not(feature = "otel"))]
+type TracingGuard = Option<std::convert::Infallible>;
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let start = Instant::now();
+
+    let _tracing_guard = match init_tracing(&cli) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.pseudonymize {
+        if let Err(e) = Pseudonymizer::from_env().and_then(pseudonym::install) {
+            eprintln!("Error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    if let Some(command) = &cli.command {
+        let result = match command {
+            Command::Shell(args) => shell::run(args),
+            Command::Diff(args) => diff(args),
+            Command::StateAt(args) => state_at(args),
+            Command::Reconcile(args) => reconcile(args),
+            Command::Simulate(args) => simulate(args),
+            Command::VerifyAudit(args) => verify_audit(args),
+            Command::Analyze(args) => analytics(args),
+            Command::Inspect(args) => inspect_file(args),
+            Command::Replay(args) => replay(args),
+            Command::Incremental(args) => incremental(args),
+        };
+        return match result {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                eprintln!("Error: {e:?}");
+                Outcome::classify(Some(&e), false).into()
+            }
+        };
+    }
+
+    #[cfg(feature = "webhooks")]
+    let alerts = match cli.alerts.as_deref().map(AlertConfig::load).transpose() {
+        Ok(alerts) => alerts,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let result = run(&cli);
+    let (digest, error) = match &result {
+        Ok(digest) => (digest.clone(), None),
+        Err(error) => (None, Some(error)),
+    };
+    if let Some(error) = error {
+        eprintln!("Error: {error:?}");
+    }
+
+    let mut outcome = Outcome::classify(error, cli.strict);
+    if outcome == Outcome::ParseFailures {
+        let metrics = metrics();
+        eprintln!(
+            "Error: {} rows could not be parsed",
+            metrics.parse_failures()
+        );
+        for (path, suggestions) in metrics.column_suggestions() {
+            for suggestion in suggestions {
+                eprintln!("  hint: {path}: {suggestion}");
+            }
+        }
+    }
+    if let (Some(expected), Some(digest)) = (&cli.expect_digest, &digest) {
+        if !expected.eq_ignore_ascii_case(digest) {
+            eprintln!("Error: state digest {digest} differs from the expected {expected}");
+            outcome = Outcome::DigestMismatch;
+        }
+    }
+    let mut status = RunStatus::new(outcome, start.elapsed(), error);
+    status.state_digest = digest;
+    if let Some(path) = &cli.status_json {
+        if let Err(e) = status.write_json(path) {
+            eprintln!("failed to write run status: {e:?}");
+        }
+    }
+    #[cfg(feature = "webhooks")]
+    if let Some(alerts) = &alerts {
+        if let Err(e) = alerts.notify(
+            &status,
+            cli.file_path.as_deref(),
+            cli.expect_digest.as_deref(),
+        ) {
+            eprintln!("failed to send the run alert: {e:?}");
+        }
+    }
+
+    outcome.into()
+}
+
+#[cfg_attr(not(feature = "otel"), allow(unused_variables))]
+fn init_tracing(cli: &Cli) -> anyhow::Result<TracingGuard> {
+    // diagnostics go to stderr, stdout is reserved for the accounts. Closing
+    // spans are logged so the duration of each stage is visible.
+    let registry = tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(std::io::stderr),
+        );
+
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        let (layer, guard) = toy_transaction_engine::otel::init(endpoint)?;
+        registry.with(layer).init();
+        return Ok(Some(guard));
+    }
+
+    registry.init();
+    Ok(None)
+}
+
+/// Processes the given csv file into the context, collecting the rejected
+/// events.
+fn process_file(
+    path: &Path,
+    context: &mut TransactionContext,
+    rejects: &mut Vec<Rejected>,
+) -> anyhow::Result<()> {
+    Pipeline::builder()
+        .csv_source(CsvSource::open(path, None)?)
+        .processor(context)
+        .sink(rejects)
+        .build()?
+        .run()
+}
+
+fn diff(args: &DiffArgs) -> anyhow::Result<()> {
+    let before = read_accounts(&args.snapshot_a)?;
+    let after = read_accounts(&args.snapshot_b)?;
+    let diffs = diff_accounts(before, after);
+    write_diff(std::io::stdout(), &diffs)
+}
+
+fn state_at(args: &StateAtArgs) -> anyhow::Result<()> {
+    let point = match (args.tx, args.offset) {
+        (Some(tx), _) => HistoryPoint::Tx(tx),
+        (None, Some(offset)) => HistoryPoint::Offset(offset),
+        (None, None) => anyhow::bail!("either --tx or --offset is required"),
+    };
+
+    let mut context = TransactionContext::new();
+    context.track_history();
+    process_file(&args.file_path, &mut context, &mut Vec::new())?;
+
+    let Some(account) = context.account_at(args.client, point) else {
+        anyhow::bail!("{point:?} is not in the history of client {}", args.client);
+    };
+    write_accounts_to_csv(std::iter::once((args.client, account)), args.extended)
+}
+
+fn analytics(args: &AnalyzeArgs) -> anyhow::Result<()> {
+    let mut context = TransactionContext::new();
+    context.track_history();
+    process_file(&args.file_path, &mut context, &mut Vec::new())?;
+    let analysis = analyze(&context, args.top);
+    write_analysis(std::io::stdout(), &analysis, args.format)
+}
+
+fn inspect_file(args: &InspectArgs) -> anyhow::Result<()> {
+    let inspection = inspect(&args.file_path, args.sample)?;
+    write_inspection(std::io::stdout(), &inspection)
+}
+
+fn replay(args: &ReplayArgs) -> anyhow::Result<()> {
+    let replay = batch::replay_file(&args.file_path, args.threads, !args.one_pass)?;
+    if !replay.rejects.is_empty() {
+        info!(events = replay.rejects.len(), "rejected events");
+    }
+    write_accounts_to_csv(replay.accounts.into_iter(), args.extended)
+}
+
+fn reconcile(args: &ReconcileArgs) -> anyhow::Result<()> {
+    let expected = read_accounts(&args.expected)?;
+    let mut context = TransactionContext::new();
+    process_file(&args.file_path, &mut context, &mut Vec::new())?;
+
+    let tolerance = Tolerance {
+        absolute: args.tolerance,
+        relative: args.relative_tolerance,
+        ignore_missing: args.ignore_missing,
+    };
+    let discrepancies = reconcile::reconcile(expected, context.into_iter_accounts(), &tolerance);
+    write_discrepancies(std::io::stdout(), &discrepancies)?;
+    if !discrepancies.is_empty() {
+        let mut clients: Vec<_> = discrepancies.iter().map(|d| d.client_id).collect();
+        clients.dedup();
+        return Err(ReconciliationError {
+            discrepancies: discrepancies.len(),
+            clients: clients.len(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+fn verify_audit(args: &VerifyAuditArgs) -> anyhow::Result<()> {
+    let file = std::fs::File::open(&args.path)
+        .with_context(|| format!("failed to open {}", args.path.display()))?;
+    let verification = audit_log::verify(std::io::BufReader::new(file), args.anchor.as_deref())
+        .with_context(|| format!("{} failed verification", args.path.display()))?;
+    println!(
+        "{} records, {} anchors, digest {}",
+        verification.records,
+        verification.anchors,
+        verification.digest.as_deref().unwrap_or("-")
+    );
+    Ok(())
+}
+
+/// Only binary snapshots carry the transactions, with an account output the
+/// hypothetical disputes of earlier transactions are rejected.
+fn simulate(args: &SimulateArgs) -> anyhow::Result<()> {
+    let mut context = TransactionContext::new();
+    if is_binary_snapshot(&args.snapshot)? {
+        restore(&mut context, &args.snapshot)?;
+    } else {
+        for (client_id, account) in read_accounts(&args.snapshot)? {
+            context.insert_account(client_id, account);
+        }
+    }
+    let before: Vec<_> = context
+        .iter_accounts()
+        .map(|(client_id, account)| (client_id, *account))
+        .collect();
+
+    let mut rejects = Vec::new();
+    process_file(&args.file_path, &mut context, &mut rejects)?;
+    if !rejects.is_empty() {
+        info!(rejected = rejects.len(), "hypothetical events rejected");
+    }
+    let diffs = diff_accounts(before, context.into_iter_accounts());
+    write_diff(std::io::stdout(), &diffs)
+}
+
+/// The key for `--snapshot-protection`, from the environment.
+fn snapshot_key(
+    protection: Option<Protection>,
+) -> anyhow::Result<Option<(Protection, Arc<SnapshotKey>)>> {
+    let Some(protection) = protection else {
+        return Ok(None);
+    };
+    let Some(key) = SnapshotKey::from_env()? else {
+        anyhow::bail!("--snapshot-protection needs a key in {KEY_ENV} or {KEY_FILE_ENV}");
+    };
+    Ok(Some((protection, Arc::new(key))))
+}
+
+fn incremental(args: &IncrementalArgs) -> anyhow::Result<()> {
+    let mut context = TransactionContext::new();
+    let binary = is_binary_snapshot(&args.snapshot)?;
+    match binary {
+        true => restore(&mut context, &args.snapshot)?,
+        false => context.seed_accounts(read_accounts(&args.snapshot)?),
+    }
+    let before: Vec<_> = context
+        .iter_accounts()
+        .map(|(client_id, account)| (client_id, *account))
+        .collect();
+
+    let format = args.snapshot_format.unwrap_or(match binary {
+        true => SnapshotFormat::Binary,
+        false => SnapshotFormat::Csv,
+    });
+    let mut snapshot = SnapshotSink::new(&args.out).format(format);
+    if let Some((protection, key)) = snapshot_key(args.snapshot_protection)? {
+        snapshot = snapshot.protect(protection, key);
+    }
+    let mut rejects = Vec::new();
+    Pipeline::builder()
+        .csv_source(CsvSource::open(&args.file_path, None)?)
+        .processor(&mut context)
+        .sink(&mut rejects)
+        .sink(&mut snapshot)
+        .build()?
+        .run()?;
+    if !rejects.is_empty() {
+        info!(rejected = rejects.len(), "rejected events");
+    }
+
+    let deltas = diff_accounts(before, context.into_iter_accounts());
+    write_diff(std::io::stdout(), &deltas)
+}
+
+/// The schema to map the input files with.
+fn schema(cli: &Cli) -> anyhow::Result<Schema> {
+    let mut schema = match &cli.schema {
+        Some(path) => Schema::load(path)?,
+        None => Schema::default(),
+    };
+    if let Some(columns) = &cli.columns {
+        schema.columns.extend(columns.clone());
+    }
+    if let Some(delimiter) = cli.delimiter {
+        schema.csv.delimiter = delimiter;
+    }
+    if let Some(quote) = cli.quote {
+        schema.csv.quote = quote;
+    }
+    if let Some(comment) = cli.comment {
+        schema.csv.comment = Some(comment);
+    }
+    if let Some(encoding) = cli.encoding {
+        schema.csv.encoding = Some(encoding);
+    }
+    if let Some(rounding) = cli.rounding {
+        schema.csv.rounding = Some(rounding);
+    }
+    if let Some(locale) = cli.locale {
+        schema.csv.locale = Some(locale);
+    }
+    if let Some(policy) = cli.out_of_range {
+        schema.csv.out_of_range = Some(policy);
+    }
+    if cli.no_headers {
+        schema.csv.headers = false;
+    }
+    schema.validate().map_err(|e| anyhow::anyhow!(e))?;
+    Ok(schema)
+}
+
+/// The filters to apply to the input events.
+fn filters(cli: &Cli) -> anyhow::Result<Filters> {
+    let clients = match (&cli.clients, &cli.clients_file) {
+        (Some(clients), _) => Some(clients.clone()),
+        (None, Some(path)) => Some(ClientSet::from_file(path)?),
+        (None, None) => None,
+    };
+    let window = (cli.from.is_some() || cli.to.is_some()).then_some(TimeWindow {
+        from: cli.from,
+        to: cli.to,
+    });
+    Ok(Filters {
+        clients,
+        ignore: cli.ignore,
+        window,
+    })
+}
+
+/// The budget of `--max-memory`, if given.
+fn memory_budget(cli: &Cli) -> Option<MemoryBudget> {
+    let budget = MemoryBudget::new(cli.max_memory?, cli.memory_policy);
+    #[cfg(feature = "sled")]
+    let budget = match &cli.spill_dir {
+        Some(dir) => budget.spill_dir(dir.clone()),
+        None => budget,
+    };
+    Some(budget)
+}
+
+/// Attaches the checks of the events, shared by the run and the pass before
+/// the window of `--window-deltas`: the settings with the rules and the max
+/// rate, the `--plugin`s and the `--script`s.
+#[cfg_attr(
+    not(any(feature = "plugins", feature = "scripting")),
+    allow(unused_variables, unused_mut)
+)]
+fn checks<'a>(
+    cli: &Cli,
+    pipeline: PipelineBuilder<'a>,
+    settings: &LiveSettings,
+) -> anyhow::Result<PipelineBuilder<'a>> {
+    let mut pipeline = pipeline.settings(settings.clone());
+    #[cfg(feature = "plugins")]
+    for path in &cli.plugin {
+        let plugin = toy_transaction_engine::plugin::Plugin::load(path)?;
+        if let Some(validator) = plugin.validator()? {
+            pipeline = pipeline.validator("plugin", validator);
+        }
+        if let Some(sink) = plugin.sink()? {
+            pipeline = pipeline.sink(sink);
+        }
+    }
+    #[cfg(feature = "scripting")]
+    for path in &cli.script {
+        let script = toy_transaction_engine::script::Script::load(path)?;
+        if let Some(validator) = script.validator() {
+            pipeline = pipeline.validator("script", validator);
+        }
+        if let Some(sink) = script.sink() {
+            pipeline = pipeline.sink(sink);
+        }
+    }
+    Ok(pipeline)
+}
+
+/// The pipeline applying the events before `--from`, to build the opening
+/// state of `--window-deltas`.
+fn before_window<'a>(
+    cli: &Cli,
+    path: &Path,
+    filters: &Filters,
+    annotations: Option<&Arc<Annotations>>,
+    settings: &LiveSettings,
+) -> anyhow::Result<PipelineBuilder<'a>> {
+    let before = Filters {
+        window: Some(TimeWindow {
+            from: None,
+            to: cli.from,
+        }),
+        ..filters.clone()
+    };
+    let source = CsvSource::open_with_schema(path, None, &schema(cli)?)?;
+    let mut pipeline = before.apply(
+        Pipeline::builder()
+            .csv_source(source)
+            .parse_threads(cli.parse_threads.into()),
+    );
+    if let Some(annotations) = annotations {
+        pipeline = pipeline.transform("enrich", Enrichment::new(annotations.clone()));
+    }
+    checks(cli, pipeline, settings)
+}
+
+/// Processes the input of every tenant into an isolated context and writes
+/// the accounts partitioned per tenant.
+fn run_tenants(cli: &Cli) -> anyhow::Result<()> {
+    let duplicates = cli
+        .cross_file_duplicates
+        .is_some()
+        .then(|| CrossFileDuplicates::new(&cli.tenant));
+    let contexts = process_tenants(
+        &cli.tenant,
+        cli.track_history,
+        &schema(cli)?,
+        &filters(cli)?,
+        duplicates.as_ref(),
+    )?;
+    if let (Some(path), Some(duplicates)) = (&cli.cross_file_duplicates, &duplicates) {
+        let duplicates = duplicates.duplicates();
+        if !duplicates.is_empty() {
+            warn!(
+                count = duplicates.len(),
+                "transaction ids appear in more than one file"
+            );
+        }
+        write_cross_file_duplicates(std::fs::File::create(path)?, &duplicates)?;
+    }
+    match &cli.output_dir {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            for (name, context) in &contexts {
+                let accounts = context.iter_accounts().map(|(id, account)| (id, *account));
+                write_accounts_to_file(accounts, &dir.join(format!("{name}.csv")), cli.extended)?;
+            }
+        }
+        None => write_tenant_accounts_to_csv(
+            contexts
+                .iter()
+                .map(|(name, context)| (name.as_str(), context)),
+            cli.extended,
+        )?,
+    }
+    if let Some(path) = &cli.pseudonym_map {
+        let clients = contexts
+            .values()
+            .flat_map(|context| context.iter_accounts().map(|(id, _)| id));
+        write_pseudonym_map(path, clients)?;
+    }
+    Ok(())
+}
+
+/// Writes the mapping of the installed pseudonymizer, sorted by client id.
+/// Tenants can share client ids, they are listed once.
+fn write_pseudonym_map(path: &Path, clients: impl Iterator<Item = u16>) -> anyhow::Result<()> {
+    let mut clients: Vec<u16> = clients.collect();
+    clients.sort_unstable();
+    clients.dedup();
+    pseudonym::installed()
+        .expect("--pseudonym-map requires --pseudonymize")
+        .write_mapping(path, clients.into_iter())
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+fn open_state_store(
+    backend: cli::StateBackend,
+    dir: &std::path::Path,
+    exactly_once: bool,
+) -> anyhow::Result<Box<dyn StateStore>> {
+    Ok(match backend {
+        #[cfg(feature = "sled")]
+        cli::StateBackend::Sled => {
+            use toy_transaction_engine::sled_store::SledStore;
+            Box::new(match exactly_once {
+                true => SledStore::open_exactly_once(dir)?,
+                false => SledStore::open(dir)?,
+            })
+        }
+        #[cfg(feature = "rocksdb")]
+        cli::StateBackend::Rocksdb => {
+            use toy_transaction_engine::rocksdb_store::RocksStore;
+            Box::new(match exactly_once {
+                true => RocksStore::open_exactly_once(dir)?,
+                false => RocksStore::open(dir)?,
+            })
+        }
+    })
+}
+
+/// The optional sinks of a run, shared by the inputs.
+struct Sinks {
+    conservation: Option<ConservationCheck>,
+    snapshot: Option<SnapshotSink>,
+    postings: Option<PostingsSink>,
+    open_disputes: Option<OpenDisputesReport>,
+    locked_accounts: Option<LockedAccountsReport>,
+    negative_balances: Option<NegativeBalancesReport>,
+    risk_scoring: Option<RiskScoring>,
+    #[cfg(feature = "kafka")]
+    kafka: Option<toy_transaction_engine::kafka::KafkaSink>,
+    #[cfg(feature = "webhooks")]
+    webhooks: Option<toy_transaction_engine::webhooks::WebhookSink>,
+}
+
+impl Sinks {
+    /// The sinks that are set, in the order they see the events.
+    fn iter_mut(&mut self) -> Vec<&mut dyn Sink> {
+        let mut sinks: Vec<&mut dyn Sink> = Vec::new();
+        if let Some(conservation) = &mut self.conservation {
+            sinks.push(conservation);
+        }
+        if let Some(snapshot) = &mut self.snapshot {
+            sinks.push(snapshot);
+        }
+        if let Some(postings) = &mut self.postings {
+            sinks.push(postings);
+        }
+        if let Some(open_disputes) = &mut self.open_disputes {
+            sinks.push(open_disputes);
+        }
+        if let Some(locked_accounts) = &mut self.locked_accounts {
+            sinks.push(locked_accounts);
+        }
+        if let Some(negative_balances) = &mut self.negative_balances {
+            sinks.push(negative_balances);
+        }
+        if let Some(risk_scoring) = &mut self.risk_scoring {
+            sinks.push(risk_scoring);
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(kafka) = &mut self.kafka {
+            sinks.push(kafka);
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(webhooks) = &mut self.webhooks {
+            sinks.push(webhooks);
+        }
+        sinks
+    }
+
+    /// Finishes the sinks, for inputs that drive the processor without a
+    /// pipeline.
+    #[cfg(feature = "grpc")]
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        for sink in self.iter_mut() {
+            sink.finish(context)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the digest of the final state, except for multi-tenant runs.
+fn run(cli: &Cli) -> anyhow::Result<Option<String>> {
+    #[cfg(feature = "grpc")]
+    let grpc = cli.grpc_addr.is_some();
+    #[cfg(not(feature = "grpc"))]
+    let grpc = false;
+
+    // keeps running until SIGINT/SIGTERM
+    let service = (cli.watch || cli.udp_addr.is_some() || grpc) && cli.tenant.is_empty();
+    // the signal handlers are installed once, batch runs keep the default
+    // handling and are simply terminated
+    let shutdown = (service || cli.follow.is_some())
+        .then(Shutdown::install)
+        .transpose()?
+        .unwrap_or_default();
+    let state_view = (cli.http_addr.is_some() || grpc).then(StateView::new);
+    // the admin endpoints are only served with a token
+    let control = match std::env::var(control::TOKEN_ENV) {
+        Ok(token) if service && cli.http_addr.is_some() => {
+            Some(Control::new(token, Some(shutdown.clone())))
+        }
+        _ => None,
+    };
+    if let Some(addr) = cli.http_addr {
+        http::serve(addr, state_view.clone(), control.clone())?;
+    }
+
+    let progress = if cli.progress {
+        let total_bytes = cli
+            .file_path
+            .iter()
+            .chain(cli.tenant.iter().map(|tenant| &tenant.path))
+            .filter_map(|path| std::fs::metadata(path).ok())
+            .map(|m| m.len())
+            .sum();
+        Some(ProgressReporter::spawn(
+            total_bytes,
+            Duration::from_secs(1),
+        )?)
+    } else {
+        None
+    };
+
+    if !cli.tenant.is_empty() {
+        run_tenants(cli)?;
+        if let Some(progress) = progress {
+            progress.finish();
+        }
+        return Ok(None);
+    }
+
+    // standbys wait here, before the state is opened
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    let _leadership = match &cli.leader_lock {
+        Some(lock) => {
+            let election = LeaderElection::new(lock.open());
+            // another instance may be applying events already
+            let on_lost = || std::process::exit(LOST_LEADERSHIP_EXIT_CODE);
+            match election.acquire(&shutdown, on_lost)? {
+                Some(leadership) => Some(leadership),
+                None => return Ok(None),
+            }
+        }
+        None => None,
+    };
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    let store: Box<dyn StateStore> = match &cli.state_dir {
+        Some(dir) => open_state_store(cli.state_backend, dir, cli.exactly_once)?,
+        None => Box::<MemoryStore>::default(),
+    };
+    #[cfg(not(any(feature = "sled", feature = "rocksdb")))]
+    let store: Box<dyn StateStore> = Box::<MemoryStore>::default();
+    // the watchdog reports the time slow events spent in the store
+    let store_timings = cli
+        .event_deadline
+        .map(|_| Arc::new(StoreTimings::default()));
+    let mut context = TransactionContext::with_store(match &store_timings {
+        Some(timings) => Box::new(TimedStore::new(store, timings.clone())),
+        None => store,
+    });
+    if cli.track_history {
+        context.track_history();
+    }
+    if cli.running_balances {
+        context.track_balances();
+    }
+    if let Some(client) = cli.suspense_account {
+        context.set_suspense_account(client);
+    }
+    if let Some(path) = &cli.client_registry {
+        context.set_client_registry(ClientSet::from_file(path)?);
+    }
+    context.set_lock_policy(cli.chargeback_lock);
+    context.set_hold_policy(cli.dispute_hold);
+    let files = SettingsFiles {
+        settings: cli.settings.clone(),
+        max_rate: cli.max_rate,
+        rules: cli.rules.clone(),
+        segments: cli.segments.clone(),
+        segment_lock_policies: cli.segment_chargeback_lock.clone(),
+        client_lock_policies: cli.chargeback_lock_for.clone(),
+    };
+    let settings = LiveSettings::new(files.load()?);
+    context.set_client_lock_policies(settings.load().lock_policies.clone());
+    let _reload = service
+        .then(|| settings.reload_on_sighup(files))
+        .transpose()?;
+    if let Some(path) = &cli.restore {
+        restore(&mut context, path)?;
+    }
+    if let Some(path) = &cli.opening_balances {
+        let accounts = read_accounts(path)?;
+        info!(path = %path.display(), accounts = accounts.len(), "opening balances");
+        context.seed_accounts(accounts);
+    }
+
+    let mut audit_log = cli.audit_log.as_deref().map(AuditLog::create).transpose()?;
+    // refreshed whenever a source pauses, periodically and at the end. Only
+    // periodic snapshots are rotated.
+    let keep = cli.snapshot_every.map_or(0, |_| cli.snapshot_keep);
+    let protection = snapshot_key(cli.snapshot_protection)?;
+    let snapshot = cli.snapshot.as_deref().map(|path| {
+        let snapshot = SnapshotSink::new(path)
+            .format(cli.snapshot_format)
+            .keep(keep);
+        match &protection {
+            Some((protection, key)) => snapshot.protect(*protection, key.clone()),
+            None => snapshot,
+        }
+    });
+
+    let annotations = cli
+        .annotations
+        .as_deref()
+        .map(Annotations::from_file)
+        .transpose()?
+        .map(Arc::new);
+    let postings = cli
+        .postings
+        .as_deref()
+        .map(|path| PostingsSink::create(path, &context))
+        .transpose()?
+        .map(|postings| match &annotations {
+            Some(annotations) => postings.annotate(annotations.clone()),
+            None => postings,
+        });
+    let open_disputes = cli.open_disputes.as_deref().map(OpenDisputesReport::new);
+    let risk_scoring = cli.risk_score.clone().map(RiskScoring::new);
+    let locked_accounts = cli
+        .locked_accounts
+        .as_deref()
+        .map(|path| LockedAccountsReport::new(path, &context));
+    let negative_balances = cli
+        .negative_balances
+        .as_deref()
+        .map(NegativeBalancesReport::new);
+    #[cfg(feature = "webhooks")]
+    let webhooks = cli
+        .webhooks
+        .as_deref()
+        .map(|path| {
+            let config = toy_transaction_engine::webhooks::WebhookConfig::load(path)?;
+            toy_transaction_engine::webhooks::WebhookSink::new(config, &context)
+        })
+        .transpose()?;
+    #[cfg(feature = "kafka")]
+    let kafka = match (&cli.kafka_brokers, &cli.kafka_topic) {
+        (Some(brokers), Some(topic)) => Some(toy_transaction_engine::kafka::KafkaSink::new(
+            brokers,
+            topic,
+            &cli.kafka_config,
+        )?),
+        _ => None,
+    };
+    let mut sinks = Sinks {
+        conservation: None,
+        snapshot,
+        postings,
+        open_disputes,
+        locked_accounts,
+        negative_balances,
+        risk_scoring,
+        #[cfg(feature = "kafka")]
+        kafka,
+        #[cfg(feature = "webhooks")]
+        webhooks,
+    };
+
+    // with window deltas, the accounts at the start of the window
+    let mut opening = None;
+    let mut replication = cli.replication_addr.map(Replication::listen).transpose()?;
 
     // source can be anything that produces [`TransactionEvent`] data.
-    run_csv_source(producer)?;
+    match &cli.file_path {
+        input if input.is_some() || cli.udp_addr.is_some() => {
+            let filters = filters(cli)?;
+            if let (Some(path), true) = (input, cli.window_deltas) {
+                // first apply everything before the window
+                let mut pipeline =
+                    before_window(cli, path, &filters, annotations.as_ref(), &settings)?;
+                if let Some(audit_log) = &mut audit_log {
+                    pipeline = pipeline.sink(audit_log);
+                }
+                if let Some(postings) = &mut sinks.postings {
+                    pipeline = pipeline.sink(postings);
+                }
+                if let Some(open_disputes) = &mut sinks.open_disputes {
+                    pipeline = pipeline.sink(open_disputes);
+                }
+                if let Some(locked_accounts) = &mut sinks.locked_accounts {
+                    pipeline = pipeline.sink(locked_accounts);
+                }
+                if let Some(negative_balances) = &mut sinks.negative_balances {
+                    pipeline = pipeline.sink(negative_balances);
+                }
+                #[cfg(feature = "kafka")]
+                if let Some(kafka) = &mut sinks.kafka {
+                    pipeline = pipeline.sink(kafka);
+                }
+                pipeline.processor(&mut context).build()?.run()?;
+                opening = Some(
+                    context
+                        .iter_accounts()
+                        .map(|(id, account)| (id, *account))
+                        .collect::<Vec<_>>(),
+                );
+            }
+
+            let source = match input {
+                Some(path) => {
+                    let follow = cli.watch.then(|| shutdown.clone());
+                    Some(CsvSource::open_with_schema(path, follow, &schema(cli)?)?)
+                }
+                None => None,
+            };
+            let pipeline = |source: Option<CsvSource>| -> anyhow::Result<_> {
+                Ok(match source {
+                    Some(source) => Pipeline::builder().csv_source(source),
+                    None => {
+                        let addr = cli.udp_addr.expect("udp address is set");
+                        let socket = UdpSocket::bind(addr)
+                            .with_context(|| format!("failed to bind {addr}"))?;
+                        let source = run_udp_source(socket, cli.udp_queue, shutdown.clone())?;
+                        Pipeline::builder().source(source)
+                    }
+                })
+            };
+            #[cfg(feature = "chaos")]
+            let (pipeline, chaos) = match (source, cli.chaos_seed) {
+                (Some(source), Some(seed)) => {
+                    // faults are injected into the parsed events
+                    let source = ChaosSource::new(source, seed, cli.chaos_rate);
+                    let report = source.report();
+                    (Pipeline::builder().source(source), Some(report))
+                }
+                (source, _) => (pipeline(source)?, None),
+            };
+            #[cfg(not(feature = "chaos"))]
+            let pipeline = pipeline(source)?;
+            let mut pipeline = filters.apply(pipeline.parse_threads(cli.parse_threads.into()));
+            if let Some(annotations) = &annotations {
+                pipeline = pipeline.transform("enrich", Enrichment::new(annotations.clone()));
+            }
+            pipeline = checks(cli, pipeline, &settings)?;
+            if let Some(control) = &control {
+                pipeline = pipeline.control(control.clone());
+            }
+            if let Some(replication) = replication.take() {
+                pipeline = pipeline.replication(replication);
+            }
+            if let Some(audit_log) = &mut audit_log {
+                pipeline = pipeline.sink(audit_log);
+            }
+            sinks.conservation = cli
+                .check_conservation
+                .then(|| ConservationCheck::new(&context));
+            for sink in sinks.iter_mut() {
+                pipeline = pipeline.sink(sink);
+            }
+            if let Some(interval) = cli.snapshot_every {
+                pipeline = pipeline.flush_every(interval);
+            }
+            if let Some(budget) = memory_budget(cli) {
+                pipeline = pipeline.memory_budget(budget);
+            }
+            if let Some(idle) = cli.prune_after {
+                pipeline = pipeline.prune_accounts(AccountPruner::new(idle));
+            }
+            if let Some(timeout) = cli.dispute_timeout {
+                pipeline = pipeline.expire_disputes(DisputeExpiry::new(timeout));
+            }
+            if let (Some(deadline), Some(timings)) = (cli.event_deadline, &store_timings) {
+                let watchdog = Watchdog::new(deadline)?.with_store_timings(timings.clone());
+                pipeline = pipeline.watchdog(watchdog);
+            }
+            if let Some(pins) = cli.pin_cores {
+                pins.validate()?;
+                pipeline = pipeline.pin_cores(pins);
+            }
+            if let Some(state_view) = state_view {
+                pipeline = pipeline.state_view(state_view);
+            }
+            #[cfg(any(feature = "sled", feature = "rocksdb"))]
+            if cli.exactly_once {
+                pipeline = pipeline.exactly_once();
+            }
+            pipeline.processor(&mut context).build()?.run()?;
+
+            #[cfg(feature = "chaos")]
+            if let Some(report) = chaos {
+                eprintln!(
+                    "chaos injected {}",
+                    report.lock().expect("chaos report poisoned")
+                );
+                check_invariants(&context)
+                    .map_err(|e| anyhow::anyhow!("invariant violated: {e}"))?;
+            }
+        }
+        None if cli.follow.is_some() => {
+            let primary = cli.follow.expect("primary address is set");
+            let mut follower = Follower::new(primary);
+            if let Some(state_view) = state_view {
+                follower = follower.with_state_view(state_view);
+            }
+            if let Some(audit_log) = &mut audit_log {
+                follower = follower.with_sink(audit_log);
+            }
+            if let Some(snapshot) = &mut sinks.snapshot {
+                follower = follower.with_sink(snapshot);
+            }
+            follower.run(&mut context, &shutdown)?;
+            context.flush()?;
+            if let Some(snapshot) = &mut sinks.snapshot {
+                snapshot.finish(&context)?;
+            }
+        }
+        #[cfg(feature = "grpc")]
+        None if grpc => {
+            // number is arbitrary guesstimate depending on incoming volume
+            let (producer, consumer) = rtrb::RingBuffer::new(1024 * 1024);
+            let addr = cli.grpc_addr.expect("grpc address is set");
+            let view = state_view.clone().expect("state view is created for gRPC");
+            toy_transaction_engine::grpc::run_grpc_source(
+                addr,
+                producer,
+                view.clone(),
+                shutdown.clone(),
+            )?;
+
+            sinks.conservation = cli
+                .check_conservation
+                .then(|| ConservationCheck::new(&context));
+            let mut processor =
+                toy_transaction_engine::transaction_processor::TransactionProcessor::new(
+                    &mut context,
+                    consumer,
+                )
+                .with_state_view(view);
+            if let Some(audit_log) = &mut audit_log {
+                processor = processor.with_audit_log(audit_log);
+            }
+            for sink in sinks.iter_mut() {
+                processor = processor.with_sink(sink);
+            }
+            if let Some(budget) = memory_budget(cli) {
+                processor = processor.with_memory_budget(budget);
+            }
+            if let Some(idle) = cli.prune_after {
+                processor = processor.with_pruner(AccountPruner::new(idle));
+            }
+            if let Some(timeout) = cli.dispute_timeout {
+                processor = processor.with_dispute_expiry(DisputeExpiry::new(timeout));
+            }
+            if let (Some(deadline), Some(timings)) = (cli.event_deadline, &store_timings) {
+                let watchdog = Watchdog::new(deadline)?.with_store_timings(timings.clone());
+                processor = processor.with_watchdog(watchdog);
+            }
+            processor = processor.with_settings(settings.clone());
+            if let Some(control) = &control {
+                processor = processor.with_control(control.clone());
+            }
+            if let Some(replication) = replication.take() {
+                processor = processor.with_replication(replication);
+            }
+            processor.run()?;
+            context.flush()?;
+            // the processor does not finish the sinks, unlike a pipeline
+            sinks.finish(&context)?;
+        }
+        _ => anyhow::bail!("no input given"),
+    }
+
+    if let Some(progress) = progress {
+        progress.finish();
+    }
+
+    if let Some(audit_log) = audit_log {
+        audit_log.finish()?;
+    }
+
+    if let Some(path) = &cli.statement {
+        write_statement_to_csv(&context, path)?;
+    }
+
+    if let Some(path) = &cli.pseudonym_map {
+        write_pseudonym_map(path, context.iter_accounts().map(|(id, _)| id))?;
+    }
+
+    if let Some(client) = context.suspense_account() {
+        let balance = context.account(client).map(|account| account.total);
+        info!(client = %pseudonym::client(client), balance = %balance.unwrap_or_default(), "suspense account");
+    }
+
+    // the segments as last reloaded
+    let settings = settings.load();
+    let segments = &settings.segments;
+    if let (Some(segments), Some(path)) = (segments, &cli.segment_summary) {
+        let summaries = segments.summarize(context.iter_accounts());
+        write_segment_summary(std::fs::File::create(path)?, &summaries)?;
+    }
+
+    let digest = context.state_digest();
+    info!(%digest, "state digest");
+
+    match opening {
+        Some(opening) => write_diff(
+            std::io::stdout(),
+            &diff_accounts(opening, context.into_iter_accounts()),
+        )?,
+        None => {
+            let mut columns = Vec::new();
+            if let (Some(segments), true) = (segments, cli.extended) {
+                columns.push(Column {
+                    name: "segment",
+                    value: Box::new(|client_id, _| {
+                        segments.segment(client_id).unwrap_or_default().to_string()
+                    }),
+                });
+            }
+            if let Some(scoring) = &sinks.risk_scoring {
+                columns.push(Column {
+                    name: "risk_score",
+                    value: Box::new(|client_id, account| {
+                        format!("{:.3}", scoring.score(client_id, account))
+                    }),
+                });
+            }
+            write_accounts_with_columns_to_csv(
+                context.into_iter_accounts(),
+                cli.extended,
+                &columns,
+            )?
+        }
+    }
+
+    let lost = metrics().events_lost();
+    if lost > 0 {
+        error!(lost, "events were lost before they could be processed");
+    }
+    Ok(Some(digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use toy_transaction_engine::data_types::Price;
+
+    /// The opening state of a `--window-deltas` run from 100 on the input,
+    /// with the given extra arguments.
+    fn opening(dir: &Path, input: &str, args: &[&std::ffi::OsStr]) -> TransactionContext {
+        let path = dir.join("input.csv");
+        std::fs::write(&path, input).unwrap();
+        let cli = Cli::try_parse_from(
+            ["txe".as_ref(), path.as_os_str(), "--window-deltas".as_ref()]
+                .into_iter()
+                .chain(["--from".as_ref(), "100".as_ref()])
+                .chain(args.iter().copied()),
+        )
+        .unwrap();
+        let files = SettingsFiles {
+            rules: cli.rules.clone(),
+            ..Default::default()
+        };
+        let settings = LiveSettings::new(files.load().unwrap());
+        let mut context = TransactionContext::new();
+        before_window(&cli, &path, &filters(&cli).unwrap(), None, &settings)
+            .unwrap()
+            .processor(&mut context)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        context
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-    let accounts = TransactionProcessor::exhaust_sources(consumer);
+    #[test]
+    fn test_before_window() {
+        let dir = temp_dir("txe_test_before_window");
+        let rules = dir.join("rules.toml");
+        std::fs::write(&rules, "[amount]\nmax = 10.0\n").unwrap();
+        // the deposit above the max of the rules is rejected before the window
+        // too, only the second one is in the opening state
+        let context = opening(
+            &dir,
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,50.0,10\n\
+             deposit,1,2,5.0,20\n\
+             deposit,1,3,5.0,200\n",
+            &["--rules".as_ref(), rules.as_os_str()],
+        );
+        assert_eq!(context.account(1).unwrap().total, Price(50_000));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-    write_accounts_to_csv(accounts)
+    #[cfg(feature = "scripting")]
+    #[test]
+    fn test_before_window_script() {
+        let dir = temp_dir("txe_test_before_window_script");
+        let script = dir.join("check.rhai");
+        std::fs::write(&script, "fn validate(event) { event.client != 2 }").unwrap();
+        let context = opening(
+            &dir,
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,5.0,10\n\
+             deposit,2,2,5.0,20\n",
+            &["--script".as_ref(), script.as_os_str()],
+        );
+        assert!(context.account(1).is_some());
+        assert!(context.account(2).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,200 @@
+//! Guardrail on the memory held by the state, so a huge input degrades
+//! according to a [`MemoryPolicy`] instead of getting the process OOM-killed.
+//!
+//! The processor checks the budget every [`CHECK_EVERY`] events and acts once
+//! the state holds more than [`HIGH_WATER`] of it, leaving room for the next
+//! allocations.
+use crate::transaction_context::TransactionContext;
+#[cfg(feature = "sled")]
+use std::path::PathBuf;
+use std::{fmt::Display, str::FromStr};
+use tracing::warn;
+
+/// Amount of events between two checks of the budget.
+pub const CHECK_EVERY: u64 = 1024;
+/// Fraction of the budget at which the policy kicks in.
+pub const HIGH_WATER: f64 = 0.9;
+
+/// What to do when the state approaches the budget.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum MemoryPolicy {
+    /// stop the run with an error
+    #[default]
+    Abort,
+    /// drop the resolved and charged back transactions, they can not be
+    /// disputed anymore. A later deposit or withdrawal reusing their id is no
+    /// longer rejected as a duplicate.
+    EvictFinalized,
+    /// move the state into a sled database and continue on disk
+    #[cfg(feature = "sled")]
+    Spill,
+}
+
+impl FromStr for MemoryPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(MemoryPolicy::Abort),
+            "evict-finalized" => Ok(MemoryPolicy::EvictFinalized),
+            #[cfg(feature = "sled")]
+            "spill" => Ok(MemoryPolicy::Spill),
+            #[cfg(not(feature = "sled"))]
+            "spill" => Err("the spill policy needs the `sled` feature".to_string()),
+            _ => Err(format!(
+                "unknown memory policy `{s}`, expected abort, evict-finalized or spill"
+            )),
+        }
+    }
+}
+
+/// Amount of bytes parsed from e.g. `512M`, `2G` or `1048576`. Suffixes are
+/// powers of 1024.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteSize(pub usize);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 10),
+            Some((i, 'm' | 'M')) => (&s[..i], 20),
+            Some((i, 'g' | 'G')) => (&s[..i], 30),
+            _ => (s, 0),
+        };
+        let n: usize = digits
+            .parse()
+            .map_err(|_| format!("expected a size like `512M`, got `{s}`"))?;
+        n.checked_mul(1 << shift)
+            .map(ByteSize)
+            .ok_or_else(|| format!("`{s}` is too large"))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.1} MiB", self.0 as f64 / (1 << 20) as f64)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    limit: usize,
+    policy: MemoryPolicy,
+    /// where [`MemoryPolicy::Spill`] puts the database
+    #[cfg(feature = "sled")]
+    spill_dir: Option<PathBuf>,
+    #[cfg(feature = "sled")]
+    spilled: bool,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: ByteSize, policy: MemoryPolicy) -> Self {
+        MemoryBudget {
+            limit: limit.0,
+            policy,
+            #[cfg(feature = "sled")]
+            spill_dir: None,
+            #[cfg(feature = "sled")]
+            spilled: false,
+        }
+    }
+
+    /// Directory to spill to, required for [`MemoryPolicy::Spill`]. It must
+    /// not hold a database yet.
+    #[cfg(feature = "sled")]
+    pub fn spill_dir(mut self, dir: PathBuf) -> Self {
+        self.spill_dir = Some(dir);
+        self
+    }
+
+    /// Applies the policy when the state is over the high water mark. Fails
+    /// when the policy is to abort, or when it could not bring the state back
+    /// under the mark.
+    pub fn enforce(&mut self, context: &mut TransactionContext) -> anyhow::Result<()> {
+        let high_water = (self.limit as f64 * HIGH_WATER) as usize;
+        let used = context.memory_usage().total();
+        if used <= high_water {
+            return Ok(());
+        }
+
+        match self.policy {
+            MemoryPolicy::Abort => {}
+            MemoryPolicy::EvictFinalized => {
+                let evicted = context.evict_finalized();
+                warn!(
+                    evicted,
+                    used = context.memory_usage().total(),
+                    "evicted finalized transactions to stay within the memory budget"
+                );
+            }
+            #[cfg(feature = "sled")]
+            MemoryPolicy::Spill if !self.spilled => {
+                let Some(dir) = &self.spill_dir else {
+                    anyhow::bail!("the spill policy needs a directory to spill to");
+                };
+                let store = crate::sled_store::SledStore::open(dir)?;
+                context.replace_store(Box::new(store))?;
+                self.spilled = true;
+                warn!(dir = %dir.display(), "spilled the state to disk to stay within the memory budget");
+            }
+            #[cfg(feature = "sled")]
+            MemoryPolicy::Spill => {}
+        }
+
+        let used = context.memory_usage().total();
+        if used > high_water {
+            anyhow::bail!(
+                "the state uses {} of the {} memory budget ({} transactions), raise --max-memory or pick another --memory-policy",
+                ByteSize(used),
+                ByteSize(self.limit),
+                context.transaction_count()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionEvent, TransactionType};
+
+    #[test]
+    fn test_parse_byte_size() {
+        assert_eq!("1048576".parse(), Ok(ByteSize(1 << 20)));
+        assert_eq!("512k".parse(), Ok(ByteSize(512 << 10)));
+        assert_eq!("2G".parse(), Ok(ByteSize(2 << 30)));
+        assert!("".parse::<ByteSize>().is_err());
+        assert!("1T".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_enforce_policies() {
+        let mut context = TransactionContext::new();
+        for tx in 1..=1000 {
+            let event = |ty| TransactionEvent::new(ty, 1, tx, Price(10_000));
+            context.apply(&event(TransactionType::Deposit)).unwrap();
+            if tx % 2 == 0 {
+                context.apply(&event(TransactionType::Dispute)).unwrap();
+                context.apply(&event(TransactionType::Resolve)).unwrap();
+            }
+        }
+        // the preallocated store is well over a megabyte
+        let limit = ByteSize(1 << 20);
+        let error = MemoryBudget::new(limit, MemoryPolicy::Abort)
+            .enforce(&mut context)
+            .unwrap_err();
+        assert!(error.to_string().contains("memory budget"));
+
+        MemoryBudget::new(limit, MemoryPolicy::EvictFinalized)
+            .enforce(&mut context)
+            .unwrap();
+        assert_eq!(context.transaction_count(), 500);
+        assert!(context.transaction(1).is_some());
+        assert!(context.transaction(2).is_none());
+        assert_eq!(context.account(1).unwrap().total, Price(10_000_000));
+    }
+}
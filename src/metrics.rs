@@ -0,0 +1,919 @@
+use crate::{
+    data_types::{TransactionError, TransactionType},
+    schema::ColumnSuggestion,
+    state_store::MemoryUsage,
+    udp_source::Sequence,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Upper bounds (in nanoseconds) of the per-event latency histogram buckets.
+const LATENCY_BUCKETS_NS: [u64; 14] = [
+    250,
+    500,
+    1_000,
+    2_500,
+    5_000,
+    10_000,
+    25_000,
+    50_000,
+    100_000,
+    250_000,
+    1_000_000,
+    2_500_000,
+    10_000_000,
+    100_000_000,
+];
+
+/// Quantiles of the latency histograms exported as gauges, with their label.
+const LATENCY_QUANTILES: [(&str, f64); 3] = [("0.5", 0.5), ("0.99", 0.99), ("0.999", 0.999)];
+
+static METRICS: Metrics = Metrics::new();
+
+/// Process wide metrics registry. Metrics are plain atomics so recording them on
+/// the hot path stays cheap and does not require any locking.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+#[derive(Debug)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS_NS.len()],
+    sum_ns: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_NS.len()],
+            sum_ns: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let ns = duration.as_nanos().min(u64::MAX as u128) as u64;
+        if let Some(idx) = LATENCY_BUCKETS_NS.iter().position(|bound| ns <= *bound) {
+            self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_ns.fetch_add(ns, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_ns.load(Ordering::Relaxed))
+    }
+
+    /// Estimates the `q` quantile (0 to 1), interpolating linearly within the
+    /// bucket it falls in. Observations above the largest bucket count as its
+    /// bound. `None` without observations.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = q.clamp(0.0, 1.0) * count as f64;
+        let mut cumulative = 0;
+        let mut lower = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_NS.iter().zip(&self.buckets) {
+            let observed = bucket.load(Ordering::Relaxed);
+            if observed > 0 && (cumulative + observed) as f64 >= rank {
+                let fraction = (rank - cumulative as f64).max(0.0) / observed as f64;
+                let ns = lower as f64 + (bound - lower) as f64 * fraction;
+                return Some(Duration::from_nanos(ns as u64));
+            }
+            cumulative += observed;
+            lower = *bound;
+        }
+        Some(Duration::from_nanos(lower))
+    }
+
+    /// Renders the [`LATENCY_QUANTILES`] as gauge samples.
+    fn render_quantiles(&self, out: &mut String, name: &str, labels: &str) {
+        for (quantile, q) in LATENCY_QUANTILES {
+            if let Some(latency) = self.quantile(q) {
+                let _ = writeln!(
+                    out,
+                    "{name}{{{labels},quantile=\"{quantile}\"}} {}",
+                    latency.as_secs_f64()
+                );
+            }
+        }
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS_NS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let le = *bound as f64 / 1e9;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{le}\"}} {cumulative}"
+            );
+        }
+        let count = self.count();
+        let _ = writeln!(out, "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {count}");
+        let sum = self.sum().as_secs_f64();
+        if labels.is_empty() {
+            let _ = writeln!(out, "{name}_sum {sum}");
+            let _ = writeln!(out, "{name}_count {count}");
+        } else {
+            let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+            let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+        }
+    }
+}
+
+/// Metrics of a single middleware stage of the pipeline, see
+/// [`Metrics::stage`].
+#[derive(Debug)]
+pub struct StageMetrics {
+    name: String,
+    events: AtomicU64,
+    dropped: AtomicU64,
+    /// items waiting in the queue in front of the stage
+    queued: AtomicU64,
+    latency: Histogram,
+}
+
+impl StageMetrics {
+    /// Records an event passing through the stage.
+    pub fn record(&self, dropped: bool, latency: Duration) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+        if dropped {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency.observe(latency);
+    }
+
+    pub fn set_queued(&self, items: usize) {
+        self.queued.store(items as u64, Ordering::Relaxed);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn queued(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    events: [AtomicU64; TransactionType::ALL.len()],
+    rejects: [AtomicU64; TransactionError::ALL.len()],
+    parse_failures: AtomicU64,
+    passthrough_rows: AtomicU64,
+    discarded_rows: AtomicU64,
+    source_errors: AtomicU64,
+    datagrams: AtomicU64,
+    datagrams_lost: AtomicU64,
+    datagrams_out_of_order: AtomicU64,
+    rows_read: AtomicU64,
+    bytes_read: AtomicU64,
+    ring_buffer_occupancy: AtomicU64,
+    accounts: AtomicU64,
+    transactions: AtomicU64,
+    accounts_memory: AtomicU64,
+    transactions_memory: AtomicU64,
+    throttled: AtomicBool,
+    throttled_nanos: AtomicU64,
+    paused: AtomicBool,
+    /// whether leader election is in use, see `leader`
+    leader_election: AtomicBool,
+    leader: AtomicBool,
+    followers: AtomicU64,
+    pruned_accounts: AtomicU64,
+    expired_disputes: AtomicU64,
+    slow_events: AtomicU64,
+    kafka_delivery_failures: AtomicU64,
+    /// delivered and failed
+    webhook_deliveries: [AtomicU64; 2],
+    latency: Histogram,
+    type_latency: [Histogram; TransactionType::ALL.len()],
+    stages: Mutex<Vec<Arc<StageMetrics>>>,
+    input_encodings: Mutex<BTreeMap<String, &'static str>>,
+    column_suggestions: Mutex<BTreeMap<String, Vec<ColumnSuggestion>>>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            events: [const { AtomicU64::new(0) }; TransactionType::ALL.len()],
+            rejects: [const { AtomicU64::new(0) }; TransactionError::ALL.len()],
+            parse_failures: AtomicU64::new(0),
+            passthrough_rows: AtomicU64::new(0),
+            discarded_rows: AtomicU64::new(0),
+            source_errors: AtomicU64::new(0),
+            datagrams: AtomicU64::new(0),
+            datagrams_lost: AtomicU64::new(0),
+            datagrams_out_of_order: AtomicU64::new(0),
+            rows_read: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
+            ring_buffer_occupancy: AtomicU64::new(0),
+            accounts: AtomicU64::new(0),
+            transactions: AtomicU64::new(0),
+            accounts_memory: AtomicU64::new(0),
+            transactions_memory: AtomicU64::new(0),
+            throttled: AtomicBool::new(false),
+            throttled_nanos: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            leader_election: AtomicBool::new(false),
+            leader: AtomicBool::new(false),
+            followers: AtomicU64::new(0),
+            pruned_accounts: AtomicU64::new(0),
+            expired_disputes: AtomicU64::new(0),
+            slow_events: AtomicU64::new(0),
+            kafka_delivery_failures: AtomicU64::new(0),
+            webhook_deliveries: [const { AtomicU64::new(0) }; 2],
+            latency: Histogram::new(),
+            type_latency: [const { Histogram::new() }; TransactionType::ALL.len()],
+            stages: Mutex::new(Vec::new()),
+            input_encodings: Mutex::new(BTreeMap::new()),
+            column_suggestions: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_event(&self, ty: TransactionType, latency: Duration) {
+        self.events[ty as usize].fetch_add(1, Ordering::Relaxed);
+        self.latency.observe(latency);
+        self.type_latency[ty as usize].observe(latency);
+    }
+
+    pub fn record_reject(&self, error: TransactionError) {
+        self.rejects[error as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_failure(&self) {
+        self.parse_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a row of a custom type that does not affect any account.
+    pub fn record_passthrough_row(&self) {
+        self.passthrough_rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the text encoding an input file was read with.
+    pub fn record_input_encoding(&self, path: &Path, encoding: &'static str) {
+        self.input_encodings
+            .lock()
+            .expect("input encodings poisoned")
+            .insert(path.display().to_string(), encoding);
+    }
+
+    /// Text encoding of every input file by path.
+    pub fn input_encodings(&self) -> BTreeMap<String, &'static str> {
+        self.input_encodings
+            .lock()
+            .expect("input encodings poisoned")
+            .clone()
+    }
+
+    /// Records the columns of an input file that resemble a canonical column
+    /// missing from its header.
+    pub fn record_column_suggestions(&self, path: &Path, suggestions: Vec<ColumnSuggestion>) {
+        self.column_suggestions
+            .lock()
+            .expect("column suggestions poisoned")
+            .insert(path.display().to_string(), suggestions);
+    }
+
+    /// Column suggestions of every input file by path.
+    pub fn column_suggestions(&self) -> BTreeMap<String, Vec<ColumnSuggestion>> {
+        self.column_suggestions
+            .lock()
+            .expect("column suggestions poisoned")
+            .clone()
+    }
+
+    pub fn record_discarded_row(&self) {
+        self.discarded_rows.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a source that stopped early, e.g. at an I/O error.
+    pub fn record_source_error(&self) {
+        self.source_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a UDP datagram holding an event, see [`crate::udp_source`].
+    pub fn record_datagram(&self, sequence: Sequence) {
+        self.datagrams.fetch_add(1, Ordering::Relaxed);
+        match sequence {
+            Sequence::InOrder => {}
+            Sequence::Gap(lost) => {
+                self.datagrams_lost.fetch_add(lost, Ordering::Relaxed);
+            }
+            Sequence::OutOfOrder => {
+                self.datagrams_out_of_order.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Adds to the progress of the sources, multiple sources can report
+    /// concurrently.
+    pub fn record_source_progress(&self, rows: u64, bytes: u64) {
+        self.rows_read.fetch_add(rows, Ordering::Relaxed);
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn set_ring_buffer_occupancy(&self, slots: usize) {
+        self.ring_buffer_occupancy
+            .store(slots as u64, Ordering::Relaxed);
+    }
+
+    /// Records whether the source had to wait for the rate limit the last time
+    /// it handed out an event, and for how long.
+    pub fn record_throttle(&self, waited: Duration) {
+        self.throttled.store(!waited.is_zero(), Ordering::Relaxed);
+        self.throttled_nanos
+            .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Whether ingestion is paused through the admin endpoints.
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Followers receiving the shipped events, see [`crate::replication`].
+    pub fn set_followers(&self, followers: usize) {
+        self.followers.store(followers as u64, Ordering::Relaxed);
+    }
+
+    /// Whether this instance holds the leader lock.
+    pub fn set_leader(&self, leader: bool) {
+        self.leader_election.store(true, Ordering::Relaxed);
+        self.leader.store(leader, Ordering::Relaxed);
+    }
+
+    pub fn set_tracked(&self, accounts: usize, transactions: usize, memory: MemoryUsage) {
+        self.accounts.store(accounts as u64, Ordering::Relaxed);
+        self.transactions
+            .store(transactions as u64, Ordering::Relaxed);
+        self.accounts_memory
+            .store(memory.accounts as u64, Ordering::Relaxed);
+        self.transactions_memory
+            .store(memory.transactions as u64, Ordering::Relaxed);
+    }
+
+    pub fn events(&self, ty: TransactionType) -> u64 {
+        self.events[ty as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn rejects(&self, error: TransactionError) -> u64 {
+        self.rejects[error as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn parse_failures(&self) -> u64 {
+        self.parse_failures.load(Ordering::Relaxed)
+    }
+
+    pub fn passthrough_rows(&self) -> u64 {
+        self.passthrough_rows.load(Ordering::Relaxed)
+    }
+
+    pub fn discarded_rows(&self) -> u64 {
+        self.discarded_rows.load(Ordering::Relaxed)
+    }
+
+    pub fn source_errors(&self) -> u64 {
+        self.source_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn datagrams(&self) -> u64 {
+        self.datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams missing from the sequence of their sender. Those arriving
+    /// late are counted as lost as well as out of order.
+    pub fn datagrams_lost(&self) -> u64 {
+        self.datagrams_lost.load(Ordering::Relaxed)
+    }
+
+    pub fn datagrams_out_of_order(&self) -> u64 {
+        self.datagrams_out_of_order.load(Ordering::Relaxed)
+    }
+
+    /// Rows that were read but never reached the processor, nor got rejected
+    /// by the parser, skipped as passthrough or dropped by a pipeline stage.
+    pub fn events_lost(&self) -> u64 {
+        let handled = self.events_total()
+            + self.parse_failures()
+            + self.passthrough_rows()
+            + self.dropped_total();
+        self.rows_read().saturating_sub(handled) + self.discarded_rows()
+    }
+
+    pub fn rows_read(&self) -> u64 {
+        self.rows_read.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Total of processed events over all transaction types.
+    pub fn events_total(&self) -> u64 {
+        TransactionType::ALL.iter().map(|ty| self.events(*ty)).sum()
+    }
+
+    /// Total of rejected events over all reasons.
+    pub fn rejects_total(&self) -> u64 {
+        TransactionError::ALL.iter().map(|e| self.rejects(*e)).sum()
+    }
+
+    /// Whether the source is currently held back by the rate limit.
+    pub fn record_pruned(&self, accounts: usize) {
+        self.pruned_accounts
+            .fetch_add(accounts as u64, Ordering::Relaxed);
+    }
+
+    pub fn pruned_accounts(&self) -> u64 {
+        self.pruned_accounts.load(Ordering::Relaxed)
+    }
+
+    pub fn record_expired_disputes(&self, disputes: usize) {
+        self.expired_disputes
+            .fetch_add(disputes as u64, Ordering::Relaxed);
+    }
+
+    pub fn expired_disputes(&self) -> u64 {
+        self.expired_disputes.load(Ordering::Relaxed)
+    }
+
+    /// Records an event that took longer than the deadline, see
+    /// [`crate::watchdog`].
+    pub fn record_slow_event(&self) {
+        self.slow_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_events(&self) -> u64 {
+        self.slow_events.load(Ordering::Relaxed)
+    }
+
+    /// An applied event that could not be published, see [`crate::kafka`].
+    pub fn record_kafka_delivery_failure(&self) {
+        self.kafka_delivery_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn kafka_delivery_failures(&self) -> u64 {
+        self.kafka_delivery_failures.load(Ordering::Relaxed)
+    }
+
+    /// A webhook notification that was delivered, or given up on, see
+    /// [`crate::webhooks`].
+    pub fn record_webhook_delivery(&self, delivered: bool) {
+        self.webhook_deliveries[!delivered as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn webhook_deliveries(&self, delivered: bool) -> u64 {
+        self.webhook_deliveries[!delivered as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn throttled(&self) -> bool {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn followers(&self) -> u64 {
+        self.followers.load(Ordering::Relaxed)
+    }
+
+    /// Whether this instance holds the leader lock, `None` without leader
+    /// election.
+    pub fn leader(&self) -> Option<bool> {
+        self.leader_election
+            .load(Ordering::Relaxed)
+            .then(|| self.leader.load(Ordering::Relaxed))
+    }
+
+    /// Total time the source waited for the rate limit.
+    pub fn throttled_time(&self) -> Duration {
+        Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn ring_buffer_occupancy(&self) -> u64 {
+        self.ring_buffer_occupancy.load(Ordering::Relaxed)
+    }
+
+    pub fn accounts_tracked(&self) -> u64 {
+        self.accounts.load(Ordering::Relaxed)
+    }
+
+    pub fn transactions_tracked(&self) -> u64 {
+        self.transactions.load(Ordering::Relaxed)
+    }
+
+    /// Bytes held in memory by the state store.
+    pub fn state_memory(&self) -> MemoryUsage {
+        MemoryUsage {
+            accounts: self.accounts_memory.load(Ordering::Relaxed) as usize,
+            transactions: self.transactions_memory.load(Ordering::Relaxed) as usize,
+        }
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+
+    /// Processing latency of the events of one transaction type.
+    pub fn type_latency(&self, ty: TransactionType) -> &Histogram {
+        &self.type_latency[ty as usize]
+    }
+
+    /// Returns the metrics of the named pipeline stage, registering it on
+    /// first use. Stages with the same name share their metrics.
+    pub fn stage(&self, name: &str) -> Arc<StageMetrics> {
+        let mut stages = self.stages.lock().expect("stage metrics poisoned");
+        if let Some(stage) = stages.iter().find(|stage| stage.name == name) {
+            return stage.clone();
+        }
+        let stage = Arc::new(StageMetrics {
+            name: name.to_string(),
+            events: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            queued: AtomicU64::new(0),
+            latency: Histogram::new(),
+        });
+        stages.push(stage.clone());
+        stage
+    }
+
+    /// Total of events dropped over all pipeline stages.
+    pub fn dropped_total(&self) -> u64 {
+        self.stages().iter().map(|stage| stage.dropped()).sum()
+    }
+
+    /// All registered pipeline stages, in order of registration.
+    pub fn stages(&self) -> Vec<Arc<StageMetrics>> {
+        self.stages.lock().expect("stage metrics poisoned").clone()
+    }
+
+    /// Renders all metrics in the prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP txe_events_total Events processed per transaction type.\n");
+        out.push_str("# TYPE txe_events_total counter\n");
+        for ty in TransactionType::ALL {
+            let _ = writeln!(out, "txe_events_total{{type=\"{ty}\"}} {}", self.events(ty));
+        }
+
+        out.push_str("# HELP txe_rejects_total Rejected events per reason.\n");
+        out.push_str("# TYPE txe_rejects_total counter\n");
+        for error in TransactionError::ALL {
+            let _ = writeln!(
+                out,
+                "txe_rejects_total{{reason=\"{}\"}} {}",
+                error.as_str(),
+                self.rejects(error)
+            );
+        }
+
+        out.push_str("# HELP txe_parse_failures_total Input rows that could not be parsed.\n");
+        out.push_str("# TYPE txe_parse_failures_total counter\n");
+        let _ = writeln!(out, "txe_parse_failures_total {}", self.parse_failures());
+
+        out.push_str("# HELP txe_passthrough_rows_total Rows of a passthrough type, skipped.\n");
+        out.push_str("# TYPE txe_passthrough_rows_total counter\n");
+        let _ = writeln!(
+            out,
+            "txe_passthrough_rows_total {}",
+            self.passthrough_rows()
+        );
+
+        out.push_str("# HELP txe_discarded_rows_total Incomplete rows discarded at shutdown.\n");
+        out.push_str("# TYPE txe_discarded_rows_total counter\n");
+        let _ = writeln!(out, "txe_discarded_rows_total {}", self.discarded_rows());
+
+        out.push_str("# HELP txe_source_errors_total Sources that stopped early at an error.\n");
+        out.push_str("# TYPE txe_source_errors_total counter\n");
+        let _ = writeln!(out, "txe_source_errors_total {}", self.source_errors());
+
+        for (name, help, counter) in [
+            (
+                "txe_udp_datagrams_total",
+                "UDP datagrams received holding an event.",
+                &self.datagrams,
+            ),
+            (
+                "txe_udp_datagrams_lost_total",
+                "UDP datagrams missing from the sequence of their sender.",
+                &self.datagrams_lost,
+            ),
+            (
+                "txe_udp_datagrams_out_of_order_total",
+                "UDP datagrams received after a later one of their sender.",
+                &self.datagrams_out_of_order,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {}", counter.load(Ordering::Relaxed));
+        }
+
+        out.push_str("# HELP txe_rows_read_total Input rows read by the source.\n");
+        out.push_str("# TYPE txe_rows_read_total counter\n");
+        let _ = writeln!(out, "txe_rows_read_total {}", self.rows_read());
+
+        out.push_str("# HELP txe_bytes_read_total Input bytes read by the source.\n");
+        out.push_str("# TYPE txe_bytes_read_total counter\n");
+        let _ = writeln!(out, "txe_bytes_read_total {}", self.bytes_read());
+
+        for (name, help, gauge) in [
+            (
+                "txe_ring_buffer_occupancy",
+                "Events waiting in the ring buffer.",
+                &self.ring_buffer_occupancy,
+            ),
+            (
+                "txe_accounts_tracked",
+                "Accounts held in memory.",
+                &self.accounts,
+            ),
+            (
+                "txe_transactions_tracked",
+                "Transactions held in memory.",
+                &self.transactions,
+            ),
+            (
+                "txe_replication_followers",
+                "Followers receiving the applied events.",
+                &self.followers,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {}", gauge.load(Ordering::Relaxed));
+        }
+
+        if let Some(leader) = self.leader() {
+            out.push_str("# HELP txe_leader Whether the instance holds the leader lock.\n");
+            out.push_str("# TYPE txe_leader gauge\n");
+            let _ = writeln!(out, "txe_leader {}", leader as u8);
+        }
+
+        out.push_str("# HELP txe_ingestion_paused Whether ingestion is paused by an operator.\n");
+        out.push_str("# TYPE txe_ingestion_paused gauge\n");
+        let _ = writeln!(out, "txe_ingestion_paused {}", self.paused() as u8);
+
+        out.push_str("# HELP txe_source_throttled Whether the source waits for the rate limit.\n");
+        out.push_str("# TYPE txe_source_throttled gauge\n");
+        let _ = writeln!(out, "txe_source_throttled {}", self.throttled() as u8);
+
+        out.push_str(
+            "# HELP txe_source_throttled_seconds_total Time the source waited for the rate limit.\n",
+        );
+        out.push_str("# TYPE txe_source_throttled_seconds_total counter\n");
+        let _ = writeln!(
+            out,
+            "txe_source_throttled_seconds_total {}",
+            self.throttled_time().as_secs_f64()
+        );
+
+        out.push_str("# HELP txe_accounts_pruned_total Dormant accounts that were pruned.\n");
+        out.push_str("# TYPE txe_accounts_pruned_total counter\n");
+        let _ = writeln!(out, "txe_accounts_pruned_total {}", self.pruned_accounts());
+
+        out.push_str(
+            "# HELP txe_disputes_expired_total Disputes resolved by the engine after the timeout.\n",
+        );
+        out.push_str("# TYPE txe_disputes_expired_total counter\n");
+        let _ = writeln!(
+            out,
+            "txe_disputes_expired_total {}",
+            self.expired_disputes()
+        );
+
+        out.push_str(
+            "# HELP txe_slow_events_total Events that took longer than the deadline to process.\n",
+        );
+        out.push_str("# TYPE txe_slow_events_total counter\n");
+        let _ = writeln!(out, "txe_slow_events_total {}", self.slow_events());
+
+        out.push_str(
+            "# HELP txe_kafka_delivery_failures_total Applied events that could not be published to Kafka.\n",
+        );
+        out.push_str("# TYPE txe_kafka_delivery_failures_total counter\n");
+        let _ = writeln!(
+            out,
+            "txe_kafka_delivery_failures_total {}",
+            self.kafka_delivery_failures()
+        );
+
+        out.push_str(
+            "# HELP txe_webhook_deliveries_total Webhook notifications by outcome of their delivery.\n",
+        );
+        out.push_str("# TYPE txe_webhook_deliveries_total counter\n");
+        for (outcome, delivered) in [("delivered", true), ("failed", false)] {
+            let _ = writeln!(
+                out,
+                "txe_webhook_deliveries_total{{outcome=\"{outcome}\"}} {}",
+                self.webhook_deliveries(delivered)
+            );
+        }
+
+        out.push_str("# HELP txe_state_memory_bytes Bytes held in memory by the state store.\n");
+        out.push_str("# TYPE txe_state_memory_bytes gauge\n");
+        for (kind, gauge) in [
+            ("accounts", &self.accounts_memory),
+            ("transactions", &self.transactions_memory),
+        ] {
+            let _ = writeln!(
+                out,
+                "txe_state_memory_bytes{{kind=\"{kind}\"}} {}",
+                gauge.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP txe_event_latency_seconds Processing latency per event.\n");
+        out.push_str("# TYPE txe_event_latency_seconds histogram\n");
+        self.latency
+            .render(&mut out, "txe_event_latency_seconds", "");
+
+        out.push_str(
+            "# HELP txe_type_latency_seconds Processing latency per event of a transaction type.\n",
+        );
+        out.push_str("# TYPE txe_type_latency_seconds histogram\n");
+        let types = TransactionType::ALL
+            .into_iter()
+            .filter(|ty| self.type_latency(*ty).count() > 0);
+        for ty in types.clone() {
+            let labels = format!("type=\"{ty}\"");
+            self.type_latency(ty)
+                .render(&mut out, "txe_type_latency_seconds", &labels);
+        }
+
+        out.push_str(
+            "# HELP txe_type_latency_quantile_seconds Estimated processing latency quantiles of a transaction type.\n",
+        );
+        out.push_str("# TYPE txe_type_latency_quantile_seconds gauge\n");
+        for ty in types {
+            let labels = format!("type=\"{ty}\"");
+            self.type_latency(ty).render_quantiles(
+                &mut out,
+                "txe_type_latency_quantile_seconds",
+                &labels,
+            );
+        }
+
+        let stages = self.stages();
+        if !stages.is_empty() {
+            out.push_str("# HELP txe_stage_events_total Events passed into a pipeline stage.\n");
+            out.push_str("# TYPE txe_stage_events_total counter\n");
+            for stage in &stages {
+                let name = escape_label(stage.name());
+                let _ = writeln!(
+                    out,
+                    "txe_stage_events_total{{stage=\"{name}\"}} {}",
+                    stage.events()
+                );
+            }
+
+            out.push_str("# HELP txe_stage_dropped_total Events dropped by a pipeline stage.\n");
+            out.push_str("# TYPE txe_stage_dropped_total counter\n");
+            for stage in &stages {
+                let name = escape_label(stage.name());
+                let _ = writeln!(
+                    out,
+                    "txe_stage_dropped_total{{stage=\"{name}\"}} {}",
+                    stage.dropped()
+                );
+            }
+
+            out.push_str(
+                "# HELP txe_stage_queued Items waiting in the queue in front of a pipeline stage.\n",
+            );
+            out.push_str("# TYPE txe_stage_queued gauge\n");
+            for stage in &stages {
+                let name = escape_label(stage.name());
+                let _ = writeln!(
+                    out,
+                    "txe_stage_queued{{stage=\"{name}\"}} {}",
+                    stage.queued()
+                );
+            }
+
+            out.push_str(
+                "# HELP txe_stage_latency_seconds Latency per event of a pipeline stage.\n",
+            );
+            out.push_str("# TYPE txe_stage_latency_seconds histogram\n");
+            for stage in &stages {
+                let labels = format!("stage=\"{}\"", escape_label(stage.name()));
+                stage
+                    .latency
+                    .render(&mut out, "txe_stage_latency_seconds", &labels);
+            }
+
+            out.push_str(
+                "# HELP txe_stage_latency_quantile_seconds Estimated latency quantiles of a pipeline stage.\n",
+            );
+            out.push_str("# TYPE txe_stage_latency_quantile_seconds gauge\n");
+            for stage in &stages {
+                let labels = format!("stage=\"{}\"", escape_label(stage.name()));
+                stage.latency.render_quantiles(
+                    &mut out,
+                    "txe_stage_latency_quantile_seconds",
+                    &labels,
+                );
+            }
+        }
+
+        out
+    }
+}
+
+/// Escapes a prometheus label value.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = Metrics::new();
+        metrics.record_event(TransactionType::Deposit, Duration::from_nanos(700));
+        metrics.record_event(TransactionType::Deposit, Duration::from_micros(30));
+        metrics.record_reject(TransactionError::InsufficientFunds);
+        metrics.record_parse_failure();
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("txe_events_total{type=\"deposit\"} 2\n"));
+        assert!(text.contains("txe_events_total{type=\"withdrawal\"} 0\n"));
+        assert!(text.contains("txe_rejects_total{reason=\"insufficient_funds\"} 1\n"));
+        assert!(text.contains("txe_parse_failures_total 1\n"));
+        assert!(text.contains("txe_event_latency_seconds_bucket{le=\"0.0000005\"} 0\n"));
+        assert!(text.contains("txe_event_latency_seconds_bucket{le=\"0.000001\"} 1\n"));
+        assert!(text.contains("txe_event_latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(text.contains("txe_event_latency_seconds_count 2\n"));
+        assert!(text.contains("txe_type_latency_seconds_count{type=\"deposit\"} 2\n"));
+        assert!(!text.contains("txe_type_latency_seconds_count{type=\"withdrawal\"}"));
+        assert!(text.contains(
+            "txe_type_latency_quantile_seconds{type=\"deposit\",quantile=\"0.5\"} 0.000001\n"
+        ));
+        assert!(!text.contains("txe_stage_events_total"));
+
+        let stage = metrics.stage("normalize \"amounts\"");
+        stage.record(false, Duration::from_nanos(100));
+        stage.record(true, Duration::from_nanos(100));
+        stage.set_queued(3);
+        assert!(Arc::ptr_eq(&stage, &metrics.stage("normalize \"amounts\"")));
+
+        let text = metrics.render_prometheus();
+        assert!(text.contains("txe_stage_events_total{stage=\"normalize \\\"amounts\\\"\"} 2\n"));
+        assert!(text.contains("txe_stage_dropped_total{stage=\"normalize \\\"amounts\\\"\"} 1\n"));
+        assert!(text.contains("txe_stage_queued{stage=\"normalize \\\"amounts\\\"\"} 3\n"));
+        assert!(text
+            .contains("txe_stage_latency_seconds_count{stage=\"normalize \\\"amounts\\\"\"} 2\n"));
+    }
+
+    #[test]
+    fn test_histogram_quantile() {
+        let histogram = Histogram::new();
+        assert_eq!(histogram.quantile(0.5), None);
+        for _ in 0..98 {
+            histogram.observe(Duration::from_nanos(200));
+        }
+        histogram.observe(Duration::from_micros(20));
+        histogram.observe(Duration::from_secs(1));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_nanos(127)));
+        assert_eq!(histogram.quantile(0.99), Some(Duration::from_micros(25)));
+        // beyond the largest bucket
+        assert_eq!(histogram.quantile(0.999), Some(Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,196 @@
+//! Report of the accounts that end the run with a negative available or total
+//! balance, e.g. after a chargeback of funds that were already withdrawn, for
+//! collections. Repayments arrive as `recovery` events, see
+//! [`Account::recover`], and are reported apart from the deposits.
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent, TransactionType},
+    pipeline::Sink,
+    pseudonym,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use csv::Writer;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NegativeBalance {
+    pub client_id: u16,
+    pub account: Account,
+    /// sum of the applied recoveries
+    pub recovered: Price,
+    pub recoveries: u32,
+}
+
+/// [`Sink`] summing the recoveries per client, and writing the accounts with a
+/// negative balance as csv once all events are processed, ordered by client.
+#[derive(Debug)]
+pub struct NegativeBalancesReport {
+    path: PathBuf,
+    recovered: HashMap<u16, (Price, u32)>,
+}
+
+impl NegativeBalancesReport {
+    pub fn new(path: &Path) -> Self {
+        NegativeBalancesReport {
+            path: path.to_path_buf(),
+            recovered: HashMap::new(),
+        }
+    }
+
+    /// The accounts of the context with a negative balance.
+    pub fn negative_balances(&self, context: &TransactionContext) -> Vec<NegativeBalance> {
+        let mut negative: Vec<_> = context
+            .iter_accounts()
+            .filter(|(_, account)| account.available().0 < 0 || account.total.0 < 0)
+            .map(|(client_id, account)| {
+                let (recovered, recoveries) =
+                    self.recovered.get(&client_id).copied().unwrap_or_default();
+                NegativeBalance {
+                    client_id,
+                    account: *account,
+                    recovered,
+                    recoveries,
+                }
+            })
+            .collect();
+        negative.sort_unstable_by_key(|negative| negative.client_id);
+        negative
+    }
+}
+
+impl Sink for NegativeBalancesReport {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+        if result.is_err() {
+            return;
+        }
+        match event.ty {
+            TransactionType::Recovery => {
+                let (recovered, recoveries) = self.recovered.entry(event.client_id).or_default();
+                recovered.try_add(event.amount);
+                *recoveries += 1;
+            }
+            TransactionType::Merge => {
+                let merged = event.merged_client.and_then(|m| self.recovered.remove(&m));
+                if let Some((amount, count)) = merged {
+                    let (recovered, recoveries) =
+                        self.recovered.entry(event.client_id).or_default();
+                    recovered.try_add(amount);
+                    *recoveries += count;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.recovered.remove(&pruned.client_id);
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        let file = std::fs::File::create(&self.path)?;
+        write_negative_balances(file, &self.negative_balances(context))
+    }
+}
+
+/// Writes the accounts with a negative balance as csv.
+pub fn write_negative_balances(
+    writer: impl Write,
+    negative: &[NegativeBalance],
+) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record([
+        "client",
+        "available",
+        "held",
+        "total",
+        "shortfall",
+        "locked",
+        "recovered",
+        "recoveries",
+    ])?;
+    for negative in negative {
+        let account = &negative.account;
+        writer.write_record(&[
+            pseudonym::client(negative.client_id).to_string(),
+            account.available().to_string(),
+            account.held.to_string(),
+            account.total.to_string(),
+            account.shortfall.to_string(),
+            (account.locked || account.withdrawals_locked).to_string(),
+            negative.recovered.to_string(),
+            negative.recoveries.to_string(),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negative_balances() {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        let mut context = TransactionContext::new();
+        let mut report = NegativeBalancesReport::new(Path::new("unused.csv"));
+        for (event, result) in [
+            (event(TransactionType::Deposit, 1, 1, 50_000), Ok(())),
+            (event(TransactionType::Withdrawal, 1, 2, 40_000), Ok(())),
+            (event(TransactionType::Dispute, 1, 1, 0), Ok(())),
+            (event(TransactionType::Chargeback, 1, 1, 0), Ok(())),
+            // the account is locked, the repayment still applies
+            (event(TransactionType::Recovery, 1, 3, 10_000), Ok(())),
+            // a replayed recovery is applied once
+            (
+                event(TransactionType::Recovery, 1, 3, 10_000),
+                Err(TransactionError::Duplicate),
+            ),
+            (
+                event(TransactionType::Dispute, 1, 3, 0),
+                Err(TransactionError::InvalidDispute),
+            ),
+            (
+                event(TransactionType::Recovery, 1, 4, 40_000),
+                Err(TransactionError::InvalidRecovery),
+            ),
+            (
+                event(TransactionType::Recovery, 1, 1, 1_000),
+                Err(TransactionError::Duplicate),
+            ),
+            (event(TransactionType::Deposit, 2, 5, 10_000), Ok(())),
+            (
+                event(TransactionType::Recovery, 2, 6, 1_000),
+                Err(TransactionError::InvalidRecovery),
+            ),
+            (event(TransactionType::Deposit, 3, 7, 10_000), Ok(())),
+            (event(TransactionType::Withdrawal, 3, 8, 10_000), Ok(())),
+            (event(TransactionType::Dispute, 3, 7, 0), Ok(())),
+            (event(TransactionType::Chargeback, 3, 7, 0), Ok(())),
+            (event(TransactionType::Recovery, 3, 9, 10_000), Ok(())),
+        ] {
+            let applied = context.apply(&event).map(|_| ());
+            assert_eq!(applied, result, "tx {}", event.tx);
+            report.record(&event, applied, context.account(event.client_id));
+        }
+
+        let negative = report.negative_balances(&context);
+        assert_eq!(negative.len(), 1);
+        assert_eq!(negative[0].client_id, 1);
+        assert_eq!(negative[0].account.total, Price(-30_000));
+        assert_eq!(
+            (negative[0].recovered, negative[0].recoveries),
+            (Price(10_000), 1)
+        );
+        // recoveries are not deposits
+        assert_eq!(context.account(1).unwrap().meta.tx_count, 2);
+    }
+}
@@ -0,0 +1,224 @@
+//! Report of the disputes still open at the end of a run, so operations can
+//! chase them without going through the ledger export. Disputes the engine
+//! resolved after the timeout are listed too, flagged as expired, see
+//! [`crate::dispute_expiry`].
+use crate::{
+    data_types::{
+        Account, Price, TransactionError, TransactionEvent, TransactionFlags, TransactionType,
+    },
+    dispute_expiry::DisputeExpiry,
+    pipeline::Sink,
+    pseudonym,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use csv::Writer;
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenDispute {
+    pub client_id: u16,
+    pub tx: u32,
+    pub amount: Price,
+    /// timestamp of the dispute, when the event had one
+    pub disputed_at: Option<u64>,
+    /// seconds between the dispute and the latest event of the run, or its
+    /// expiry
+    pub age: Option<u64>,
+    /// resolved by the engine after the dispute timeout
+    pub expired: bool,
+}
+
+/// [`Sink`] remembering when the disputes were opened, and writing the
+/// disputes that are still open as csv once all events are processed, ordered
+/// by client and transaction.
+///
+/// Time is taken from the timestamps of the events. Disputes opened before a
+/// restored snapshot, or by events without a timestamp, have no age.
+#[derive(Debug)]
+pub struct OpenDisputesReport {
+    path: PathBuf,
+    opened: HashMap<u32, u64>,
+    expired: Vec<OpenDispute>,
+    /// latest timestamp seen on any event
+    now: Option<u64>,
+}
+
+impl OpenDisputesReport {
+    pub fn new(path: &Path) -> Self {
+        OpenDisputesReport {
+            path: path.to_path_buf(),
+            opened: HashMap::new(),
+            expired: Vec::new(),
+            now: None,
+        }
+    }
+
+    /// The transactions of the context that are disputed, and the expired
+    /// disputes.
+    pub fn open_disputes(&self, context: &TransactionContext) -> Vec<OpenDispute> {
+        let mut disputes: Vec<_> = context
+            .iter_transactions()
+            .filter(|(_, (_, flags, _))| *flags == TransactionFlags::Disputed)
+            .map(|(tx, (amount, _, client_id))| {
+                let disputed_at = self.opened.get(&tx).copied();
+                OpenDispute {
+                    client_id,
+                    tx,
+                    amount,
+                    disputed_at,
+                    age: disputed_at
+                        .zip(self.now)
+                        .map(|(at, now)| now.saturating_sub(at)),
+                    expired: false,
+                }
+            })
+            .chain(self.expired.iter().map(|dispute| {
+                OpenDispute {
+                    amount: context
+                        .transaction(dispute.tx)
+                        .map_or(dispute.amount, |(amount, _, _)| amount),
+                    ..*dispute
+                }
+            }))
+            .collect();
+        disputes.sort_unstable_by_key(|dispute| (dispute.client_id, dispute.tx));
+        disputes
+    }
+}
+
+impl Sink for OpenDisputesReport {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+        if let Some(timestamp) = event.timestamp {
+            self.now = Some(self.now.map_or(timestamp, |now| now.max(timestamp)));
+        }
+        if result.is_err() {
+            return;
+        }
+        match event.ty {
+            TransactionType::Dispute => {
+                if let Some(timestamp) = event.timestamp {
+                    self.opened.insert(event.tx, timestamp);
+                }
+            }
+            TransactionType::Resolve if DisputeExpiry::is_expiry(event) => {
+                let disputed_at = self.opened.remove(&event.tx);
+                self.expired.push(OpenDispute {
+                    client_id: event.client_id,
+                    tx: event.tx,
+                    // taken from the context at the end, see open_disputes
+                    amount: Price::default(),
+                    disputed_at,
+                    age: disputed_at
+                        .zip(event.timestamp)
+                        .map(|(at, now)| now.saturating_sub(at)),
+                    expired: true,
+                });
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                self.opened.remove(&event.tx);
+            }
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Unlock
+            | TransactionType::Adjustment
+            | TransactionType::Merge
+            | TransactionType::Recovery => {}
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        for (tx, _) in &pruned.transactions {
+            self.opened.remove(tx);
+        }
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        let file = std::fs::File::create(&self.path)?;
+        write_open_disputes(file, &self.open_disputes(context))
+    }
+}
+
+/// Writes the disputes as csv.
+pub fn write_open_disputes(writer: impl Write, disputes: &[OpenDispute]) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(["client", "tx", "amount", "disputed_at", "age", "status"])?;
+    let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for dispute in disputes {
+        writer.write_record(&[
+            pseudonym::client(dispute.client_id).to_string(),
+            dispute.tx.to_string(),
+            dispute.amount.to_string(),
+            optional(dispute.disputed_at),
+            optional(dispute.age),
+            if dispute.expired { "expired" } else { "open" }.to_string(),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_disputes() {
+        let event = |ty, client_id, tx, amount, timestamp| TransactionEvent {
+            timestamp,
+            ..TransactionEvent::new(ty, client_id, tx, Price(amount))
+        };
+        let mut context = TransactionContext::new();
+        let mut report = OpenDisputesReport::new(Path::new("unused.csv"));
+        for event in [
+            event(TransactionType::Deposit, 2, 1, 10_000, Some(0)),
+            event(TransactionType::Deposit, 1, 2, 20_000, Some(10)),
+            event(TransactionType::Deposit, 1, 3, 30_000, None),
+            event(TransactionType::Deposit, 1, 4, 40_000, Some(20)),
+            event(TransactionType::Dispute, 2, 1, 0, Some(100)),
+            event(TransactionType::Dispute, 1, 2, 0, Some(200)),
+            event(TransactionType::Dispute, 1, 3, 0, None),
+            event(TransactionType::Dispute, 1, 4, 0, Some(300)),
+            event(TransactionType::Resolve, 1, 4, 0, Some(400)),
+            // rejected, the dispute stays open
+            event(TransactionType::Resolve, 2, 2, 0, Some(1000)),
+            event(TransactionType::Deposit, 3, 5, 50_000, Some(0)),
+            event(TransactionType::Dispute, 3, 5, 0, Some(50)),
+            TransactionEvent {
+                reason: crate::dispute_expiry::EXPIRED.parse().unwrap(),
+                ..event(TransactionType::Resolve, 3, 5, 0, Some(1000))
+            },
+        ] {
+            let result = context.apply(&event);
+            report.record(&event, result.map(|_| ()), result.ok().as_ref());
+        }
+
+        let open = |client_id, tx, amount, disputed_at, age| OpenDispute {
+            client_id,
+            tx,
+            amount: Price(amount),
+            disputed_at,
+            age,
+            expired: false,
+        };
+        assert_eq!(
+            report.open_disputes(&context),
+            vec![
+                open(1, 2, 20_000, Some(200), Some(800)),
+                open(1, 3, 30_000, None, None),
+                open(2, 1, 10_000, Some(100), Some(900)),
+                OpenDispute {
+                    expired: true,
+                    ..open(3, 5, 50_000, Some(50), Some(950))
+                },
+            ]
+        );
+    }
+}
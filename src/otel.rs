@@ -0,0 +1,228 @@
+//! Export of tracing spans and metrics to an OTLP (http/protobuf) endpoint.
+use crate::{
+    data_types::{TransactionError, TransactionType},
+    metrics::metrics,
+};
+use opentelemetry::{metrics::MeterProvider, trace::TracerProvider, KeyValue};
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    metrics::{PeriodicReader, SdkMeterProvider},
+    trace::{SdkTracer, SdkTracerProvider},
+    Resource,
+};
+use tracing::Subscriber;
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Flushes and shuts down the exporters when dropped.
+pub struct OtelGuard {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            eprintln!("failed to shutdown OTLP trace exporter: {e}");
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            eprintln!("failed to shutdown OTLP metric exporter: {e}");
+        }
+    }
+}
+
+/// Sets up the OTLP exporters for the given endpoint (e.g.
+/// `http://localhost:4318`). Returns a tracing layer exporting the spans, and
+/// a guard that needs to be kept alive for the duration of the run.
+pub fn init<S>(endpoint: &str) -> anyhow::Result<(OpenTelemetryLayer<S, SdkTracer>, OtelGuard)>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder()
+        .with_service_name(env!("CARGO_PKG_NAME"))
+        .build();
+
+    let span_exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/traces"))
+        .build()?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_resource(resource.clone())
+        .build();
+
+    let metric_exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/metrics"))
+        .build()?;
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(metric_exporter).build())
+        .with_resource(resource)
+        .build();
+    register_metrics(&meter_provider);
+
+    let layer =
+        tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(env!("CARGO_PKG_NAME")));
+    let guard = OtelGuard {
+        tracer_provider,
+        meter_provider,
+    };
+    Ok((layer, guard))
+}
+
+/// Mirrors the process wide [`metrics`] as observable instruments, which are
+/// read out every time the exporter collects.
+fn register_metrics(provider: &SdkMeterProvider) {
+    let meter = provider.meter(env!("CARGO_PKG_NAME"));
+
+    meter
+        .u64_observable_counter("txe_events_total")
+        .with_description("Events processed per transaction type.")
+        .with_callback(|observer| {
+            for ty in TransactionType::ALL {
+                observer.observe(metrics().events(ty), &[KeyValue::new("type", ty.as_str())]);
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("txe_rejects_total")
+        .with_description("Rejected events per reason.")
+        .with_callback(|observer| {
+            for error in TransactionError::ALL {
+                observer.observe(
+                    metrics().rejects(error),
+                    &[KeyValue::new("reason", error.as_str())],
+                );
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("txe_parse_failures_total")
+        .with_description("Input rows that could not be parsed.")
+        .with_callback(|observer| observer.observe(metrics().parse_failures(), &[]))
+        .build();
+
+    meter
+        .u64_observable_counter("txe_passthrough_rows_total")
+        .with_description("Rows of a passthrough type, skipped.")
+        .with_callback(|observer| observer.observe(metrics().passthrough_rows(), &[]))
+        .build();
+
+    meter
+        .u64_observable_gauge("txe_ring_buffer_occupancy")
+        .with_description("Events waiting in the ring buffer.")
+        .with_callback(|observer| observer.observe(metrics().ring_buffer_occupancy(), &[]))
+        .build();
+
+    meter
+        .u64_observable_gauge("txe_accounts_tracked")
+        .with_description("Accounts held in memory.")
+        .with_callback(|observer| observer.observe(metrics().accounts_tracked(), &[]))
+        .build();
+
+    meter
+        .u64_observable_gauge("txe_transactions_tracked")
+        .with_description("Transactions held in memory.")
+        .with_callback(|observer| observer.observe(metrics().transactions_tracked(), &[]))
+        .build();
+
+    meter
+        .u64_observable_counter("txe_event_latency_count")
+        .with_description("Number of latency samples.")
+        .with_callback(|observer| observer.observe(metrics().latency().count(), &[]))
+        .build();
+
+    meter
+        .f64_observable_counter("txe_event_latency_seconds_sum")
+        .with_description("Accumulated processing latency of all events.")
+        .with_unit("s")
+        .with_callback(|observer| observer.observe(metrics().latency().sum().as_secs_f64(), &[]))
+        .build();
+
+    meter
+        .f64_observable_gauge("txe_type_latency_quantile_seconds")
+        .with_description("Estimated processing latency quantiles of a transaction type.")
+        .with_unit("s")
+        .with_callback(|observer| {
+            for ty in TransactionType::ALL {
+                for q in [0.5, 0.99, 0.999] {
+                    if let Some(latency) = metrics().type_latency(ty).quantile(q) {
+                        observer.observe(
+                            latency.as_secs_f64(),
+                            &[
+                                KeyValue::new("type", ty.as_str()),
+                                KeyValue::new("quantile", q),
+                            ],
+                        );
+                    }
+                }
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("txe_stage_events_total")
+        .with_description("Events passed into a pipeline stage.")
+        .with_callback(|observer| {
+            for stage in metrics().stages() {
+                observer.observe(
+                    stage.events(),
+                    &[KeyValue::new("stage", stage.name().to_string())],
+                );
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_counter("txe_stage_dropped_total")
+        .with_description("Events dropped by a pipeline stage.")
+        .with_callback(|observer| {
+            for stage in metrics().stages() {
+                observer.observe(
+                    stage.dropped(),
+                    &[KeyValue::new("stage", stage.name().to_string())],
+                );
+            }
+        })
+        .build();
+
+    meter
+        .u64_observable_gauge("txe_stage_queued")
+        .with_description("Items waiting in the queue in front of a pipeline stage.")
+        .with_callback(|observer| {
+            for stage in metrics().stages() {
+                observer.observe(
+                    stage.queued(),
+                    &[KeyValue::new("stage", stage.name().to_string())],
+                );
+            }
+        })
+        .build();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_export_on_shutdown() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let (_layer, guard) =
+            init::<tracing_subscriber::Registry>(&format!("http://{addr}/")).unwrap();
+
+        // dropping the guard flushes the metrics to the endpoint
+        let thread = std::thread::spawn(move || drop(guard));
+        let request = server
+            .recv_timeout(Duration::from_secs(10))
+            .unwrap()
+            .expect("metrics are exported on shutdown");
+        assert_eq!(request.url(), "/v1/metrics");
+        request.respond(tiny_http::Response::empty(200)).unwrap();
+        thread.join().unwrap();
+    }
+}
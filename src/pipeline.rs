@@ -0,0 +1,1621 @@
+//! Composes sources, transforms, the processor and sinks without having to
+//! deal with the threads and ring buffers in between.
+//!
+//! # Stages
+//!
+//! Events pass through the stages read → parse → validate → apply → emit,
+//! with a bounded queue in front of every stage but the first and the last:
+//!
+//! * read: one thread reading the sources one after the other.
+//! * parse: parses the rows of the sources added with
+//!   [`PipelineBuilder::csv_source`], on [`PipelineBuilder::parse_threads`]
+//!   threads. Other sources hand out events that are parsed already.
+//! * validate: one thread applying the transforms, the [`Validator`]s, the
+//!   rate limit and the periodic flushes.
+//! * apply: the processor, on the thread calling [`Pipeline::run`].
+//! * emit: the sinks, on the processor thread as they see the state right
+//!   after every event.
+//!
+//! The stages are a layer on top of the processor. To apply events without
+//! threads or queues, see [`TransactionProcessor::from_iter`].
+//!
+//! The events passing through, the latency per event and the items waiting
+//! in the queue of every stage are recorded in the [`metrics`] under the name
+//! of the stage.
+//!
+//! Sources hand out [`Message`]s, besides events they can signal a batch
+//! boundary with [`Message::Flush`], e.g. when a followed file reached its end
+//! for now. The processor then flushes the sinks so intermediate outputs are up
+//! to date. Once all sources are exhausted the processor receives
+//! [`Message::EndOfStream`].
+//!
+//! # Ordering
+//!
+//! Events are applied in the order they arrive: sources are read one after
+//! the other, the parse threads take the rows in turn and hand them on in the
+//! same turn, every transform and queue keeps the order, and a single
+//! processor applies the events. In particular the events of a
+//! client are applied in the order its source handed them out, embedders can
+//! rely on this. Sinks see the events in the same order.
+//!
+//! ```no_run
+//! use std::path::Path;
+//! use toy_transaction_engine::{
+//!     csv_source::{AccountsCsvSink, CsvSource},
+//!     data_types::TransactionEvent,
+//!     pipeline::Pipeline,
+//!     transaction_context::TransactionContext,
+//! };
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let mut context = TransactionContext::new();
+//! let mut rejects = Vec::new();
+//! Pipeline::builder()
+//!     .csv_source(CsvSource::open(Path::new("transactions.csv"), None)?)
+//!     .transform("drop client 0", |event: TransactionEvent| {
+//!         (event.client_id != 0).then_some(event)
+//!     })
+//!     .parse_threads(2)
+//!     .processor(&mut context)
+//!     .sink(&mut rejects)
+//!     .sink(AccountsCsvSink::new(std::io::stdout(), false))
+//!     .build()?
+//!     .run()?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{
+    audit_log::AuditLog,
+    control::Control,
+    csv_source::{CsvSource, RecordParser, Row},
+    data_types::{Account, Rejected, TransactionError, TransactionEvent},
+    dispute_expiry::DisputeExpiry,
+    memory_budget::MemoryBudget,
+    metrics::{metrics, StageMetrics},
+    pruning::AccountPruner,
+    replication::Replication,
+    settings::LiveSettings,
+    shutdown::Shutdown,
+    state_view::StateView,
+    transaction_context::{PrunedAccount, TransactionContext},
+    transaction_processor::TransactionProcessor,
+    watchdog::Watchdog,
+};
+use csv::StringRecord;
+use rtrb::{Consumer, Producer, PushError, RingBuffer};
+// spinning threads have to yield to loom, see the `loom` tests
+#[cfg(loom)]
+use loom::thread::yield_now;
+#[cfg(not(loom))]
+use std::thread::yield_now;
+use std::{
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info_span, warn};
+
+/// Number of events that can be queued between the sources and the processor.
+const DEFAULT_CAPACITY: usize = 1024 * 1024;
+
+/// Number of items that can be queued in front of every parse thread and
+/// between every parse thread and the validate stage.
+const STAGE_CAPACITY: usize = 64 * 1024;
+
+/// What is passed from the sources to the processor.
+#[derive(Debug, Clone, Copy)]
+pub enum Message {
+    Event(TransactionEvent),
+    /// an event rejected before it reached the processor, e.g. by the
+    /// [`Validator`]s. The sinks see it like any other rejected event.
+    Rejected(Rejected),
+    /// batch boundary, the sinks are flushed
+    Flush,
+    /// no more messages follow, the processor stops
+    EndOfStream,
+}
+
+impl Message {
+    pub fn into_event(self) -> Option<TransactionEvent> {
+        match self {
+            Message::Event(event) => Some(event),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransactionEvent> for Message {
+    fn from(event: TransactionEvent) -> Self {
+        Message::Event(event)
+    }
+}
+
+/// How often the pipeline inserts a [`Message::Flush`] by itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushInterval {
+    Events(u64),
+    /// checked whenever an event passes, the sources signal a pause by
+    /// themselves
+    Time(Duration),
+}
+
+impl FlushInterval {
+    fn is_due(&self, events: u64, since: Instant) -> bool {
+        match self {
+            FlushInterval::Events(every) => events >= *every,
+            FlushInterval::Time(every) => since.elapsed() >= *every,
+        }
+    }
+}
+
+/// Parses a number of events (`10000`) or a duration in seconds, minutes or
+/// hours (`60s`, `5m`, `1h`).
+impl FromStr for FlushInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("expected a number of events or a duration, got `{s}`"))?;
+        if number == 0 {
+            return Err("interval must be greater than zero".to_string());
+        }
+        let seconds = match unit {
+            "" => return Ok(FlushInterval::Events(number)),
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            _ => return Err(format!("unknown unit `{unit}`, expected s, m or h")),
+        };
+        Ok(FlushInterval::Time(Duration::from_secs(number * seconds)))
+    }
+}
+
+/// Cores the pipeline threads are pinned to, parsed from `<source>,<processor>`,
+/// e.g. `2,3`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CorePins {
+    pub source: usize,
+    pub processor: usize,
+}
+
+impl CorePins {
+    /// Fails when one of the cores does not exist (or is not available to the
+    /// process).
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let available = core_affinity::get_core_ids().unwrap_or_default();
+        for core in [self.source, self.processor] {
+            if !available.iter().any(|id| id.id == core) {
+                anyhow::bail!(
+                    "core {core} is not available, available are {:?}",
+                    available.iter().map(|id| id.id).collect::<Vec<_>>()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for CorePins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let core = |core: &str| {
+            core.trim()
+                .parse()
+                .map_err(|_| format!("expected a core number, got `{core}`"))
+        };
+        match s.split_once(',') {
+            Some((source, processor)) => Ok(CorePins {
+                source: core(source)?,
+                processor: core(processor)?,
+            }),
+            None => Err(format!("expected `<source>,<processor>`, got `{s}`")),
+        }
+    }
+}
+
+/// Token bucket limiting the events per second a source hands out. Up to one
+/// second worth of events passes without waiting, e.g. after a pause.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(events_per_second: u64) -> Self {
+        RateLimiter {
+            rate: events_per_second as f64,
+            tokens: events_per_second as f64,
+            last: Instant::now(),
+        }
+    }
+
+    fn set_rate(&mut self, events_per_second: u64) {
+        self.rate = events_per_second as f64;
+        self.tokens = self.tokens.min(self.rate);
+    }
+
+    /// Waits until an event may pass.
+    fn acquire(&mut self) {
+        let now = Instant::now();
+        self.tokens = (self.tokens + (now - self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+
+        let mut waited = Duration::ZERO;
+        if self.tokens < 1.0 {
+            waited = Duration::from_secs_f64((1.0 - self.tokens) / self.rate);
+            std::thread::sleep(waited);
+            self.tokens = 1.0;
+            self.last = Instant::now();
+        }
+        self.tokens -= 1.0;
+        metrics().record_throttle(waited);
+    }
+}
+
+/// Pins the current thread, a failure only costs performance so it is logged.
+fn pin_current_thread(core: usize) {
+    if core_affinity::set_for_current(core_affinity::CoreId { id: core }) {
+        debug!(core, "pinned thread");
+    } else {
+        warn!(core, "failed to pin thread");
+    }
+}
+
+/// Middleware that modifies or drops events before they are queued for
+/// processing, e.g. for validation, amount normalization, client id remapping
+/// or filtering. Runs on the validate stage thread, so blocking in it stalls
+/// the processor.
+pub trait Transform: Send {
+    /// Returns the event to process, or `None` to drop it.
+    fn apply(&mut self, event: TransactionEvent) -> Option<TransactionEvent>;
+}
+
+impl<F> Transform for F
+where
+    F: FnMut(TransactionEvent) -> Option<TransactionEvent> + Send,
+{
+    fn apply(&mut self, event: TransactionEvent) -> Option<TransactionEvent> {
+        self(event)
+    }
+}
+
+/// Checks the events after the transforms, e.g. the [`Rules`]. Events that
+/// fail a check are not applied, they reach the sinks as rejected with
+/// [`TransactionError::RuleViolation`]. Runs on the validate stage thread
+/// after the transforms.
+///
+/// [`Rules`]: crate::rules::Rules
+pub trait Validator: Send {
+    /// Returns why the event is rejected, if it is.
+    fn validate(&mut self, event: &TransactionEvent) -> Result<(), String>;
+}
+
+/// Receives the outcome of every processed event, and the final state once
+/// the sources are exhausted. Runs on the processor thread.
+pub trait Sink {
+    /// Called for every processed event with the account of the client after
+    /// processing.
+    fn record(
+        &mut self,
+        _event: &TransactionEvent,
+        _result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+    }
+
+    /// Called for every account removed by pruning.
+    fn prune(&mut self, _pruned: &PrunedAccount) {}
+
+    /// Called at every batch boundary signalled by a source, with the state
+    /// after all events so far.
+    fn flush(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called once after all events are processed.
+    fn finish(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Called when an operator asks to rotate the files written by the sink,
+    /// e.g. the audit log.
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        (**self).record(event, result, account)
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        (**self).prune(pruned)
+    }
+
+    fn flush(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        (**self).flush(context)
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        (**self).finish(context)
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        (**self).rotate()
+    }
+}
+
+/// Collects the rejected events.
+impl Sink for Vec<Rejected> {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+        if let Err(error) = result {
+            self.push(Rejected {
+                event: *event,
+                error,
+            });
+        }
+    }
+}
+
+impl Sink for AuditLog {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        AuditLog::record(self, event, result, account)
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.record_prune(pruned)
+    }
+
+    fn flush(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        Ok(AuditLog::flush(self)?)
+    }
+
+    fn finish(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        self.anchor();
+        Ok(self.flush()?)
+    }
+
+    fn rotate(&mut self) -> anyhow::Result<()> {
+        Ok(AuditLog::rotate(self)?)
+    }
+}
+
+/// The sending end of the queue in front of a stage. Implemented by the ring
+/// buffers, and by a queue loom can model in the `loom` tests.
+pub(crate) trait QueueProducer<T> {
+    /// Hands the item back when the queue is full.
+    fn push(&mut self, item: T) -> Result<(), T>;
+
+    /// Whether the consumer is gone.
+    fn is_abandoned(&self) -> bool;
+}
+
+/// The receiving end of the queue in front of a stage.
+pub(crate) trait QueueConsumer<T> {
+    fn pop(&mut self) -> Option<T>;
+
+    /// Whether the producer is gone.
+    fn is_abandoned(&self) -> bool;
+}
+
+impl<T> QueueProducer<T> for Producer<T> {
+    fn push(&mut self, item: T) -> Result<(), T> {
+        Producer::push(self, item).map_err(|PushError::Full(item)| item)
+    }
+
+    fn is_abandoned(&self) -> bool {
+        Producer::is_abandoned(self)
+    }
+}
+
+impl<T> QueueConsumer<T> for Consumer<T> {
+    fn pop(&mut self) -> Option<T> {
+        Consumer::pop(self).ok()
+    }
+
+    fn is_abandoned(&self) -> bool {
+        Consumer::is_abandoned(self)
+    }
+}
+
+/// Pushes the message, waiting for room when the ring buffer is full. Returns
+/// false when the consumer is gone.
+pub(crate) fn push_message<T>(producer: &mut impl QueueProducer<T>, message: T) -> bool {
+    let mut message = message;
+    loop {
+        match producer.push(message) {
+            Ok(()) => return true,
+            Err(rejected) => {
+                if producer.is_abandoned() {
+                    return false;
+                }
+                message = rejected;
+                yield_now();
+            }
+        }
+    }
+}
+
+/// Pops the next item, waiting for one when the ring buffer is empty. Returns
+/// `None` when the producer is gone.
+fn pop_message<T>(consumer: &mut impl QueueConsumer<T>) -> Option<T> {
+    loop {
+        match consumer.pop() {
+            Some(item) => return Some(item),
+            // the producer could have pushed its last items after the pop above
+            None if consumer.is_abandoned() => return consumer.pop(),
+            None => yield_now(),
+        }
+    }
+}
+
+/// The queues of the parse threads. Items are pushed to the threads in turn
+/// and popped from them in the same turn, which keeps their order.
+struct RoundRobin<T> {
+    queues: Vec<T>,
+    next: usize,
+}
+
+impl<T> RoundRobin<T> {
+    fn new(queues: Vec<T>) -> Self {
+        RoundRobin { queues, next: 0 }
+    }
+
+    fn turn(&mut self) -> &mut T {
+        let current = self.next;
+        self.next = (current + 1) % self.queues.len();
+        &mut self.queues[current]
+    }
+
+    fn push<I>(&mut self, item: I) -> bool
+    where
+        T: QueueProducer<I>,
+    {
+        push_message(self.turn(), item)
+    }
+
+    fn pop<I>(&mut self) -> Option<I>
+    where
+        T: QueueConsumer<I>,
+    {
+        pop_message(self.turn())
+    }
+}
+
+impl<T> RoundRobin<Producer<T>> {
+    fn queued(&self) -> usize {
+        self.queues
+            .iter()
+            .map(|queue| queue.buffer().capacity() - queue.slots())
+            .sum()
+    }
+}
+
+impl<T> RoundRobin<Consumer<T>> {
+    fn queued(&self) -> usize {
+        self.queues.iter().map(Consumer::slots).sum()
+    }
+}
+
+type BoxedSource<'a> = Box<dyn Iterator<Item = Message> + Send + 'a>;
+
+enum Source<'a> {
+    Messages(BoxedSource<'a>),
+    /// the rows of a csv file and the index of their parser
+    Csv(Box<CsvSource>, usize),
+}
+
+impl Source<'_> {
+    /// The error the source stopped at, see [`CsvSource::take_error`].
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        match self {
+            Source::Messages(_) => None,
+            Source::Csv(csv, _) => csv.take_error(),
+        }
+    }
+
+    /// The shutdown a followed csv file is read until.
+    fn follows(&self) -> Option<Shutdown> {
+        match self {
+            Source::Messages(_) => None,
+            Source::Csv(csv, _) => csv.follows().cloned(),
+        }
+    }
+
+    fn read(&mut self) -> Option<Unparsed> {
+        match self {
+            Source::Messages(messages) => messages.next().map(Unparsed::Message),
+            Source::Csv(csv, parser) => csv.read_row().map(|row| match row {
+                Row::Record(record, row) => Unparsed::Row(record, row, *parser),
+                Row::Pause => Unparsed::Message(Message::Flush),
+            }),
+        }
+    }
+}
+
+/// What the read stage hands to the parse stage.
+enum Unparsed {
+    Message(Message),
+    /// a csv row, its number and the index of its parser
+    Row(csv::Result<StringRecord>, u64, usize),
+}
+
+/// Runs a parse thread: parses the items in the order they are taken and hands
+/// them on, skipped rows as well to keep the turn, until the end of the stream.
+fn parse_items(
+    input: &mut impl QueueConsumer<Unparsed>,
+    out: &mut impl QueueProducer<Option<Message>>,
+    mut parse: impl FnMut(csv::Result<StringRecord>, u64, usize) -> Option<Message>,
+) {
+    while let Some(item) = pop_message(input) {
+        let message = match item {
+            Unparsed::Message(message) => Some(message),
+            Unparsed::Row(record, row, parser) => parse(record, row, parser),
+        };
+        let end = matches!(message, Some(Message::EndOfStream));
+        if !push_message(out, message) || end {
+            break;
+        }
+    }
+}
+
+/// A transform together with the metrics of its stage.
+type Stage<'a> = (Arc<StageMetrics>, Box<dyn Transform + 'a>);
+
+/// A validator together with the metrics of its stage.
+type Check<'a> = (Arc<StageMetrics>, Box<dyn Validator + 'a>);
+
+pub struct PipelineBuilder<'a> {
+    sources: Vec<Source<'a>>,
+    parsers: Vec<Arc<RecordParser>>,
+    parse_threads: usize,
+    transforms: Vec<Stage<'a>>,
+    context: Option<&'a mut TransactionContext>,
+    state_view: Option<StateView>,
+    sinks: Vec<Box<dyn Sink + 'a>>,
+    capacity: usize,
+    flush_every: Option<FlushInterval>,
+    pin_cores: Option<CorePins>,
+    max_rate: Option<u64>,
+    memory_budget: Option<MemoryBudget>,
+    pruner: Option<AccountPruner>,
+    dispute_expiry: Option<DisputeExpiry>,
+    watchdog: Option<Watchdog>,
+    settings: Option<LiveSettings>,
+    control: Option<Control>,
+    replication: Option<Replication>,
+    validators: Vec<Check<'a>>,
+    exactly_once: bool,
+}
+
+impl<'a> PipelineBuilder<'a> {
+    /// Adds a source of events or [`Message`]s. Multiple sources are read one
+    /// after the other.
+    pub fn source<M: Into<Message> + 'a>(
+        mut self,
+        source: impl IntoIterator<Item = M, IntoIter: Send + 'a>,
+    ) -> Self {
+        self.sources.push(Source::Messages(Box::new(
+            source.into_iter().map(Into::into),
+        )));
+        self
+    }
+
+    /// Adds a csv source, its rows are parsed by the parse stage rather than
+    /// while reading.
+    pub fn csv_source(mut self, source: CsvSource) -> Self {
+        self.parsers.push(source.parser());
+        self.sources
+            .push(Source::Csv(Box::new(source), self.parsers.len() - 1));
+        self
+    }
+
+    /// Amount of threads parsing the rows of the csv sources, 1 by default.
+    pub fn parse_threads(mut self, threads: usize) -> Self {
+        self.parse_threads = threads;
+        self
+    }
+
+    /// Adds a transform as a named stage, transforms are applied in the order
+    /// they are added. The events passing through, the events dropped and the
+    /// latency of every stage are recorded in the [`metrics`] under its name.
+    pub fn transform(mut self, name: &str, transform: impl Transform + 'a) -> Self {
+        self.transforms
+            .push((metrics().stage(name), Box::new(transform)));
+        self
+    }
+
+    /// The context the events are processed into.
+    pub fn processor(mut self, context: &'a mut TransactionContext) -> Self {
+        self.context = Some(context);
+        self
+    }
+
+    /// Publish every account change to the given view.
+    pub fn state_view(mut self, state_view: StateView) -> Self {
+        self.state_view = Some(state_view);
+        self
+    }
+
+    /// Adds a sink, sinks are called in the order they are added.
+    pub fn sink(mut self, sink: impl Sink + 'a) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Amount of events that can be queued between the sources and the
+    /// processor.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Flush the sinks periodically, besides at the batch boundaries signalled
+    /// by the sources.
+    pub fn flush_every(mut self, interval: FlushInterval) -> Self {
+        self.flush_every = Some(interval);
+        self
+    }
+
+    /// Pin the read thread and the processor (the thread calling
+    /// [`Pipeline::run`]) to the given cores, to reduce jitter.
+    pub fn pin_cores(mut self, pins: CorePins) -> Self {
+        self.pin_cores = Some(pins);
+        self
+    }
+
+    /// Hand at most the given amount of events per second to the processor,
+    /// so a flood of input can not starve other workloads on the host. The
+    /// state of the limit is in the [`metrics`].
+    pub fn max_rate(mut self, events_per_second: u64) -> Self {
+        self.max_rate = Some(events_per_second);
+        self
+    }
+
+    /// Keep the state within the given budget, see [`MemoryBudget`].
+    pub fn memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Remove dormant accounts, see [`AccountPruner`].
+    pub fn prune_accounts(mut self, pruner: AccountPruner) -> Self {
+        self.pruner = Some(pruner);
+        self
+    }
+
+    /// Resolve disputes that stay open too long, see [`DisputeExpiry`].
+    pub fn expire_disputes(mut self, expiry: DisputeExpiry) -> Self {
+        self.dispute_expiry = Some(expiry);
+        self
+    }
+
+    /// Log the events that take longer than a deadline, see [`Watchdog`].
+    pub fn watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Follow settings that can be reloaded: the max rate, replacing
+    /// [`Self::max_rate`], the lock policies and, when there are rules, the
+    /// rules as a validator.
+    pub fn settings(mut self, settings: LiveSettings) -> Self {
+        if settings.load().rules.is_some() {
+            self = self.validator("rules", settings.clone());
+        }
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Run the commands of the admin endpoints, see [`Control`].
+    pub fn control(mut self, control: Control) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Ship the processed events to followers, see [`Replication`].
+    pub fn replication(mut self, replication: Replication) -> Self {
+        self.replication = Some(replication);
+        self
+    }
+
+    /// Adds a validator, the validators check the events in the order they
+    /// are added, after the transforms. Events that fail a check are rejected
+    /// without being applied.
+    pub fn validator(mut self, name: &str, validator: impl Validator + 'a) -> Self {
+        self.validators
+            .push((metrics().stage(name), Box::new(validator)));
+        self
+    }
+
+    /// Commit the state with the position in the input and skip the events
+    /// committed by an earlier run, see
+    /// [`TransactionProcessor::with_exactly_once`].
+    pub fn exactly_once(mut self) -> Self {
+        self.exactly_once = true;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Pipeline<'a>> {
+        let Some(context) = self.context else {
+            anyhow::bail!("pipeline has no processor");
+        };
+        if self.sources.is_empty() {
+            anyhow::bail!("pipeline has no source");
+        }
+        if self.parse_threads == 0 {
+            anyhow::bail!("pipeline needs at least one parse thread");
+        }
+
+        Ok(Pipeline {
+            sources: self.sources,
+            parsers: self.parsers,
+            parse_threads: self.parse_threads,
+            transforms: self.transforms,
+            context,
+            state_view: self.state_view,
+            sinks: self.sinks,
+            capacity: self.capacity,
+            flush_every: self.flush_every,
+            pin_cores: self.pin_cores,
+            max_rate: self.max_rate,
+            memory_budget: self.memory_budget,
+            pruner: self.pruner,
+            dispute_expiry: self.dispute_expiry,
+            watchdog: self.watchdog,
+            settings: self.settings,
+            control: self.control,
+            replication: self.replication,
+            validators: self.validators,
+            exactly_once: self.exactly_once,
+        })
+    }
+}
+
+pub struct Pipeline<'a> {
+    sources: Vec<Source<'a>>,
+    parsers: Vec<Arc<RecordParser>>,
+    parse_threads: usize,
+    transforms: Vec<Stage<'a>>,
+    context: &'a mut TransactionContext,
+    state_view: Option<StateView>,
+    sinks: Vec<Box<dyn Sink + 'a>>,
+    capacity: usize,
+    flush_every: Option<FlushInterval>,
+    pin_cores: Option<CorePins>,
+    max_rate: Option<u64>,
+    memory_budget: Option<MemoryBudget>,
+    pruner: Option<AccountPruner>,
+    dispute_expiry: Option<DisputeExpiry>,
+    watchdog: Option<Watchdog>,
+    settings: Option<LiveSettings>,
+    control: Option<Control>,
+    replication: Option<Replication>,
+    validators: Vec<Check<'a>>,
+    exactly_once: bool,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn builder() -> PipelineBuilder<'a> {
+        PipelineBuilder {
+            sources: Vec::new(),
+            parsers: Vec::new(),
+            parse_threads: 1,
+            transforms: Vec::new(),
+            context: None,
+            state_view: None,
+            sinks: Vec::new(),
+            capacity: DEFAULT_CAPACITY,
+            flush_every: None,
+            pin_cores: None,
+            max_rate: None,
+            memory_budget: None,
+            pruner: None,
+            dispute_expiry: None,
+            watchdog: None,
+            settings: None,
+            control: None,
+            replication: None,
+            validators: Vec::new(),
+            exactly_once: false,
+        }
+    }
+
+    /// Runs the read, parse and validate stages on threads of their own and
+    /// processes the events on the current thread until the sources are
+    /// exhausted. Afterwards the state is flushed and every sink is finished.
+    ///
+    /// Fails when a stage panicked or a source stopped at an error, after
+    /// processing the events read up to then. The sinks are not finished in
+    /// that case.
+    pub fn run(self) -> anyhow::Result<()> {
+        let Pipeline {
+            mut sources,
+            parsers,
+            parse_threads,
+            mut transforms,
+            context,
+            state_view,
+            mut sinks,
+            capacity,
+            flush_every,
+            pin_cores,
+            max_rate,
+            memory_budget,
+            pruner,
+            dispute_expiry,
+            watchdog,
+            settings,
+            control,
+            replication,
+            mut validators,
+            exactly_once,
+        } = self;
+        let (mut unparsed, mut parsed) = (Vec::new(), Vec::new());
+        let mut parse_queues = Vec::new();
+        for _ in 0..parse_threads {
+            let (producer, consumer) = RingBuffer::new(STAGE_CAPACITY);
+            unparsed.push(producer);
+            let (out, parsed_consumer) = RingBuffer::new(STAGE_CAPACITY);
+            parsed.push(parsed_consumer);
+            parse_queues.push((consumer, out));
+        }
+        let (mut unparsed, mut parsed) = (RoundRobin::new(unparsed), RoundRobin::new(parsed));
+        let (mut producer, consumer) = RingBuffer::new(capacity);
+        let followed: Vec<_> = sources.iter().filter_map(Source::follows).collect();
+
+        std::thread::scope(|scope| {
+            let read = std::thread::Builder::new()
+                .name("pipeline read".to_string())
+                .spawn_scoped(scope, move || {
+                    let _span = info_span!("read").entered();
+                    if let Some(pins) = pin_cores {
+                        pin_current_thread(pins.source);
+                    }
+                    let (stage, parse_stage) = (metrics().stage("read"), metrics().stage("parse"));
+                    let mut result = Ok(());
+                    'sources: for source in &mut sources {
+                        loop {
+                            let start = Instant::now();
+                            let Some(item) = source.read() else {
+                                // the events read so far are still processed
+                                if let Some(error) = source.take_error() {
+                                    result = Err(error);
+                                    break 'sources;
+                                }
+                                break;
+                            };
+                            stage.record(false, start.elapsed());
+                            // the stream ends once all sources are exhausted
+                            if matches!(item, Unparsed::Message(Message::EndOfStream)) {
+                                continue;
+                            }
+                            if !unparsed.push(item) {
+                                break 'sources;
+                            }
+                            parse_stage.set_queued(unparsed.queued());
+                        }
+                    }
+                    // every parse thread stops at the end of the stream
+                    for _ in 0..parse_threads {
+                        unparsed.push(Unparsed::Message(Message::EndOfStream));
+                    }
+                    result
+                })?;
+
+            let mut parse = Vec::new();
+            for (i, (mut consumer, mut out)) in parse_queues.into_iter().enumerate() {
+                let parsers = parsers.clone();
+                let thread = std::thread::Builder::new()
+                    .name(format!("pipeline parse {i}"))
+                    .spawn_scoped(scope, move || {
+                        let _span = info_span!("parse", thread = i).entered();
+                        let stage = metrics().stage("parse");
+                        parse_items(&mut consumer, &mut out, |record, row, parser| {
+                            let start = Instant::now();
+                            let message = parsers[parser].parse(record, row);
+                            stage.record(false, start.elapsed());
+                            message
+                        });
+                    })?;
+                parse.push(thread);
+            }
+
+            // the max rate is followed by the validate stage, the lock
+            // policies by the processor
+            let reloaded = settings.clone();
+            let validate = std::thread::Builder::new()
+                .name("pipeline validate".to_string())
+                .spawn_scoped(scope, move || {
+                    let _span = info_span!("validate").entered();
+                    let stage = metrics().stage("validate");
+                    // events since the last flush
+                    let (mut events, mut since) = (0, Instant::now());
+                    let mut limiter = max_rate.map(RateLimiter::new);
+                    'messages: while let Some(message) = parsed.pop() {
+                        stage.set_queued(parsed.queued());
+                        let message = match message {
+                            Some(Message::Event(mut event)) => {
+                                let start = Instant::now();
+                                for (stage, transform) in &mut transforms {
+                                    let start = Instant::now();
+                                    let transformed = transform.apply(event);
+                                    stage.record(transformed.is_none(), start.elapsed());
+                                    match transformed {
+                                        Some(transformed) => event = transformed,
+                                        None => continue 'messages,
+                                    }
+                                }
+                                let mut checked = Ok(());
+                                for (stage, validator) in &mut validators {
+                                    let start = Instant::now();
+                                    checked = validator.validate(&event);
+                                    // rejected events are not applied, they count as
+                                    // dropped by the stage and reach the sinks as rejects
+                                    stage.record(checked.is_err(), start.elapsed());
+                                    if checked.is_err() {
+                                        break;
+                                    }
+                                }
+                                stage.record(false, start.elapsed());
+                                if let Err(reason) = checked {
+                                    debug!(%reason, ty = %event.ty, event.tx, "event failed validation");
+                                    let error = TransactionError::RuleViolation;
+                                    Message::Rejected(Rejected { event, error })
+                                } else {
+                                    if let Some(settings) = &reloaded {
+                                        match settings.load().max_rate {
+                                            Some(rate) => limiter
+                                                .get_or_insert_with(|| RateLimiter::new(rate))
+                                                .set_rate(rate),
+                                            None => limiter = None,
+                                        }
+                                    }
+                                    if let Some(limiter) = &mut limiter {
+                                        limiter.acquire();
+                                    }
+                                    Message::Event(event)
+                                }
+                            }
+                            Some(Message::EndOfStream) => break,
+                            Some(message @ (Message::Flush | Message::Rejected(_))) => message,
+                            None => continue,
+                        };
+                        if !push_message(&mut producer, message) {
+                            break;
+                        }
+
+                        let due = match message {
+                            Message::Flush => false,
+                            _ => {
+                                events += 1;
+                                flush_every.is_some_and(|every| every.is_due(events, since))
+                            }
+                        };
+                        if due && !push_message(&mut producer, Message::Flush) {
+                            break;
+                        }
+                        if due || matches!(message, Message::Flush) {
+                            (events, since) = (0, Instant::now());
+                        }
+                    }
+                    push_message(&mut producer, Message::EndOfStream);
+                })?;
+
+            if let Some(pins) = pin_cores {
+                pin_current_thread(pins.processor);
+            }
+            let mut processor = TransactionProcessor::new(&mut *context, consumer);
+            if let Some(state_view) = state_view {
+                processor = processor.with_state_view(state_view);
+            }
+            for sink in &mut sinks {
+                processor = processor.with_sink(sink.as_mut());
+            }
+            if let Some(budget) = memory_budget {
+                processor = processor.with_memory_budget(budget);
+            }
+            if let Some(pruner) = pruner {
+                processor = processor.with_pruner(pruner);
+            }
+            if let Some(expiry) = dispute_expiry {
+                processor = processor.with_dispute_expiry(expiry);
+            }
+            if let Some(watchdog) = watchdog {
+                processor = processor.with_watchdog(watchdog);
+            }
+            if let Some(settings) = settings {
+                processor = processor.with_settings(settings);
+            }
+            if let Some(control) = control {
+                processor = processor.with_control(control);
+            }
+            if let Some(replication) = replication {
+                processor = processor.with_replication(replication);
+            }
+            if exactly_once {
+                processor = processor.with_exactly_once();
+            }
+            // the stages stop once the processor is gone, a followed file is
+            // only left at shutdown
+            let result = processor.run();
+            if result.is_err() {
+                followed.iter().for_each(Shutdown::request);
+            }
+
+            let read = read
+                .join()
+                .map_err(|_| anyhow::anyhow!("pipeline read stage panicked"))?;
+            for thread in parse {
+                thread
+                    .join()
+                    .map_err(|_| anyhow::anyhow!("pipeline parse stage panicked"))?;
+            }
+            validate
+                .join()
+                .map_err(|_| anyhow::anyhow!("pipeline validate stage panicked"))?;
+            result.and(read)
+        })?;
+
+        context.flush()?;
+        for sink in &mut sinks {
+            sink.finish(context)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Price, TransactionType},
+        memory_budget::{ByteSize, MemoryPolicy, CHECK_EVERY},
+    };
+
+    fn deposit(client_id: u16, tx: u32, amount: i64) -> TransactionEvent {
+        TransactionEvent::new(TransactionType::Deposit, client_id, tx, Price(amount))
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mut context = TransactionContext::new();
+        let mut rejects = Vec::new();
+        let events = vec![deposit(1, 1, 10), deposit(2, 2, 20), deposit(1, 1, 30)];
+
+        Pipeline::builder()
+            .source(events)
+            .source([deposit(3, 3, 40)])
+            .transform("test drop client 2", |event: TransactionEvent| {
+                (event.client_id != 2).then_some(event)
+            })
+            .transform("test double amounts", |mut event: TransactionEvent| {
+                event.amount = Price(event.amount.0 * 2);
+                Some(event)
+            })
+            .processor(&mut context)
+            .sink(&mut rejects)
+            .capacity(1)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(context.account(1).unwrap().total, Price(20));
+        assert!(context.account(2).is_none());
+        assert_eq!(context.account(3).unwrap().total, Price(80));
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].error, TransactionError::Duplicate);
+
+        let drop = metrics().stage("test drop client 2");
+        assert_eq!((drop.events(), drop.dropped()), (4, 1));
+        let double = metrics().stage("test double amounts");
+        assert_eq!((double.events(), double.dropped()), (3, 0));
+    }
+
+    #[derive(Default)]
+    struct Flushes(Vec<usize>);
+
+    impl Sink for Flushes {
+        fn flush(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+            self.0.push(context.account_count());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut context = TransactionContext::new();
+        let mut flushes = Flushes::default();
+        let messages = [
+            Message::Event(deposit(1, 1, 10)),
+            Message::Flush,
+            // ignored, the stream only ends after the last source
+            Message::EndOfStream,
+            Message::Event(deposit(2, 2, 10)),
+            Message::Flush,
+        ];
+
+        Pipeline::builder()
+            .source(messages)
+            .source([deposit(3, 3, 10)])
+            .processor(&mut context)
+            .sink(&mut flushes)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(flushes.0, [1, 2]);
+        assert_eq!(context.account_count(), 3);
+    }
+
+    #[test]
+    fn test_flush_every() {
+        let mut context = TransactionContext::new();
+        let mut flushes = Flushes::default();
+        let events: Vec<_> = (1..=5).map(|tx| deposit(tx as u16, tx, 10)).collect();
+
+        Pipeline::builder()
+            .source(events)
+            .processor(&mut context)
+            .sink(&mut flushes)
+            .flush_every(FlushInterval::Events(2))
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+
+        assert_eq!(flushes.0, [2, 4]);
+    }
+
+    #[test]
+    fn test_parse_flush_interval() {
+        assert_eq!("1000".parse(), Ok(FlushInterval::Events(1000)));
+        assert_eq!(
+            "60s".parse(),
+            Ok(FlushInterval::Time(Duration::from_secs(60)))
+        );
+        assert_eq!(
+            "5m".parse(),
+            Ok(FlushInterval::Time(Duration::from_secs(300)))
+        );
+        assert!("0s".parse::<FlushInterval>().is_err());
+        assert!("5d".parse::<FlushInterval>().is_err());
+        assert!("s".parse::<FlushInterval>().is_err());
+    }
+
+    #[test]
+    fn test_parse_core_pins() {
+        assert_eq!(
+            "2, 3".parse(),
+            Ok(CorePins {
+                source: 2,
+                processor: 3
+            })
+        );
+        assert!("2".parse::<CorePins>().is_err());
+        assert!("a,3".parse::<CorePins>().is_err());
+        assert!(CorePins {
+            source: 0,
+            processor: usize::MAX
+        }
+        .validate()
+        .is_err());
+    }
+
+    /// Records the order in which the events are applied.
+    #[derive(Default)]
+    struct Applied(Vec<TransactionEvent>);
+
+    impl Sink for Applied {
+        fn record(
+            &mut self,
+            event: &TransactionEvent,
+            _result: Result<(), TransactionError>,
+            _account: Option<&Account>,
+        ) {
+            self.0.push(*event);
+        }
+    }
+
+    #[test]
+    fn test_per_client_order() {
+        // interleaved clients, a tiny queue and a yielding transform, so the
+        // source and processor constantly wait on each other
+        let events =
+            |offset: u32| (0..100).map(move |i| deposit((i % 7) as u16, offset + i, i64::from(i)));
+        for _ in 0..3 {
+            let mut context = TransactionContext::new();
+            let mut applied = Applied::default();
+            Pipeline::builder()
+                .source(events(0))
+                .source(events(100))
+                .transform("yield", |event: TransactionEvent| {
+                    if event.tx.is_multiple_of(3) {
+                        std::thread::yield_now();
+                    }
+                    Some(event)
+                })
+                .capacity(2)
+                .processor(&mut context)
+                .sink(&mut applied)
+                .build()
+                .unwrap()
+                .run()
+                .unwrap();
+
+            let expected: Vec<_> = events(0).chain(events(100)).collect();
+            for client_id in 0..7 {
+                let of_client = |events: &[TransactionEvent]| {
+                    events
+                        .iter()
+                        .filter(|event| event.client_id == client_id)
+                        .map(|event| event.tx)
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(of_client(&applied.0), of_client(&expected));
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_threads_keep_order() {
+        let path = std::env::temp_dir().join("txe_test_parse_threads.csv");
+        let mut csv = "type,client,tx,amount\n".to_string();
+        for tx in 1..=500 {
+            csv.push_str(&format!("deposit,{},{tx},1.0\n", tx % 7));
+            if tx % 100 == 0 {
+                csv.push_str("deposit,not a client,0,1.0\n");
+            }
+        }
+        std::fs::write(&path, csv).unwrap();
+
+        let mut context = TransactionContext::new();
+        let mut applied = Applied::default();
+        Pipeline::builder()
+            .csv_source(CsvSource::open(&path, None).unwrap())
+            .source([deposit(1, 501, 10)])
+            .parse_threads(3)
+            .processor(&mut context)
+            .sink(&mut applied)
+            .build()
+            .unwrap()
+            .run()
+            .unwrap();
+        let _ = std::fs::remove_file(path);
+
+        let txs: Vec<_> = applied.0.iter().map(|event| event.tx).collect();
+        assert_eq!(txs, (1..=501).collect::<Vec<_>>());
+        assert!(metrics().stage("parse").events() >= 505);
+    }
+
+    #[test]
+    fn test_source_failures() {
+        let path = std::env::temp_dir().join("txe_test_pipeline_truncated.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,",
+        )
+        .unwrap();
+        let mut context = TransactionContext::new();
+        let result = Pipeline::builder()
+            .csv_source(CsvSource::open(&path, None).unwrap())
+            .source([deposit(2, 3, 10)])
+            .processor(&mut context)
+            .build()
+            .unwrap()
+            .run();
+        let _ = std::fs::remove_file(path);
+        // the events before the error are processed, later sources are not read
+        assert!(result.is_err());
+        assert!(context.account(1).is_some());
+        assert!(context.account(2).is_none());
+
+        let panicking = (1..=3).map(|tx| {
+            assert!(tx < 3, "source broke");
+            deposit(1, tx, 10)
+        });
+        let mut context = TransactionContext::new();
+        let result = Pipeline::builder()
+            .source(panicking)
+            .processor(&mut context)
+            .build()
+            .unwrap()
+            .run();
+        assert!(result.is_err());
+        assert_eq!(context.transaction_count(), 2);
+    }
+
+    #[test]
+    fn test_failing_processor_ends_follow() {
+        struct Failing;
+        impl Sink for Failing {
+            fn flush(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+                anyhow::bail!("sink broke")
+            }
+        }
+
+        let path = std::env::temp_dir().join("txe_test_pipeline_follow_fails.csv");
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        let shutdown = Shutdown::default();
+        let mut context = TransactionContext::new();
+        // the processor fails at the first pause, the read stage would
+        // otherwise wait for appended rows
+        let result = Pipeline::builder()
+            .csv_source(CsvSource::open(&path, Some(shutdown.clone())).unwrap())
+            .processor(&mut context)
+            .sink(Failing)
+            .build()
+            .unwrap()
+            .run();
+        let _ = std::fs::remove_file(path);
+        assert!(result.is_err());
+        assert!(shutdown.is_requested());
+        assert!(context.account(1).is_some());
+    }
+
+    #[test]
+    fn test_memory_budget_aborts_follow() {
+        let path = std::env::temp_dir().join("txe_test_pipeline_follow_budget.csv");
+        let mut csv = "type,client,tx,amount\n".to_string();
+        for tx in 1..=CHECK_EVERY {
+            csv.push_str(&format!("deposit,1,{tx},1.0\n"));
+        }
+        std::fs::write(&path, csv).unwrap();
+        let shutdown = Shutdown::default();
+        let mut context = TransactionContext::new();
+        let result = Pipeline::builder()
+            .csv_source(CsvSource::open(&path, Some(shutdown.clone())).unwrap())
+            .processor(&mut context)
+            .memory_budget(MemoryBudget::new(ByteSize(1024), MemoryPolicy::Abort))
+            .build()
+            .unwrap()
+            .run();
+        let _ = std::fs::remove_file(path);
+        let error = result.unwrap_err();
+        assert!(error.to_string().contains("memory budget"));
+        assert!(shutdown.is_requested());
+    }
+
+    #[test]
+    fn test_build_requires_source_and_processor() {
+        let mut context = TransactionContext::new();
+        assert!(Pipeline::builder().processor(&mut context).build().is_err());
+        assert!(Pipeline::builder().source::<Message>([]).build().is_err());
+        assert!(Pipeline::builder()
+            .source([deposit(1, 1, 10)])
+            .processor(&mut context)
+            .parse_threads(0)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        // a second worth of events passes at once, the rest at the rate
+        for _ in 0..1000 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+        for _ in 0..100 {
+            limiter.acquire();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert!(metrics().throttled());
+        assert!(metrics().throttled_time() >= Duration::from_millis(90));
+    }
+}
+
+/// Model checks the ordering guarantee of the read, parse and validate stages
+/// with loom, under every interleaving of their threads. Run with
+/// `RUSTFLAGS="--cfg loom" cargo test --release --lib loom`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionType};
+    use loom::sync::{Arc, Condvar, Mutex};
+    use std::collections::VecDeque;
+
+    /// Bounded queue loom can see into, standing in for a ring buffer. It
+    /// blocks where the ring buffer would make the stage spin, the spinning
+    /// only adds interleavings in which nothing happens.
+    struct Shared<T> {
+        state: Mutex<State<T>>,
+        changed: Condvar,
+        capacity: usize,
+    }
+
+    struct State<T> {
+        items: VecDeque<T>,
+        producer_gone: bool,
+        consumer_gone: bool,
+    }
+
+    struct ModelProducer<T>(Arc<Shared<T>>);
+
+    struct ModelConsumer<T>(Arc<Shared<T>>);
+
+    fn queue<T>(capacity: usize) -> (ModelProducer<T>, ModelConsumer<T>) {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                producer_gone: false,
+                consumer_gone: false,
+            }),
+            changed: Condvar::new(),
+            capacity,
+        });
+        (ModelProducer(shared.clone()), ModelConsumer(shared))
+    }
+
+    impl<T> Shared<T> {
+        fn update<R>(&self, update: impl FnOnce(&mut State<T>) -> R) -> R {
+            let result = update(&mut self.state.lock().unwrap());
+            self.changed.notify_all();
+            result
+        }
+    }
+
+    impl<T> QueueProducer<T> for ModelProducer<T> {
+        fn push(&mut self, item: T) -> Result<(), T> {
+            let mut state = self.0.state.lock().unwrap();
+            while state.items.len() == self.0.capacity && !state.consumer_gone {
+                state = self.0.changed.wait(state).unwrap();
+            }
+            if state.items.len() == self.0.capacity {
+                return Err(item);
+            }
+            state.items.push_back(item);
+            drop(state);
+            self.0.changed.notify_all();
+            Ok(())
+        }
+
+        fn is_abandoned(&self) -> bool {
+            self.0.state.lock().unwrap().consumer_gone
+        }
+    }
+
+    impl<T> Drop for ModelProducer<T> {
+        fn drop(&mut self) {
+            self.0.update(|state| state.producer_gone = true);
+        }
+    }
+
+    impl<T> QueueConsumer<T> for ModelConsumer<T> {
+        fn pop(&mut self) -> Option<T> {
+            let mut state = self.0.state.lock().unwrap();
+            while state.items.is_empty() && !state.producer_gone {
+                state = self.0.changed.wait(state).unwrap();
+            }
+            let item = state.items.pop_front();
+            drop(state);
+            self.0.changed.notify_all();
+            item
+        }
+
+        fn is_abandoned(&self) -> bool {
+            self.0.state.lock().unwrap().producer_gone
+        }
+    }
+
+    impl<T> Drop for ModelConsumer<T> {
+        fn drop(&mut self) {
+            self.0.update(|state| state.consumer_gone = true);
+        }
+    }
+
+    fn deposit(client_id: u16, tx: u32) -> Unparsed {
+        Unparsed::Message(Message::Event(TransactionEvent::new(
+            TransactionType::Deposit,
+            client_id,
+            tx,
+            Price(10_000),
+        )))
+    }
+
+    /// Starts the parse threads between queues of the given capacity, like
+    /// [`Pipeline::run`] does.
+    #[allow(clippy::type_complexity)]
+    fn parse_stage(
+        threads: usize,
+        capacity: usize,
+    ) -> (
+        RoundRobin<ModelProducer<Unparsed>>,
+        RoundRobin<ModelConsumer<Option<Message>>>,
+        Vec<loom::thread::JoinHandle<()>>,
+    ) {
+        let (mut unparsed, mut parsed, mut handles) = (Vec::new(), Vec::new(), Vec::new());
+        for _ in 0..threads {
+            let (producer, mut input) = queue(capacity);
+            let (mut out, consumer) = queue(capacity);
+            unparsed.push(producer);
+            parsed.push(consumer);
+            handles.push(loom::thread::spawn(move || {
+                parse_items(&mut input, &mut out, |_, _, _| None)
+            }));
+        }
+        (RoundRobin::new(unparsed), RoundRobin::new(parsed), handles)
+    }
+
+    #[test]
+    fn test_stages_keep_order() {
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            // the events of the client go through different parse threads
+            let (mut unparsed, mut parsed, parse) = parse_stage(2, 2);
+            let read = loom::thread::spawn(move || {
+                for tx in [1, 2] {
+                    assert!(unparsed.push(deposit(1, tx)));
+                }
+                for _ in 0..2 {
+                    unparsed.push(Unparsed::Message(Message::EndOfStream));
+                }
+            });
+
+            let mut applied = Vec::new();
+            while let Some(message) = parsed.pop() {
+                match message {
+                    Some(Message::Event(event)) => applied.push(event.tx),
+                    Some(Message::EndOfStream) => break,
+                    _ => {}
+                }
+            }
+            assert_eq!(applied, [1, 2]);
+            read.join().unwrap();
+            for thread in parse {
+                thread.join().unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn test_stages_stop_when_abandoned() {
+        let mut model = loom::model::Builder::new();
+        model.preemption_bound = Some(3);
+        model.check(|| {
+            let (mut unparsed, mut parsed, parse) = parse_stage(1, 1);
+            let read = loom::thread::spawn(move || {
+                // stops once the processor is gone instead of waiting for room,
+                // at most one slot per queue and one event per thread is taken
+                (1..=6)
+                    .take_while(|tx| unparsed.push(deposit(1, *tx)))
+                    .count()
+            });
+
+            let first = parsed.pop();
+            assert!(matches!(first, Some(Some(Message::Event(event))) if event.tx == 1));
+            drop(parsed);
+            assert!(read.join().unwrap() < 6);
+            for thread in parse {
+                thread.join().unwrap();
+            }
+        });
+    }
+}
@@ -0,0 +1,250 @@
+//! Custom rules as WebAssembly modules, see `--plugin`. Business teams can
+//! ship their own validations without forking the engine.
+//!
+//! A plugin exports one or both of the hooks below, with events passed as
+//! plain numbers. The type is the index in [`TransactionType::ALL`] (0 is a
+//! deposit, 1 a withdrawal, ..), amounts are in ten-thousandths and a missing
+//! timestamp is -1.
+//!
+//! * `validate(type: i32, client: i32, tx: i64, amount: i64, timestamp: i64)
+//!   -> i32` is called for every event before it is applied. 0 accepts the
+//!   event, any other value rejects it with that reason code, as
+//!   `rule_violation`. An optional `reason(code: i32) -> i64` describes a
+//!   code for the logs, as a UTF-8 string in the exported `memory` with its
+//!   offset in the upper and its length in the lower 32 bits.
+//! * `on_applied(type: i32, client: i32, tx: i64, amount: i64, timestamp: i64,
+//!   available: i64, held: i64, total: i64, locked: i32)` is called after
+//!   every applied event with the account of the client.
+//!
+//! The module can not import anything. The hooks run in separate instances
+//! on different threads, so they do not share state.
+//!
+//! [`TransactionType::ALL`]: crate::data_types::TransactionType::ALL
+use crate::{
+    data_types::{Account, TransactionError, TransactionEvent},
+    pipeline::{Sink, Validator},
+};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+use wasmtime::{Engine, Instance, Memory, Module, Store, TypedFunc};
+
+type Event = (i32, i32, i64, i64, i64);
+/// An event and the account it was applied to.
+type Applied = (i32, i32, i64, i64, i64, i64, i64, i64, i32);
+
+fn event_params(event: &TransactionEvent) -> Event {
+    (
+        event.ty as i32,
+        event.client_id.into(),
+        event.tx.into(),
+        event.amount.0,
+        event.timestamp.map_or(-1, |timestamp| timestamp as i64),
+    )
+}
+
+/// A compiled plugin, instantiated once per hook.
+#[derive(Clone)]
+pub struct Plugin {
+    path: PathBuf,
+    engine: Engine,
+    module: Module,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("path", &self.path).finish()
+    }
+}
+
+impl Plugin {
+    /// Compiles the module at `path`, in the binary or the text format.
+    /// Fails when it exports neither hook.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let engine = Engine::default();
+        let module = Module::from_file(&engine, path)
+            .map_err(|e| anyhow::anyhow!("{}: {e:#}", path.display()))?;
+        let plugin = Plugin {
+            path: path.to_path_buf(),
+            engine,
+            module,
+        };
+        if !plugin.exports("validate") && !plugin.exports("on_applied") {
+            anyhow::bail!(
+                "{}: plugin exports neither `validate` nor `on_applied`",
+                path.display()
+            );
+        }
+        Ok(plugin)
+    }
+
+    fn exports(&self, name: &str) -> bool {
+        self.module.get_export(name).is_some()
+    }
+
+    fn instantiate(&self) -> anyhow::Result<(Store<()>, Instance)> {
+        let mut store = Store::new(&self.engine, ());
+        let instance = Instance::new(&mut store, &self.module, &[])
+            .map_err(|e| anyhow::anyhow!("{}: {e:#}", self.path.display()))?;
+        Ok((store, instance))
+    }
+
+    /// The `validate` hook, if the plugin exports it.
+    pub fn validator(&self) -> anyhow::Result<Option<PluginValidator>> {
+        if !self.exports("validate") {
+            return Ok(None);
+        }
+        let (mut store, instance) = self.instantiate()?;
+        let validate = instance.get_typed_func(&mut store, "validate")?;
+        let reason = match self.exports("reason") {
+            true => Some(instance.get_typed_func(&mut store, "reason")?),
+            false => None,
+        };
+        let memory = instance.get_memory(&mut store, "memory");
+        Ok(Some(PluginValidator {
+            store,
+            validate,
+            reason,
+            memory,
+        }))
+    }
+
+    /// The `on_applied` hook as a [`Sink`], if the plugin exports it.
+    pub fn sink(&self) -> anyhow::Result<Option<PluginSink>> {
+        if !self.exports("on_applied") {
+            return Ok(None);
+        }
+        let (mut store, instance) = self.instantiate()?;
+        let on_applied = instance.get_typed_func(&mut store, "on_applied")?;
+        Ok(Some(PluginSink {
+            path: self.path.clone(),
+            store,
+            on_applied,
+            trapped: false,
+        }))
+    }
+}
+
+/// Rejects the events the `validate` hook of a plugin rejects. A trap
+/// rejects the event as well.
+pub struct PluginValidator {
+    store: Store<()>,
+    validate: TypedFunc<Event, i32>,
+    reason: Option<TypedFunc<i32, i64>>,
+    memory: Option<Memory>,
+}
+
+impl PluginValidator {
+    fn describe(&mut self, code: i32) -> Option<String> {
+        let packed = self.reason.as_ref()?.call(&mut self.store, code).ok()? as u64;
+        let (offset, len) = ((packed >> 32) as usize, (packed & 0xffff_ffff) as usize);
+        let data = self.memory?.data(&self.store);
+        let bytes = data.get(offset..offset.checked_add(len)?)?;
+        Some(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+impl Validator for PluginValidator {
+    fn validate(&mut self, event: &TransactionEvent) -> Result<(), String> {
+        match self.validate.call(&mut self.store, event_params(event)) {
+            Ok(0) => Ok(()),
+            Ok(code) => Err(match self.describe(code) {
+                Some(reason) => format!("plugin rejected with {code}: {reason}"),
+                None => format!("plugin rejected with {code}"),
+            }),
+            Err(e) => Err(format!("plugin trapped: {e:#}")),
+        }
+    }
+}
+
+/// Calls the `on_applied` hook of a plugin for every applied event. After a
+/// trap the hook is not called anymore.
+pub struct PluginSink {
+    path: PathBuf,
+    store: Store<()>,
+    on_applied: TypedFunc<Applied, ()>,
+    trapped: bool,
+}
+
+impl Sink for PluginSink {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let (Ok(()), Some(account), false) = (result, account, self.trapped) else {
+            return;
+        };
+        let (ty, client, tx, amount, timestamp) = event_params(event);
+        let params = (
+            ty,
+            client,
+            tx,
+            amount,
+            timestamp,
+            account.available().0,
+            account.held.0,
+            account.total.0,
+            account.locked.into(),
+        );
+        if let Err(error) = self.on_applied.call(&mut self.store, params) {
+            warn!(path = %self.path.display(), error = %format!("{error:#}"), "plugin trapped, on_applied is disabled");
+            self.trapped = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionType};
+
+    #[test]
+    fn test_plugin() {
+        // rejects withdrawals above 100, on_applied traps on withdrawals
+        let path = std::env::temp_dir().join("txe_test_plugin.wat");
+        std::fs::write(
+            &path,
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 16) "withdrawal too large")
+                (func (export "validate")
+                    (param $ty i32) (param $client i32) (param $tx i64)
+                    (param $amount i64) (param $timestamp i64) (result i32)
+                    (i32.and
+                        (i32.eq (local.get $ty) (i32.const 1))
+                        (i64.gt_s (local.get $amount) (i64.const 1000000))))
+                (func (export "reason") (param $code i32) (result i64)
+                    (i64.or (i64.shl (i64.const 16) (i64.const 32)) (i64.const 20)))
+                (func (export "on_applied")
+                    (param i32 i32 i64 i64 i64 i64 i64 i64 i32)
+                    (if (i32.eq (local.get 0) (i32.const 1)) (then unreachable))))"#,
+        )
+        .unwrap();
+        let plugin = Plugin::load(&path);
+        let _ = std::fs::remove_file(path);
+        let plugin = plugin.unwrap();
+
+        let event = |ty, amount| TransactionEvent::new(ty, 1, 1, Price(amount));
+        let mut validator = plugin.validator().unwrap().unwrap();
+        assert_eq!(
+            validator.validate(&event(TransactionType::Deposit, 2_000_000)),
+            Ok(())
+        );
+        assert_eq!(
+            validator.validate(&event(TransactionType::Withdrawal, 2_000_000)),
+            Err("plugin rejected with 1: withdrawal too large".to_string())
+        );
+
+        let mut sink = plugin.sink().unwrap().unwrap();
+        let account = Account::default();
+        sink.record(&event(TransactionType::Deposit, 10), Ok(()), Some(&account));
+        assert!(!sink.trapped);
+        sink.record(
+            &event(TransactionType::Withdrawal, 10),
+            Ok(()),
+            Some(&account),
+        );
+        assert!(sink.trapped);
+    }
+}
@@ -0,0 +1,313 @@
+//! Double-entry view of the processed events, so the output can be reconciled
+//! against a general ledger. Every applied event moves its amount between two
+//! ledger accounts: the omnibus account holding the funds of all clients, and
+//! the available and held balances of the client.
+//!
+//! The client balances are liabilities (credit normal), the omnibus account is
+//! an asset (debit normal). Summing the postings per ledger account gives the
+//! available and held amounts of every client, and the omnibus balance equals
+//! the sum of their totals.
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent, TransactionType},
+    enrichment::Annotations,
+    pipeline::Sink,
+    pseudonym,
+    reference::Reference,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use std::{collections::HashMap, fmt::Display, fs::File, io::BufWriter, path::Path, sync::Arc};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LedgerAccount {
+    Omnibus,
+    Available(u16),
+    Held(u16),
+}
+
+impl Display for LedgerAccount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerAccount::Omnibus => write!(f, "omnibus"),
+            LedgerAccount::Available(client) => {
+                write!(f, "client/{}/available", pseudonym::client(*client))
+            }
+            LedgerAccount::Held(client) => write!(f, "client/{}/held", pseudonym::client(*client)),
+        }
+    }
+}
+
+/// A balanced entry: `amount` is debited from one account and credited to
+/// the other.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Posting {
+    pub tx: u32,
+    pub ty: TransactionType,
+    pub debit: LedgerAccount,
+    pub credit: LedgerAccount,
+    pub amount: Price,
+    /// external reference of the event, see [`crate::reference`]
+    pub reference: Option<Reference>,
+    /// annotation of the event, see [`crate::enrichment`]
+    pub annotation: Option<u32>,
+}
+
+impl Posting {
+    /// The postings of an applied event, given the account of the client
+    /// before and after it. Disputes, resolves and chargebacks carry no
+    /// amount, it is taken from the change of the held funds of the client.
+    /// Chargebacks are credited to the suspense account when there is one, see
+    /// [`TransactionContext::set_suspense_account`], the part of a shortfall
+    /// comes from the available funds. Adjustments are posted by
+    /// their sign. A merge moves the available and held funds of the merged
+    /// client in two postings. Unlocks move no funds and have no posting.
+    pub fn of(
+        event: &TransactionEvent,
+        before: &Account,
+        account: &Account,
+        suspense: Option<u16>,
+    ) -> impl Iterator<Item = Posting> {
+        use LedgerAccount::*;
+        let client = event.client_id;
+        let held_change = Price(account.held.0 - before.held.0);
+        let released = Price(before.held.0 - account.held.0);
+        let mut moves = [None, None];
+        moves[0] = match event.ty {
+            TransactionType::Deposit => Some((Omnibus, Available(client), event.amount)),
+            TransactionType::Withdrawal => Some((Available(client), Omnibus, event.amount)),
+            TransactionType::Dispute => Some((Available(client), Held(client), held_change)),
+            TransactionType::Resolve => Some((Held(client), Available(client), released)),
+            TransactionType::Chargeback => {
+                let to = suspense.map_or(Omnibus, Available);
+                let shortfall = Price(before.shortfall.0 - account.shortfall.0);
+                if shortfall.0 != 0 {
+                    moves[1] = Some((Available(client), to, shortfall));
+                }
+                Some((Held(client), to, released))
+            }
+            TransactionType::Adjustment | TransactionType::Recovery => {
+                Some((Omnibus, Available(client), event.amount))
+            }
+            TransactionType::Merge => {
+                let from = event.merged_client.unwrap_or_default();
+                let available_change = Price(account.available().0 - before.available().0);
+                if held_change.0 != 0 {
+                    moves[1] = Some((Held(from), Held(client), held_change));
+                }
+                Some((Available(from), Available(client), available_change))
+            }
+            TransactionType::Unlock => None,
+        };
+        let (tx, ty, reference, annotation) =
+            (event.tx, event.ty, event.reference, event.annotation);
+        moves
+            .into_iter()
+            .flatten()
+            .map(move |(debit, credit, amount)| {
+                // a negative amount moves the other way
+                let (debit, credit, amount) = match amount.0 < 0 {
+                    true => (credit, debit, Price(amount.0.saturating_neg())),
+                    false => (debit, credit, amount),
+                };
+                Posting {
+                    tx,
+                    ty,
+                    debit,
+                    credit,
+                    amount,
+                    reference,
+                    annotation,
+                }
+            })
+    }
+}
+
+/// [`Sink`] writing a posting per applied event as csv, with the columns
+/// `tx,type,debit,credit,amount,reference` and the annotation columns, if
+/// any. Rejected events are not posted.
+///
+/// Like the audit log, io errors do not interrupt processing. The first one
+/// is returned at the next flush.
+#[derive(Debug)]
+pub struct PostingsSink {
+    writer: csv::Writer<BufWriter<File>>,
+    /// last known account per client, to derive the amounts of disputes and
+    /// merges
+    accounts: HashMap<u16, Account>,
+    suspense: Option<u16>,
+    annotations: Option<Arc<Annotations>>,
+    header: bool,
+    error: Option<csv::Error>,
+}
+
+impl PostingsSink {
+    /// Creates the postings file. The held funds of the accounts in the
+    /// context are the opening state, e.g. after restoring a snapshot, and
+    /// its suspense account receives the chargebacks.
+    pub fn create(path: &Path, context: &TransactionContext) -> anyhow::Result<Self> {
+        let writer = csv::Writer::from_writer(BufWriter::new(File::create(path)?));
+        let accounts = context
+            .iter_accounts()
+            .map(|(client, account)| (client, *account))
+            .collect();
+        Ok(PostingsSink {
+            writer,
+            accounts,
+            suspense: context.suspense_account(),
+            annotations: None,
+            header: false,
+            error: None,
+        })
+    }
+
+    /// Adds the columns of the annotations the events are enriched with.
+    pub fn annotate(mut self, annotations: Arc<Annotations>) -> Self {
+        self.annotations = Some(annotations);
+        self
+    }
+
+    fn write(&mut self, posting: &Posting) -> Result<(), csv::Error> {
+        let columns = self.annotations.as_ref().map_or(&[][..], |a| a.columns());
+        if !self.header {
+            let mut header = vec!["tx", "type", "debit", "credit", "amount", "reference"];
+            header.extend(columns.iter().map(String::as_str));
+            self.writer.write_record(header)?;
+            self.header = true;
+        }
+        let mut record = vec![
+            posting.tx.to_string(),
+            posting.ty.as_str().to_string(),
+            posting.debit.to_string(),
+            posting.credit.to_string(),
+            posting.amount.to_string(),
+            posting
+                .reference
+                .map(|reference| reference.to_string())
+                .unwrap_or_default(),
+        ];
+        if let Some(annotations) = &self.annotations {
+            match posting.annotation.and_then(|index| annotations.get(index)) {
+                Some(values) => record.extend(values.iter().cloned()),
+                None => record.extend(columns.iter().map(|_| String::new())),
+            }
+        }
+        self.writer.write_record(record)
+    }
+}
+
+impl Sink for PostingsSink {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let (Ok(()), Some(account)) = (result, account) else {
+            return;
+        };
+        let before = self
+            .accounts
+            .insert(event.client_id, *account)
+            .unwrap_or_default();
+        if let (TransactionType::Merge, Some(merged)) = (event.ty, event.merged_client) {
+            self.accounts.remove(&merged);
+        }
+        for posting in Posting::of(event, &before, account, self.suspense) {
+            if self.error.is_none() {
+                if let Err(error) = self.write(&posting) {
+                    self.error = Some(error);
+                }
+            }
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.accounts.remove(&pruned.client_id);
+    }
+
+    fn flush(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error.into());
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn finish(&mut self, context: &TransactionContext) -> anyhow::Result<()> {
+        self.flush(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{admin::admin_event, data_types::HoldPolicy};
+
+    #[test]
+    fn test_postings_reconcile() {
+        reconcile(None, HoldPolicy::Full);
+        // the suspense account is a client like any other
+        reconcile(Some(9), HoldPolicy::Full);
+        reconcile(Some(9), HoldPolicy::Cap);
+    }
+
+    fn reconcile(suspense: Option<u16>, hold: HoldPolicy) {
+        let event =
+            |ty, client_id, tx, amount| TransactionEvent::new(ty, client_id, tx, Price(amount));
+        let mut context = TransactionContext::new();
+        if let Some(suspense) = suspense {
+            context.set_suspense_account(suspense);
+        }
+        context.set_hold_policy(hold);
+        let mut accounts = HashMap::new();
+        let mut balances: HashMap<LedgerAccount, i64> = HashMap::new();
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 30_000),
+            event(TransactionType::Deposit, 1, 2, 20_000),
+            event(TransactionType::Withdrawal, 1, 3, 5_000),
+            event(TransactionType::Dispute, 1, 1, 0),
+            event(TransactionType::Dispute, 1, 2, 0),
+            event(TransactionType::Resolve, 1, 2, 0),
+            event(TransactionType::Chargeback, 1, 1, 0),
+            event(TransactionType::Deposit, 2, 4, 10_000),
+            event(TransactionType::Dispute, 2, 4, 0),
+            event(TransactionType::Deposit, 3, 5, 7_000),
+            TransactionEvent {
+                merged_client: Some(2),
+                ..admin_event(TransactionType::Merge, 3, 6)
+            },
+            // charged back after most of it was withdrawn
+            event(TransactionType::Deposit, 4, 7, 10_000),
+            event(TransactionType::Withdrawal, 4, 8, 8_000),
+            event(TransactionType::Dispute, 4, 7, 0),
+            event(TransactionType::Chargeback, 4, 7, 0),
+        ] {
+            let account = context.apply(&event).unwrap();
+            let before = accounts
+                .insert(event.client_id, account)
+                .unwrap_or_default();
+            for posting in Posting::of(&event, &before, &account, suspense) {
+                *balances.entry(posting.debit).or_default() += posting.amount.0;
+                *balances.entry(posting.credit).or_default() -= posting.amount.0;
+            }
+        }
+
+        // every posting is balanced
+        assert_eq!(balances.values().sum::<i64>(), 0);
+        let mut funds = 0;
+        for (client, account) in context.iter_accounts() {
+            let balance = |account| -balances.get(&account).copied().unwrap_or_default();
+            assert_eq!(
+                balance(LedgerAccount::Available(client)),
+                account.available().0
+            );
+            assert_eq!(balance(LedgerAccount::Held(client)), account.held.0);
+            funds += account.total.0;
+        }
+        assert_eq!(balances[&LedgerAccount::Omnibus], funds);
+        // charged back funds stay in the system with a suspense account
+        assert_eq!(funds, if suspense.is_some() { 64_000 } else { 24_000 });
+        // the merged client is gone, its balances moved
+        assert!(context.account(2).is_none());
+        assert_eq!(balances.get(&LedgerAccount::Held(2)), Some(&0));
+    }
+}
@@ -0,0 +1,91 @@
+use crate::metrics::metrics;
+use std::{
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// Periodically reports the progress of a run to stderr. The reporter stops
+/// and prints a final line once [`ProgressReporter::finish`] is called.
+pub struct ProgressReporter {
+    stop: Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl ProgressReporter {
+    /// `total_bytes` is the size of the input, used to estimate the remaining
+    /// time. Pass 0 when unknown.
+    pub fn spawn(total_bytes: u64, interval: Duration) -> anyhow::Result<Self> {
+        let (stop, stopped) = mpsc::channel();
+        let start = Instant::now();
+
+        let handle = std::thread::Builder::new()
+            .name("progress".to_string())
+            .spawn(move || loop {
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => {
+                        eprintln!("{}", progress_line(start.elapsed(), total_bytes, false));
+                    }
+                    _ => {
+                        eprintln!("{}", progress_line(start.elapsed(), total_bytes, true));
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(ProgressReporter { stop, handle })
+    }
+
+    pub fn finish(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.join();
+    }
+}
+
+fn progress_line(elapsed: Duration, total_bytes: u64, done: bool) -> String {
+    let metrics = metrics();
+    let mut line = format!(
+        "progress: rows read {}, processed {}, rejects {}, parse failures {}",
+        metrics.rows_read(),
+        metrics.events_total(),
+        metrics.rejects_total(),
+        metrics.parse_failures(),
+    );
+
+    if done {
+        line.push_str(&format!(", done in {:.1}s", elapsed.as_secs_f64()));
+    } else if let Some(eta) = eta(elapsed, metrics.bytes_read(), total_bytes) {
+        let percentage = metrics.bytes_read() as f64 / total_bytes as f64 * 100.0;
+        line.push_str(&format!(
+            ", {percentage:.1}%, eta {:.0}s",
+            eta.as_secs_f64()
+        ));
+    }
+
+    line
+}
+
+/// Estimates the remaining time, assuming the remaining bytes are read at the
+/// same rate as the bytes read so far.
+fn eta(elapsed: Duration, offset: u64, total_bytes: u64) -> Option<Duration> {
+    if offset == 0 || total_bytes == 0 || offset > total_bytes {
+        return None;
+    }
+
+    let remaining = (total_bytes - offset) as f64 / offset as f64;
+    Some(elapsed.mul_f64(remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(eta(elapsed, 0, 100), None);
+        assert_eq!(eta(elapsed, 10, 0), None);
+        assert_eq!(eta(elapsed, 25, 100), Some(Duration::from_secs(30)));
+        assert_eq!(eta(elapsed, 100, 100), Some(Duration::ZERO));
+    }
+}
@@ -0,0 +1,133 @@
+//! Pruning of dormant accounts, so the account map of a long running service
+//! stays bounded. An account is dormant when it holds nothing, is not locked
+//! and its client had no events for the configured period.
+//!
+//! Time is taken from the timestamps of the events, a replay prunes the same
+//! accounts as the original run. Clients whose events have no timestamp are
+//! never pruned.
+use crate::{
+    data_types::TransactionEvent,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Debug, Clone)]
+pub struct AccountPruner {
+    idle: u64,
+    /// latest timestamp seen on any event
+    now: u64,
+    last_activity: HashMap<u16, u64>,
+}
+
+impl AccountPruner {
+    /// Prunes accounts that had no events for the given period.
+    pub fn new(idle: Duration) -> Self {
+        AccountPruner {
+            idle: idle.as_secs(),
+            now: 0,
+            last_activity: HashMap::new(),
+        }
+    }
+
+    /// Registers the activity of an event, applied or not.
+    pub fn observe(&mut self, event: &TransactionEvent) {
+        if let Some(timestamp) = event.timestamp {
+            self.now = self.now.max(timestamp);
+            let last = self.last_activity.entry(event.client_id).or_default();
+            *last = (*last).max(timestamp);
+        }
+    }
+
+    /// Removes the dormant accounts from the context.
+    pub fn prune(&mut self, context: &mut TransactionContext) -> Vec<PrunedAccount> {
+        let dormant: Vec<_> = self
+            .last_activity
+            .iter()
+            .filter(|(_, last)| self.now.saturating_sub(**last) >= self.idle)
+            .map(|(client_id, _)| *client_id)
+            .filter(|client_id| {
+                context.account(*client_id).is_some_and(|account| {
+                    account.total.0 == 0 && account.held.0 == 0 && !account.locked
+                })
+            })
+            .collect();
+        if dormant.is_empty() {
+            return Vec::new();
+        }
+        for client_id in &dormant {
+            self.last_activity.remove(client_id);
+        }
+        context.prune_accounts(&dormant)
+    }
+}
+
+/// Parses a period in milliseconds, seconds, minutes, hours or days (`250ms`,
+/// `90s`, `30m`, `12h`, `7d`).
+pub fn parse_period(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (number, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a period like `7d`, got `{s}`"))?;
+    let seconds = match unit {
+        "ms" => return Ok(Duration::from_millis(number)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown unit `{unit}`, expected ms, s, m, h or d")),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionFlags, TransactionType};
+
+    #[test]
+    fn test_prune_dormant_accounts() {
+        let event = |ty, client_id, tx, amount, timestamp| TransactionEvent {
+            timestamp: Some(timestamp),
+            ..TransactionEvent::new(ty, client_id, tx, Price(amount))
+        };
+        let mut context = TransactionContext::new();
+        let mut pruner = AccountPruner::new(parse_period("1h").unwrap());
+        for event in [
+            // emptied and dormant
+            event(TransactionType::Deposit, 1, 1, 10_000, 0),
+            event(TransactionType::Withdrawal, 1, 2, 10_000, 10),
+            // holds funds
+            event(TransactionType::Deposit, 2, 3, 10_000, 20),
+            // emptied, but locked
+            event(TransactionType::Deposit, 3, 4, 10_000, 30),
+            event(TransactionType::Dispute, 3, 4, 0, 40),
+            event(TransactionType::Chargeback, 3, 4, 0, 50),
+            // emptied and recently active
+            event(TransactionType::Deposit, 4, 5, 10_000, 3000),
+            event(TransactionType::Withdrawal, 4, 6, 10_000, 3700),
+        ] {
+            pruner.observe(&event);
+            let _ = context.apply(&event);
+        }
+
+        let pruned = pruner.prune(&mut context);
+        assert_eq!(
+            pruned,
+            vec![PrunedAccount {
+                client_id: 1,
+                account: pruned[0].account,
+                transactions: vec![(1, (Price(10_000), TransactionFlags::None, 1))],
+            }]
+        );
+        assert_eq!(pruned[0].account.meta.tx_count, 2);
+        assert!(context.account(1).is_none() && context.transaction(1).is_none());
+        assert_eq!(context.account_count(), 3);
+        assert_eq!(context.transaction_count(), 3);
+        assert!(pruner.prune(&mut context).is_empty());
+
+        assert_eq!(parse_period("7d"), Ok(Duration::from_secs(7 * 24 * 3600)));
+        assert_eq!(parse_period("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_period("7").is_err());
+    }
+}
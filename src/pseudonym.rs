@@ -0,0 +1,173 @@
+//! Pseudonymization of client ids, so outputs can be shared without telling
+//! who the clients are. Once a [`Pseudonymizer`] is installed, every output
+//! and log shows a keyed hash of the client id in place of the id, see
+//! [`client`]. Snapshots keep the real ids, they are read back by the engine.
+//!
+//! The key is read from [`KEY_ENV`] (hex), so pseudonyms stay the same across
+//! runs. Without it a random key is used, and the pseudonyms of a run can only
+//! be traced back with its mapping file, see [`Pseudonymizer::write_mapping`].
+use crate::seal::decode_hex;
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Serializer};
+use sha2::Sha256;
+use std::{fs::OpenOptions, path::Path, sync::OnceLock};
+
+/// Environment variable holding the key as hex.
+pub const KEY_ENV: &str = "TXE_PSEUDONYM_KEY";
+
+static PSEUDONYMIZER: OnceLock<Pseudonymizer> = OnceLock::new();
+
+/// Maps client ids to pseudonyms with HMAC-SHA256.
+#[derive(Clone)]
+pub struct Pseudonymizer {
+    mac: Hmac<Sha256>,
+}
+
+impl std::fmt::Debug for Pseudonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Pseudonymizer(..)")
+    }
+}
+
+impl Pseudonymizer {
+    pub fn new(key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() < 16 {
+            anyhow::bail!(
+                "the pseudonym key needs at least 16 bytes, got {}",
+                key.len()
+            );
+        }
+        let mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("any key length");
+        Ok(Pseudonymizer { mac })
+    }
+
+    /// Uses the key of [`KEY_ENV`], or a random key when it is not set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var(KEY_ENV) {
+            Ok(hex) => {
+                let key = decode_hex(hex.trim())
+                    .ok_or_else(|| anyhow::anyhow!("{KEY_ENV} is not a hex string"))?;
+                Self::new(&key)
+            }
+            Err(_) => {
+                let mut key = [0; 32];
+                OsRng.fill_bytes(&mut key);
+                Self::new(&key)
+            }
+        }
+    }
+
+    /// The pseudonym of a client, 16 hex digits.
+    pub fn pseudonym(&self, client_id: u16) -> String {
+        let mut mac = self.mac.clone();
+        mac.update(&client_id.to_be_bytes());
+        mac.finalize().into_bytes()[..8]
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect()
+    }
+
+    /// Writes the pseudonym of every given client as csv, readable by the
+    /// owner only.
+    pub fn write_mapping(
+        &self,
+        path: &Path,
+        clients: impl Iterator<Item = u16>,
+    ) -> anyhow::Result<()> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+            options.mode(0o600);
+            // the mode only applies to new files
+            if path.exists() {
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+        }
+        let mut writer = csv::Writer::from_writer(options.open(path)?);
+        writer.write_record(["pseudonym", "client"])?;
+        for client_id in clients {
+            writer.write_record([self.pseudonym(client_id), client_id.to_string()])?;
+        }
+        Ok(writer.flush()?)
+    }
+}
+
+/// Pseudonymizes the client ids of all outputs and logs from now on.
+pub fn install(pseudonymizer: Pseudonymizer) -> anyhow::Result<()> {
+    PSEUDONYMIZER
+        .set(pseudonymizer)
+        .map_err(|_| anyhow::anyhow!("a pseudonymizer is already installed"))
+}
+
+/// The pseudonymizer installed with [`install`].
+pub fn installed() -> Option<&'static Pseudonymizer> {
+    PSEUDONYMIZER.get()
+}
+
+/// The client id as it is shown in outputs and logs.
+pub fn client(client_id: u16) -> DisplayClient {
+    DisplayClient(client_id)
+}
+
+/// Displays and serializes as the pseudonym of the client once a
+/// [`Pseudonymizer`] is installed, and as the client id otherwise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisplayClient(pub u16);
+
+impl std::fmt::Display for DisplayClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match PSEUDONYMIZER.get() {
+            Some(pseudonymizer) => f.write_str(&pseudonymizer.pseudonym(self.0)),
+            None => self.0.fmt(f),
+        }
+    }
+}
+
+impl Serialize for DisplayClient {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match PSEUDONYMIZER.get() {
+            Some(pseudonymizer) => serializer.serialize_str(&pseudonymizer.pseudonym(self.0)),
+            None => serializer.serialize_u16(self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pseudonym() {
+        let pseudonymizer = Pseudonymizer::new(&[7; 32]).unwrap();
+        let pseudonym = pseudonymizer.pseudonym(1);
+        assert_eq!(pseudonym.len(), 16);
+        assert_eq!(pseudonym, pseudonymizer.pseudonym(1));
+        assert_ne!(pseudonym, pseudonymizer.pseudonym(2));
+        let other = Pseudonymizer::new(&[8; 32]).unwrap();
+        assert_ne!(pseudonym, other.pseudonym(1));
+        assert!(Pseudonymizer::new(&[7; 8]).is_err());
+
+        let path = std::env::temp_dir().join("txe_test_pseudonym_map.csv");
+        pseudonymizer
+            .write_mapping(&path, [1, 2].into_iter())
+            .unwrap();
+        let mapping = std::fs::read_to_string(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let _ = std::fs::remove_file(path);
+        assert_eq!(
+            mapping,
+            format!(
+                "pseudonym,client\n{pseudonym},1\n{},2\n",
+                pseudonymizer.pseudonym(2)
+            )
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//! Sources that other threads push messages into, e.g. a UDP listener or a
+//! webhook handler. Unlike an iterator the pipeline pulls from, such a source
+//! can not always be slowed down, so the queue between the pushers and the
+//! pipeline decides what happens during a burst, see [`QueueMode`].
+use crate::{
+    metrics::{metrics, StageMetrics},
+    pipeline::Message,
+};
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How the queue of a [`PushSource`] handles a burst.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueMode {
+    /// up to the given amount of messages, a pusher waits for room
+    Block(usize),
+    /// up to the given amount of messages, messages that do not fit are
+    /// dropped and counted in the [`metrics`]
+    Drop(usize),
+    /// the queue grows as needed, for sources that can neither wait nor lose
+    /// messages
+    Unbounded,
+}
+
+/// Parses `block:<capacity>`, `drop:<capacity>` or `unbounded`.
+impl FromStr for QueueMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unbounded" {
+            return Ok(QueueMode::Unbounded);
+        }
+        let capacity = |capacity: &str| match capacity.parse() {
+            Ok(0) | Err(_) => Err(format!("expected a capacity above zero, got `{capacity}`")),
+            Ok(capacity) => Ok(capacity),
+        };
+        match s.split_once(':') {
+            Some(("block", n)) => Ok(QueueMode::Block(capacity(n)?)),
+            Some(("drop", n)) => Ok(QueueMode::Drop(capacity(n)?)),
+            _ => Err(format!(
+                "expected block:<capacity>, drop:<capacity> or unbounded, got `{s}`"
+            )),
+        }
+    }
+}
+
+/// Creates a source and the handle to push its messages with. The metrics of
+/// the queue are recorded as the stage with the given name.
+pub fn push_source(name: &str, mode: QueueMode) -> (Pusher, PushSource) {
+    let stage = metrics().stage(name);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = match mode {
+        QueueMode::Block(capacity) | QueueMode::Drop(capacity) => {
+            let (sender, receiver) = mpsc::sync_channel(capacity);
+            (QueueSender::Bounded(sender), receiver)
+        }
+        QueueMode::Unbounded => {
+            let (sender, receiver) = mpsc::channel();
+            (QueueSender::Unbounded(sender), receiver)
+        }
+    };
+    let pusher = Pusher {
+        sender,
+        drop: matches!(mode, QueueMode::Drop(_)),
+        stage: stage.clone(),
+        queued: queued.clone(),
+    };
+    let source = PushSource {
+        receiver,
+        stage,
+        queued,
+    };
+    (pusher, source)
+}
+
+#[derive(Debug, Clone)]
+enum QueueSender {
+    Bounded(SyncSender<Message>),
+    Unbounded(Sender<Message>),
+}
+
+/// The pipeline stopped reading the source.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Disconnected;
+
+impl std::fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the pipeline stopped reading the source")
+    }
+}
+
+impl std::error::Error for Disconnected {}
+
+/// Pushes messages into a [`PushSource`], can be cloned for every thread
+/// pushing. The source ends once every pusher is dropped.
+#[derive(Debug, Clone)]
+pub struct Pusher {
+    sender: QueueSender,
+    drop: bool,
+    stage: Arc<StageMetrics>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Pusher {
+    /// Queues the message. Returns whether it was queued, it is not when the
+    /// queue drops messages and is full.
+    pub fn push(&self, message: impl Into<Message>) -> Result<bool, Disconnected> {
+        let message = message.into();
+        // counted up front, the source could take the message before the
+        // push returns
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        let result = match &self.sender {
+            QueueSender::Bounded(sender) if self.drop => match sender.try_send(message) {
+                Ok(()) => Ok(true),
+                Err(TrySendError::Full(_)) => Ok(false),
+                Err(TrySendError::Disconnected(_)) => Err(Disconnected),
+            },
+            QueueSender::Bounded(sender) => sender
+                .send(message)
+                .map(|()| true)
+                .map_err(|_| Disconnected),
+            QueueSender::Unbounded(sender) => sender
+                .send(message)
+                .map(|()| true)
+                .map_err(|_| Disconnected),
+        };
+        if result != Ok(true) {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.stage.set_queued(self.queued.load(Ordering::Relaxed));
+        // only events count, flushes are no input of their own
+        if let (Ok(queued), Message::Event(_)) = (result, message) {
+            self.stage.record(!queued, Duration::ZERO);
+        }
+        result
+    }
+}
+
+/// Source handing out the messages of its [`Pusher`]s in the order they were
+/// queued.
+#[derive(Debug)]
+pub struct PushSource {
+    receiver: Receiver<Message>,
+    stage: Arc<StageMetrics>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Iterator for PushSource {
+    type Item = Message;
+
+    fn next(&mut self) -> Option<Message> {
+        let message = self.receiver.recv().ok()?;
+        let queued = self.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.stage.set_queued(queued);
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionEvent, TransactionType};
+
+    #[test]
+    fn test_queue_modes() {
+        let deposit = |tx| TransactionEvent::new(TransactionType::Deposit, 1, tx, Price(10_000));
+        let burst = |mode: &str| {
+            let (pusher, source) = push_source(&format!("test push {mode}"), mode.parse().unwrap());
+            let queued: Vec<_> = (1..=5)
+                .map(|tx| pusher.push(deposit(tx)).unwrap())
+                .collect();
+            drop(pusher);
+            let txs: Vec<_> = source
+                .filter_map(Message::into_event)
+                .map(|event| event.tx)
+                .collect();
+            (queued, txs)
+        };
+
+        assert_eq!(burst("unbounded"), (vec![true; 5], vec![1, 2, 3, 4, 5]));
+        assert_eq!(
+            burst("drop:2"),
+            (vec![true, true, false, false, false], vec![1, 2])
+        );
+        assert_eq!(metrics().stage("test push drop:2").dropped(), 3);
+
+        let (pusher, source) = push_source("test push block", QueueMode::Block(1));
+        let pushing = std::thread::spawn(move || {
+            for tx in 1..=3 {
+                pusher.push(deposit(tx)).unwrap();
+            }
+        });
+        let txs: Vec<_> = source
+            .filter_map(Message::into_event)
+            .map(|event| event.tx)
+            .collect();
+        pushing.join().unwrap();
+        assert_eq!(txs, [1, 2, 3]);
+
+        assert!("drop:0".parse::<QueueMode>().is_err());
+        assert!("block".parse::<QueueMode>().is_err());
+    }
+}
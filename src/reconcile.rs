@@ -0,0 +1,225 @@
+//! Reconciliation of computed balances against an external balance file, e.g.
+//! the balances the core banking system reports for the same clients.
+use crate::{
+    data_types::{Account, Price},
+    pseudonym,
+};
+use csv::Writer;
+use std::{collections::BTreeMap, fmt::Display, io::Write};
+
+/// How far a balance may be off before it is reported. A difference is
+/// accepted when it is within either bound.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Tolerance {
+    pub absolute: Price,
+    /// fraction of the expected balance
+    pub relative: f64,
+    /// do not report clients that are only on one side
+    pub ignore_missing: bool,
+}
+
+impl Tolerance {
+    fn accepts(&self, expected: Price, actual: Price) -> bool {
+        let difference = (actual.0 - expected.0).abs();
+        difference <= self.absolute.0.abs()
+            || difference as f64 <= self.relative * expected.0.abs() as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    /// the client is only on one side
+    Account,
+    Available,
+    Held,
+    Total,
+    Locked,
+}
+
+impl Field {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Field::Account => "account",
+            Field::Available => "available",
+            Field::Held => "held",
+            Field::Total => "total",
+            Field::Locked => "locked",
+        }
+    }
+}
+
+/// A field of an account that differs from the expected balance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discrepancy {
+    pub client_id: u16,
+    pub field: Field,
+    pub expected: String,
+    pub actual: String,
+    /// actual minus expected, for amounts
+    pub difference: Option<Price>,
+}
+
+/// The computed balances differ from the expected ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconciliationError {
+    pub discrepancies: usize,
+    pub clients: usize,
+}
+
+impl Display for ReconciliationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} discrepancies in {} clients",
+            self.discrepancies, self.clients
+        )
+    }
+}
+
+impl std::error::Error for ReconciliationError {}
+
+/// Compares the computed accounts with the expected ones, returning the
+/// discrepancies ordered by client.
+pub fn reconcile(
+    expected: impl IntoIterator<Item = (u16, Account)>,
+    actual: impl IntoIterator<Item = (u16, Account)>,
+    tolerance: &Tolerance,
+) -> Vec<Discrepancy> {
+    let mut pairs: BTreeMap<u16, (Option<Account>, Option<Account>)> = BTreeMap::new();
+    for (client_id, account) in expected {
+        pairs.entry(client_id).or_default().0 = Some(account);
+    }
+    for (client_id, account) in actual {
+        pairs.entry(client_id).or_default().1 = Some(account);
+    }
+
+    let mut discrepancies = Vec::new();
+    for (client_id, pair) in pairs {
+        let (expected, actual) = match pair {
+            (Some(expected), Some(actual)) => (expected, actual),
+            (expected, _) if !tolerance.ignore_missing => {
+                let side = |present: bool| if present { "present" } else { "missing" };
+                discrepancies.push(Discrepancy {
+                    client_id,
+                    field: Field::Account,
+                    expected: side(expected.is_some()).to_string(),
+                    actual: side(expected.is_none()).to_string(),
+                    difference: None,
+                });
+                continue;
+            }
+            _ => continue,
+        };
+        for (field, expected, actual) in [
+            (Field::Available, expected.available(), actual.available()),
+            (Field::Held, expected.held, actual.held),
+            (Field::Total, expected.total, actual.total),
+        ] {
+            if !tolerance.accepts(expected, actual) {
+                discrepancies.push(Discrepancy {
+                    client_id,
+                    field,
+                    expected: expected.to_string(),
+                    actual: actual.to_string(),
+                    difference: Some(Price(actual.0 - expected.0)),
+                });
+            }
+        }
+        if expected.locked != actual.locked {
+            discrepancies.push(Discrepancy {
+                client_id,
+                field: Field::Locked,
+                expected: expected.locked.to_string(),
+                actual: actual.locked.to_string(),
+                difference: None,
+            });
+        }
+    }
+    discrepancies
+}
+
+/// Writes the discrepancies as csv.
+pub fn write_discrepancies(
+    writer: impl Write,
+    discrepancies: &[Discrepancy],
+) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record(["client", "field", "expected", "actual", "difference"])?;
+    for discrepancy in discrepancies {
+        writer.write_record(&[
+            pseudonym::client(discrepancy.client_id).to_string(),
+            discrepancy.field.as_str().to_string(),
+            discrepancy.expected.clone(),
+            discrepancy.actual.clone(),
+            discrepancy
+                .difference
+                .map(|d| d.to_string())
+                .unwrap_or_default(),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(total: i64, held: i64, locked: bool) -> Account {
+        Account {
+            total: Price(total),
+            held: Price(held),
+            locked,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let expected = [
+            (1, account(10_000, 0, false)),
+            (2, account(20_000, 0, false)),
+            (3, account(1_000_000, 0, false)),
+            (4, account(0, 0, true)),
+        ];
+        let actual = [
+            (1, account(10_001, 0, false)),
+            (2, account(20_000, 5_000, false)),
+            (3, account(1_000_500, 0, false)),
+            (5, account(0, 0, false)),
+        ];
+        let summary = |tolerance| {
+            reconcile(expected, actual, &tolerance)
+                .into_iter()
+                .map(|d| (d.client_id, d.field, d.difference))
+                .collect::<Vec<_>>()
+        };
+
+        let exact = summary(Tolerance::default());
+        assert_eq!(
+            exact,
+            [
+                (1, Field::Available, Some(Price(1))),
+                (1, Field::Total, Some(Price(1))),
+                (2, Field::Available, Some(Price(-5_000))),
+                (2, Field::Held, Some(Price(5_000))),
+                (3, Field::Available, Some(Price(500))),
+                (3, Field::Total, Some(Price(500))),
+                (4, Field::Account, None),
+                (5, Field::Account, None),
+            ]
+        );
+
+        let tolerant = summary(Tolerance {
+            absolute: Price(1),
+            relative: 0.001,
+            ignore_missing: true,
+        });
+        assert_eq!(
+            tolerant,
+            [
+                (2, Field::Available, Some(Price(-5_000))),
+                (2, Field::Held, Some(Price(5_000))),
+            ]
+        );
+    }
+}
@@ -0,0 +1,97 @@
+//! External references of transactions, e.g. the reference on a bank
+//! statement, from the optional `reference` column. Events stay `Copy` by
+//! carrying a [`Reference`] to the text, which is interned once per process:
+//! a reference shared by many events is stored once. Texts longer than
+//! [`MAX_LEN`] bytes are truncated.
+use serde::{Serialize, Serializer};
+use std::{
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// Longest reference kept, in bytes.
+pub const MAX_LEN: usize = 64;
+
+static REFERENCES: OnceLock<Mutex<Interner>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Interner {
+    texts: Vec<Arc<str>>,
+    ids: HashMap<Arc<str>, NonZeroU32>,
+}
+
+fn interner() -> &'static Mutex<Interner> {
+    REFERENCES.get_or_init(Default::default)
+}
+
+/// An interned reference, displays and serializes as its text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Reference(NonZeroU32);
+
+impl Reference {
+    /// Interns the text, truncated to [`MAX_LEN`] bytes. `None` when it is
+    /// empty.
+    pub fn intern(text: &str) -> Option<Reference> {
+        let text = truncate(text.trim());
+        if text.is_empty() {
+            return None;
+        }
+        let mut interner = interner().lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(id) = interner.ids.get(text) {
+            return Some(Reference(*id));
+        }
+        let text: Arc<str> = text.into();
+        let id = NonZeroU32::new(interner.texts.len() as u32 + 1)?;
+        interner.texts.push(text.clone());
+        interner.ids.insert(text, id);
+        Some(Reference(id))
+    }
+
+    pub fn text(&self) -> Arc<str> {
+        let interner = interner().lock().unwrap_or_else(|e| e.into_inner());
+        interner.texts[self.0.get() as usize - 1].clone()
+    }
+}
+
+fn truncate(text: &str) -> &str {
+    if text.len() <= MAX_LEN {
+        return text;
+    }
+    let end = (0..=MAX_LEN)
+        .rev()
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap_or_default();
+    &text[..end]
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text())
+    }
+}
+
+impl Serialize for Reference {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.text())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reference() {
+        let reference = Reference::intern(" INV-2024-001 ").unwrap();
+        assert_eq!(reference.to_string(), "INV-2024-001");
+        assert_eq!(Reference::intern("INV-2024-001"), Some(reference));
+        assert_ne!(Reference::intern("INV-2024-002"), Some(reference));
+        assert_eq!(Reference::intern(""), None);
+
+        let long = "é".repeat(MAX_LEN);
+        let truncated = Reference::intern(&long).unwrap().text();
+        assert_eq!(truncated.len(), MAX_LEN);
+        assert!(long.starts_with(&*truncated));
+    }
+}
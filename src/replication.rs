@@ -0,0 +1,572 @@
+//! Ships the events a primary applies to follower instances, see
+//! `--replication-addr` and `--follow`. A follower keeps a warm replica of the
+//! account state by applying the same events, it serves the account API to
+//! scale reads and its `--snapshot` lets a new primary take over quickly.
+//!
+//! A joining follower first receives a binary snapshot of the state, then
+//! every event the primary applies (including the rejected ones, they can
+//! create accounts) and every pruned account, in order. Followers need the
+//! options shaping the accounting rules of the primary, like
+//! `--chargeback-lock` and `--dispute-hold`. Expired disputes are shipped as
+//! resolve events, so followers do not expire disputes themselves.
+//!
+//! A follower that falls more than [`BACKLOG`] records behind is disconnected,
+//! it reconnects and starts over from a new snapshot.
+use crate::{
+    data_types::{Price, ReasonCode, TransactionEvent, TransactionType},
+    metrics::metrics,
+    pipeline::Sink,
+    reference::Reference,
+    shutdown::Shutdown,
+    snapshot::{read_binary_snapshot, write_binary_snapshot},
+    state_view::StateView,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, BufReader, BufWriter, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, warn};
+
+/// Records a follower may lag behind before it is disconnected.
+pub const BACKLOG: usize = 65_536;
+
+const MAGIC: [u8; 4] = *b"TXEW";
+const VERSION: u16 = 1;
+
+const SNAPSHOT: u8 = 0;
+const EVENT: u8 = 1;
+const PRUNE: u8 = 2;
+
+/// How often a waiting follower checks for a shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A shipped event, the annotations of an enrichment stage stay local.
+#[derive(Debug, Serialize, Deserialize)]
+struct EventRecord {
+    /// index in [`TransactionType::ALL`]
+    ty: u8,
+    client: u16,
+    tx: u32,
+    amount: i64,
+    timestamp: Option<u64>,
+    reason: String,
+    merged_client: Option<u16>,
+    reference: Option<String>,
+    authenticated: bool,
+}
+
+impl EventRecord {
+    fn new(event: &TransactionEvent) -> Self {
+        EventRecord {
+            ty: event.ty as u8,
+            client: event.client_id,
+            tx: event.tx,
+            amount: event.amount.0,
+            timestamp: event.timestamp,
+            reason: event.reason.to_string(),
+            merged_client: event.merged_client,
+            reference: event
+                .reference
+                .map(|reference| reference.text().to_string()),
+            authenticated: event.authenticated,
+        }
+    }
+
+    fn event(self) -> anyhow::Result<TransactionEvent> {
+        let Some(ty) = TransactionType::ALL.get(self.ty as usize) else {
+            anyhow::bail!("unknown transaction type {} in tx {}", self.ty, self.tx);
+        };
+        Ok(TransactionEvent {
+            ty: *ty,
+            client_id: self.client,
+            tx: self.tx,
+            amount: Price(self.amount),
+            timestamp: self.timestamp,
+            reason: match self.reason.is_empty() {
+                true => ReasonCode::default(),
+                false => self.reason.parse().map_err(anyhow::Error::msg)?,
+            },
+            merged_client: self.merged_client,
+            reference: self.reference.as_deref().and_then(Reference::intern),
+            annotation: None,
+            authenticated: self.authenticated,
+        })
+    }
+}
+
+fn frame(tag: u8, payload: &[u8]) -> Arc<[u8]> {
+    let mut frame = Vec::with_capacity(payload.len() + 5);
+    frame.push(tag);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame.into()
+}
+
+/// A connected follower, written to by a thread of its own.
+#[derive(Debug)]
+struct Link {
+    peer: SocketAddr,
+    frames: SyncSender<Arc<[u8]>>,
+}
+
+#[derive(Debug, Default)]
+struct Joining {
+    streams: Mutex<Vec<TcpStream>>,
+    /// set when there are streams
+    pending: AtomicBool,
+}
+
+/// The primary side: accepts followers and ships them the applied events.
+/// Used by the processor, see
+/// [`crate::transaction_processor::TransactionProcessor::with_replication`].
+#[derive(Debug)]
+pub struct Replication {
+    addr: SocketAddr,
+    joining: Arc<Joining>,
+    followers: Vec<Link>,
+}
+
+impl Replication {
+    /// Accepts followers on the given address, on a separate thread.
+    pub fn listen(addr: SocketAddr) -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        info!(%addr, "accepting followers");
+        let joining = Arc::new(Joining::default());
+        std::thread::Builder::new()
+            .name("replication".to_string())
+            .spawn({
+                let joining = joining.clone();
+                move || {
+                    for stream in listener.incoming() {
+                        match stream {
+                            Ok(stream) => {
+                                let mut streams = joining
+                                    .streams
+                                    .lock()
+                                    .expect("replication lock is poisoned");
+                                streams.push(stream);
+                                joining.pending.store(true, Ordering::Release);
+                            }
+                            Err(error) => warn!(%error, "failed to accept a follower"),
+                        }
+                    }
+                }
+            })?;
+        Ok(Replication {
+            addr,
+            joining,
+            followers: Vec::new(),
+        })
+    }
+
+    /// The address followers connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Whether followers are waiting for their snapshot.
+    pub fn has_joining(&self) -> bool {
+        self.joining.pending.load(Ordering::Acquire)
+    }
+
+    /// Sends the waiting followers a snapshot of the state, they receive the
+    /// events shipped after it.
+    pub fn admit(&mut self, context: &TransactionContext) {
+        let streams = {
+            let mut streams = self
+                .joining
+                .streams
+                .lock()
+                .expect("replication lock is poisoned");
+            self.joining.pending.store(false, Ordering::Release);
+            std::mem::take(&mut *streams)
+        };
+        if streams.is_empty() {
+            return;
+        }
+        let mut snapshot = Vec::new();
+        if let Err(error) = write_binary_snapshot(context, &mut snapshot) {
+            warn!(
+                error = format!("{error:#}"),
+                "failed to snapshot the state for followers"
+            );
+            return;
+        }
+        let snapshot = frame(SNAPSHOT, &snapshot);
+        for stream in streams {
+            match Self::connect(stream, snapshot.clone()) {
+                Ok(link) => {
+                    info!(peer = %link.peer, accounts = context.account_count(), "follower joined");
+                    self.followers.push(link);
+                }
+                Err(error) => warn!(%error, "failed to admit a follower"),
+            }
+        }
+        metrics().set_followers(self.followers.len());
+    }
+
+    fn connect(stream: TcpStream, snapshot: Arc<[u8]>) -> io::Result<Link> {
+        let peer = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        let (frames, queue) = mpsc::sync_channel::<Arc<[u8]>>(BACKLOG);
+        frames
+            .try_send(snapshot)
+            .expect("the queue of a new follower has room");
+        std::thread::Builder::new()
+            .name(format!("follower {peer}"))
+            .spawn(move || {
+                let mut writer = BufWriter::new(stream);
+                let mut write = || -> io::Result<()> {
+                    writer.write_all(&MAGIC)?;
+                    writer.write_all(&VERSION.to_le_bytes())?;
+                    while let Ok(frame) = queue.recv() {
+                        writer.write_all(&frame)?;
+                        if let Ok(frame) = queue.try_recv() {
+                            writer.write_all(&frame)?;
+                            continue;
+                        }
+                        writer.flush()?;
+                    }
+                    writer.flush()
+                };
+                if let Err(error) = write() {
+                    debug!(%peer, %error, "stopped shipping to the follower");
+                }
+            })?;
+        Ok(Link { peer, frames })
+    }
+
+    /// Ships an event the processor applied, or rejected.
+    pub fn ship(&mut self, event: &TransactionEvent) {
+        if self.followers.is_empty() {
+            return;
+        }
+        match postcard::to_allocvec(&EventRecord::new(event)) {
+            Ok(payload) => self.send(frame(EVENT, &payload)),
+            Err(error) => warn!(%error, "failed to encode the event for followers"),
+        }
+    }
+
+    /// Ships an account removed by pruning.
+    pub fn ship_prune(&mut self, pruned: &PrunedAccount) {
+        if !self.followers.is_empty() {
+            self.send(frame(PRUNE, &pruned.client_id.to_le_bytes()));
+        }
+    }
+
+    fn send(&mut self, frame: Arc<[u8]>) {
+        let followers = self.followers.len();
+        self.followers
+            .retain(|link| match link.frames.try_send(frame.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(peer = %link.peer, "follower fell behind, disconnecting it");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    info!(peer = %link.peer, "follower left");
+                    false
+                }
+            });
+        if self.followers.len() != followers {
+            metrics().set_followers(self.followers.len());
+        }
+    }
+}
+
+/// Fills the buffer, `Ok(false)` when a shutdown is requested while waiting.
+fn read_exact(reader: &mut impl Read, mut buf: &mut [u8], shutdown: &Shutdown) -> io::Result<bool> {
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if shutdown.is_requested() {
+                    return Ok(false);
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// The follower side: applies the events shipped by a primary.
+pub struct Follower<'a> {
+    primary: SocketAddr,
+    retry: Duration,
+    state_view: Option<StateView>,
+    sinks: Vec<&'a mut dyn Sink>,
+}
+
+impl std::fmt::Debug for Follower<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Follower")
+            .field("primary", &self.primary)
+            .field("retry", &self.retry)
+            .field("state_view", &self.state_view)
+            .field("sinks", &self.sinks.len())
+            .finish()
+    }
+}
+
+impl<'a> Follower<'a> {
+    pub fn new(primary: SocketAddr) -> Self {
+        Follower {
+            primary,
+            retry: Duration::from_secs(1),
+            state_view: None,
+            sinks: Vec::new(),
+        }
+    }
+
+    /// How long to wait before connecting again, a second by default.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Publish every account change to the given view.
+    pub fn with_state_view(mut self, state_view: StateView) -> Self {
+        self.state_view = Some(state_view);
+        self
+    }
+
+    /// Report the outcome of every shipped event to the given sink, the sink
+    /// is flushed when the connection to the primary is lost, e.g. to refresh
+    /// the snapshot a new primary restores. Finishing the sink is up to the
+    /// caller.
+    pub fn with_sink(mut self, sink: &'a mut dyn Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Follows the primary until a shutdown is requested. The connection is
+    /// retried when it is lost, the last state is kept meanwhile and replaced
+    /// by the snapshot of the next connection.
+    pub fn run(
+        mut self,
+        context: &mut TransactionContext,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<()> {
+        let mut unreachable = false;
+        while !shutdown.is_requested() {
+            let stream = match TcpStream::connect_timeout(&self.primary, self.retry) {
+                Ok(stream) => stream,
+                Err(error) => {
+                    if !unreachable {
+                        warn!(primary = %self.primary, %error, "primary is unreachable, retrying");
+                        unreachable = true;
+                    }
+                    std::thread::sleep(self.retry);
+                    continue;
+                }
+            };
+            unreachable = false;
+            info!(primary = %self.primary, "following the primary");
+            stream.set_read_timeout(Some(POLL_INTERVAL))?;
+            match self.follow(BufReader::new(stream), context, shutdown) {
+                Ok(()) => break,
+                Err(error) => {
+                    warn!(
+                        primary = %self.primary,
+                        error = format!("{error:#}"),
+                        "lost the primary, serving the last state"
+                    );
+                    for sink in &mut self.sinks {
+                        sink.flush(context)?;
+                    }
+                    std::thread::sleep(self.retry);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the frames of one connection, `Ok` on a shutdown.
+    fn follow(
+        &mut self,
+        mut reader: impl Read,
+        context: &mut TransactionContext,
+        shutdown: &Shutdown,
+    ) -> anyhow::Result<()> {
+        let mut header = [0; 6];
+        if !read_exact(&mut reader, &mut header, shutdown)? {
+            return Ok(());
+        }
+        if header[..4] != MAGIC {
+            anyhow::bail!("not a primary");
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != VERSION {
+            anyhow::bail!("primary ships version {version}, this engine supports {VERSION}");
+        }
+
+        loop {
+            let mut head = [0; 5];
+            if !read_exact(&mut reader, &mut head, shutdown)? {
+                return Ok(());
+            }
+            let len = u32::from_le_bytes([head[1], head[2], head[3], head[4]]);
+            let mut payload = vec![0; len as usize];
+            if !read_exact(&mut reader, &mut payload, shutdown)? {
+                return Ok(());
+            }
+            match head[0] {
+                SNAPSHOT => self.reset(context, &payload)?,
+                EVENT => {
+                    let event = postcard::from_bytes::<EventRecord>(&payload)?.event()?;
+                    self.apply(context, &event);
+                }
+                PRUNE if payload.len() == 2 => {
+                    let client_id = u16::from_le_bytes([payload[0], payload[1]]);
+                    for pruned in context.prune_accounts(&[client_id]) {
+                        for sink in &mut self.sinks {
+                            sink.prune(&pruned);
+                        }
+                    }
+                    self.publish(context, client_id);
+                }
+                tag => anyhow::bail!("unknown record {tag}"),
+            }
+        }
+    }
+
+    /// Replaces the state with the snapshot of the primary.
+    fn reset(&mut self, context: &mut TransactionContext, snapshot: &[u8]) -> anyhow::Result<()> {
+        let clients: Vec<_> = context.iter_accounts().map(|(client, _)| client).collect();
+        context.prune_accounts(&clients);
+        read_binary_snapshot(snapshot, context)?;
+        if let Some(view) = &self.state_view {
+            for client in clients {
+                view.remove(client);
+            }
+            for (client, account) in context.iter_accounts() {
+                view.update(client, *account);
+            }
+        }
+        info!(
+            accounts = context.account_count(),
+            "synced with the primary"
+        );
+        Ok(())
+    }
+
+    fn apply(&mut self, context: &mut TransactionContext, event: &TransactionEvent) {
+        let start = Instant::now();
+        let result = context.apply(event).map(|_| ());
+        let metrics = metrics();
+        metrics.record_event(event.ty, start.elapsed());
+        if let Err(error) = result {
+            metrics.record_reject(error);
+        }
+        metrics.set_tracked(
+            context.account_count(),
+            context.transaction_count(),
+            context.memory_usage(),
+        );
+
+        let account = context.account(event.client_id);
+        for sink in &mut self.sinks {
+            sink.record(event, result, account);
+        }
+        self.publish(context, event.client_id);
+        if let Some(merged) = event.merged_client {
+            self.publish(context, merged);
+        }
+        if let (TransactionType::Chargeback, Some(suspense)) =
+            (event.ty, context.suspense_account())
+        {
+            self.publish(context, suspense);
+        }
+    }
+
+    fn publish(&self, context: &TransactionContext, client_id: u16) {
+        if let Some(view) = &self.state_view {
+            match context.account(client_id) {
+                Some(account) => view.update(client_id, *account),
+                None => view.remove(client_id),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(ty: TransactionType, client_id: u16, tx: u32, amount: i64) -> TransactionEvent {
+        TransactionEvent {
+            timestamp: Some(1_700_000_000),
+            reason: "ops-fix".parse().unwrap(),
+            reference: Reference::intern("INV-1"),
+            ..TransactionEvent::new(ty, client_id, tx, Price(amount))
+        }
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut primary = TransactionContext::new();
+        let deposit = event(TransactionType::Deposit, 1, 1, 100_000);
+        primary.apply(&deposit).unwrap();
+        let mut replication = Replication::listen("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = replication.local_addr();
+
+        let shutdown = Shutdown::default();
+        let view = StateView::new();
+        let follower = std::thread::spawn({
+            let (shutdown, view) = (shutdown.clone(), view.clone());
+            move || {
+                let mut context = TransactionContext::new();
+                // replaced by the snapshot
+                context.insert_account(7, Default::default());
+                Follower::new(addr)
+                    .retry(Duration::from_millis(10))
+                    .with_state_view(view)
+                    .run(&mut context, &shutdown)
+                    .unwrap();
+                context
+            }
+        });
+        while !replication.has_joining() {
+            std::thread::yield_now();
+        }
+        replication.admit(&primary);
+        for event in [
+            event(TransactionType::Deposit, 2, 2, 50_000),
+            event(TransactionType::Dispute, 1, 1, 0),
+            event(TransactionType::Withdrawal, 2, 3, 90_000),
+            event(TransactionType::Deposit, 3, 4, 10_000),
+        ] {
+            let _ = primary.apply(&event);
+            replication.ship(&event);
+        }
+        while view.account(3).is_none() {
+            // a connection the follower gave up on gets admitted as well, the
+            // snapshot of its retry already holds the events
+            if replication.has_joining() {
+                replication.admit(&primary);
+            }
+            std::thread::yield_now();
+        }
+        shutdown.request();
+        let follower = follower.join().unwrap();
+        assert_eq!(follower.state_digest(), primary.state_digest());
+        assert_eq!(view.account(7), None);
+        assert_eq!(view.account(1).unwrap().held, Price(100_000));
+
+        let record = EventRecord::new(&deposit).event().unwrap();
+        assert_eq!(record.reason, deposit.reason);
+        assert_eq!(record.reference, deposit.reference);
+    }
+}
@@ -0,0 +1,192 @@
+//! Risk scoring of accounts, so review queues downstream can be prioritized.
+//! A [`RiskScorer`] assigns every account a score between 0 (no risk) and 1,
+//! either one of the builtin [`Heuristic`]s or an implementation of the user.
+use crate::{
+    data_types::{Account, TransactionError, TransactionEvent},
+    pipeline::Sink,
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use std::{collections::HashMap, str::FromStr};
+
+/// Events per hour at which [`Heuristic::Velocity`] scores 1.
+pub const VELOCITY_LIMIT: f64 = 100.0;
+
+/// Activity of a client, applied and rejected events alike.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Activity {
+    pub events: u64,
+    pub rejected: u64,
+    /// timestamps of the first and last event that had one
+    pub first_seen: Option<u64>,
+    pub last_seen: Option<u64>,
+}
+
+pub trait RiskScorer {
+    /// Scores the account, from 0 (no risk) to 1.
+    fn score(&self, account: &Account, activity: &Activity) -> f64;
+}
+
+impl<F: Fn(&Account, &Activity) -> f64> RiskScorer for F {
+    fn score(&self, account: &Account, activity: &Activity) -> f64 {
+        self(account, activity)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Heuristic {
+    /// halves the distance to 1 with every chargeback
+    Chargebacks,
+    /// disputes per applied deposit or withdrawal
+    DisputeRatio,
+    /// events per hour, relative to [`VELOCITY_LIMIT`]
+    Velocity,
+}
+
+impl FromStr for Heuristic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "chargebacks" => Ok(Heuristic::Chargebacks),
+            "dispute-ratio" => Ok(Heuristic::DisputeRatio),
+            "velocity" => Ok(Heuristic::Velocity),
+            _ => Err(format!(
+                "unknown heuristic `{s}`, expected chargebacks, dispute-ratio or velocity"
+            )),
+        }
+    }
+}
+
+impl RiskScorer for Heuristic {
+    fn score(&self, account: &Account, activity: &Activity) -> f64 {
+        let meta = &account.meta;
+        let score = match self {
+            Heuristic::Chargebacks => 1.0 - 0.5f64.powi(meta.chargebacks as i32),
+            Heuristic::DisputeRatio => meta.disputes as f64 / meta.tx_count.max(1) as f64,
+            Heuristic::Velocity => {
+                let (Some(first), Some(last)) = (activity.first_seen, activity.last_seen) else {
+                    return 0.0;
+                };
+                // a burst within the hour counts as an hour
+                let hours = (last.saturating_sub(first) as f64 / 3600.0).max(1.0);
+                activity.events as f64 / hours / VELOCITY_LIMIT
+            }
+        };
+        score.clamp(0.0, 1.0)
+    }
+}
+
+/// Scores the highest of the given heuristics.
+impl RiskScorer for Vec<Heuristic> {
+    fn score(&self, account: &Account, activity: &Activity) -> f64 {
+        self.iter()
+            .map(|heuristic| heuristic.score(account, activity))
+            .fold(0.0, f64::max)
+    }
+}
+
+/// [`Sink`] following the activity of every client, to score the accounts
+/// once all events are processed.
+pub struct RiskScoring {
+    scorer: Box<dyn RiskScorer>,
+    activity: HashMap<u16, Activity>,
+}
+
+impl std::fmt::Debug for RiskScoring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RiskScoring")
+            .field("clients", &self.activity.len())
+            .finish()
+    }
+}
+
+impl RiskScoring {
+    pub fn new(scorer: impl RiskScorer + 'static) -> Self {
+        RiskScoring {
+            scorer: Box::new(scorer),
+            activity: HashMap::new(),
+        }
+    }
+
+    /// Scores the account of the client.
+    pub fn score(&self, client_id: u16, account: &Account) -> f64 {
+        let activity = self.activity.get(&client_id).copied().unwrap_or_default();
+        self.scorer.score(account, &activity)
+    }
+
+    /// Scores all accounts of the context.
+    pub fn scores(&self, context: &TransactionContext) -> HashMap<u16, f64> {
+        context
+            .iter_accounts()
+            .map(|(client_id, account)| (client_id, self.score(client_id, account)))
+            .collect()
+    }
+}
+
+impl Sink for RiskScoring {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        _account: Option<&Account>,
+    ) {
+        let activity = self.activity.entry(event.client_id).or_default();
+        activity.events += 1;
+        activity.rejected += result.is_err() as u64;
+        if let Some(timestamp) = event.timestamp {
+            activity.first_seen = Some(activity.first_seen.map_or(timestamp, |t| t.min(timestamp)));
+            activity.last_seen = Some(activity.last_seen.map_or(timestamp, |t| t.max(timestamp)));
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.activity.remove(&pruned.client_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionType};
+
+    #[test]
+    fn test_heuristics() {
+        let event = |ty, client_id, tx, timestamp| TransactionEvent {
+            timestamp: Some(timestamp),
+            ..TransactionEvent::new(ty, client_id, tx, Price(10_000))
+        };
+        let mut context = TransactionContext::new();
+        let mut scoring = RiskScoring::new(vec![
+            Heuristic::Chargebacks,
+            Heuristic::DisputeRatio,
+            Heuristic::Velocity,
+        ]);
+        let mut events = vec![
+            event(TransactionType::Deposit, 1, 1, 0),
+            event(TransactionType::Deposit, 1, 2, 0),
+            event(TransactionType::Dispute, 1, 1, 60),
+        ];
+        // 150 deposits in two hours
+        events.extend((0..150).map(|i| event(TransactionType::Deposit, 2, 10 + i, i as u64 * 48)));
+        for event in events {
+            let result = context.apply(&event).map(|_| ());
+            scoring.record(&event, result, context.account(event.client_id));
+        }
+
+        let scores = scoring.scores(&context);
+        assert_eq!(scores[&1], 0.5);
+        assert!((scores[&2] - 0.75).abs() < 0.01, "{}", scores[&2]);
+
+        let chargebacks =
+            |account: &Account| Heuristic::Chargebacks.score(account, &Activity::default());
+        let mut account = Account::default();
+        assert_eq!(chargebacks(&account), 0.0);
+        account.meta.chargebacks = 2;
+        assert_eq!(chargebacks(&account), 0.75);
+
+        // anything scoring accounts is a scorer
+        let scoring =
+            RiskScoring::new(|account: &Account, _: &Activity| account.locked as u8 as f64);
+        assert_eq!(scoring.score(1, &account), 0.0);
+    }
+}
@@ -0,0 +1,383 @@
+//! [`StateStore`] persisted in RocksDB, with a column family for the accounts
+//! and one for the transactions.
+use crate::{
+    data_types::Account,
+    state_store::{
+        decode_account, decode_position, decode_transaction, encode_account, encode_transaction,
+        map_bytes, Ledger, MemoryUsage, StateStore, StoredTransaction, TxFilter, POSITION_KEY,
+    },
+};
+use rocksdb::{ColumnFamilyDescriptor, IteratorMode, Options, WriteBatch, DB};
+use std::{collections::HashMap, path::Path};
+use tracing::{error, info};
+
+const ACCOUNTS: &str = "accounts";
+const TRANSACTIONS: &str = "transactions";
+const META: &str = "meta";
+
+/// Amount of writes collected before the batch is written to the database.
+const BATCH_SIZE: usize = 4096;
+
+/// Accounts are cached in memory, writes are collected in a batch that is
+/// written when it is full or on [`StateStore::flush`]. Transactions in the
+/// pending batch are kept in memory so they can be read back, a filter of the
+/// stored ids saves the lookup of new transactions.
+pub struct RocksStore {
+    db: DB,
+    accounts: HashMap<u16, Account>,
+    /// `None` once removed
+    pending: HashMap<u32, Option<StoredTransaction>>,
+    batch: WriteBatch,
+    filter: TxFilter,
+    transaction_count: usize,
+    position: Option<u64>,
+    /// the batch is only written on [`StateStore::commit`]
+    exactly_once: bool,
+    /// a batch that failed to be written when it was full, the next
+    /// [`StateStore::flush`] or [`StateStore::commit`] fails with it
+    write_error: Option<rocksdb::Error>,
+}
+
+impl std::fmt::Debug for RocksStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RocksStore")
+            .field("path", &self.db.path())
+            .field("accounts", &self.accounts.len())
+            .field("pending", &self.batch.len())
+            .field("transaction_count", &self.transaction_count)
+            .finish()
+    }
+}
+
+impl RocksStore {
+    /// Opens or creates the database in the given directory and loads the
+    /// accounts it holds.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let families = [ACCOUNTS, TRANSACTIONS, META]
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&options, path, families)?;
+
+        let mut accounts = HashMap::new();
+        for entry in db.iterator_cf(cf(&db, ACCOUNTS), IteratorMode::Start) {
+            let (key, value) = entry?;
+            let (Ok(key), Some(account)) = (key.as_ref().try_into(), decode_account(&value)) else {
+                anyhow::bail!("corrupt account in {}", path.display());
+            };
+            accounts.insert(u16::from_be_bytes(key), account);
+        }
+        // rocksdb only estimates the amount of keys, so they are counted once
+        let mut filter = TxFilter::new();
+        let mut transaction_count = 0;
+        for entry in db.iterator_cf(cf(&db, TRANSACTIONS), IteratorMode::Start) {
+            let Ok(key) = entry?.0.as_ref().try_into() else {
+                anyhow::bail!("corrupt transaction in {}", path.display());
+            };
+            filter.insert(u32::from_be_bytes(key));
+            transaction_count += 1;
+        }
+        let position = match db.get_cf(cf(&db, META), POSITION_KEY)? {
+            Some(value) => Some(
+                decode_position(&value)
+                    .ok_or_else(|| anyhow::anyhow!("corrupt position in {}", path.display()))?,
+            ),
+            None => None,
+        };
+        info!(
+            path = %path.display(),
+            accounts = accounts.len(),
+            transactions = transaction_count,
+            position,
+            "opened state store"
+        );
+
+        Ok(RocksStore {
+            db,
+            accounts,
+            pending: HashMap::new(),
+            batch: WriteBatch::default(),
+            filter,
+            transaction_count,
+            position,
+            exactly_once: false,
+            write_error: None,
+        })
+    }
+
+    /// Like [`RocksStore::open`], but the batch is only written on
+    /// [`StateStore::commit`], however large it grows.
+    pub fn open_exactly_once(path: &Path) -> anyhow::Result<Self> {
+        let mut store = Self::open(path)?;
+        store.exactly_once = true;
+        Ok(store)
+    }
+
+    /// Writes the batch, failing as well when an earlier batch could not be
+    /// written, its writes are lost.
+    fn write_batch(&mut self) -> anyhow::Result<()> {
+        if let Some(error) = self.write_error.take() {
+            anyhow::bail!("failed to persist state: {error}");
+        }
+        let batch = std::mem::take(&mut self.batch);
+        self.pending.clear();
+        self.db.write(batch)?;
+        Ok(())
+    }
+
+    fn persist_account(&mut self, client_id: u16, account: &Account) {
+        self.batch.put_cf(
+            cf(&self.db, ACCOUNTS),
+            client_id.to_be_bytes(),
+            encode_account(account),
+        );
+        self.write_batch_if_full();
+    }
+
+    fn write_batch_if_full(&mut self) {
+        if self.exactly_once || self.batch.len() < BATCH_SIZE || self.write_error.is_some() {
+            return;
+        }
+        let batch = std::mem::take(&mut self.batch);
+        self.pending.clear();
+        if let Err(error) = self.db.write(batch) {
+            error!(%error, "failed to persist state");
+            self.write_error = Some(error);
+        }
+    }
+}
+
+fn cf<'a>(db: &'a DB, name: &str) -> &'a rocksdb::ColumnFamily {
+    db.cf_handle(name)
+        .expect("column families are created on open")
+}
+
+impl Ledger for RocksStore {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        if !self.filter.may_contain(tx) {
+            return None;
+        }
+        if let Some(transaction) = self.pending.get(&tx) {
+            return *transaction;
+        }
+        match self
+            .db
+            .get_pinned_cf(cf(&self.db, TRANSACTIONS), tx.to_be_bytes())
+        {
+            Ok(value) => value.and_then(|value| decode_transaction(&value)),
+            Err(error) => {
+                error!(%error, tx, "failed to read transaction");
+                None
+            }
+        }
+    }
+
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        // updates and merges store a transaction again
+        if self.transaction(tx).is_none() {
+            self.transaction_count += 1;
+        }
+        self.filter.insert(tx);
+        self.pending.insert(tx, Some(transaction));
+        self.batch.put_cf(
+            cf(&self.db, TRANSACTIONS),
+            tx.to_be_bytes(),
+            encode_transaction(&transaction),
+        );
+        self.write_batch_if_full();
+    }
+
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account {
+        let account = self.accounts.entry(client_id).or_default();
+        update(account);
+        let account = *account;
+        self.persist_account(client_id, &account);
+        account
+    }
+}
+
+impl StateStore for RocksStore {
+    fn account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    fn put_account(&mut self, client_id: u16, account: Account) {
+        self.accounts.insert(client_id, account);
+        self.persist_account(client_id, &account);
+    }
+
+    fn reserve_accounts(&mut self, additional: usize) {
+        self.accounts.reserve(additional);
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (u16, &Account)> + '_> {
+        Box::new(self.accounts.iter().map(|(id, account)| (*id, account)))
+    }
+
+    fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transaction_count
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = (u32, StoredTransaction)> + '_> {
+        // the pending batch holds the latest state of its transactions
+        let stored = self
+            .db
+            .iterator_cf(cf(&self.db, TRANSACTIONS), IteratorMode::Start)
+            .filter_map(|entry| match entry {
+                Ok((key, value)) => {
+                    let key = key.as_ref().try_into().ok().map(u32::from_be_bytes);
+                    key.zip(decode_transaction(&value))
+                }
+                Err(error) => {
+                    error!(%error, "failed to read transactions");
+                    None
+                }
+            })
+            .filter(|(tx, _)| !self.pending.contains_key(tx));
+        Box::new(
+            self.pending
+                .iter()
+                .filter_map(|(tx, transaction)| transaction.map(|transaction| (*tx, transaction)))
+                .chain(stored),
+        )
+    }
+
+    fn remove_account(&mut self, client_id: u16) -> Option<Account> {
+        self.batch
+            .delete_cf(cf(&self.db, ACCOUNTS), client_id.to_be_bytes());
+        self.write_batch_if_full();
+        self.accounts.remove(&client_id)
+    }
+
+    fn remove_transactions(&mut self, remove: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        // the ids stay in the filter, it only costs a lookup when they recur
+        let removed: Vec<_> = self
+            .transactions()
+            .filter(|(_, transaction)| remove(transaction))
+            .map(|(tx, _)| tx)
+            .collect();
+        for tx in &removed {
+            self.pending.insert(*tx, None);
+            self.batch
+                .delete_cf(cf(&self.db, TRANSACTIONS), tx.to_be_bytes());
+            self.transaction_count -= 1;
+            self.write_batch_if_full();
+        }
+        removed.len()
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            accounts: map_bytes(&self.accounts),
+            transactions: self.filter.bytes() + map_bytes(&self.pending),
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.exactly_once {
+            return Ok(());
+        }
+        self.write_batch()?;
+        self.db.flush_wal(true)?;
+        Ok(())
+    }
+
+    fn position(&self) -> Option<u64> {
+        self.position
+    }
+
+    fn commit(&mut self, position: u64) -> anyhow::Result<()> {
+        self.batch
+            .put_cf(cf(&self.db, META), POSITION_KEY, position.to_be_bytes());
+        self.write_batch()?;
+        self.db.flush_wal(true)?;
+        self.position = Some(position);
+        Ok(())
+    }
+}
+
+impl Drop for RocksStore {
+    fn drop(&mut self) {
+        // uncommitted writes of exactly-once processing are lost, as after a
+        // crash
+        if self.exactly_once {
+            return;
+        }
+        if let Err(error) = self.write_batch() {
+            // losing the writes silently would leave the state behind the
+            // input that was already consumed
+            if !std::thread::panicking() {
+                panic!("{error:#}");
+            }
+            error!(%error, "failed to persist state");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Price, TransactionEvent, TransactionFlags, TransactionType},
+        transaction_context::TransactionContext,
+    };
+
+    #[test]
+    fn test_state_survives_reopen() {
+        let path = std::env::temp_dir().join("txe_test_rocksdb_store");
+        let _ = std::fs::remove_dir_all(&path);
+        let deposit = TransactionEvent::new(TransactionType::Deposit, 1, 1, Price(10_000));
+
+        {
+            let mut context =
+                TransactionContext::with_store(Box::new(RocksStore::open(&path).unwrap()));
+            context
+                .handle_transaction(&deposit, Account::deposit, true)
+                .unwrap();
+            // read back from the pending batch
+            assert_eq!(
+                context.handle_transaction(&deposit, Account::deposit, true),
+                Err(crate::data_types::TransactionError::Duplicate)
+            );
+            context.flush().unwrap();
+        }
+
+        let mut context =
+            TransactionContext::with_store(Box::new(RocksStore::open(&path).unwrap()));
+        assert_eq!(context.account(1).unwrap().total, Price(10_000));
+        assert_eq!(context.account(1).unwrap().meta.tx_count, 1);
+        assert_eq!(context.transaction_count(), 1);
+        assert_eq!(
+            context.handle_transaction(&deposit, Account::deposit, true),
+            Err(crate::data_types::TransactionError::Duplicate)
+        );
+        drop(context);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_transaction_count() {
+        let path = std::env::temp_dir().join("txe_test_rocksdb_count");
+        let _ = std::fs::remove_dir_all(&path);
+        let mut store = RocksStore::open(&path).unwrap();
+        store.put_transaction(1, (Price(10), TransactionFlags::None, 1));
+        // a merge hands the transaction over to another client
+        store.put_transaction(1, (Price(10), TransactionFlags::None, 2));
+        store.put_transaction(1, (Price(10), TransactionFlags::Disputed, 2));
+        store.put_transaction(2, (Price(10), TransactionFlags::None, 2));
+        assert_eq!(store.transaction_count(), 2);
+        assert_eq!(store.remove_transactions(&|(_, _, client)| *client == 2), 2);
+        store.put_transaction(1, (Price(10), TransactionFlags::None, 1));
+        assert_eq!(store.transaction_count(), 1);
+        store.flush().unwrap();
+        drop(store);
+
+        assert_eq!(RocksStore::open(&path).unwrap().transaction_count(), 1);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,217 @@
+//! Declarative row-level validations, see `--rules`. The rules are a
+//! [`Validator`], checked by the validate stage of the [`crate::pipeline`].
+//! Events that violate a rule are not applied, they reach the sinks as
+//! rejected with [`TransactionError::RuleViolation`].
+//!
+//! ```toml
+//! # client ids that may appear, as for --clients
+//! clients = "1-1000,5000"
+//!
+//! # limits of the absolute amount of deposits, withdrawals, adjustments and
+//! # recoveries
+//! [amount]
+//! min = 0.01
+//! max = 10000.0
+//!
+//! # optional columns that need a value for events of the type
+//! [required]
+//! deposit = ["timestamp"]
+//! adjustment = ["reason_code", "reference"]
+//! ```
+//!
+//! [`TransactionError::RuleViolation`]: crate::data_types::TransactionError::RuleViolation
+use crate::{
+    data_types::{Price, TransactionEvent, TransactionType},
+    filter::ClientSet,
+    pipeline::Validator,
+};
+use serde::{Deserialize, Deserializer};
+use std::{collections::BTreeMap, fmt::Display, path::Path};
+
+/// Optional columns that can be required.
+pub const OPTIONAL_COLUMNS: [&str; 5] = [
+    "amount",
+    "timestamp",
+    "reason_code",
+    "merged_client",
+    "reference",
+];
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AmountLimits {
+    pub min: Option<Price>,
+    pub max: Option<Price>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    /// client ids that may appear, all when not given
+    #[serde(default, deserialize_with = "deserialize_clients")]
+    pub clients: Option<ClientSet>,
+    #[serde(default)]
+    pub amount: AmountLimits,
+    /// transaction type to the optional columns that need a value
+    #[serde(default)]
+    pub required: BTreeMap<String, Vec<String>>,
+}
+
+fn deserialize_clients<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<ClientSet>, D::Error> {
+    let spec = String::deserialize(deserializer)?;
+    spec.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Why an event violates the rules.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    Client,
+    AmountBelow(Price),
+    AmountAbove(Price),
+    Missing(&'static str),
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Client => write!(f, "client is not allowed"),
+            Violation::AmountBelow(min) => write!(f, "amount is below {min}"),
+            Violation::AmountAbove(max) => write!(f, "amount is above {max}"),
+            Violation::Missing(column) => write!(f, "`{column}` is missing"),
+        }
+    }
+}
+
+impl Rules {
+    /// Loads the rules from a toml file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let rules: Rules =
+            toml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        rules
+            .validate()
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        Ok(rules)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let (Some(min), Some(max)) = (self.amount.min, self.amount.max) {
+            if min > max {
+                return Err(format!("amount min {min} exceeds max {max}"));
+            }
+        }
+        for (ty, columns) in &self.required {
+            ty.parse::<TransactionType>()?;
+            if let Some(column) = columns
+                .iter()
+                .find(|c| !OPTIONAL_COLUMNS.contains(&c.as_str()))
+            {
+                return Err(format!(
+                    "unknown column `{column}`, expected one of {}",
+                    OPTIONAL_COLUMNS.join(", ")
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the event against the rules, returns the first violation.
+    pub fn check(&self, event: &TransactionEvent) -> Result<(), Violation> {
+        if let Some(clients) = &self.clients {
+            if !clients.contains(event.client_id) {
+                return Err(Violation::Client);
+            }
+        }
+
+        if matches!(
+            event.ty,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Adjustment
+                | TransactionType::Recovery
+        ) {
+            let amount = Price(event.amount.0.saturating_abs());
+            if let Some(min) = self.amount.min.filter(|min| amount < *min) {
+                return Err(Violation::AmountBelow(min));
+            }
+            if let Some(max) = self.amount.max.filter(|max| amount > *max) {
+                return Err(Violation::AmountAbove(max));
+            }
+        }
+
+        for column in self.required.get(event.ty.as_str()).into_iter().flatten() {
+            let (column, present) = match column.as_str() {
+                "amount" => ("amount", event.amount.0 != 0),
+                "timestamp" => ("timestamp", event.timestamp.is_some()),
+                "reason_code" => ("reason_code", !event.reason.is_empty()),
+                "merged_client" => ("merged_client", event.merged_client.is_some()),
+                _ => ("reference", event.reference.is_some()),
+            };
+            if !present {
+                return Err(Violation::Missing(column));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Validator for Rules {
+    fn validate(&mut self, event: &TransactionEvent) -> Result<(), String> {
+        self.check(event).map_err(|violation| violation.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let rules: Rules = toml::from_str(
+            r#"
+            clients = "1-10"
+
+            [amount]
+            min = 1.0
+            max = 100.0
+
+            [required]
+            deposit = ["timestamp"]
+            "#,
+        )
+        .unwrap();
+        assert!(rules.validate().is_ok());
+
+        let event = |ty, client_id, amount, timestamp| TransactionEvent {
+            timestamp,
+            ..TransactionEvent::new(ty, client_id, 1, Price(amount))
+        };
+        use TransactionType::*;
+        assert_eq!(rules.check(&event(Deposit, 1, 10_000, Some(0))), Ok(()));
+        assert_eq!(
+            rules.check(&event(Deposit, 11, 10_000, Some(0))),
+            Err(Violation::Client)
+        );
+        assert_eq!(
+            rules.check(&event(Withdrawal, 1, 5_000, None)),
+            Err(Violation::AmountBelow(Price(10_000)))
+        );
+        assert_eq!(
+            rules.check(&event(Adjustment, 1, -1_000_001, None)),
+            Err(Violation::AmountAbove(Price(1_000_000)))
+        );
+        assert_eq!(
+            rules.check(&event(Deposit, 1, 10_000, None)),
+            Err(Violation::Missing("timestamp"))
+        );
+        // disputes carry no amount
+        assert_eq!(rules.check(&event(Dispute, 1, 0, None)), Ok(()));
+
+        let invalid: Rules = toml::from_str("[required]\ndeposit = [\"client\"]").unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: Rules = toml::from_str("[required]\nrefund = [\"amount\"]").unwrap();
+        assert!(invalid.validate().is_err());
+    }
+}
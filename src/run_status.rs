@@ -0,0 +1,175 @@
+use crate::{
+    conservation::ConservationError, data_types::TransactionError, metrics::metrics,
+    reconcile::ReconciliationError, schema::ColumnSuggestion,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, path::Path, process::ExitCode, time::Duration};
+
+/// Outcome of a run, each outcome maps onto a distinct exit code so
+/// orchestration tooling can branch on it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Success,
+    /// all input was processed, but some events were rejected
+    SuccessWithRejects,
+    /// unparsable rows were encountered while running in strict mode
+    ParseFailures,
+    IoError,
+    /// the engine detected an inconsistency, e.g. events got lost
+    InvariantViolation,
+    /// the state digest differs from `--expect-digest`
+    DigestMismatch,
+    /// `reconcile` found balances that differ from the expected ones
+    ReconciliationMismatch,
+    /// any other error
+    Error,
+}
+
+impl Outcome {
+    /// Determines the outcome from the error the run ended with (if any) and
+    /// the process wide [`metrics`].
+    pub fn classify(error: Option<&anyhow::Error>, strict: bool) -> Self {
+        if let Some(error) = error {
+            if error.chain().any(|cause| cause.is::<ConservationError>()) {
+                return Outcome::InvariantViolation;
+            }
+            if error.chain().any(|cause| cause.is::<ReconciliationError>()) {
+                return Outcome::ReconciliationMismatch;
+            }
+            let is_io = error.chain().any(|cause| {
+                cause.is::<std::io::Error>()
+                    || cause
+                        .downcast_ref::<csv::Error>()
+                        .is_some_and(|e| e.is_io_error())
+            });
+            return if is_io {
+                Outcome::IoError
+            } else {
+                Outcome::Error
+            };
+        }
+
+        let metrics = metrics();
+        if metrics.source_errors() > 0 {
+            // the input was not read completely
+            Outcome::IoError
+        } else if metrics.events_lost() > 0 {
+            Outcome::InvariantViolation
+        } else if strict && metrics.parse_failures() > 0 {
+            Outcome::ParseFailures
+        } else if metrics.rejects_total() > 0 {
+            Outcome::SuccessWithRejects
+        } else {
+            Outcome::Success
+        }
+    }
+
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Outcome::Success => 0,
+            Outcome::Error => 1,
+            Outcome::SuccessWithRejects => 3,
+            Outcome::ParseFailures => 4,
+            Outcome::IoError => 5,
+            Outcome::InvariantViolation => 6,
+            Outcome::DigestMismatch => 7,
+            Outcome::ReconciliationMismatch => 8,
+        }
+    }
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        ExitCode::from(outcome.exit_code())
+    }
+}
+
+/// Machine readable summary of a run.
+#[derive(Debug, Serialize)]
+pub struct RunStatus {
+    pub outcome: Outcome,
+    pub exit_code: u8,
+    pub duration_ms: u128,
+    pub rows_read: u64,
+    pub events_processed: u64,
+    pub rejects: u64,
+    pub rejects_by_reason: BTreeMap<&'static str, u64>,
+    pub parse_failures: u64,
+    /// sources that stopped early, e.g. at an I/O error or a truncated row
+    pub source_errors: u64,
+    /// rows of a custom passthrough type, see the schema
+    pub passthrough_rows: u64,
+    /// events dropped by a pipeline stage, e.g. the client filter
+    pub events_dropped: u64,
+    pub events_lost: u64,
+    pub accounts: u64,
+    /// approximate bytes held in memory by the state store
+    pub state_memory_bytes: u64,
+    /// see [`TransactionContext::state_digest`]
+    ///
+    /// [`TransactionContext::state_digest`]: crate::transaction_context::TransactionContext::state_digest
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state_digest: Option<String>,
+    /// text encoding every input file was read with
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub input_encodings: BTreeMap<String, &'static str>,
+    /// columns resembling a canonical column missing from the header, per
+    /// input file, see [`suggest_columns`]
+    ///
+    /// [`suggest_columns`]: crate::schema::suggest_columns
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub column_suggestions: BTreeMap<String, Vec<ColumnSuggestion>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RunStatus {
+    pub fn new(outcome: Outcome, duration: Duration, error: Option<&anyhow::Error>) -> Self {
+        let metrics = metrics();
+        RunStatus {
+            outcome,
+            exit_code: outcome.exit_code(),
+            duration_ms: duration.as_millis(),
+            rows_read: metrics.rows_read(),
+            events_processed: metrics.events_total(),
+            rejects: metrics.rejects_total(),
+            rejects_by_reason: TransactionError::ALL
+                .iter()
+                .map(|e| (e.as_str(), metrics.rejects(*e)))
+                .filter(|(_, count)| *count > 0)
+                .collect(),
+            parse_failures: metrics.parse_failures(),
+            source_errors: metrics.source_errors(),
+            passthrough_rows: metrics.passthrough_rows(),
+            events_dropped: metrics.dropped_total(),
+            events_lost: metrics.events_lost(),
+            accounts: metrics.accounts_tracked(),
+            state_memory_bytes: metrics.state_memory().total() as u64,
+            state_digest: None,
+            input_encodings: metrics.input_encodings(),
+            column_suggestions: metrics.column_suggestions(),
+            error: error.map(|e| format!("{e:#}")),
+        }
+    }
+
+    pub fn write_json(&self, path: &Path) -> anyhow::Result<()> {
+        let file = std::fs::File::create(path)?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_errors() {
+        let io = anyhow::Error::from(std::io::Error::other("disk full")).context("writing output");
+        assert_eq!(Outcome::classify(Some(&io), false), Outcome::IoError);
+
+        let other = anyhow::anyhow!("something else");
+        assert_eq!(Outcome::classify(Some(&other), false), Outcome::Error);
+    }
+}
@@ -0,0 +1,613 @@
+use crate::{
+    admin::SIGNATURE_COLUMN,
+    data_types::{ParseDecimalError, Price, Rounding, TransactionType},
+    encoding::parse_encoding,
+};
+use csv::StringRecord;
+use encoding_rs::Encoding;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    path::Path,
+    str::FromStr,
+};
+
+/// Columns of the canonical input format, in order.
+pub const COLUMNS: [&str; 6] = ["type", "client", "tx", "amount", "timestamp", "reason_code"];
+
+/// Options of the csv reader.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub quote: char,
+    /// lines starting with this character are skipped
+    pub comment: Option<char>,
+    /// whether the first line is a header, without one the columns are
+    /// expected in the canonical order
+    pub headers: bool,
+    /// text encoding of the file, detected when not given
+    #[serde(deserialize_with = "deserialize_encoding")]
+    pub encoding: Option<&'static Encoding>,
+    /// how amounts with more than four decimals are rounded, half away from
+    /// zero when not given
+    #[serde(deserialize_with = "deserialize_parsed")]
+    pub rounding: Option<Rounding>,
+    /// separators of the amounts, `1234.56` when not given
+    #[serde(deserialize_with = "deserialize_parsed")]
+    pub locale: Option<Locale>,
+    /// what happens to amounts beyond the range of a price
+    #[serde(deserialize_with = "deserialize_parsed")]
+    pub out_of_range: Option<OutOfRange>,
+}
+
+impl CsvOptions {
+    /// How to parse the amounts, `None` when they are parsed as plain floats.
+    pub fn amount_format(&self) -> Option<AmountFormat> {
+        let configured =
+            self.rounding.is_some() || self.locale.is_some() || self.out_of_range.is_some();
+        configured.then_some(AmountFormat {
+            rounding: self.rounding.unwrap_or_default(),
+            locale: self.locale,
+            out_of_range: self.out_of_range.unwrap_or_default(),
+        })
+    }
+}
+
+fn deserialize_encoding<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static Encoding>, D::Error> {
+    let label = String::deserialize(deserializer)?;
+    parse_encoding(&label)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_parsed<'de, D: Deserializer<'de>, T: FromStr<Err = String>>(
+    deserializer: D,
+) -> Result<Option<T>, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    value.parse().map(Some).map_err(serde::de::Error::custom)
+}
+
+/// Decimal and thousands separators of the amounts of an export.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Locale {
+    /// `1,234.56`
+    En,
+    /// `1.234,56`
+    De,
+    /// `1 234,56`, also with a (narrow) no-break space
+    Fr,
+    /// `1'234.56`
+    Ch,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 4] = [Locale::En, Locale::De, Locale::Fr, Locale::Ch];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::De => "de",
+            Locale::Fr => "fr",
+            Locale::Ch => "ch",
+        }
+    }
+
+    fn decimal_separator(&self) -> char {
+        match self {
+            Locale::En | Locale::Ch => '.',
+            Locale::De | Locale::Fr => ',',
+        }
+    }
+
+    fn is_thousands_separator(&self, c: char) -> bool {
+        match self {
+            Locale::En => c == ',',
+            Locale::De => c == '.',
+            Locale::Fr => matches!(c, ' ' | '\u{a0}' | '\u{202f}'),
+            Locale::Ch => c == '\'',
+        }
+    }
+
+    /// Rewrites an amount to the canonical format, e.g. `-1.234,56` to
+    /// `-1234.56`. Fails when the thousands separators do not separate groups
+    /// of three digits.
+    pub fn normalize(&self, amount: &str) -> Option<String> {
+        let amount = amount.trim();
+        let (sign, amount) = match amount.strip_prefix('-') {
+            Some(amount) => ("-", amount),
+            None => ("", amount),
+        };
+        let (integral, fractional) = match amount.split_once(self.decimal_separator()) {
+            Some((integral, fractional)) => (integral, Some(fractional)),
+            None => (amount, None),
+        };
+        let mut groups = integral.split(|c| self.is_thousands_separator(c));
+        let mut normalized = String::from(sign);
+        normalized.push_str(groups.next()?);
+        for group in groups {
+            if group.len() != 3 || normalized.len() == sign.len() {
+                return None;
+            }
+            normalized.push_str(group);
+        }
+        if let Some(fractional) = fractional {
+            if fractional
+                .contains(|c| c == self.decimal_separator() || self.is_thousands_separator(c))
+            {
+                return None;
+            }
+            normalized.push('.');
+            normalized.push_str(fractional);
+        }
+        Some(normalized)
+    }
+}
+
+impl FromStr for Locale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Locale::ALL
+            .into_iter()
+            .find(|locale| locale.as_str() == s)
+            .ok_or_else(|| format!("unknown locale `{s}`, expected en, de, fr or ch"))
+    }
+}
+
+impl Display for Locale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// What happens to an amount beyond the range of a price, roughly 922
+/// trillion.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutOfRange {
+    /// reject the event as `amount_out_of_range`
+    #[default]
+    Reject,
+    /// apply the event with the largest price of the same sign
+    Clamp,
+}
+
+impl OutOfRange {
+    pub const ALL: [OutOfRange; 2] = [OutOfRange::Reject, OutOfRange::Clamp];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutOfRange::Reject => "reject",
+            OutOfRange::Clamp => "clamp",
+        }
+    }
+}
+
+impl FromStr for OutOfRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        OutOfRange::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| format!("unknown out of range policy `{s}`, expected reject or clamp"))
+    }
+}
+
+impl Display for OutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Why [`AmountFormat::parse`] failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AmountError {
+    Invalid(String),
+    /// beyond the range of a price, with the bound it can be clamped to
+    OutOfRange(Price),
+}
+
+/// Parses the amounts of an input exactly, with a [`Locale`] and
+/// [`Rounding`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AmountFormat {
+    pub rounding: Rounding,
+    pub locale: Option<Locale>,
+    pub out_of_range: OutOfRange,
+}
+
+impl AmountFormat {
+    pub fn parse(&self, amount: &str) -> Result<Price, AmountError> {
+        let normalized = match self.locale {
+            Some(locale) => locale.normalize(amount).ok_or_else(|| {
+                AmountError::Invalid(format!("amount `{amount}` is not formatted as {locale}"))
+            })?,
+            None => amount.to_string(),
+        };
+        Price::parse_decimal(&normalized, self.rounding).map_err(|error| match error {
+            ParseDecimalError::Invalid => {
+                AmountError::Invalid(format!("invalid amount `{amount}`"))
+            }
+            ParseDecimalError::Inexact => AmountError::Invalid(format!(
+                "amount `{amount}` can not be rounded with {}",
+                self.rounding
+            )),
+            ParseDecimalError::OutOfRange(bound) => AmountError::OutOfRange(bound),
+        })
+    }
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: ',',
+            quote: '"',
+            comment: None,
+            headers: true,
+            encoding: None,
+            rounding: None,
+            locale: None,
+            out_of_range: None,
+        }
+    }
+}
+
+/// Describes the format of an input file and maps its column names and type
+/// spellings onto the canonical format, so exports of other systems can be
+/// processed as is.
+///
+/// ```toml
+/// # types that are counted and skipped instead of failing to parse
+/// passthrough = ["fee", "interest"]
+///
+/// [columns]
+/// type = "txn_type"
+/// client = "customer"
+/// tx = "txid"
+/// amount = "value"
+///
+/// [csv]
+/// delimiter = ";"
+///
+/// [types]
+/// withdraw = "withdrawal"
+/// credit = "deposit"
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schema {
+    /// canonical column name to the column name in the input
+    #[serde(default)]
+    pub columns: HashMap<String, String>,
+    /// spelling in the input to the canonical type
+    #[serde(default)]
+    pub types: HashMap<String, TransactionType>,
+    /// custom types that do not affect any account, rows of these types are
+    /// counted and skipped
+    #[serde(default)]
+    pub passthrough: HashSet<String>,
+    #[serde(default)]
+    pub csv: CsvOptions,
+}
+
+impl Schema {
+    /// Loads a schema from a toml file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let schema: Schema =
+            toml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        schema
+            .validate()
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        Ok(schema)
+    }
+
+    /// Parses column mappings given as `type=txn_type,client=customer`.
+    pub fn parse_columns(spec: &str) -> Result<HashMap<String, String>, String> {
+        let mut columns = HashMap::new();
+        for mapping in spec.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            let Some((canonical, input)) = mapping.split_once('=') else {
+                return Err(format!("expected COLUMN=NAME, got `{mapping}`"));
+            };
+            columns.insert(canonical.trim().to_string(), input.trim().to_string());
+        }
+        let schema = Schema {
+            columns,
+            ..Default::default()
+        };
+        schema.validate()?;
+        Ok(schema.columns)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(column) = self
+            .columns
+            .keys()
+            .find(|c| !COLUMNS.contains(&c.as_str()) && *c != SIGNATURE_COLUMN)
+        {
+            return Err(format!(
+                "unknown column `{column}`, expected one of {}, {SIGNATURE_COLUMN}",
+                COLUMNS.join(", ")
+            ));
+        }
+
+        let options = &self.csv;
+        if let Some(c) = [
+            Some(options.delimiter),
+            Some(options.quote),
+            options.comment,
+        ]
+        .into_iter()
+        .flatten()
+        .find(|c| !c.is_ascii())
+        {
+            return Err(format!("csv option `{c}` is not an ascii character"));
+        }
+        if !options.headers && !self.columns.is_empty() {
+            return Err("mapping columns requires a header".to_string());
+        }
+
+        let is_known = |ty: &String| {
+            self.types.contains_key(ty) || TransactionType::ALL.iter().any(|t| t.as_str() == ty)
+        };
+        if let Some(ty) = self.passthrough.iter().find(|ty| is_known(ty)) {
+            return Err(format!(
+                "passthrough type `{ty}` is already a transaction type"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the types of the input need to be mapped.
+    pub fn maps_types(&self) -> bool {
+        !self.types.is_empty() || !self.passthrough.is_empty()
+    }
+
+    /// Translates the header of an input file into the canonical header.
+    /// Fails when a mapped column is missing from the input.
+    pub fn map_headers(&self, headers: &StringRecord) -> anyhow::Result<StringRecord> {
+        let mut mapped: Vec<&str> = headers.iter().collect();
+        for (canonical, input) in &self.columns {
+            let Some(idx) = headers.iter().position(|h| h == input) else {
+                anyhow::bail!("column `{input}` (mapped to `{canonical}`) not found in input");
+            };
+            mapped[idx] = canonical;
+        }
+        Ok(StringRecord::from(mapped))
+    }
+
+    /// Returns the canonical spelling of a transaction type, when it is
+    /// aliased.
+    pub fn map_type(&self, ty: &str) -> Option<&'static str> {
+        self.types.get(ty).map(TransactionType::as_str)
+    }
+
+    pub fn is_passthrough(&self, ty: &str) -> bool {
+        self.passthrough.contains(ty)
+    }
+}
+
+/// A column of the input whose name resembles a canonical column that is
+/// missing from the header, e.g. `txn` for `tx`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ColumnSuggestion {
+    pub column: String,
+    pub looks_like: &'static str,
+}
+
+impl Display for ColumnSuggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "column '{}' looks like '{}', map it with --columns {}={}",
+            self.column, self.looks_like, self.looks_like, self.column
+        )
+    }
+}
+
+/// Matches the canonical columns missing from the (mapped) header with the
+/// unmapped columns by name. Case and punctuation are ignored, a name matches
+/// when it contains the canonical one or is a few edits away from it.
+pub fn suggest_columns(headers: &StringRecord) -> Vec<ColumnSuggestion> {
+    let normalize = |s: &str| {
+        s.chars()
+            .filter(char::is_ascii_alphanumeric)
+            .collect::<String>()
+            .to_ascii_lowercase()
+    };
+    let mut candidates: Vec<(&str, String)> = headers
+        .iter()
+        .filter(|h| !COLUMNS.contains(h) && *h != SIGNATURE_COLUMN)
+        .map(|h| (h, normalize(h)))
+        .filter(|(_, normalized)| !normalized.is_empty())
+        .collect();
+
+    let mut suggestions = Vec::new();
+    for canonical in COLUMNS {
+        if headers.iter().any(|h| h == canonical) {
+            continue;
+        }
+        let target = normalize(canonical);
+        let threshold = (target.len() / 3).max(1);
+        let best = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, (_, normalized))| (edit_distance(normalized, &target), i))
+            .filter(|(distance, i)| {
+                let normalized = &candidates[*i].1;
+                *distance <= threshold || normalized.contains(&target)
+            })
+            .min();
+        if let Some((_, i)) = best {
+            let (column, _) = candidates.remove(i);
+            suggestions.push(ColumnSuggestion {
+                column: column.to_string(),
+                looks_like: canonical,
+            });
+        }
+    }
+    suggestions
+}
+
+/// Levenshtein distance of two ascii strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema() {
+        let schema: Schema = toml::from_str(
+            r#"
+            [columns]
+            type = "txn_type"
+            client = "customer"
+
+            [types]
+            withdraw = "withdrawal"
+            "#,
+        )
+        .unwrap();
+        assert!(schema.validate().is_ok());
+
+        let headers = StringRecord::from(vec!["txn_type", "customer", "tx", "amount"]);
+        assert_eq!(
+            schema.map_headers(&headers).unwrap(),
+            StringRecord::from(vec!["type", "client", "tx", "amount"])
+        );
+        assert!(schema
+            .map_headers(&StringRecord::from(vec!["type", "client"]))
+            .is_err());
+
+        assert_eq!(schema.map_type("withdraw"), Some("withdrawal"));
+        assert_eq!(schema.map_type("deposit"), None);
+    }
+
+    #[test]
+    fn test_suggest_columns() {
+        let headers = StringRecord::from(vec!["Type", "client_id", "txn", "amount", "notes"]);
+        let suggestions: Vec<_> = suggest_columns(&headers)
+            .into_iter()
+            .map(|s| (s.column, s.looks_like))
+            .collect();
+        assert_eq!(
+            suggestions,
+            [
+                ("Type".to_string(), "type"),
+                ("client_id".to_string(), "client"),
+                ("txn".to_string(), "tx"),
+            ]
+        );
+        assert_eq!(edit_distance("reasoncode", "reason"), 4);
+
+        let headers = StringRecord::from(vec!["type", "client", "tx", "amount"]);
+        assert!(suggest_columns(&headers).is_empty());
+    }
+
+    #[test]
+    fn test_custom_types() {
+        let schema: Schema = toml::from_str(
+            r#"
+            passthrough = ["fee"]
+
+            [types]
+            credit = "deposit"
+            debit = "withdrawal"
+            "#,
+        )
+        .unwrap();
+        assert!(schema.validate().is_ok());
+        assert_eq!(schema.map_type("credit"), Some("deposit"));
+        assert_eq!(schema.map_type("debit"), Some("withdrawal"));
+        assert!(schema.is_passthrough("fee"));
+        assert!(!schema.is_passthrough("credit"));
+
+        let clash: Schema =
+            toml::from_str("passthrough = [\"credit\"]\n[types]\ncredit = \"deposit\"").unwrap();
+        assert!(clash.validate().is_err());
+        let clash: Schema = toml::from_str("passthrough = [\"deposit\"]").unwrap();
+        assert!(clash.validate().is_err());
+    }
+
+    #[test]
+    fn test_csv_options() {
+        let schema: Schema = toml::from_str("[csv]\ndelimiter = \";\"\nheaders = false").unwrap();
+        assert_eq!(schema.csv.delimiter, ';');
+        assert_eq!(schema.csv.quote, '"');
+        assert!(!schema.csv.headers);
+        assert!(schema.validate().is_ok());
+
+        let schema: Schema = toml::from_str("[csv]\nencoding = \"latin1\"").unwrap();
+        assert_eq!(schema.csv.encoding, Some(encoding_rs::WINDOWS_1252));
+        assert!(toml::from_str::<Schema>("[csv]\nencoding = \"klingon\"").is_err());
+
+        let schema: Schema = toml::from_str("[csv]\nrounding = \"half-even\"").unwrap();
+        assert_eq!(schema.csv.rounding, Some(Rounding::HalfEven));
+        assert!(toml::from_str::<Schema>("[csv]\nrounding = \"up\"").is_err());
+
+        let schema: Schema = toml::from_str("[csv]\ndelimiter = \"§\"").unwrap();
+        assert!(schema.validate().is_err());
+    }
+
+    #[test]
+    fn test_amount_locale() {
+        let format = |locale| AmountFormat {
+            rounding: Rounding::Reject,
+            locale: Some(locale),
+            out_of_range: OutOfRange::Reject,
+        };
+        let parse = |locale, amount| format(locale).parse(amount).ok();
+        assert_eq!(parse(Locale::De, "1.234,56"), Some(Price(12_345_600)));
+        assert_eq!(
+            parse(Locale::De, "-1.234.567"),
+            Some(Price(-12_345_670_000))
+        );
+        assert_eq!(parse(Locale::De, "0,5"), Some(Price(5_000)));
+        assert_eq!(parse(Locale::En, "1,234.56"), Some(Price(12_345_600)));
+        assert_eq!(parse(Locale::Fr, "1\u{202f}234,5"), Some(Price(12_345_000)));
+        assert_eq!(parse(Locale::Ch, "12'345.5"), Some(Price(123_455_000)));
+        for invalid in ["1.23,5", "1,2,3", ".123,4", "1,234.5", "12.34.567"] {
+            assert_eq!(parse(Locale::De, invalid), None, "{invalid}");
+        }
+
+        let format = AmountFormat {
+            rounding: Rounding::HalfUp,
+            locale: Some(Locale::De),
+            out_of_range: OutOfRange::Reject,
+        };
+        assert_eq!(format.parse("1,00005"), Ok(Price(10_001)));
+        assert_eq!(
+            format.parse("-1.000.000.000.000.000"),
+            Err(AmountError::OutOfRange(Price::MIN))
+        );
+        let schema: Schema = toml::from_str("[csv]\nlocale = \"fr\"").unwrap();
+        assert_eq!(schema.csv.locale, Some(Locale::Fr));
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let columns = Schema::parse_columns("type=txn_type, tx=txid").unwrap();
+        assert_eq!(columns["type"], "txn_type");
+        assert_eq!(columns["tx"], "txid");
+        assert!(Schema::parse_columns("kind=txn_type").is_err());
+        assert!(Schema::parse_columns("type").is_err());
+    }
+}
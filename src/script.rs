@@ -0,0 +1,266 @@
+//! Custom rules as [rhai](https://rhai.rs) scripts, see `--script`. A lighter
+//! alternative to the WebAssembly plugins with the same hooks, for teams that
+//! do not want a compiler toolchain for a few checks.
+//!
+//! A script defines one or both of the functions below. Events are maps with
+//! the keys `type`, `client`, `tx`, `amount`, `timestamp`, `reason_code`,
+//! `merged_client` and `reference`, a missing value is `()`. Amounts are
+//! floats.
+//!
+//! * `validate(event)` is called for every event before it is applied.
+//!   Returning `()` or `true` accepts the event, `false` or a string rejects
+//!   it as `rule_violation`, the string is the reason in the logs. An error
+//!   rejects the event as well.
+//! * `on_applied(event, account)` is called after every applied event with
+//!   the account of the client, a map with the keys `available`, `held`,
+//!   `total` and `locked`. After an error it is not called anymore.
+//!
+//! ```rhai
+//! fn validate(event) {
+//!     if event.type == "withdrawal" && event.amount > 1000.0 {
+//!         return "withdrawal too large";
+//!     }
+//! }
+//! ```
+//!
+//! Scripts can not access the file system or the network, and a call is
+//! aborted after [`MAX_OPERATIONS`] operations.
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent, PRICE_SCALAR},
+    pipeline::{Sink, Validator},
+};
+use rhai::{Dynamic, Engine, Map, Scope, AST};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::warn;
+
+/// Operations a single call of a hook may take, guards against endless loops.
+pub const MAX_OPERATIONS: u64 = 100_000;
+
+fn float(price: Price) -> Dynamic {
+    Dynamic::from_float(price.0 as f64 / PRICE_SCALAR as f64)
+}
+
+fn optional<T: Into<Dynamic>>(value: Option<T>) -> Dynamic {
+    value.map_or(Dynamic::UNIT, Into::into)
+}
+
+fn event_map(event: &TransactionEvent) -> Map {
+    let mut map = Map::new();
+    map.insert("type".into(), event.ty.as_str().into());
+    map.insert("client".into(), (event.client_id as i64).into());
+    map.insert("tx".into(), (event.tx as i64).into());
+    map.insert("amount".into(), float(event.amount));
+    map.insert(
+        "timestamp".into(),
+        optional(event.timestamp.map(|timestamp| timestamp as i64)),
+    );
+    map.insert(
+        "reason_code".into(),
+        optional((!event.reason.is_empty()).then(|| event.reason.to_string())),
+    );
+    map.insert(
+        "merged_client".into(),
+        optional(event.merged_client.map(i64::from)),
+    );
+    map.insert(
+        "reference".into(),
+        optional(
+            event
+                .reference
+                .map(|reference| reference.text().to_string()),
+        ),
+    );
+    map
+}
+
+fn account_map(account: &Account) -> Map {
+    let mut map = Map::new();
+    map.insert("available".into(), float(account.available()));
+    map.insert("held".into(), float(account.held));
+    map.insert("total".into(), float(account.total));
+    map.insert("locked".into(), account.locked.into());
+    map
+}
+
+/// A compiled script.
+#[derive(Clone)]
+pub struct Script {
+    path: PathBuf,
+    engine: Arc<Engine>,
+    ast: Arc<AST>,
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Script").field("path", &self.path).finish()
+    }
+}
+
+impl Script {
+    /// Compiles the script at `path`. Fails when it defines neither hook.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        let script = Script {
+            path: path.to_path_buf(),
+            engine: Arc::new(engine),
+            ast: Arc::new(ast),
+        };
+        if !script.defines("validate", 1) && !script.defines("on_applied", 2) {
+            anyhow::bail!(
+                "{}: script defines neither `validate(event)` nor `on_applied(event, account)`",
+                path.display()
+            );
+        }
+        Ok(script)
+    }
+
+    fn defines(&self, name: &str, params: usize) -> bool {
+        self.ast
+            .iter_functions()
+            .any(|f| f.name == name && f.params.len() == params)
+    }
+
+    fn call(
+        &self,
+        scope: &mut Scope,
+        name: &str,
+        args: impl rhai::FuncArgs,
+    ) -> Result<Dynamic, String> {
+        self.engine
+            .call_fn::<Dynamic>(scope, &self.ast, name, args)
+            .map_err(|e| e.to_string())
+    }
+
+    /// The `validate` hook, if the script defines it.
+    pub fn validator(&self) -> Option<ScriptValidator> {
+        self.defines("validate", 1).then(|| ScriptValidator {
+            script: self.clone(),
+            scope: Scope::new(),
+        })
+    }
+
+    /// The `on_applied` hook as a [`Sink`], if the script defines it.
+    pub fn sink(&self) -> Option<ScriptSink> {
+        self.defines("on_applied", 2).then(|| ScriptSink {
+            script: self.clone(),
+            scope: Scope::new(),
+            failed: false,
+        })
+    }
+}
+
+/// Rejects the events the `validate` hook of a script rejects.
+pub struct ScriptValidator {
+    script: Script,
+    scope: Scope<'static>,
+}
+
+impl Validator for ScriptValidator {
+    fn validate(&mut self, event: &TransactionEvent) -> Result<(), String> {
+        let result = self
+            .script
+            .call(&mut self.scope, "validate", (event_map(event),))
+            .map_err(|e| format!("script failed: {e}"))?;
+        if result.is_unit() || result.as_bool() == Ok(true) {
+            Ok(())
+        } else if result.is_string() {
+            Err(format!("script rejected: {result}"))
+        } else {
+            Err("script rejected".to_string())
+        }
+    }
+}
+
+/// Calls the `on_applied` hook of a script for every applied event. After an
+/// error the hook is not called anymore.
+pub struct ScriptSink {
+    script: Script,
+    scope: Scope<'static>,
+    failed: bool,
+}
+
+impl Sink for ScriptSink {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        let (Ok(()), Some(account), false) = (result, account, self.failed) else {
+            return;
+        };
+        let args = (event_map(event), account_map(account));
+        if let Err(error) = self.script.call(&mut self.scope, "on_applied", args) {
+            warn!(path = %self.script.path.display(), %error, "script failed, on_applied is disabled");
+            self.failed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionType;
+
+    #[test]
+    fn test_script() {
+        let path = std::env::temp_dir().join("txe_test_script.rhai");
+        std::fs::write(
+            &path,
+            r#"
+            fn validate(event) {
+                if event.type == "withdrawal" && event.amount > 100.0 {
+                    return "withdrawal too large";
+                }
+                event.client != 0
+            }
+            fn on_applied(event, account) {
+                if account.total < 0.0 { throw "negative"; }
+            }
+            fn spin() { loop {} }
+            "#,
+        )
+        .unwrap();
+        let script = Script::load(&path);
+        let _ = std::fs::remove_file(path);
+        let script = script.unwrap();
+
+        let event = |ty, client_id, amount| TransactionEvent::new(ty, client_id, 1, Price(amount));
+        let mut validator = script.validator().unwrap();
+        assert_eq!(
+            validator.validate(&event(TransactionType::Deposit, 1, 2_000_000)),
+            Ok(())
+        );
+        assert_eq!(
+            validator.validate(&event(TransactionType::Withdrawal, 1, 2_000_000)),
+            Err("script rejected: withdrawal too large".to_string())
+        );
+        assert_eq!(
+            validator.validate(&event(TransactionType::Deposit, 0, 10)),
+            Err("script rejected".to_string())
+        );
+        assert!(script
+            .call(&mut Scope::new(), "spin", ())
+            .unwrap_err()
+            .contains("Too many operations"));
+
+        let mut sink = script.sink().unwrap();
+        let deposit = event(TransactionType::Deposit, 1, 10);
+        sink.record(&deposit, Ok(()), Some(&Account::default()));
+        assert!(!sink.failed);
+        let overdrawn = Account {
+            total: Price(-10),
+            ..Default::default()
+        };
+        sink.record(&deposit, Ok(()), Some(&overdrawn));
+        assert!(sink.failed);
+    }
+}
@@ -0,0 +1,259 @@
+//! Protection of snapshot files, which hold the balances of every account:
+//! AES-256-GCM encryption against reading them, and HMAC-SHA256 signing
+//! against tampering with them.
+//!
+//! A protected file starts with [`MAGIC`], the version of the layout and the
+//! [`Protection`] it was written with. An encrypted body is the random nonce
+//! followed by the ciphertext, a signature is appended to the end. Both keys
+//! are derived from one key given in [`KEY_ENV`] (hex) or in the file named
+//! by [`KEY_FILE_ENV`] (hex or 32 raw bytes).
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{borrow::Cow, path::Path, str::FromStr};
+
+pub const MAGIC: [u8; 4] = *b"TXEP";
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+const NONCE_LEN: usize = 12;
+const SIGNATURE_LEN: usize = 32;
+
+const ENCRYPTED: u8 = 1;
+const SIGNED: u8 = 2;
+
+/// Environment variable holding the key as hex.
+pub const KEY_ENV: &str = "TXE_SNAPSHOT_KEY";
+/// Environment variable naming a file that holds the key.
+pub const KEY_FILE_ENV: &str = "TXE_SNAPSHOT_KEY_FILE";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How a snapshot is protected, parsed from `encrypt`, `sign` or both
+/// separated by a comma.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Protection {
+    pub encrypt: bool,
+    pub sign: bool,
+}
+
+impl FromStr for Protection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut protection = Protection::default();
+        for part in s.split(',').map(str::trim) {
+            match part {
+                "encrypt" => protection.encrypt = true,
+                "sign" => protection.sign = true,
+                _ => {
+                    return Err(format!(
+                        "unknown protection `{part}`, expected encrypt or sign"
+                    ))
+                }
+            }
+        }
+        Ok(protection)
+    }
+}
+
+/// The keys to encrypt and sign with, derived from a single key of at least
+/// 32 bytes.
+#[derive(Clone)]
+pub struct SnapshotKey {
+    encryption: [u8; 32],
+    signing: [u8; 32],
+}
+
+impl std::fmt::Debug for SnapshotKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SnapshotKey(..)")
+    }
+}
+
+impl SnapshotKey {
+    pub fn new(key: &[u8]) -> anyhow::Result<Self> {
+        if key.len() < 32 {
+            anyhow::bail!(
+                "the snapshot key needs at least 32 bytes, got {}",
+                key.len()
+            );
+        }
+        let derive = |purpose: &[u8]| -> [u8; 32] {
+            let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("any key length");
+            mac.update(purpose);
+            mac.finalize().into_bytes().into()
+        };
+        Ok(SnapshotKey {
+            encryption: derive(b"txe snapshot encryption"),
+            signing: derive(b"txe snapshot signing"),
+        })
+    }
+
+    /// The key from [`KEY_ENV`] or [`KEY_FILE_ENV`], `None` when neither is
+    /// set.
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        if let Ok(hex) = std::env::var(KEY_ENV) {
+            let key = decode_hex(hex.trim())
+                .ok_or_else(|| anyhow::anyhow!("{KEY_ENV} is not a hex string"))?;
+            return Self::new(&key).map(Some);
+        }
+        let Some(path) = std::env::var_os(KEY_FILE_ENV) else {
+            return Ok(None);
+        };
+        let content = std::fs::read(&path)?;
+        let key = std::str::from_utf8(&content)
+            .ok()
+            .and_then(|text| decode_hex(text.trim()))
+            .unwrap_or(content);
+        Self::new(&key).map(Some)
+    }
+
+    fn sign(&self, data: &[u8]) -> HmacSha256 {
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(&self.signing).expect("any key length");
+        mac.update(data);
+        mac
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.encryption))
+    }
+}
+
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether the data starts like a protected file.
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Protects the content of a snapshot.
+pub fn seal(plain: &[u8], protection: Protection, key: &SnapshotKey) -> anyhow::Result<Vec<u8>> {
+    let flags = (protection.encrypt as u8 * ENCRYPTED) | (protection.sign as u8 * SIGNED);
+    let mut sealed = Vec::with_capacity(HEADER_LEN + NONCE_LEN + plain.len() + 16 + SIGNATURE_LEN);
+    sealed.extend_from_slice(&MAGIC);
+    sealed.extend_from_slice(&[VERSION, flags]);
+    if protection.encrypt {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plain,
+            aad: &sealed[..HEADER_LEN],
+        };
+        let ciphertext = key
+            .cipher()
+            .encrypt(&nonce, payload)
+            .map_err(|_| anyhow::anyhow!("failed to encrypt the snapshot"))?;
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+    } else {
+        sealed.extend_from_slice(plain);
+    }
+    if protection.sign {
+        let signature = key.sign(&sealed).finalize().into_bytes();
+        sealed.extend_from_slice(&signature);
+    }
+    Ok(sealed)
+}
+
+/// Verifies and decrypts a protected snapshot, other data is returned as is.
+pub fn unseal<'a>(data: &'a [u8], key: Option<&SnapshotKey>) -> anyhow::Result<Cow<'a, [u8]>> {
+    if !is_sealed(data) {
+        return Ok(Cow::Borrowed(data));
+    }
+    let Some(&[version, flags]) = data.get(MAGIC.len()..HEADER_LEN) else {
+        anyhow::bail!("truncated snapshot header");
+    };
+    if version != VERSION || flags & !(ENCRYPTED | SIGNED) != 0 {
+        anyhow::bail!("unsupported snapshot protection (version {version}, flags {flags})");
+    }
+    let Some(key) = key else {
+        anyhow::bail!("the snapshot is protected, set {KEY_ENV} or {KEY_FILE_ENV}");
+    };
+
+    let mut body = &data[HEADER_LEN..];
+    if flags & SIGNED != 0 {
+        let Some(signed_len) = data
+            .len()
+            .checked_sub(SIGNATURE_LEN)
+            .filter(|len| *len >= HEADER_LEN)
+        else {
+            anyhow::bail!("truncated snapshot signature");
+        };
+        key.sign(&data[..signed_len])
+            .verify_slice(&data[signed_len..])
+            .map_err(|_| anyhow::anyhow!("invalid snapshot signature, wrong key or tampered"))?;
+        body = &data[HEADER_LEN..signed_len];
+    }
+    if flags & ENCRYPTED == 0 {
+        return Ok(Cow::Borrowed(body));
+    }
+    if body.len() < NONCE_LEN {
+        anyhow::bail!("truncated encrypted snapshot");
+    }
+    let (nonce, ciphertext) = body.split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: ciphertext,
+        aad: &data[..HEADER_LEN],
+    };
+    let plain = key
+        .cipher()
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| anyhow::anyhow!("failed to decrypt the snapshot, wrong key or tampered"))?;
+    Ok(Cow::Owned(plain))
+}
+
+/// Reads a file, verifying and decrypting it with the key of the
+/// environment when it is protected.
+pub fn read_file(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let data = std::fs::read(path)?;
+    if !is_sealed(&data) {
+        return Ok(data);
+    }
+    let key = SnapshotKey::from_env()?;
+    unseal(&data, key.as_ref())
+        .map(Cow::into_owned)
+        .map_err(|e| e.context(format!("failed to read {}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = SnapshotKey::new(&[7; 32]).unwrap();
+        let plain = b"client,available,held,total,locked\n1,1.0,0.0,1.0,false\n";
+        for protection in ["encrypt", "sign", "encrypt,sign"] {
+            let protection: Protection = protection.parse().unwrap();
+            let sealed = seal(plain, protection, &key).unwrap();
+            assert!(is_sealed(&sealed));
+            assert_eq!(
+                sealed.windows(plain.len()).any(|w| w == plain),
+                !protection.encrypt
+            );
+            assert_eq!(unseal(&sealed, Some(&key)).unwrap(), &plain[..]);
+
+            let mut tampered = sealed.clone();
+            tampered[HEADER_LEN + NONCE_LEN + 1] ^= 1;
+            assert!(unseal(&tampered, Some(&key)).is_err());
+            let other = SnapshotKey::new(&[8; 32]).unwrap();
+            assert!(unseal(&sealed, Some(&other)).is_err());
+            assert!(unseal(&sealed, None).is_err());
+        }
+
+        assert_eq!(unseal(plain, None).unwrap(), &plain[..]);
+        assert!(SnapshotKey::new(&[7; 16]).is_err());
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert!("compress".parse::<Protection>().is_err());
+    }
+}
@@ -0,0 +1,212 @@
+//! Segmentation of clients, e.g. `retail`, `business` or `internal`, loaded
+//! from a sidecar file. Segments drive policies per group of clients and are
+//! summarized at the end of a run.
+use crate::{
+    data_types::{Account, Price},
+    filter::ClientSet,
+};
+use csv::{ReaderBuilder, Writer};
+use serde::Deserialize;
+use std::{collections::HashMap, io::Write, path::Path};
+
+#[derive(Debug, Deserialize)]
+struct SegmentRow {
+    client: u16,
+    segment: String,
+}
+
+/// The segment of every client listed in the sidecar file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Segments {
+    names: Vec<String>,
+    /// index in `names` per client
+    by_client: HashMap<u16, usize>,
+}
+
+impl Segments {
+    /// Reads a csv file with the columns `client,segment`. A client listed
+    /// twice is an error.
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let mut rdr = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .comment(Some(b'#'))
+            .from_path(path)?;
+        let mut segments = Segments::default();
+        for row in rdr.deserialize() {
+            let row: SegmentRow = row?;
+            segments
+                .insert(row.client, &row.segment)
+                .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        }
+        Ok(segments)
+    }
+
+    pub fn insert(&mut self, client_id: u16, segment: &str) -> Result<(), String> {
+        if let Some(previous) = self.segment(client_id) {
+            return Err(format!(
+                "client {client_id} is in segment `{previous}` and `{segment}`"
+            ));
+        }
+        let index = match self.names.iter().position(|name| name == segment) {
+            Some(index) => index,
+            None => {
+                self.names.push(segment.to_string());
+                self.names.len() - 1
+            }
+        };
+        self.by_client.insert(client_id, index);
+        Ok(())
+    }
+
+    pub fn segment(&self, client_id: u16) -> Option<&str> {
+        self.by_client
+            .get(&client_id)
+            .map(|index| self.names[*index].as_str())
+    }
+
+    /// The segments in order of first appearance.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(String::as_str)
+    }
+
+    /// The clients of the segment, empty when it is unknown.
+    pub fn clients(&self, segment: &str) -> ClientSet {
+        let mut clients = ClientSet::new();
+        if let Some(index) = self.names.iter().position(|name| name == segment) {
+            self.by_client
+                .iter()
+                .filter(|(_, i)| **i == index)
+                .for_each(|(client_id, _)| clients.insert(*client_id));
+        }
+        clients
+    }
+
+    /// Sums up the accounts per segment, in the order of [`Segments::names`].
+    /// Clients without a segment are summed up last, under an empty name.
+    pub fn summarize<'a>(
+        &self,
+        accounts: impl Iterator<Item = (u16, &'a Account)>,
+    ) -> Vec<SegmentSummary> {
+        let mut summaries: Vec<_> = self
+            .names
+            .iter()
+            .chain(std::iter::once(&String::new()))
+            .map(|name| SegmentSummary {
+                segment: name.clone(),
+                ..Default::default()
+            })
+            .collect();
+        for (client_id, account) in accounts {
+            let index = self
+                .by_client
+                .get(&client_id)
+                .copied()
+                .unwrap_or(self.names.len());
+            let summary = &mut summaries[index];
+            summary.accounts += 1;
+            summary.locked += account.locked as usize;
+            summary.held.try_add(account.held);
+            summary.total.try_add(account.total);
+            summary.disputes += account.meta.disputes as u64;
+            summary.chargebacks += account.meta.chargebacks as u64;
+        }
+        // without unsegmented clients there is nothing to report for them
+        if summaries
+            .last()
+            .is_some_and(|summary| summary.accounts == 0)
+        {
+            summaries.pop();
+        }
+        summaries
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SegmentSummary {
+    pub segment: String,
+    pub accounts: usize,
+    pub locked: usize,
+    pub held: Price,
+    pub total: Price,
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+/// Writes the summaries as csv.
+pub fn write_segment_summary(
+    writer: impl Write,
+    summaries: &[SegmentSummary],
+) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record([
+        "segment",
+        "accounts",
+        "locked",
+        "available",
+        "held",
+        "total",
+        "disputes",
+        "chargebacks",
+    ])?;
+    for summary in summaries {
+        writer.write_record(&[
+            summary.segment.clone(),
+            summary.accounts.to_string(),
+            summary.locked.to_string(),
+            Price(summary.total.0 - summary.held.0).to_string(),
+            summary.held.to_string(),
+            summary.total.to_string(),
+            summary.disputes.to_string(),
+            summary.chargebacks.to_string(),
+        ])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments() {
+        let path = std::env::temp_dir().join("txe_test_segments.csv");
+        std::fs::write(
+            &path,
+            "client,segment\n1,retail\n2,business\n# staff\n3, retail\n",
+        )
+        .unwrap();
+        let mut segments = Segments::from_file(&path).unwrap();
+        let _ = std::fs::remove_file(path);
+
+        assert_eq!(segments.segment(3), Some("retail"));
+        assert_eq!(segments.segment(4), None);
+        assert_eq!(segments.names().collect::<Vec<_>>(), ["retail", "business"]);
+        let retail = segments.clients("retail");
+        assert!(retail.contains(1) && retail.contains(3) && !retail.contains(2));
+        assert!(segments.insert(1, "business").is_err());
+
+        let account = |total, locked| Account {
+            total: Price(total),
+            locked,
+            ..Default::default()
+        };
+        let accounts = [
+            (1, account(10_000, false)),
+            (3, account(20_000, true)),
+            (4, account(5_000, false)),
+        ];
+        let summaries = segments.summarize(accounts.iter().map(|(id, account)| (*id, account)));
+        let totals: Vec<_> = summaries
+            .iter()
+            .map(|s| (s.segment.as_str(), s.accounts, s.locked, s.total))
+            .collect();
+        assert_eq!(
+            totals,
+            [
+                ("retail", 2, 1, Price(30_000)),
+                ("business", 0, 0, Price(0)),
+                ("", 1, 0, Price(5_000)),
+            ]
+        );
+    }
+}
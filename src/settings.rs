@@ -0,0 +1,216 @@
+//! Tunable settings that can be reloaded on SIGHUP in service mode, without a
+//! restart losing the in-memory state: the `--max-rate` of a `--settings`
+//! file, the `--rules` and the `--segments` with their lock policies. When a
+//! file fails to load, the error is logged and the previous settings stay in
+//! effect.
+//!
+//! ```toml
+//! # events per second, as --max-rate
+//! max_rate = 5000
+//! ```
+use crate::{
+    data_types::{LockPolicy, TransactionEvent},
+    filter::ClientSet,
+    pipeline::Validator,
+    rules::Rules,
+    segments::Segments,
+};
+use arc_swap::ArcSwap;
+use serde::Deserialize;
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+use std::{path::PathBuf, sync::Arc, thread::JoinHandle};
+use tracing::{error, info};
+
+/// The contents of a `--settings` file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    max_rate: Option<u64>,
+}
+
+/// Where the settings are loaded from.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsFiles {
+    pub settings: Option<PathBuf>,
+    /// used when there is no settings file
+    pub max_rate: Option<u64>,
+    pub rules: Option<PathBuf>,
+    pub segments: Option<PathBuf>,
+    /// lock policies per segment name
+    pub segment_lock_policies: Vec<(String, LockPolicy)>,
+    /// lock policies per client, more specific than the segments
+    pub client_lock_policies: Vec<(ClientSet, LockPolicy)>,
+}
+
+impl SettingsFiles {
+    pub fn load(&self) -> anyhow::Result<Settings> {
+        let max_rate = match &self.settings {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)?;
+                let file: SettingsFile = toml::from_str(&content)
+                    .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+                if file.max_rate == Some(0) {
+                    anyhow::bail!("{}: max_rate needs to be at least 1", path.display());
+                }
+                file.max_rate
+            }
+            None => self.max_rate,
+        };
+        let rules = self.rules.as_deref().map(Rules::load).transpose()?;
+        let segments = self
+            .segments
+            .as_deref()
+            .map(Segments::from_file)
+            .transpose()?;
+        let mut lock_policies = Vec::new();
+        if let Some(segments) = &segments {
+            for (segment, policy) in &self.segment_lock_policies {
+                if segments.names().all(|name| name != segment) {
+                    anyhow::bail!("unknown segment `{segment}`");
+                }
+                lock_policies.push((segments.clients(segment), *policy));
+            }
+        }
+        lock_policies.extend(self.client_lock_policies.iter().cloned());
+        Ok(Settings {
+            max_rate,
+            rules,
+            segments,
+            lock_policies,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub max_rate: Option<u64>,
+    pub rules: Option<Rules>,
+    pub segments: Option<Segments>,
+    /// lock policy overrides, later ones take precedence
+    pub lock_policies: Vec<(ClientSet, LockPolicy)>,
+}
+
+/// The settings in effect, shared by the stages using them. Cloning shares
+/// the settings.
+#[derive(Debug, Clone)]
+pub struct LiveSettings(Arc<ArcSwap<Settings>>);
+
+impl LiveSettings {
+    pub fn new(settings: Settings) -> Self {
+        LiveSettings(Arc::new(ArcSwap::from_pointee(settings)))
+    }
+
+    pub fn load(&self) -> Arc<Settings> {
+        self.0.load_full()
+    }
+
+    /// Loads the files again. On failure the current settings are kept.
+    pub fn reload(&self, files: &SettingsFiles) -> anyhow::Result<()> {
+        self.0.store(Arc::new(files.load()?));
+        Ok(())
+    }
+
+    /// Reloads the settings on every SIGHUP until the returned handle is
+    /// dropped.
+    pub fn reload_on_sighup(&self, files: SettingsFiles) -> anyhow::Result<ReloadHandle> {
+        let mut signals = Signals::new([SIGHUP])?;
+        let handle = signals.handle();
+        let settings = self.clone();
+        let thread = std::thread::Builder::new()
+            .name("settings reload".to_string())
+            .spawn(move || {
+                for _ in signals.forever() {
+                    match settings.reload(&files) {
+                        Ok(()) => {
+                            let current = settings.load();
+                            info!(
+                                max_rate = ?current.max_rate,
+                                rules = current.rules.is_some(),
+                                lock_policies = current.lock_policies.len(),
+                                "reloaded settings"
+                            );
+                        }
+                        Err(e) => error!(
+                            error = format!("{e:#}"),
+                            "failed to reload the settings, keeping the previous ones"
+                        ),
+                    }
+                }
+            })?;
+        Ok(ReloadHandle {
+            signals: handle,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// Validates the events against the current `--rules`.
+impl Validator for LiveSettings {
+    fn validate(&mut self, event: &TransactionEvent) -> Result<(), String> {
+        match &self.0.load().rules {
+            Some(rules) => rules
+                .check(event)
+                .map_err(|violation| violation.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Stops reloading the settings when dropped.
+#[derive(Debug)]
+pub struct ReloadHandle {
+    signals: signal_hook::iterator::Handle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for ReloadHandle {
+    fn drop(&mut self) {
+        self.signals.close();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{Price, TransactionType};
+
+    #[test]
+    fn test_reload() {
+        let dir = std::env::temp_dir().join("txe_test_settings_reload");
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = SettingsFiles {
+            settings: Some(dir.join("settings.toml")),
+            max_rate: Some(10),
+            rules: Some(dir.join("rules.toml")),
+            segments: Some(dir.join("segments.csv")),
+            segment_lock_policies: vec![("vip".to_string(), LockPolicy::None)],
+            client_lock_policies: Vec::new(),
+        };
+        std::fs::write(dir.join("settings.toml"), "max_rate = 100\n").unwrap();
+        std::fs::write(dir.join("rules.toml"), "[amount]\nmax = 10.0\n").unwrap();
+        std::fs::write(dir.join("segments.csv"), "client,segment\n1,vip\n").unwrap();
+        let mut settings = LiveSettings::new(files.load().unwrap());
+        assert_eq!(settings.load().max_rate, Some(100));
+        assert!(settings.load().lock_policies[0].0.contains(1));
+
+        let event = TransactionEvent::new(TransactionType::Deposit, 2, 1, Price(500_000));
+        assert!(settings.validate(&event).is_err());
+
+        std::fs::write(dir.join("settings.toml"), "max_rate = 200\n").unwrap();
+        std::fs::write(dir.join("rules.toml"), "[amount]\nmax = 100.0\n").unwrap();
+        std::fs::write(dir.join("segments.csv"), "client,segment\n2,vip\n").unwrap();
+        settings.reload(&files).unwrap();
+        assert_eq!(settings.load().max_rate, Some(200));
+        assert!(settings.load().lock_policies[0].0.contains(2));
+        assert!(settings.validate(&event).is_ok());
+
+        // a broken file keeps the previous settings
+        std::fs::write(dir.join("settings.toml"), "max_rate = 0\n").unwrap();
+        assert!(settings.reload(&files).is_err());
+        assert_eq!(settings.load().max_rate, Some(200));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
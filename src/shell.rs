@@ -0,0 +1,197 @@
+use crate::{cli::ShellArgs, process_file};
+use std::io::{BufRead, Write};
+use toy_transaction_engine::{
+    csv_source::read_accounts,
+    data_types::{Account, Price, Rejected, TransactionError},
+    transaction_context::TransactionContext,
+};
+
+const HELP: &str = "\
+commands:
+  account <client>           balances and audit data of an account
+  tx <tx>                    state of a stored transaction (deposits only)
+  top <n> by <field>         accounts with the largest available|held|total
+  rejects [n]                rejected events per reason, and the first n events
+  help                       this text
+  quit                       leave the shell";
+
+/// Processes a file or loads a snapshot and reads commands from stdin until
+/// `quit` or end of input.
+pub fn run(args: &ShellArgs) -> anyhow::Result<()> {
+    let mut context = TransactionContext::new();
+    let mut rejects = Vec::new();
+
+    if let Some(path) = &args.snapshot {
+        for (client_id, account) in read_accounts(path)? {
+            context.insert_account(client_id, account);
+        }
+    } else if let Some(path) = &args.file_path {
+        process_file(path, &mut context, &mut rejects)?;
+    }
+
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+    println!(
+        "loaded {} accounts, {} rejected events. Type `help` for commands.",
+        context.account_count(),
+        rejects.len()
+    );
+
+    loop {
+        print!("> ");
+        stdout.flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            break;
+        }
+
+        match line.trim() {
+            "" => continue,
+            "quit" | "exit" => break,
+            line => println!("{}", execute(line, &context, &rejects)),
+        }
+    }
+
+    Ok(())
+}
+
+fn execute(line: &str, context: &TransactionContext, rejects: &[Rejected]) -> String {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    match args.as_slice() {
+        ["help"] => HELP.to_string(),
+        ["account", client] => match client.parse() {
+            Ok(client_id) => match context.account(client_id) {
+                Some(account) => format_account(client_id, account),
+                None => format!("no account for client {client_id}"),
+            },
+            Err(_) => format!("invalid client id `{client}`"),
+        },
+        ["tx", tx] => match tx.parse() {
+            Ok(tx) => match context.transaction(tx) {
+                Some((amount, flags, client_id)) => {
+                    format!("tx {tx}: client {client_id}, amount {amount}, state {flags:?}")
+                }
+                None => format!("no stored transaction {tx}"),
+            },
+            Err(_) => format!("invalid tx id `{tx}`"),
+        },
+        ["top", n, "by", field] => {
+            let Ok(n) = n.parse::<usize>() else {
+                return format!("invalid count `{n}`");
+            };
+            let key: fn(&Account) -> Price = match *field {
+                "available" => Account::available,
+                "held" => |account| account.held,
+                "total" => |account| account.total,
+                _ => return format!("unknown field `{field}`, use available, held or total"),
+            };
+
+            let mut accounts: Vec<_> = context.iter_accounts().collect();
+            accounts.sort_by(|a, b| key(b.1).0.cmp(&key(a.1).0).then(a.0.cmp(&b.0)));
+            accounts
+                .iter()
+                .take(n)
+                .map(|(client_id, account)| format!("{client_id}: {}", key(account)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        ["rejects"] => format_rejects(rejects, 10),
+        ["rejects", n] => match n.parse() {
+            Ok(n) => format_rejects(rejects, n),
+            Err(_) => format!("invalid count `{n}`"),
+        },
+        _ => format!("unknown command `{line}`, type `help` for commands"),
+    }
+}
+
+fn format_account(client_id: u16, account: &Account) -> String {
+    let meta = &account.meta;
+    format!(
+        "client {client_id}: available {}, held {}, total {}, locked {}\n\
+         transactions {}, disputes {}, chargebacks {}, lock tx {}",
+        account.available(),
+        account.held,
+        account.total,
+        account.locked,
+        meta.tx_count,
+        meta.disputes,
+        meta.chargebacks,
+        meta.lock_tx.map(|tx| tx.to_string()).unwrap_or("-".into()),
+    )
+}
+
+fn format_rejects(rejects: &[Rejected], n: usize) -> String {
+    let mut lines = vec![format!("{} rejected events", rejects.len())];
+    for error in TransactionError::ALL {
+        let count = rejects.iter().filter(|r| r.error == error).count();
+        if count > 0 {
+            lines.push(format!("  {error}: {count}"));
+        }
+    }
+    for rejected in rejects.iter().take(n) {
+        let event = &rejected.event;
+        lines.push(format!(
+            "  {} client {} tx {} amount {}: {}",
+            event.ty, event.client_id, event.tx, event.amount, rejected.error
+        ));
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toy_transaction_engine::data_types::{TransactionEvent, TransactionType};
+
+    fn context() -> TransactionContext {
+        let mut context = TransactionContext::new();
+        for (client_id, held, total) in [(1, 0, 50_000), (2, 20_000, 30_000), (3, 0, 10_000)] {
+            let account = Account {
+                total: Price(total),
+                held: Price(held),
+                ..Default::default()
+            };
+            context.insert_account(client_id, account);
+        }
+        context
+    }
+
+    #[test]
+    fn test_top() {
+        let context = context();
+        assert_eq!(execute("top 2 by total", &context, &[]), "1: 5.0\n2: 3.0");
+        assert_eq!(execute("top 1 by held", &context, &[]), "2: 2.0");
+        assert_eq!(
+            execute("top 5 by available", &context, &[]),
+            "1: 5.0\n2: 1.0\n3: 1.0"
+        );
+        assert!(execute("top 1 by nothing", &context, &[]).starts_with("unknown field"));
+    }
+
+    #[test]
+    fn test_rejects() {
+        let event = TransactionEvent::new(TransactionType::Withdrawal, 1, 9, Price(10_000));
+        let rejects = [Rejected {
+            event,
+            error: TransactionError::InsufficientFunds,
+        }];
+        assert_eq!(
+            execute("rejects", &context(), &rejects),
+            "1 rejected events\n  insufficient_funds: 1\n  withdrawal client 1 tx 9 amount 1.0: insufficient_funds"
+        );
+    }
+
+    #[test]
+    fn test_account() {
+        let context = context();
+        assert!(
+            execute("account 2", &context, &[]).starts_with("client 2: available 1.0, held 2.0")
+        );
+        assert_eq!(
+            execute("account 9", &context, &[]),
+            "no account for client 9"
+        );
+        assert_eq!(execute("account x", &context, &[]), "invalid client id `x`");
+    }
+}
@@ -0,0 +1,52 @@
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// Shutdown request triggered by SIGINT or SIGTERM. The first signal only sets
+/// the flag so the pipeline can wind down gracefully, a second signal
+/// terminates the process immediately.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn install() -> std::io::Result<Self> {
+        let shutdown = Shutdown::default();
+        for signal in [SIGINT, SIGTERM] {
+            // order matters, the conditional shutdown only fires when the flag
+            // was already set by an earlier signal.
+            signal_hook::flag::register_conditional_shutdown(signal, 130, shutdown.0.clone())?;
+            signal_hook::flag::register(signal, shutdown.0.clone())?;
+        }
+        Ok(shutdown)
+    }
+
+    pub fn request(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Shutdown::default();
+        let clone = shutdown.clone();
+        assert!(!clone.is_requested());
+        shutdown.request();
+        assert!(clone.is_requested());
+
+        // the first signal only sets the flag
+        let shutdown = Shutdown::install().unwrap();
+        assert!(!shutdown.is_requested());
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert!(shutdown.is_requested());
+    }
+}
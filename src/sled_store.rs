@@ -0,0 +1,400 @@
+//! [`StateStore`] persisted in a sled database, so the state survives restarts
+//! and the transactions do not need to fit in memory.
+use crate::{
+    data_types::Account,
+    pseudonym,
+    state_store::{
+        decode_account, decode_position, decode_transaction, encode_account, encode_transaction,
+        map_bytes, Ledger, MemoryUsage, StateStore, StoredTransaction, TxFilter, POSITION_KEY,
+    },
+};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError},
+    Transactional,
+};
+use std::{collections::HashMap, convert::Infallible, path::Path};
+use tracing::{error, info};
+
+/// Accounts are cached in memory and written through, transactions are only
+/// kept on disk (and in the page cache of sled) behind a filter of the stored
+/// ids.
+#[derive(Debug)]
+pub struct SledStore {
+    db: sled::Db,
+    accounts_tree: sled::Tree,
+    transactions_tree: sled::Tree,
+    meta_tree: sled::Tree,
+    accounts: HashMap<u16, Account>,
+    filter: TxFilter,
+    /// counting the tree is a full scan, so the count is kept separately
+    transaction_count: usize,
+    position: Option<u64>,
+    /// writes since the last commit, when opened for exactly-once processing
+    pending: Option<Pending>,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    accounts: sled::Batch,
+    transactions: sled::Batch,
+    /// latest state of the transactions in the batch, `None` once removed
+    stored: HashMap<u32, Option<StoredTransaction>>,
+}
+
+impl SledStore {
+    /// Opens or creates the database in the given directory and loads the
+    /// accounts it holds.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)?;
+        let accounts_tree = db.open_tree("accounts")?;
+        let transactions_tree = db.open_tree("transactions")?;
+        let meta_tree = db.open_tree("meta")?;
+        let position = match meta_tree.get(POSITION_KEY)? {
+            Some(value) => Some(
+                decode_position(&value)
+                    .ok_or_else(|| anyhow::anyhow!("corrupt position in {}", path.display()))?,
+            ),
+            None => None,
+        };
+
+        let mut accounts = HashMap::new();
+        for entry in accounts_tree.iter() {
+            let (key, value) = entry?;
+            let (Ok(key), Some(account)) = (key.as_ref().try_into(), decode_account(&value)) else {
+                anyhow::bail!("corrupt account in {}", path.display());
+            };
+            accounts.insert(u16::from_be_bytes(key), account);
+        }
+        let mut filter = TxFilter::new();
+        let mut transaction_count = 0;
+        for key in transactions_tree.iter().keys() {
+            let Ok(key) = key?.as_ref().try_into() else {
+                anyhow::bail!("corrupt transaction in {}", path.display());
+            };
+            filter.insert(u32::from_be_bytes(key));
+            transaction_count += 1;
+        }
+        info!(
+            path = %path.display(),
+            accounts = accounts.len(),
+            transactions = transaction_count,
+            position,
+            "opened state store"
+        );
+
+        Ok(SledStore {
+            db,
+            accounts_tree,
+            transactions_tree,
+            meta_tree,
+            accounts,
+            filter,
+            transaction_count,
+            position,
+            pending: None,
+        })
+    }
+
+    /// Like [`SledStore::open`], but the writes are held back until the next
+    /// [`StateStore::commit`], which applies them in one transaction.
+    pub fn open_exactly_once(path: &Path) -> anyhow::Result<Self> {
+        let mut store = Self::open(path)?;
+        store.pending = Some(Pending::default());
+        Ok(store)
+    }
+
+    fn persist_account(&mut self, client_id: u16, account: &Account) {
+        if let Some(pending) = &mut self.pending {
+            pending
+                .accounts
+                .insert(&client_id.to_be_bytes(), encode_account(account).as_slice());
+            return;
+        }
+        if let Err(error) = self
+            .accounts_tree
+            .insert(client_id.to_be_bytes(), encode_account(account).as_slice())
+        {
+            error!(%error, client = %pseudonym::client(client_id), "failed to persist account");
+        }
+    }
+}
+
+impl Ledger for SledStore {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        if !self.filter.may_contain(tx) {
+            return None;
+        }
+        if let Some(stored) = self.pending.as_ref().and_then(|p| p.stored.get(&tx)) {
+            return *stored;
+        }
+        match self.transactions_tree.get(tx.to_be_bytes()) {
+            Ok(value) => value.and_then(|value| decode_transaction(&value)),
+            Err(error) => {
+                error!(%error, tx, "failed to read transaction");
+                None
+            }
+        }
+    }
+
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        self.filter.insert(tx);
+        if let Some(pending) = &mut self.pending {
+            // updates of a transaction only change its flags, a transaction
+            // is new when it is stored in an initial state
+            if matches!(
+                transaction.1,
+                crate::data_types::TransactionFlags::None
+                    | crate::data_types::TransactionFlags::Final
+            ) {
+                self.transaction_count += 1;
+            }
+            pending.stored.insert(tx, Some(transaction));
+            pending.transactions.insert(
+                &tx.to_be_bytes(),
+                encode_transaction(&transaction).as_slice(),
+            );
+            return;
+        }
+        match self.transactions_tree.insert(
+            tx.to_be_bytes(),
+            encode_transaction(&transaction).as_slice(),
+        ) {
+            Ok(None) => self.transaction_count += 1,
+            Ok(Some(_)) => {}
+            Err(error) => error!(%error, tx, "failed to persist transaction"),
+        }
+    }
+
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account {
+        let account = self.accounts.entry(client_id).or_default();
+        update(account);
+        let account = *account;
+        self.persist_account(client_id, &account);
+        account
+    }
+}
+
+impl StateStore for SledStore {
+    fn account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    fn put_account(&mut self, client_id: u16, account: Account) {
+        self.accounts.insert(client_id, account);
+        self.persist_account(client_id, &account);
+    }
+
+    fn reserve_accounts(&mut self, additional: usize) {
+        self.accounts.reserve(additional);
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (u16, &Account)> + '_> {
+        Box::new(self.accounts.iter().map(|(id, account)| (*id, account)))
+    }
+
+    fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transaction_count
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = (u32, StoredTransaction)> + '_> {
+        let stored = self
+            .transactions_tree
+            .iter()
+            .filter_map(|entry| match entry {
+                Ok((key, value)) => {
+                    let key = key.as_ref().try_into().ok().map(u32::from_be_bytes);
+                    key.zip(decode_transaction(&value))
+                }
+                Err(error) => {
+                    error!(%error, "failed to read transactions");
+                    None
+                }
+            });
+        let Some(pending) = &self.pending else {
+            return Box::new(stored);
+        };
+        // the pending batch holds the latest state of its transactions
+        Box::new(
+            pending
+                .stored
+                .iter()
+                .filter_map(|(tx, stored)| stored.map(|stored| (*tx, stored)))
+                .chain(stored.filter(|(tx, _)| !pending.stored.contains_key(tx))),
+        )
+    }
+
+    fn remove_account(&mut self, client_id: u16) -> Option<Account> {
+        if let Some(pending) = &mut self.pending {
+            pending.accounts.remove(&client_id.to_be_bytes());
+        } else if let Err(error) = self.accounts_tree.remove(client_id.to_be_bytes()) {
+            error!(%error, client = %pseudonym::client(client_id), "failed to remove account");
+        }
+        self.accounts.remove(&client_id)
+    }
+
+    fn remove_transactions(&mut self, remove: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        // the ids stay in the filter, it only costs a lookup when they recur
+        let removed: Vec<_> = self
+            .transactions()
+            .filter(|(_, transaction)| remove(transaction))
+            .map(|(tx, _)| tx)
+            .collect();
+        for tx in &removed {
+            if let Some(pending) = &mut self.pending {
+                pending.stored.insert(*tx, None);
+                pending.transactions.remove(&tx.to_be_bytes());
+                self.transaction_count -= 1;
+                continue;
+            }
+            match self.transactions_tree.remove(tx.to_be_bytes()) {
+                Ok(Some(_)) => self.transaction_count -= 1,
+                Ok(None) => {}
+                Err(error) => error!(%error, tx, "failed to remove transaction"),
+            }
+        }
+        removed.len()
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            accounts: map_bytes(&self.accounts),
+            transactions: self.filter.bytes(),
+        }
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn position(&self) -> Option<u64> {
+        self.position
+    }
+
+    fn commit(&mut self, position: u64) -> anyhow::Result<()> {
+        let position_bytes = position.to_be_bytes();
+        match self.pending.replace(Pending::default()) {
+            Some(pending) => (
+                &self.accounts_tree,
+                &self.transactions_tree,
+                &self.meta_tree,
+            )
+                .transaction(|(accounts, transactions, meta)| {
+                    accounts.apply_batch(&pending.accounts)?;
+                    transactions.apply_batch(&pending.transactions)?;
+                    meta.insert(POSITION_KEY, &position_bytes)?;
+                    Ok::<_, ConflictableTransactionError<Infallible>>(())
+                })
+                .map_err(|error| match error {
+                    TransactionError::Abort(never) => match never {},
+                    TransactionError::Storage(error) => error,
+                })?,
+            None => {
+                self.meta_tree.insert(POSITION_KEY, &position_bytes)?;
+            }
+        }
+        self.db.flush()?;
+        self.position = Some(position);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Price, TransactionEvent, TransactionType},
+        transaction_context::TransactionContext,
+    };
+
+    #[test]
+    fn test_state_survives_reopen() {
+        let path = std::env::temp_dir().join("txe_test_sled_store");
+        let _ = std::fs::remove_dir_all(&path);
+        let deposit = TransactionEvent::new(TransactionType::Deposit, 1, 1, Price(10_000));
+
+        {
+            let mut context =
+                TransactionContext::with_store(Box::new(SledStore::open(&path).unwrap()));
+            context
+                .handle_transaction(&deposit, Account::deposit, true)
+                .unwrap();
+            context.flush().unwrap();
+        }
+
+        // the background flusher of sled can hold on to the lock of the
+        // database for a moment after it is dropped
+        let store = (0..50)
+            .find_map(|_| {
+                SledStore::open(&path)
+                    .inspect_err(|_| std::thread::sleep(std::time::Duration::from_millis(20)))
+                    .ok()
+            })
+            .unwrap();
+        let mut context = TransactionContext::with_store(Box::new(store));
+        assert_eq!(context.account(1).unwrap().total, Price(10_000));
+        assert_eq!(context.account(1).unwrap().meta.tx_count, 1);
+        assert_eq!(context.transaction_count(), 1);
+        assert_eq!(
+            context.handle_transaction(&deposit, Account::deposit, true),
+            Err(crate::data_types::TransactionError::Duplicate)
+        );
+        drop(context);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_exactly_once() {
+        let path = std::env::temp_dir().join("txe_test_sled_store_exactly_once");
+        let _ = std::fs::remove_dir_all(&path);
+        let deposit = |tx| TransactionEvent::new(TransactionType::Deposit, 1, tx, Price(10_000));
+        let open = || {
+            (0..50)
+                .find_map(|_| {
+                    SledStore::open_exactly_once(&path)
+                        .inspect_err(|_| std::thread::sleep(std::time::Duration::from_millis(20)))
+                        .ok()
+                })
+                .unwrap()
+        };
+        let run = |events: Vec<TransactionEvent>| {
+            let mut context = TransactionContext::with_store(Box::new(open()));
+            let result = crate::pipeline::Pipeline::builder()
+                .source(events)
+                .exactly_once()
+                .processor(&mut context)
+                .build()
+                .unwrap()
+                .run();
+            (result, context.account(1).map(|account| account.total))
+        };
+
+        {
+            let mut context = TransactionContext::with_store(Box::new(open()));
+            for tx in 1..=2 {
+                context
+                    .handle_transaction(&deposit(tx), Account::deposit, true)
+                    .unwrap();
+            }
+            context.commit(2).unwrap();
+            // lost in the crash
+            context
+                .handle_transaction(&deposit(3), Account::deposit, true)
+                .unwrap();
+            assert_eq!(context.iter_transactions().count(), 3);
+        }
+
+        // the restart skips the two committed deposits
+        let (result, total) = run((1..=4).map(deposit).collect());
+        assert!(result.is_ok());
+        assert_eq!(total, Some(Price(40_000)));
+        let (result, total) = run((1..=3).map(deposit).collect());
+        assert!(result.is_err());
+        assert_eq!(total, Some(Price(40_000)));
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,361 @@
+//! Versioned binary snapshot of a [`TransactionContext`]: the accounts with
+//! their metadata and the stored transactions, so a run can be continued where
+//! another one, possibly of an older engine, left off.
+//!
+//! A snapshot starts with [`MAGIC`], the version it was written with and the
+//! oldest version that can read it. Sections follow, each a tag, the length of
+//! the payload and the payload encoded with postcard. Readers skip the
+//! sections they do not know. Later versions therefore add data as new
+//! sections, and only raise the minimum version for changes older readers
+//! cannot ignore.
+use crate::{
+    data_types::{Account, AccountMetadata, Price},
+    seal::{self, SnapshotKey},
+    state_store::FLAGS,
+    transaction_context::TransactionContext,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+use tracing::{debug, info, info_span};
+
+pub const MAGIC: [u8; 4] = *b"TXES";
+/// Version of the format this engine writes.
+pub const VERSION: u16 = 3;
+/// Oldest version a reader needs to support to read the snapshots this engine
+/// writes.
+const MIN_VERSION: u16 = 1;
+
+const ACCOUNTS: u8 = 1;
+const TRANSACTIONS: u8 = 2;
+/// clients whose withdrawals are locked, since version 2
+const WITHDRAWALS_LOCKED: u8 = 3;
+/// disputed funds that are not held and overdrawn flags, since version 3
+const SHORTFALLS: u8 = 4;
+
+/// Format of the `--snapshot` output.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SnapshotFormat {
+    /// the accounts as csv, like the extended output
+    #[default]
+    Csv,
+    /// the accounts and transactions, see [`write_binary_snapshot`]
+    Binary,
+}
+
+impl FromStr for SnapshotFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(SnapshotFormat::Csv),
+            "binary" => Ok(SnapshotFormat::Binary),
+            _ => Err(format!(
+                "unknown snapshot format `{s}`, expected csv or binary"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountRecord {
+    client: u16,
+    total: i64,
+    held: i64,
+    locked: bool,
+    tx_count: u32,
+    disputes: u32,
+    chargebacks: u32,
+    locked_at: Option<u64>,
+    lock_tx: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShortfallRecord {
+    client: u16,
+    shortfall: i64,
+    overdrawn: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TransactionRecord {
+    tx: u32,
+    amount: i64,
+    /// index in [`FLAGS`]
+    flags: u8,
+    client: u16,
+}
+
+/// Writes the state of the context in the binary format.
+pub fn write_binary_snapshot(
+    context: &TransactionContext,
+    writer: impl Write,
+) -> anyhow::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&MIN_VERSION.to_le_bytes())?;
+
+    let accounts: Vec<_> = context
+        .iter_accounts()
+        .map(|(client, account)| AccountRecord {
+            client,
+            total: account.total.0,
+            held: account.held.0,
+            locked: account.locked,
+            tx_count: account.meta.tx_count,
+            disputes: account.meta.disputes,
+            chargebacks: account.meta.chargebacks,
+            locked_at: account.meta.locked_at,
+            lock_tx: account.meta.lock_tx,
+        })
+        .collect();
+    write_section(&mut writer, ACCOUNTS, &accounts)?;
+
+    let transactions: Vec<_> = context
+        .iter_transactions()
+        .map(|(tx, (amount, flags, client))| TransactionRecord {
+            tx,
+            amount: amount.0,
+            flags: FLAGS.iter().position(|f| *f == flags).unwrap_or_default() as u8,
+            client,
+        })
+        .collect();
+    write_section(&mut writer, TRANSACTIONS, &transactions)?;
+
+    let withdrawals_locked: Vec<u16> = context
+        .iter_accounts()
+        .filter(|(_, account)| account.withdrawals_locked)
+        .map(|(client, _)| client)
+        .collect();
+    write_section(&mut writer, WITHDRAWALS_LOCKED, &withdrawals_locked)?;
+
+    let shortfalls: Vec<_> = context
+        .iter_accounts()
+        .filter(|(_, account)| account.shortfall.0 != 0 || account.meta.overdrawn)
+        .map(|(client, account)| ShortfallRecord {
+            client,
+            shortfall: account.shortfall.0,
+            overdrawn: account.meta.overdrawn,
+        })
+        .collect();
+    write_section(&mut writer, SHORTFALLS, &shortfalls)?;
+
+    Ok(writer.flush()?)
+}
+
+fn write_section(writer: &mut impl Write, tag: u8, payload: &impl Serialize) -> anyhow::Result<()> {
+    let payload = postcard::to_allocvec(payload)?;
+    writer.write_all(&[tag])?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Loads a snapshot written by [`write_binary_snapshot`] into the context,
+/// replacing the accounts and transactions it holds with the same ids.
+pub fn read_binary_snapshot(
+    reader: impl Read,
+    context: &mut TransactionContext,
+) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut header = [0; 8];
+    reader.read_exact(&mut header)?;
+    if header[..4] != MAGIC {
+        anyhow::bail!("not a binary snapshot");
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    let min_version = u16::from_le_bytes([header[6], header[7]]);
+    if min_version > VERSION {
+        anyhow::bail!(
+            "snapshot of version {version} needs support for version {min_version}, this engine supports {VERSION}"
+        );
+    }
+
+    loop {
+        let mut tag = [0];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(());
+        }
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let mut payload = vec![0; u64::from_le_bytes(len).try_into()?];
+        reader.read_exact(&mut payload)?;
+
+        match tag[0] {
+            ACCOUNTS => {
+                let accounts: Vec<AccountRecord> = postcard::from_bytes(&payload)?;
+                for record in accounts {
+                    let account = Account {
+                        total: Price(record.total),
+                        held: Price(record.held),
+                        locked: record.locked,
+                        withdrawals_locked: false,
+                        shortfall: Price(0),
+                        meta: AccountMetadata {
+                            tx_count: record.tx_count,
+                            disputes: record.disputes,
+                            chargebacks: record.chargebacks,
+                            locked_at: record.locked_at,
+                            lock_tx: record.lock_tx,
+                            overdrawn: false,
+                        },
+                    };
+                    context.insert_account(record.client, account);
+                }
+            }
+            TRANSACTIONS => {
+                let transactions: Vec<TransactionRecord> = postcard::from_bytes(&payload)?;
+                for record in transactions {
+                    let Some(flags) = FLAGS.get(record.flags as usize) else {
+                        anyhow::bail!("invalid state of transaction {}", record.tx);
+                    };
+                    context.insert_transaction(
+                        record.tx,
+                        (Price(record.amount), *flags, record.client),
+                    );
+                }
+            }
+            WITHDRAWALS_LOCKED => {
+                let clients: Vec<u16> = postcard::from_bytes(&payload)?;
+                for client in clients {
+                    // follows the accounts section
+                    if let Some(mut account) = context.account(client).copied() {
+                        account.withdrawals_locked = true;
+                        context.insert_account(client, account);
+                    }
+                }
+            }
+            SHORTFALLS => {
+                let records: Vec<ShortfallRecord> = postcard::from_bytes(&payload)?;
+                for record in records {
+                    if let Some(mut account) = context.account(record.client).copied() {
+                        account.shortfall = Price(record.shortfall);
+                        account.meta.overdrawn = record.overdrawn;
+                        context.insert_account(record.client, account);
+                    }
+                }
+            }
+            tag => debug!(tag, version, "skipping unknown snapshot section"),
+        }
+    }
+}
+
+/// Writes a binary snapshot of the context to the given path.
+pub fn write_binary_snapshot_to_file(
+    context: &TransactionContext,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let _span = info_span!("snapshot", path = %path.display()).entered();
+    write_binary_snapshot(context, File::create(path)?)
+}
+
+/// Loads a binary snapshot from the given path into the context. While a
+/// snapshot key is set, only protected snapshots are loaded, see
+/// [`crate::seal`].
+pub fn restore(context: &mut TransactionContext, path: &Path) -> anyhow::Result<()> {
+    let data = std::fs::read(path)?;
+    let key = SnapshotKey::from_env()?;
+    if key.is_some() && !seal::is_sealed(&data) {
+        anyhow::bail!(
+            "{} is not protected, while a snapshot key is set",
+            path.display()
+        );
+    }
+    seal::unseal(&data, key.as_ref())
+        .and_then(|data| read_binary_snapshot(&*data, context))
+        .map_err(|e| e.context(format!("failed to restore {}", path.display())))?;
+    info!(
+        path = %path.display(),
+        accounts = context.account_count(),
+        transactions = context.transaction_count(),
+        "restored snapshot"
+    );
+    Ok(())
+}
+
+/// Whether the file starts like a binary snapshot, once unprotected.
+pub fn is_binary_snapshot(path: &Path) -> anyhow::Result<bool> {
+    let mut magic = [0; 4];
+    let n = File::open(path)?.read(&mut magic)?;
+    if n == seal::MAGIC.len() && magic == seal::MAGIC {
+        return Ok(seal::read_file(path)?.starts_with(&MAGIC));
+    }
+    Ok(n == MAGIC.len() && magic == MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::{
+        HoldPolicy, LockPolicy, TransactionEvent, TransactionFlags, TransactionType,
+    };
+
+    fn event(ty: TransactionType, client_id: u16, tx: u32, amount: i64) -> TransactionEvent {
+        TransactionEvent::new(ty, client_id, tx, Price(amount))
+    }
+
+    #[test]
+    fn test_binary_snapshot_roundtrip() {
+        let mut context = TransactionContext::new();
+        context.set_client_lock_policy("2".parse().unwrap(), LockPolicy::Withdrawals);
+        context.set_hold_policy(HoldPolicy::Cap);
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 10_000),
+            event(TransactionType::Deposit, 2, 2, 20_000),
+            event(TransactionType::Dispute, 2, 2, 0),
+            event(TransactionType::Chargeback, 2, 2, 0),
+            event(TransactionType::Deposit, 3, 3, 20_000),
+            event(TransactionType::Withdrawal, 3, 4, 15_000),
+            event(TransactionType::Dispute, 3, 3, 0),
+        ] {
+            context.apply(&event).unwrap();
+        }
+        let mut buf = Vec::new();
+        write_binary_snapshot(&context, &mut buf).unwrap();
+
+        let mut restored = TransactionContext::new();
+        read_binary_snapshot(buf.as_slice(), &mut restored).unwrap();
+        assert_eq!(restored.account(1), context.account(1));
+        assert_eq!(restored.account(2), context.account(2));
+        assert!(restored.account(2).unwrap().withdrawals_locked);
+        assert_eq!(restored.account(3), context.account(3));
+        assert_eq!(restored.account(3).unwrap().shortfall, Price(15_000));
+        assert_eq!(
+            restored.transaction(2),
+            Some((Price(20_000), TransactionFlags::Chargeback, 2))
+        );
+        // the restored context rejects what the original one rejects
+        assert!(restored
+            .apply(&event(TransactionType::Deposit, 1, 1, 10_000))
+            .is_err());
+    }
+
+    #[test]
+    fn test_forward_compatibility() {
+        let mut context = TransactionContext::new();
+        context
+            .apply(&event(TransactionType::Deposit, 1, 1, 10_000))
+            .unwrap();
+        let mut buf = Vec::new();
+        write_binary_snapshot(&context, &mut buf).unwrap();
+
+        // a section of a later version is skipped
+        buf[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        write_section(&mut buf, 200, &"later").unwrap();
+        let mut restored = TransactionContext::new();
+        read_binary_snapshot(buf.as_slice(), &mut restored).unwrap();
+        assert_eq!(restored.account(1), context.account(1));
+
+        // unless the snapshot says it can not be read without it
+        buf[6..8].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let error = read_binary_snapshot(buf.as_slice(), &mut TransactionContext::new());
+        assert!(error.unwrap_err().to_string().contains("needs support"));
+
+        assert!(read_binary_snapshot(b"client,available".as_slice(), &mut restored).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{
+    data_types::{Account, Price},
+    pseudonym,
+};
+use csv::Writer;
+use std::{collections::BTreeMap, io::Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    /// the account only exists in the second snapshot
+    Added,
+    /// the account only exists in the first snapshot
+    Removed,
+    /// the account got locked in between the snapshots
+    Locked,
+    Unlocked,
+    /// balances changed
+    Changed,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Locked => "locked",
+            Change::Unlocked => "unlocked",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+/// Difference of a single account between two snapshots.
+#[derive(Debug, Clone, Copy)]
+pub struct AccountDiff {
+    pub client_id: u16,
+    pub before: Option<Account>,
+    pub after: Option<Account>,
+}
+
+impl AccountDiff {
+    pub fn change(&self) -> Change {
+        match (self.before, self.after) {
+            (None, _) => Change::Added,
+            (_, None) => Change::Removed,
+            (Some(before), Some(after)) if !before.locked && after.locked => Change::Locked,
+            (Some(before), Some(after)) if before.locked && !after.locked => Change::Unlocked,
+            _ => Change::Changed,
+        }
+    }
+
+    pub fn available_delta(&self) -> Price {
+        delta(
+            self.before.map(|a| a.available()),
+            self.after.map(|a| a.available()),
+        )
+    }
+
+    pub fn held_delta(&self) -> Price {
+        delta(self.before.map(|a| a.held), self.after.map(|a| a.held))
+    }
+
+    pub fn total_delta(&self) -> Price {
+        delta(self.before.map(|a| a.total), self.after.map(|a| a.total))
+    }
+}
+
+fn delta(before: Option<Price>, after: Option<Price>) -> Price {
+    let before = before.unwrap_or_default();
+    let after = after.unwrap_or_default();
+    Price(after.0.saturating_sub(before.0))
+}
+
+fn differs(a: &Account, b: &Account) -> bool {
+    a.total != b.total || a.held != b.held || a.locked != b.locked
+}
+
+/// Compares two sets of accounts, returning only the accounts that differ in
+/// balances or lock status, ordered by client id.
+pub fn diff_accounts(
+    before: impl IntoIterator<Item = (u16, Account)>,
+    after: impl IntoIterator<Item = (u16, Account)>,
+) -> Vec<AccountDiff> {
+    let mut diffs: BTreeMap<u16, AccountDiff> = BTreeMap::new();
+    for (client_id, account) in before {
+        diffs.insert(
+            client_id,
+            AccountDiff {
+                client_id,
+                before: Some(account),
+                after: None,
+            },
+        );
+    }
+    for (client_id, account) in after {
+        diffs
+            .entry(client_id)
+            .or_insert(AccountDiff {
+                client_id,
+                before: None,
+                after: None,
+            })
+            .after = Some(account);
+    }
+
+    diffs
+        .into_values()
+        .filter(|diff| match (&diff.before, &diff.after) {
+            (Some(before), Some(after)) => differs(before, after),
+            _ => true,
+        })
+        .collect()
+}
+
+/// Writes the differences as csv.
+pub fn write_diff(writer: impl Write, diffs: &[AccountDiff]) -> anyhow::Result<()> {
+    let mut writer = Writer::from_writer(writer);
+    writer.write_record([
+        "client",
+        "change",
+        "available_delta",
+        "held_delta",
+        "total_delta",
+        "locked",
+    ])?;
+
+    for diff in diffs {
+        writer.write_record(&[
+            pseudonym::client(diff.client_id).to_string(),
+            diff.change().as_str().to_string(),
+            diff.available_delta().to_string(),
+            diff.held_delta().to_string(),
+            diff.total_delta().to_string(),
+            diff.after.is_some_and(|a| a.locked).to_string(),
+        ])?;
+    }
+
+    Ok(writer.flush()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(total: i64, held: i64, locked: bool) -> Account {
+        Account {
+            total: Price(total),
+            held: Price(held),
+            locked,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_diff_accounts() {
+        let before = [
+            (1, account(10_000, 0, false)),
+            (2, account(20_000, 0, false)),
+            (3, account(30_000, 0, false)),
+            (4, account(40_000, 0, false)),
+        ];
+        let after = [
+            (1, account(10_000, 0, false)),
+            (2, account(0, 0, true)),
+            (3, account(35_000, 5_000, false)),
+            (5, account(50_000, 0, false)),
+        ];
+
+        let diffs = diff_accounts(before, after);
+        let summary: Vec<_> = diffs
+            .iter()
+            .map(|d| (d.client_id, d.change(), d.total_delta()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (2, Change::Locked, Price(-20_000)),
+                (3, Change::Changed, Price(5_000)),
+                (4, Change::Removed, Price(-40_000)),
+                (5, Change::Added, Price(50_000)),
+            ]
+        );
+        assert_eq!(diffs[1].available_delta(), Price(0));
+        assert_eq!(diffs[1].held_delta(), Price(5_000));
+    }
+}
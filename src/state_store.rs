@@ -0,0 +1,443 @@
+//! Storage of the accounts and transactions of a [`TransactionContext`].
+//!
+//! [`TransactionContext`]: crate::transaction_context::TransactionContext
+use crate::data_types::{Account, TransactionFlags};
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+use crate::data_types::{AccountMetadata, Price};
+use std::collections::HashMap;
+pub use txe_accounting::{Ledger, StoredTransaction};
+
+/// Approximate amount of bytes a backend holds in memory.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub accounts: usize,
+    pub transactions: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.accounts + self.transactions
+    }
+}
+
+/// Allocated size of a hash map, one control byte per bucket included.
+pub(crate) fn map_bytes<K, V>(map: &HashMap<K, V>) -> usize {
+    map.capacity() * (std::mem::size_of::<(K, V)>() + 1)
+}
+
+/// Backend holding the state of a context, on top of the lookups the
+/// accounting rules need ([`Ledger`]). Accounts are few (one per client id),
+/// backends are expected to keep them in memory so they can be handed out by
+/// reference. Transactions can be many and may live on disk.
+pub trait StateStore: Ledger + std::fmt::Debug + Send {
+    fn account(&self, client_id: u16) -> Option<&Account>;
+
+    /// Inserts or replaces the account of the client.
+    fn put_account(&mut self, client_id: u16, account: Account);
+
+    /// Makes room for at least `additional` more accounts.
+    fn reserve_accounts(&mut self, additional: usize);
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (u16, &Account)> + '_>;
+
+    fn account_count(&self) -> usize;
+
+    fn transaction_count(&self) -> usize;
+
+    /// The stored transactions, in no particular order.
+    fn transactions(&self) -> Box<dyn Iterator<Item = (u32, StoredTransaction)> + '_>;
+
+    fn memory_usage(&self) -> MemoryUsage;
+
+    /// Removes the account of the client, its transactions are left alone.
+    fn remove_account(&mut self, client_id: u16) -> Option<Account>;
+
+    /// Removes the transactions matching the predicate, returns how many were
+    /// removed.
+    fn remove_transactions(&mut self, remove: &dyn Fn(&StoredTransaction) -> bool) -> usize;
+
+    /// Like [`StateStore::remove_transactions`], but only to release memory.
+    /// Backends that keep the transactions on disk keep them.
+    fn evict_transactions(&mut self, _evict: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        0
+    }
+
+    /// Makes the writes so far durable, a no-op for volatile backends.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Position in the input the stored state covers, as of the last
+    /// [`StateStore::commit`].
+    fn position(&self) -> Option<u64> {
+        None
+    }
+
+    /// Makes the writes so far durable together with the position in the
+    /// input they cover. Backends opened for exactly-once processing only
+    /// write at a commit, in one atomic step, so after a crash the stored
+    /// state always matches the stored position.
+    fn commit(&mut self, _position: u64) -> anyhow::Result<()> {
+        self.flush()
+    }
+}
+
+/// The default, volatile backend. Transactions are stored in a slab, the map
+/// only holds their index. This keeps the map small, so probing it stays in
+/// cache, and the records are never moved around when the map grows.
+#[derive(Debug)]
+pub struct MemoryStore {
+    index: HashMap<u32, u32>,
+    transactions: Vec<StoredTransaction>,
+    accounts: HashMap<u16, Account>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        MemoryStore {
+            // arbitrary chosen capacity values
+            index: HashMap::with_capacity(1024 * 1024),
+            transactions: Vec::with_capacity(1024 * 1024),
+            accounts: HashMap::with_capacity(1024),
+        }
+    }
+}
+
+impl Ledger for MemoryStore {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        let slot = *self.index.get(&tx)?;
+        Some(self.transactions[slot as usize])
+    }
+
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        match self.index.get(&tx) {
+            Some(slot) => self.transactions[*slot as usize] = transaction,
+            None => {
+                // tx ids are u32, so the amount of transactions fits as well
+                self.index.insert(tx, self.transactions.len() as u32);
+                self.transactions.push(transaction);
+            }
+        }
+    }
+
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account {
+        let account = self.accounts.entry(client_id).or_default();
+        update(account);
+        *account
+    }
+}
+
+impl StateStore for MemoryStore {
+    fn account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.get(&client_id)
+    }
+
+    fn put_account(&mut self, client_id: u16, account: Account) {
+        self.accounts.insert(client_id, account);
+    }
+
+    fn reserve_accounts(&mut self, additional: usize) {
+        self.accounts.reserve(additional);
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (u16, &Account)> + '_> {
+        Box::new(self.accounts.iter().map(|(id, account)| (*id, account)))
+    }
+
+    fn account_count(&self) -> usize {
+        self.accounts.len()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = (u32, StoredTransaction)> + '_> {
+        Box::new(
+            self.index
+                .iter()
+                .map(|(tx, slot)| (*tx, self.transactions[*slot as usize])),
+        )
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            accounts: map_bytes(&self.accounts),
+            transactions: map_bytes(&self.index)
+                + self.transactions.capacity() * std::mem::size_of::<StoredTransaction>(),
+        }
+    }
+
+    fn remove_account(&mut self, client_id: u16) -> Option<Account> {
+        self.accounts.remove(&client_id)
+    }
+
+    fn remove_transactions(&mut self, remove: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        // compact the slab, remembering where every kept record moved to
+        let mut slots = Vec::with_capacity(self.transactions.len());
+        let mut kept = 0;
+        for transaction in &self.transactions {
+            slots.push((!remove(transaction)).then(|| {
+                kept += 1;
+                kept - 1
+            }));
+        }
+        let removed = self.transactions.len() - kept as usize;
+        let mut slot = 0;
+        self.transactions.retain(|_| {
+            slot += 1;
+            slots[slot - 1].is_some()
+        });
+        self.index.retain(|_, slot| match slots[*slot as usize] {
+            Some(moved) => {
+                *slot = moved;
+                true
+            }
+            None => false,
+        });
+        self.transactions.shrink_to_fit();
+        self.index.shrink_to_fit();
+        removed
+    }
+
+    fn evict_transactions(&mut self, evict: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        self.remove_transactions(evict)
+    }
+}
+
+// Fixed width encoding of the state for the backends that persist it.
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) const ACCOUNT_LEN: usize = 8 + 8 + 1 + 4 + 4 + 4 + 9 + 5 + 8;
+/// Length of the accounts written before the shortfall was added.
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+const LEGACY_ACCOUNT_LEN: usize = ACCOUNT_LEN - 8;
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) fn encode_account(account: &Account) -> [u8; ACCOUNT_LEN] {
+    let mut buf = [0; ACCOUNT_LEN];
+    let meta = &account.meta;
+    buf[0..8].copy_from_slice(&account.total.0.to_be_bytes());
+    buf[8..16].copy_from_slice(&account.held.0.to_be_bytes());
+    buf[16] = account.locked as u8
+        | (account.withdrawals_locked as u8) << 1
+        | (meta.overdrawn as u8) << 2;
+    buf[17..21].copy_from_slice(&meta.tx_count.to_be_bytes());
+    buf[21..25].copy_from_slice(&meta.disputes.to_be_bytes());
+    buf[25..29].copy_from_slice(&meta.chargebacks.to_be_bytes());
+    if let Some(locked_at) = meta.locked_at {
+        buf[29] = 1;
+        buf[30..38].copy_from_slice(&locked_at.to_be_bytes());
+    }
+    if let Some(lock_tx) = meta.lock_tx {
+        buf[38] = 1;
+        buf[39..43].copy_from_slice(&lock_tx.to_be_bytes());
+    }
+    buf[43..51].copy_from_slice(&account.shortfall.0.to_be_bytes());
+    buf
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) fn decode_account(buf: &[u8]) -> Option<Account> {
+    let mut padded = [0; ACCOUNT_LEN];
+    match buf.len() {
+        ACCOUNT_LEN | LEGACY_ACCOUNT_LEN => padded[..buf.len()].copy_from_slice(buf),
+        _ => return None,
+    }
+    let buf = &padded;
+    let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+    let u64_at = |i: usize| u64::from_be_bytes(buf[i..i + 8].try_into().unwrap());
+    let i64_at = |i: usize| u64_at(i) as i64;
+    Some(Account {
+        total: Price(i64_at(0)),
+        held: Price(i64_at(8)),
+        locked: buf[16] & 1 != 0,
+        withdrawals_locked: buf[16] & 2 != 0,
+        shortfall: Price(i64_at(43)),
+        meta: AccountMetadata {
+            tx_count: u32_at(17),
+            disputes: u32_at(21),
+            chargebacks: u32_at(25),
+            locked_at: (buf[29] != 0).then(|| u64_at(30)),
+            lock_tx: (buf[38] != 0).then(|| u32_at(39)),
+            overdrawn: buf[16] & 4 != 0,
+        },
+    })
+}
+
+/// The transaction states in the order of their encoding.
+pub(crate) const FLAGS: [TransactionFlags; 5] = [
+    TransactionFlags::None,
+    TransactionFlags::Disputed,
+    TransactionFlags::Resolved,
+    TransactionFlags::Chargeback,
+    TransactionFlags::Final,
+];
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) fn encode_transaction((amount, flags, client_id): &StoredTransaction) -> [u8; 11] {
+    let mut buf = [0; 11];
+    buf[0..8].copy_from_slice(&amount.0.to_be_bytes());
+    buf[8] = FLAGS.iter().position(|f| f == flags).unwrap_or_default() as u8;
+    buf[9..11].copy_from_slice(&client_id.to_be_bytes());
+    buf
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) fn decode_transaction(buf: &[u8]) -> Option<StoredTransaction> {
+    let buf: &[u8; 11] = buf.try_into().ok()?;
+    Some((
+        Price(i64::from_be_bytes(buf[0..8].try_into().unwrap())),
+        *FLAGS.get(buf[8] as usize)?,
+        u16::from_be_bytes([buf[9], buf[10]]),
+    ))
+}
+
+/// Key of the committed position, see [`StateStore::commit`].
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) const POSITION_KEY: &[u8] = b"position";
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) fn decode_position(buf: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(buf.try_into().ok()?))
+}
+
+/// Bloom filter over the ids of the stored transactions, so backends that keep
+/// the transactions on disk can answer the common case of a new transaction
+/// without a disk lookup.
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+pub(crate) struct TxFilter {
+    bits: Vec<u64>,
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+impl TxFilter {
+    /// 2^27 bits (16 MiB) keep the false positive rate below 0.5% up to ten
+    /// million transactions.
+    const BITS: u32 = 27;
+    const HASHES: u64 = 4;
+
+    pub(crate) fn new() -> Self {
+        TxFilter {
+            bits: vec![0; 1 << (Self::BITS - 6)],
+        }
+    }
+
+    fn positions(tx: u32) -> impl Iterator<Item = usize> {
+        // splitmix64 finalizer, the halves serve as two independent hashes
+        let mut h = (tx as u64).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+        (0..Self::HASHES)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) & ((1 << Self::BITS) - 1)) as usize)
+    }
+
+    pub(crate) fn insert(&mut self, tx: u32) {
+        for pos in Self::positions(tx) {
+            self.bits[pos / 64] |= 1 << (pos % 64);
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    /// False means the transaction is certainly not stored.
+    pub(crate) fn may_contain(&self, tx: u32) -> bool {
+        Self::positions(tx).all(|pos| self.bits[pos / 64] & (1 << (pos % 64)) != 0)
+    }
+}
+
+#[cfg(any(feature = "sled", feature = "rocksdb"))]
+impl std::fmt::Debug for TxFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TxFilter").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::Price;
+
+    #[test]
+    fn test_memory_store_slab() {
+        let mut store = MemoryStore::default();
+        store.put_transaction(7, (Price(1), TransactionFlags::None, 1));
+        store.put_transaction(3, (Price(2), TransactionFlags::None, 2));
+        store.put_transaction(7, (Price(1), TransactionFlags::Disputed, 1));
+        assert_eq!(store.transaction_count(), 2);
+        assert_eq!(
+            store.transaction(7),
+            Some((Price(1), TransactionFlags::Disputed, 1))
+        );
+        assert_eq!(
+            store.transaction(3),
+            Some((Price(2), TransactionFlags::None, 2))
+        );
+        assert_eq!(store.transaction(5), None);
+
+        let usage = store.memory_usage();
+        assert!(usage.transactions >= 1024 * 1024 * 24);
+        assert!(usage.accounts > 0);
+
+        assert_eq!(
+            store.evict_transactions(&|(_, flags, _)| *flags == TransactionFlags::Disputed),
+            1
+        );
+        assert_eq!(store.transaction(7), None);
+        assert_eq!(
+            store.transaction(3),
+            Some((Price(2), TransactionFlags::None, 2))
+        );
+        assert!(store.memory_usage().transactions < usage.transactions);
+    }
+
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[test]
+    fn test_encoding_roundtrip() {
+        let account = Account {
+            total: Price(-5),
+            held: Price(7),
+            locked: true,
+            withdrawals_locked: true,
+            shortfall: Price(3),
+            meta: AccountMetadata {
+                tx_count: 3,
+                disputes: 2,
+                chargebacks: 1,
+                locked_at: Some(1_700_000_000),
+                lock_tx: Some(42),
+                overdrawn: true,
+            },
+        };
+        let encoded = encode_account(&account);
+        assert_eq!(decode_account(&encoded), Some(account));
+        // accounts written before the shortfall was added
+        let legacy = decode_account(&encoded[..LEGACY_ACCOUNT_LEN]).unwrap();
+        assert_eq!((legacy.total, legacy.shortfall), (account.total, Price(0)));
+
+        let transaction = (Price(12), TransactionFlags::Chargeback, 9);
+        assert_eq!(
+            decode_transaction(&encode_transaction(&transaction)),
+            Some(transaction)
+        );
+    }
+
+    #[cfg(any(feature = "sled", feature = "rocksdb"))]
+    #[test]
+    fn test_tx_filter() {
+        let mut filter = TxFilter::new();
+        for tx in (0..100_000).step_by(2) {
+            filter.insert(tx);
+        }
+        assert!((0..100_000).step_by(2).all(|tx| filter.may_contain(tx)));
+        let false_positives = (1..100_000)
+            .step_by(2)
+            .filter(|tx| filter.may_contain(*tx))
+            .count();
+        assert!(false_positives < 50, "{false_positives} false positives");
+    }
+}
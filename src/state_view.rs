@@ -0,0 +1,95 @@
+use crate::data_types::Account;
+use arc_swap::ArcSwap;
+use im::OrdMap;
+use std::sync::Arc;
+
+/// Read view of the account state, shared between the processor (the single
+/// writer) and readers on other threads, like the HTTP query API.
+///
+/// Every update publishes a new immutable snapshot (RCU-style). The accounts
+/// are kept in a persistent map, so a snapshot shares all unchanged accounts
+/// with the previous one and publishing costs a single path copy. Reads load
+/// the current snapshot without locking and never block the writer.
+#[derive(Debug, Clone, Default)]
+pub struct StateView {
+    accounts: Arc<ArcSwap<OrdMap<u16, Account>>>,
+}
+
+impl StateView {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Publishes the current state of an account.
+    pub fn update(&self, client_id: u16, account: Account) {
+        // with a single writer the closure runs exactly once
+        self.accounts
+            .rcu(|accounts| accounts.update(client_id, account));
+    }
+
+    /// Publishes the removal of an account.
+    pub fn remove(&self, client_id: u16) {
+        self.accounts.rcu(|accounts| accounts.without(&client_id));
+    }
+
+    pub fn account(&self, client_id: u16) -> Option<Account> {
+        self.accounts.load().get(&client_id).copied()
+    }
+
+    /// Returns all accounts, ordered by client id.
+    pub fn accounts(&self) -> Vec<(u16, Account)> {
+        self.snapshot()
+            .iter()
+            .map(|(id, account)| (*id, *account))
+            .collect()
+    }
+
+    /// Returns a consistent snapshot of all accounts. Later updates are not
+    /// visible in the returned snapshot.
+    pub fn snapshot(&self) -> Arc<OrdMap<u16, Account>> {
+        self.accounts.load_full()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::Price;
+
+    #[test]
+    fn test_reads_during_updates() {
+        let view = StateView::new();
+        let reader = {
+            let view = view.clone();
+            std::thread::spawn(move || {
+                // balances only grow, every snapshot must be internally consistent
+                let mut last = Price(0);
+                loop {
+                    let snapshot = view.snapshot();
+                    let total = snapshot.get(&1).map(|a| a.total).unwrap_or_default();
+                    assert!(total >= last);
+                    last = total;
+                    if snapshot.len() == 2 {
+                        break;
+                    }
+                }
+            })
+        };
+
+        for i in 1..=10_000 {
+            let account = Account {
+                total: Price(i),
+                ..Default::default()
+            };
+            view.update(1, account);
+        }
+        view.update(2, Account::default());
+        reader.join().unwrap();
+
+        let before = view.snapshot();
+        view.update(3, Account::default());
+        assert_eq!(before.len(), 2);
+        assert_eq!(view.accounts().len(), 3);
+        assert_eq!(view.account(1).unwrap().total, Price(10_000));
+    }
+}
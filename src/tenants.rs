@@ -0,0 +1,182 @@
+use crate::{
+    cross_file::CrossFileDuplicates,
+    csv_source::CsvSource,
+    filter::Filters,
+    pipeline::{Message, Pipeline},
+    schema::Schema,
+    transaction_context::TransactionContext,
+};
+use std::{collections::BTreeMap, path::PathBuf, str::FromStr};
+use tracing::info_span;
+
+/// Input file of a tenant, parsed from `NAME=PATH`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TenantInput {
+    pub name: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for TenantInput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((name, path)) = s.split_once('=') else {
+            return Err(format!("expected NAME=PATH, got `{s}`"));
+        };
+        if name.is_empty() || path.is_empty() {
+            return Err(format!("expected NAME=PATH, got `{s}`"));
+        }
+        // the name ends up in output file names
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "tenant name `{name}` may only contain alphanumerics, `-` and `_`"
+            ));
+        }
+        Ok(TenantInput {
+            name: name.to_string(),
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// Processes the input of every tenant into its own isolated context. Every
+/// tenant gets its own source, ring buffer and processor thread, so tenants
+/// never observe each other's clients or transactions.
+///
+/// Multiple inputs of the same tenant are processed one after the other into
+/// the same context. Their transaction ids are checked for duplicates across
+/// the files when `duplicates` is given.
+pub fn process_tenants(
+    inputs: &[TenantInput],
+    track_history: bool,
+    schema: &Schema,
+    filters: &Filters,
+    duplicates: Option<&CrossFileDuplicates>,
+) -> anyhow::Result<BTreeMap<String, TransactionContext>> {
+    let mut paths: BTreeMap<&str, Vec<&PathBuf>> = BTreeMap::new();
+    for input in inputs {
+        paths.entry(&input.name).or_default().push(&input.path);
+    }
+
+    std::thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .into_iter()
+            .map(|(name, paths)| {
+                let worker = std::thread::Builder::new()
+                    .name(format!("tenant {name}"))
+                    .spawn_scoped(scope, move || {
+                        process_tenant(name, &paths, track_history, schema, filters, duplicates)
+                    });
+                (name, worker)
+            })
+            .collect();
+
+        let mut contexts = BTreeMap::new();
+        for (name, worker) in workers {
+            let context = worker?
+                .join()
+                .map_err(|_| anyhow::anyhow!("processing of tenant {name} panicked"))??;
+            contexts.insert(name.to_string(), context);
+        }
+        Ok(contexts)
+    })
+}
+
+fn process_tenant(
+    name: &str,
+    paths: &[&PathBuf],
+    track_history: bool,
+    schema: &Schema,
+    filters: &Filters,
+    duplicates: Option<&CrossFileDuplicates>,
+) -> anyhow::Result<TransactionContext> {
+    let _span = info_span!("tenant", name).entered();
+    let mut context = TransactionContext::new();
+    if track_history {
+        context.track_history();
+    }
+
+    let mut pipeline = Pipeline::builder();
+    for (file, path) in paths.iter().enumerate() {
+        let source = CsvSource::open_with_schema(path, None, schema)?;
+        pipeline = match duplicates {
+            // parsed while reading, to know the file of every event
+            Some(duplicates) => pipeline.source(source.inspect(move |message| {
+                if let Message::Event(event) = message {
+                    duplicates.observe(name, file, event);
+                }
+            })),
+            None => pipeline.csv_source(source),
+        };
+    }
+    filters
+        .apply(pipeline)
+        .processor(&mut context)
+        .build()?
+        .run()?;
+    Ok(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tenant_input() {
+        assert_eq!(
+            "acme=data/acme.csv".parse(),
+            Ok(TenantInput {
+                name: "acme".to_string(),
+                path: PathBuf::from("data/acme.csv")
+            })
+        );
+        assert!("acme".parse::<TenantInput>().is_err());
+        assert!("=acme.csv".parse::<TenantInput>().is_err());
+        assert!("../acme=acme.csv".parse::<TenantInput>().is_err());
+    }
+
+    #[test]
+    fn test_tenants_are_isolated() {
+        let dir = std::env::temp_dir().join("txe_test_tenants");
+        std::fs::create_dir_all(&dir).unwrap();
+        let acme = dir.join("acme.csv");
+        let globex = dir.join("globex.csv");
+        // both tenants use the same client and transaction ids
+        std::fs::write(&acme, "type,client,tx,amount\ndeposit,1,1,2.0\n").unwrap();
+        std::fs::write(
+            &globex,
+            "type,client,tx,amount\ndeposit,1,1,5.0\ndispute,1,1,\n",
+        )
+        .unwrap();
+
+        let inputs = [
+            TenantInput {
+                name: "acme".to_string(),
+                path: acme,
+            },
+            TenantInput {
+                name: "globex".to_string(),
+                path: globex,
+            },
+        ];
+        let contexts = process_tenants(
+            &inputs,
+            false,
+            &Schema::default(),
+            &Filters::default(),
+            None,
+        )
+        .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let acme = contexts["acme"].account(1).unwrap();
+        assert_eq!(acme.total, 2.0.try_into().unwrap());
+        assert_eq!(acme.held, 0.0.try_into().unwrap());
+        let globex = contexts["globex"].account(1).unwrap();
+        assert_eq!(globex.total, 5.0.try_into().unwrap());
+        assert_eq!(globex.held, 5.0.try_into().unwrap());
+    }
+}
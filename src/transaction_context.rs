@@ -1,89 +1,656 @@
-use crate::data_types::{
-    Account, Price, TransactionError, TransactionEvent, TransactionFlags, TransactionType,
+use crate::{
+    data_types::{
+        Account, Balance, HoldPolicy, LockPolicy, Price, TransactionError, TransactionEvent,
+        TransactionFlags, TransactionType, TxRecord,
+    },
+    filter::ClientSet,
+    state_store::{MemoryStore, MemoryUsage, StateStore, StoredTransaction},
 };
-use std::collections::{hash_map::Entry, HashMap};
-use tracing::debug;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use txe_accounting::{apply_dispute, apply_transaction};
+
+/// A point in the history of a client, see [`TransactionContext::account_at`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryPoint {
+    /// up to and including the n-th (zero based) applied event of the client
+    Offset(usize),
+    /// up to and including the first applied event referencing the transaction
+    Tx(u32),
+}
+
+/// An account removed by [`TransactionContext::prune_accounts`], together with
+/// the transactions that were stored for it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrunedAccount {
+    pub client_id: u16,
+    pub account: Account,
+    pub transactions: Vec<(u32, StoredTransaction)>,
+}
 
 #[derive(Debug)]
 pub struct TransactionContext {
-    transactions: HashMap<u32, (Price, TransactionFlags, u16)>,
-    accounts: HashMap<u16, Account>,
+    store: Box<dyn StateStore>,
+    /// applied transactions per client, only populated when tracking is enabled
+    history: Option<HashMap<u16, Vec<TxRecord>>>,
+    /// balances after every entry of the history, only populated when enabled
+    balances: Option<HashMap<u16, Vec<Balance>>>,
+    /// merged accounts by the tx of their merge, with the amount of entries of
+    /// the history of the merged client in front of the merge
+    merged: HashMap<u32, (Account, usize)>,
+    /// client receiving the charged back amounts
+    suspense: Option<u16>,
+    lock_policy: LockPolicy,
+    /// overrides of the lock policy, the last matching one wins
+    client_lock_policies: Vec<(ClientSet, LockPolicy)>,
+    hold_policy: HoldPolicy,
+    /// the clients events are accepted for, all when not set
+    registry: Option<ClientSet>,
+}
+
+impl Default for TransactionContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TransactionContext {
     pub fn new() -> Self {
+        Self::with_store(Box::new(MemoryStore::default()))
+    }
+
+    /// Creates a context on top of the given backend, continuing with the
+    /// state it already holds.
+    pub fn with_store(store: Box<dyn StateStore>) -> Self {
         TransactionContext {
-            // arbitrary chosen capacity values
-            transactions: HashMap::with_capacity(1024 * 1024),
-            accounts: HashMap::with_capacity(1024),
+            store,
+            history: None,
+            balances: None,
+            merged: HashMap::new(),
+            suspense: None,
+            lock_policy: LockPolicy::default(),
+            client_lock_policies: Vec::new(),
+            hold_policy: HoldPolicy::default(),
+            registry: None,
         }
     }
 
+    /// Keep an index of the applied transactions per client, see
+    /// [`TransactionContext::history`]. Memory usage grows with every applied
+    /// transaction.
+    pub fn track_history(&mut self) {
+        self.history.get_or_insert_with(HashMap::new);
+    }
+
+    /// Keep the balances of the account after every applied transaction as
+    /// well, see [`TransactionContext::balances`]. Enables history tracking.
+    pub fn track_balances(&mut self) {
+        self.track_history();
+        self.balances.get_or_insert_with(HashMap::new);
+    }
+
+    pub fn tracks_balances(&self) -> bool {
+        self.balances.is_some()
+    }
+
+    /// Credit the amounts of chargebacks to the account of the given client,
+    /// instead of removing them from the system. Events of the client itself
+    /// are rejected as [`TransactionError::ReservedClient`].
+    pub fn set_suspense_account(&mut self, client_id: u16) {
+        self.suspense = Some(client_id);
+    }
+
+    pub fn suspense_account(&self) -> Option<u16> {
+        self.suspense
+    }
+
+    /// What a chargeback does to the account of the client, locking it
+    /// altogether by default.
+    pub fn set_lock_policy(&mut self, policy: LockPolicy) {
+        self.lock_policy = policy;
+    }
+
+    /// Overrides the lock policy for the given clients. Later overrides take
+    /// precedence over earlier ones.
+    pub fn set_client_lock_policy(&mut self, clients: ClientSet, policy: LockPolicy) {
+        self.client_lock_policies.push((clients, policy));
+    }
+
+    /// Replaces all lock policy overrides, e.g. when the settings are
+    /// reloaded.
+    pub fn set_client_lock_policies(&mut self, policies: Vec<(ClientSet, LockPolicy)>) {
+        self.client_lock_policies = policies;
+    }
+
+    /// Only accept events of the given clients, for environments where the
+    /// accounts are created elsewhere. Without a registry the account of a
+    /// client is created by its first deposit or withdrawal.
+    pub fn set_client_registry(&mut self, registry: ClientSet) {
+        self.registry = Some(registry);
+    }
+
+    /// What a dispute holds when it exceeds the available funds, the full
+    /// amount by default.
+    pub fn set_hold_policy(&mut self, policy: HoldPolicy) {
+        self.hold_policy = policy;
+    }
+
+    /// The lock policy that applies to the client.
+    pub fn lock_policy(&self, client_id: u16) -> LockPolicy {
+        self.client_lock_policies
+            .iter()
+            .rev()
+            .find(|(clients, _)| clients.contains(client_id))
+            .map_or(self.lock_policy, |(_, policy)| *policy)
+    }
+
     pub fn into_iter_accounts(self) -> impl Iterator<Item = (u16, Account)> {
-        self.accounts.into_iter()
+        self.iter_accounts()
+            .map(|(id, account)| (id, *account))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    /// Seeds an account, e.g. when loading a snapshot. Replaces the existing
+    /// account of the client.
+    pub fn insert_account(&mut self, client_id: u16, account: Account) {
+        self.store.put_account(client_id, account);
+    }
+
+    /// Seeds the accounts, e.g. from the opening balances of the day, sizing
+    /// the account map for them up front. Replaces the existing accounts of
+    /// the clients.
+    pub fn seed_accounts(&mut self, accounts: Vec<(u16, Account)>) {
+        self.store.reserve_accounts(accounts.len());
+        for (client_id, account) in accounts {
+            self.store.put_account(client_id, account);
+        }
+    }
+
+    /// Returns the amount, state and owning client of a stored transaction.
+    pub fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        self.store.transaction(tx)
     }
 
+    /// Seeds a stored transaction, e.g. when loading a snapshot. Replaces the
+    /// existing transaction with the same id.
+    pub fn insert_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        self.store.put_transaction(tx, transaction);
+    }
+
+    /// The stored transactions, in no particular order.
+    pub fn iter_transactions(&self) -> impl Iterator<Item = (u32, StoredTransaction)> + '_ {
+        self.store.transactions()
+    }
+
+    pub fn account(&self, client_id: u16) -> Option<&Account> {
+        self.store.account(client_id)
+    }
+
+    pub fn iter_accounts(&self) -> impl Iterator<Item = (u16, &Account)> {
+        self.store.accounts()
+    }
+
+    /// Makes the state so far durable, see [`StateStore::flush`].
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.store.flush()
+    }
+
+    /// See [`StateStore::position`].
+    pub fn position(&self) -> Option<u64> {
+        self.store.position()
+    }
+
+    /// Makes the state so far durable together with the position in the
+    /// input it covers, see [`StateStore::commit`].
+    pub fn commit(&mut self, position: u64) -> anyhow::Result<()> {
+        self.store.commit(position)
+    }
+
+    /// Returns the applied transactions of the given client in order of
+    /// processing. Yields nothing when history tracking is disabled.
+    pub fn history(&self, client_id: u16) -> impl Iterator<Item = TxRecord> + '_ {
+        self.history
+            .as_ref()
+            .and_then(|history| history.get(&client_id))
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    /// Returns the balances of the given client after each entry of its
+    /// [`TransactionContext::history`]. Yields nothing when balance tracking
+    /// is disabled.
+    pub fn balances(&self, client_id: u16) -> impl Iterator<Item = Balance> + '_ {
+        self.balances
+            .as_ref()
+            .and_then(|balances| balances.get(&client_id))
+            .into_iter()
+            .flatten()
+            .copied()
+    }
+
+    /// Reconstructs the account of the client as it was at the given point by
+    /// replaying its history. Returns `None` when history tracking is disabled
+    /// or the point is not part of the history of the client.
+    ///
+    /// The lock timestamp is not part of the history and is left empty.
+    pub fn account_at(&self, client_id: u16, point: HistoryPoint) -> Option<Account> {
+        let history = self.history.as_ref()?.get(&client_id)?;
+        let end = match point {
+            HistoryPoint::Offset(offset) => (offset < history.len()).then_some(offset)?,
+            HistoryPoint::Tx(tx) => history.iter().position(|record| record.tx == tx)?,
+        };
+
+        // the history of a merged client moved in front of its merge, which
+        // takes over the merged account as a whole
+        let mut moved = vec![false; history.len()];
+        for (idx, record) in history.iter().enumerate() {
+            if let (TransactionType::Merge, Some((_, len))) =
+                (record.ty, self.merged.get(&record.tx))
+            {
+                moved[idx - len..idx].fill(true);
+            }
+        }
+
+        let mut account = Account::default();
+        for (record, _) in history[..=end]
+            .iter()
+            .zip(moved)
+            .filter(|(_, moved)| !moved)
+        {
+            // only applied events are recorded, so replaying them cannot fail
+            match record.ty {
+                TransactionType::Deposit => {
+                    let _ = account.deposit(record.amount);
+                    account.meta.tx_count += 1;
+                }
+                TransactionType::Withdrawal => {
+                    let _ = account.withdraw(record.amount);
+                    account.meta.tx_count += 1;
+                }
+                TransactionType::Dispute => {
+                    account.dispute(record.amount, self.hold_policy);
+                    account.meta.disputes += 1;
+                }
+                TransactionType::Resolve => account.resolve(record.amount),
+                TransactionType::Chargeback => {
+                    account.chargeback(record.amount, self.lock_policy(client_id));
+                    account.meta.chargebacks += 1;
+                    if account.locked || account.withdrawals_locked {
+                        account.meta.lock_tx.get_or_insert(record.tx);
+                    }
+                }
+                TransactionType::Unlock => account.unlock(),
+                TransactionType::Adjustment => {
+                    let _ = account.adjust(record.amount);
+                }
+                TransactionType::Recovery => {
+                    let _ = account.recover(record.amount);
+                }
+                TransactionType::Merge => {
+                    if let Some((merged, _)) = self.merged.get(&record.tx) {
+                        let _ = account.merge(merged);
+                    }
+                }
+            }
+        }
+        Some(account)
+    }
+
+    fn record_history(&mut self, event: &TransactionEvent, amount: Price, account: &Account) {
+        if let Some(history) = &mut self.history {
+            history.entry(event.client_id).or_default().push(TxRecord {
+                ty: event.ty,
+                tx: event.tx,
+                amount,
+                reference: event.reference,
+            });
+        }
+        if let Some(balances) = &mut self.balances {
+            balances.entry(event.client_id).or_default().push(Balance {
+                held: account.held,
+                total: account.total,
+            });
+        }
+    }
+
+    /// Applies the event and returns the updated account.
+    ///
+    /// Admin events are rejected as [`TransactionError::Unauthorized`] unless
+    /// the source authenticated them, see [`crate::admin`]. Events of clients
+    /// outside the registry are rejected as [`TransactionError::UnknownClient`],
+    /// see [`TransactionContext::set_client_registry`], and events of the
+    /// suspense account as [`TransactionError::ReservedClient`].
+    pub fn apply(&mut self, event: &TransactionEvent) -> Result<Account, TransactionError> {
+        if event.ty.is_admin() && !event.authenticated {
+            return Err(TransactionError::Unauthorized);
+        }
+        if Some(event.client_id) == self.suspense {
+            return Err(TransactionError::ReservedClient);
+        }
+        if let Some(registry) = &self.registry {
+            if !registry.contains(event.client_id) {
+                return Err(TransactionError::UnknownClient);
+            }
+        }
+        match event.ty {
+            TransactionType::Deposit => self.handle_transaction(event, Account::deposit, true),
+            TransactionType::Withdrawal => self.handle_transaction(event, Account::withdraw, false),
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                self.handle_dispute(event)
+            }
+            TransactionType::Unlock => self.handle_unlock(event),
+            TransactionType::Adjustment => self.handle_adjustment(event),
+            TransactionType::Merge => self.handle_merge(event),
+            TransactionType::Recovery => self.handle_recovery(event),
+        }
+    }
+
+    /// Applies a batch of events grouped by client, so the account of a client
+    /// stays in cache while its events are processed, and returns the results
+    /// in the order of the batch. The accounts are not prefetched, they are
+    /// behind the [`StateStore`].
+    ///
+    /// The events of a client keep their order, across clients they do not:
+    /// the clients are applied in the order of their ids. When two clients use
+    /// the same tx id within the batch, the lowest client id gets it and the
+    /// other one the duplicate reject.
+    pub fn apply_batch(
+        &mut self,
+        events: &[TransactionEvent],
+    ) -> Vec<Result<(), TransactionError>> {
+        let mut order: Vec<usize> = (0..events.len()).collect();
+        // stable, the order within a client is kept
+        order.sort_by_key(|i| events[*i].client_id);
+
+        let mut results = vec![Ok(()); events.len()];
+        for i in order {
+            results[i] = self.apply(&events[i]).map(|_| ());
+        }
+        results
+    }
+
+    /// Applies a deposit or withdrawal to the account of the client and
+    /// returns the updated account.
     pub fn handle_transaction(
         &mut self,
         event: &TransactionEvent,
         action: impl Fn(&mut Account, Price) -> Result<(), TransactionError>,
         store_transaction: bool,
-    ) {
-        let Entry::Vacant(entry) = self.transactions.entry(event.tx) else {
-            debug!(error = ?TransactionError::Duplicate, event.tx);
-            return;
-        };
+    ) -> Result<Account, TransactionError> {
+        let account = apply_transaction(
+            &mut *self.store,
+            event.client_id,
+            event.tx,
+            event.amount,
+            action,
+            store_transaction,
+        )?;
+        self.record_history(event, event.amount, &account);
+        Ok(account)
+    }
 
-        if store_transaction {
-            entry.insert((event.amount, TransactionFlags::None, event.client_id));
+    /// Applies a dispute, resolve or chargeback to the transaction referenced
+    /// by the event and returns the updated account. A lock is stamped with
+    /// the timestamp of the event, if it has one, so a replay of the input
+    /// gives the same state.
+    pub fn handle_dispute(
+        &mut self,
+        event: &TransactionEvent,
+    ) -> Result<Account, TransactionError> {
+        let policy = self.lock_policy(event.client_id);
+        let (account, amount) = apply_dispute(
+            &mut *self.store,
+            event.ty,
+            event.client_id,
+            event.tx,
+            policy,
+            self.hold_policy,
+            event.timestamp,
+        )?;
+        self.record_history(event, amount, &account);
+        if let (TransactionType::Chargeback, Some(suspense)) = (event.ty, self.suspense) {
+            self.store.update_account(suspense, &mut |account| {
+                account.total.try_add(amount);
+            });
         }
+        Ok(account)
+    }
 
-        let account = self.accounts.entry(event.client_id).or_default();
-        if let Err(e) = action(account, event.amount) {
-            // there is a nightly .insert_entry which we could use to remove
-            // again the emplaced item. To keep stay in rust stable, lookup and
-            // remove instead.
-            self.transactions.remove(&event.tx);
-            debug!(error = ?e, event.client_id, event.tx, %event.amount);
+    /// Lifts the lock of the account of the client, see [`Account::unlock`].
+    pub fn handle_unlock(&mut self, event: &TransactionEvent) -> Result<Account, TransactionError> {
+        if self.store.account(event.client_id).is_none() {
+            return Err(TransactionError::NotFound);
         }
+        let account = self
+            .store
+            .update_account(event.client_id, &mut Account::unlock);
+        self.record_history(event, Price::default(), &account);
+        Ok(account)
     }
 
-    pub fn handle_dispute(
+    /// Applies a back-office correction, see [`Account::adjust`]. Adjustments
+    /// are stored as final, so their tx id can not be reused but they can not
+    /// be disputed.
+    pub fn handle_adjustment(
+        &mut self,
+        event: &TransactionEvent,
+    ) -> Result<Account, TransactionError> {
+        if event.reason.is_empty() {
+            return Err(TransactionError::MissingReason);
+        }
+        if self.store.transaction(event.tx).is_some() {
+            return Err(TransactionError::Duplicate);
+        }
+        let mut result = Ok(());
+        let account = self.store.update_account(event.client_id, &mut |account| {
+            result = account.adjust(event.amount);
+        });
+        result?;
+        self.store.put_transaction(
+            event.tx,
+            (event.amount, TransactionFlags::Final, event.client_id),
+        );
+        self.record_history(event, event.amount, &account);
+        Ok(account)
+    }
+
+    /// Repays the negative balance of the account, see [`Account::recover`].
+    /// Recoveries are stored as final, so their tx id can not be reused but
+    /// they can not be disputed.
+    pub fn handle_recovery(
         &mut self,
         event: &TransactionEvent,
-        expected_desired: (TransactionFlags, TransactionFlags),
-        dispute_action: impl Fn(&mut Account, Price),
-    ) {
-        let Entry::Occupied(mut entry) = self.transactions.entry(event.tx) else {
-            debug!(error = ?TransactionError::NotFound, typ= ?TransactionType::Dispute, event.tx);
-            return;
+    ) -> Result<Account, TransactionError> {
+        if self.store.account(event.client_id).is_none() {
+            return Err(TransactionError::NotFound);
+        }
+        if self.store.transaction(event.tx).is_some() {
+            return Err(TransactionError::Duplicate);
+        }
+        let mut result = Ok(());
+        let account = self.store.update_account(event.client_id, &mut |account| {
+            result = account.recover(event.amount);
+        });
+        result?;
+        self.store.put_transaction(
+            event.tx,
+            (event.amount, TransactionFlags::Final, event.client_id),
+        );
+        self.record_history(event, event.amount, &account);
+        Ok(account)
+    }
+
+    /// Moves the account of the merged client into the one of the event, see
+    /// [`Account::merge`], and hands its stored transactions and history over.
+    /// Either all of it happens or nothing. The suspense account can not be
+    /// merged. The merge is stored as final, so its tx id can not be reused.
+    pub fn handle_merge(&mut self, event: &TransactionEvent) -> Result<Account, TransactionError> {
+        let Some(from) = event
+            .merged_client
+            .filter(|from| *from != event.client_id && Some(*from) != self.suspense)
+        else {
+            return Err(TransactionError::InvalidMerge);
+        };
+        let (Some(into), Some(merged)) = (
+            self.store.account(event.client_id),
+            self.store.account(from).copied(),
+        ) else {
+            return Err(TransactionError::NotFound);
         };
+        if self.store.transaction(event.tx).is_some() {
+            return Err(TransactionError::Duplicate);
+        }
+        let mut account = *into;
+        account.merge(&merged)?;
 
-        let mut_entry = entry.get_mut();
-        if mut_entry.2 != event.client_id {
-            debug!(error = ?TransactionError::ClientMismatch, event.client_id, ?mut_entry);
-            return;
+        let owned: Vec<_> = self
+            .store
+            .transactions()
+            .filter(|(_, (_, _, owner))| *owner == from)
+            .collect();
+        for (tx, (amount, flags, _)) in owned {
+            self.store
+                .put_transaction(tx, (amount, flags, event.client_id));
         }
+        self.store.remove_account(from);
+        self.store.put_account(event.client_id, account);
+        self.store.put_transaction(
+            event.tx,
+            (event.amount, TransactionFlags::Final, event.client_id),
+        );
 
-        if mut_entry.1 != expected_desired.0 {
-            debug!(error = ?TransactionError::InvalidDispute, event.client_id, ?mut_entry);
-            return;
+        // the history of the merged client goes in front of the merge
+        if let Some(history) = &mut self.history {
+            let records = history.remove(&from).unwrap_or_default();
+            self.merged.insert(event.tx, (merged, records.len()));
+            history.entry(event.client_id).or_default().extend(records);
         }
+        if let Some(balances) = &mut self.balances {
+            let records = balances.remove(&from).unwrap_or_default();
+            balances.entry(event.client_id).or_default().extend(records);
+        }
+        self.record_history(event, merged.total, &account);
+        Ok(account)
+    }
 
-        let Entry::Occupied(mut account) = self.accounts.entry(event.client_id) else {
-            debug!(error = ?TransactionError::InvalidDispute, event.client_id);
-            return;
-        };
+    pub fn account_count(&self) -> usize {
+        self.store.account_count()
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.store.transaction_count()
+    }
+
+    /// Memory held by the state store, the history is not included.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.store.memory_usage()
+    }
+
+    /// Drops the resolved, charged back and final transactions from memory,
+    /// returns how many were dropped. Disputes on them are rejected as not
+    /// found from then on, and their ids are no longer detected as duplicates.
+    pub fn evict_finalized(&mut self) -> usize {
+        self.store.evict_transactions(&|(_, flags, _)| {
+            matches!(
+                flags,
+                TransactionFlags::Resolved | TransactionFlags::Chargeback | TransactionFlags::Final
+            )
+        })
+    }
 
-        entry.get_mut().1 = expected_desired.1;
-        dispute_action(account.get_mut(), entry.get().0);
+    /// Removes the accounts of the given clients and their stored
+    /// transactions. Events of these clients start from a fresh account, and
+    /// disputes of the removed transactions are rejected as not found.
+    pub fn prune_accounts(&mut self, clients: &[u16]) -> Vec<PrunedAccount> {
+        let mut pruned: Vec<_> = clients
+            .iter()
+            .filter_map(|client_id| {
+                Some(PrunedAccount {
+                    client_id: *client_id,
+                    account: self.store.remove_account(*client_id)?,
+                    transactions: Vec::new(),
+                })
+            })
+            .collect();
+        if pruned.is_empty() {
+            return pruned;
+        }
+        for (tx, transaction) in self.store.transactions() {
+            if let Some(account) = pruned.iter_mut().find(|p| p.client_id == transaction.2) {
+                account.transactions.push((tx, transaction));
+            }
+        }
+        self.store
+            .remove_transactions(&|(_, _, client_id)| clients.contains(client_id));
+        pruned
+    }
+
+    /// Moves the state into the given backend and continues on top of it.
+    /// Fails when the backend already holds a state.
+    pub fn replace_store(&mut self, mut store: Box<dyn StateStore>) -> anyhow::Result<()> {
+        if store.account_count() > 0 || store.transaction_count() > 0 {
+            anyhow::bail!("the new state store is not empty");
+        }
+        for (client_id, account) in self.store.accounts() {
+            store.put_account(client_id, *account);
+        }
+        for (tx, transaction) in self.store.transactions() {
+            store.put_transaction(tx, transaction);
+        }
+        store.flush()?;
+        self.store = store;
+        Ok(())
+    }
+
+    /// SHA-256 (hex) over the accounts ordered by client and the stored
+    /// transactions ordered by id. Equal state gives an equal digest,
+    /// independent of the backend and of the order the state was built in.
+    /// The balances, shortfall, locks and overdrawn flag of the accounts and
+    /// the transaction states are covered, not the audit metadata.
+    pub fn state_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(b"txe-state-v2");
+
+        let mut accounts: Vec<_> = self.iter_accounts().collect();
+        accounts.sort_unstable_by_key(|(client_id, _)| *client_id);
+        hasher.update((accounts.len() as u64).to_be_bytes());
+        for (client_id, account) in accounts {
+            hasher.update(client_id.to_be_bytes());
+            hasher.update(account.total.0.to_be_bytes());
+            hasher.update(account.held.0.to_be_bytes());
+            hasher.update(account.shortfall.0.to_be_bytes());
+            hasher.update([account.locked as u8
+                | (account.withdrawals_locked as u8) << 1
+                | (account.meta.overdrawn as u8) << 2]);
+        }
+
+        let mut transactions: Vec<_> = self.iter_transactions().collect();
+        transactions.sort_unstable_by_key(|(tx, _)| *tx);
+        hasher.update((transactions.len() as u64).to_be_bytes());
+        for (tx, (amount, flags, client_id)) in transactions {
+            hasher.update(tx.to_be_bytes());
+            hasher.update(amount.0.to_be_bytes());
+            hasher.update([flags as u8]);
+            hasher.update(client_id.to_be_bytes());
+        }
+
+        hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::data_types::{Account, TransactionEvent, TransactionType};
+    use crate::data_types::{
+        Account, AccountMetadata, TransactionEvent, TransactionFlags, TransactionType,
+    };
+    use std::collections::HashSet;
 
     fn create_event(
         tx_type: TransactionType,
@@ -91,12 +658,12 @@ mod tests {
         tx: u32,
         amount: f64,
     ) -> TransactionEvent {
-        TransactionEvent {
-            ty: tx_type,
+        TransactionEvent::new(
+            tx_type,
             client_id,
             tx,
-            amount: amount.try_into().unwrap_or_default(),
-        }
+            amount.try_into().unwrap_or_default(),
+        )
     }
 
     #[test]
@@ -104,9 +671,12 @@ mod tests {
         let mut context = TransactionContext::new();
 
         let deposit_event = create_event(TransactionType::Deposit, 1, 1, 10.0);
-        context.handle_transaction(&deposit_event, Account::deposit, true);
+        let updated = context
+            .handle_transaction(&deposit_event, Account::deposit, true)
+            .unwrap();
 
-        let account = context.accounts.get(&1).expect("Account not found");
+        let account = context.account(1).expect("Account not found");
+        assert_eq!(*account, updated);
         assert_eq!(account.available(), 10.0.try_into().unwrap());
         assert_eq!(account.total, 10.0.try_into().unwrap());
         assert_eq!(account.held, 0.0.try_into().unwrap());
@@ -118,15 +688,22 @@ mod tests {
 
         // insufficient funds
         let withdrawal_event = create_event(TransactionType::Withdrawal, 1, 3, 5.0);
-        context.handle_transaction(&withdrawal_event, Account::withdraw, false);
+        assert_eq!(
+            context.handle_transaction(&withdrawal_event, Account::withdraw, false),
+            Err(TransactionError::InsufficientFunds)
+        );
 
         let deposit_event = create_event(TransactionType::Deposit, 1, 1, 10.0);
-        context.handle_transaction(&deposit_event, Account::deposit, true);
+        context
+            .handle_transaction(&deposit_event, Account::deposit, true)
+            .unwrap();
 
         let withdrawal_event = create_event(TransactionType::Withdrawal, 1, 2, 5.0);
-        context.handle_transaction(&withdrawal_event, Account::withdraw, false);
+        context
+            .handle_transaction(&withdrawal_event, Account::withdraw, false)
+            .unwrap();
 
-        let account = context.accounts.get(&1).expect("Account not found");
+        let account = context.account(1).expect("Account not found");
         assert_eq!(account.available(), 5.0.try_into().unwrap());
         assert_eq!(account.total, 5.0.try_into().unwrap());
         assert_eq!(account.held, 0.0.try_into().unwrap());
@@ -137,16 +714,14 @@ mod tests {
         let mut context = TransactionContext::new();
 
         let deposit_event = create_event(TransactionType::Deposit, 1, 1, 10.0);
-        context.handle_transaction(&deposit_event, Account::deposit, true);
+        context
+            .handle_transaction(&deposit_event, Account::deposit, true)
+            .unwrap();
 
         let dispute_event = create_event(TransactionType::Dispute, 1, 1, 0.0);
-        context.handle_dispute(
-            &dispute_event,
-            (TransactionFlags::None, TransactionFlags::Disputed),
-            Account::dispute,
-        );
+        context.handle_dispute(&dispute_event).unwrap();
 
-        let account = context.accounts.get(&1).expect("Account not found");
+        let account = context.account(1).expect("Account not found");
         assert_eq!(account.available(), 0.0.try_into().unwrap());
         assert_eq!(account.held, 10.0.try_into().unwrap());
         assert_eq!(account.total, 10.0.try_into().unwrap());
@@ -157,23 +732,17 @@ mod tests {
         let mut context = TransactionContext::new();
 
         let deposit_event = create_event(TransactionType::Deposit, 1, 1, 10.0);
-        context.handle_transaction(&deposit_event, Account::deposit, true);
+        context
+            .handle_transaction(&deposit_event, Account::deposit, true)
+            .unwrap();
 
         let dispute_event = create_event(TransactionType::Dispute, 1, 1, 0.0);
-        context.handle_dispute(
-            &dispute_event,
-            (TransactionFlags::None, TransactionFlags::Disputed),
-            Account::dispute,
-        );
+        context.handle_dispute(&dispute_event).unwrap();
 
         let resolve_event = create_event(TransactionType::Resolve, 1, 1, 0.0);
-        context.handle_dispute(
-            &resolve_event,
-            (TransactionFlags::Disputed, TransactionFlags::Resolved),
-            Account::resolve,
-        );
+        context.handle_dispute(&resolve_event).unwrap();
 
-        let account = context.accounts.get(&1).expect("Account not found");
+        let account = context.account(1).expect("Account not found");
         assert_eq!(account.available(), 10.0.try_into().unwrap());
         assert_eq!(account.held, 0.0.try_into().unwrap());
         assert_eq!(account.total, 10.0.try_into().unwrap());
@@ -184,26 +753,498 @@ mod tests {
         let mut context = TransactionContext::new();
 
         let deposit_event = create_event(TransactionType::Deposit, 1, 1, 10.0);
-        context.handle_transaction(&deposit_event, Account::deposit, true);
+        context
+            .handle_transaction(&deposit_event, Account::deposit, true)
+            .unwrap();
 
         let dispute_event = create_event(TransactionType::Dispute, 1, 1, 0.0);
-        context.handle_dispute(
-            &dispute_event,
-            (TransactionFlags::None, TransactionFlags::Disputed),
-            Account::dispute,
-        );
+        context.handle_dispute(&dispute_event).unwrap();
 
         let chargeback_event = create_event(TransactionType::Chargeback, 1, 1, 0.0);
-        context.handle_dispute(
-            &chargeback_event,
-            (TransactionFlags::Disputed, TransactionFlags::Chargeback),
-            Account::chargeback,
-        );
+        context.handle_dispute(&chargeback_event).unwrap();
 
-        let account = context.accounts.get(&1).expect("Account not found");
+        let account = context.account(1).expect("Account not found");
         assert_eq!(account.available(), 0.0.try_into().unwrap());
         assert_eq!(account.held, 0.0.try_into().unwrap());
         assert_eq!(account.total, 0.0.try_into().unwrap());
         assert!(account.locked);
     }
+
+    #[test]
+    fn test_adjustment() {
+        let mut context = TransactionContext::new();
+        context
+            .apply(&create_event(TransactionType::Deposit, 1, 1, 10.0))
+            .unwrap();
+
+        let mut adjustment = crate::admin::admin_event(TransactionType::Adjustment, 1, 2);
+        adjustment.amount = (-2.5).try_into().unwrap();
+        assert_eq!(
+            context.apply(&adjustment),
+            Err(TransactionError::MissingReason)
+        );
+        adjustment.reason = "fee_refund".parse().unwrap();
+        let account = context.apply(&adjustment).unwrap();
+        assert_eq!(account.total, 7.5.try_into().unwrap());
+        assert_eq!(account.meta.tx_count, 1);
+
+        // adjustments can not be disputed
+        let dispute = create_event(TransactionType::Dispute, 1, 2, 0.0);
+        assert_eq!(
+            context.apply(&dispute),
+            Err(TransactionError::InvalidDispute)
+        );
+
+        // a replayed adjustment is applied once
+        assert_eq!(context.apply(&adjustment), Err(TransactionError::Duplicate));
+        assert_eq!(context.account(1).unwrap().total, 7.5.try_into().unwrap());
+        let deposit = create_event(TransactionType::Deposit, 1, 2, 1.0);
+        assert_eq!(context.apply(&deposit), Err(TransactionError::Duplicate));
+
+        adjustment.tx = 1;
+        assert_eq!(context.apply(&adjustment), Err(TransactionError::Duplicate));
+        adjustment.tx = 3;
+        adjustment.amount = (-8.0).try_into().unwrap();
+        assert_eq!(
+            context.apply(&adjustment),
+            Err(TransactionError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut context = TransactionContext::new();
+        context.track_balances();
+        for event in [
+            create_event(TransactionType::Deposit, 1, 1, 10.0),
+            create_event(TransactionType::Deposit, 2, 2, 5.0),
+            create_event(TransactionType::Deposit, 2, 3, 1.0),
+            create_event(TransactionType::Dispute, 2, 3, 0.0),
+        ] {
+            context.apply(&event).unwrap();
+        }
+
+        let mut merge = crate::admin::admin_event(TransactionType::Merge, 1, 4);
+        assert_eq!(context.apply(&merge), Err(TransactionError::InvalidMerge));
+        merge.merged_client = Some(3);
+        assert_eq!(context.apply(&merge), Err(TransactionError::NotFound));
+        merge.merged_client = Some(2);
+        let account = context.apply(&merge).unwrap();
+        assert_eq!(account.total, 16.0.try_into().unwrap());
+        assert_eq!(account.held, 1.0.try_into().unwrap());
+        assert_eq!(account.meta.tx_count, 3);
+        assert!(context.account(2).is_none());
+        assert_eq!(context.account_at(1, HistoryPoint::Tx(4)), Some(account));
+
+        // the history of the merged client moved in front of the merge
+        let history: Vec<_> = context.history(1).map(|record| record.tx).collect();
+        assert_eq!(history, [1, 2, 3, 3, 4]);
+        assert_eq!(context.balances(1).count(), history.len());
+        assert_eq!(context.history(2).count(), 0);
+        let before = context.account_at(1, HistoryPoint::Offset(2)).unwrap();
+        assert_eq!(before.total, 10.0.try_into().unwrap());
+
+        // the transactions of the merged client moved along
+        let resolve = create_event(TransactionType::Resolve, 2, 3, 0.0);
+        assert_eq!(
+            context.apply(&resolve),
+            Err(TransactionError::ClientMismatch)
+        );
+        let resolve = create_event(TransactionType::Resolve, 1, 3, 0.0);
+        assert_eq!(context.apply(&resolve).unwrap().held, Price(0));
+
+        // a replayed merge is applied once
+        context
+            .apply(&create_event(TransactionType::Deposit, 2, 5, 1.0))
+            .unwrap();
+        assert_eq!(context.apply(&merge), Err(TransactionError::Duplicate));
+        assert_eq!(context.account(1).unwrap().total, 16.0.try_into().unwrap());
+        assert_eq!(
+            context.account_at(1, HistoryPoint::Offset(5)).as_ref(),
+            context.account(1)
+        );
+    }
+
+    #[test]
+    fn test_seed_accounts() {
+        let mut context = TransactionContext::new();
+        let opening = Account {
+            total: 5.0.try_into().unwrap(),
+            ..Default::default()
+        };
+        context.seed_accounts(vec![(1, opening), (2, opening)]);
+        assert_eq!(context.account_count(), 2);
+
+        let withdrawal = create_event(TransactionType::Withdrawal, 1, 1, 2.0);
+        let account = context.apply(&withdrawal).unwrap();
+        assert_eq!(account.total, 3.0.try_into().unwrap());
+        let dispute = create_event(TransactionType::Dispute, 2, 7, 0.0);
+        assert_eq!(context.apply(&dispute), Err(TransactionError::NotFound));
+    }
+
+    #[test]
+    fn test_client_registry() {
+        let mut context = TransactionContext::new();
+        context.set_client_registry("1,3-4".parse().unwrap());
+        let deposit = create_event(TransactionType::Deposit, 2, 1, 5.0);
+        assert_eq!(
+            context.apply(&deposit),
+            Err(TransactionError::UnknownClient)
+        );
+        assert_eq!(context.account(2), None);
+        let deposit = create_event(TransactionType::Deposit, 3, 2, 5.0);
+        assert!(context.apply(&deposit).is_ok());
+        assert_eq!(context.account_count(), 1);
+    }
+
+    #[test]
+    fn test_suspense_account() {
+        let mut context = TransactionContext::new();
+        context.set_suspense_account(9);
+        for event in [
+            create_event(TransactionType::Deposit, 1, 1, 5.0),
+            create_event(TransactionType::Dispute, 1, 1, 0.0),
+            create_event(TransactionType::Chargeback, 1, 1, 0.0),
+        ] {
+            context.apply(&event).unwrap();
+        }
+        assert_eq!(context.account(9).unwrap().total, Price(50_000));
+
+        // the input can not move the charged back funds
+        let withdrawal = create_event(TransactionType::Withdrawal, 9, 2, 5.0);
+        assert_eq!(
+            context.apply(&withdrawal),
+            Err(TransactionError::ReservedClient)
+        );
+        let deposit = create_event(TransactionType::Deposit, 9, 3, 1.0);
+        assert_eq!(
+            context.apply(&deposit),
+            Err(TransactionError::ReservedClient)
+        );
+        assert_eq!(context.account(9).unwrap().total, Price(50_000));
+    }
+
+    #[test]
+    fn test_apply_batch() {
+        let events = [
+            create_event(TransactionType::Deposit, 2, 1, 10.0),
+            create_event(TransactionType::Deposit, 1, 2, 5.0),
+            create_event(TransactionType::Withdrawal, 2, 3, 4.0),
+            create_event(TransactionType::Dispute, 1, 2, 0.0),
+            create_event(TransactionType::Withdrawal, 1, 4, 1.0),
+            create_event(TransactionType::Dispute, 2, 2, 0.0),
+        ];
+
+        let mut context = TransactionContext::new();
+        let results = context.apply_batch(&events);
+        assert_eq!(
+            results,
+            [
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Ok(()),
+                Err(TransactionError::InsufficientFunds),
+                Err(TransactionError::ClientMismatch),
+            ]
+        );
+
+        // same outcome as applying the events one by one
+        let mut sequential = TransactionContext::new();
+        for event in &events {
+            let _ = sequential.apply(event);
+        }
+        for client_id in [1, 2] {
+            assert_eq!(context.account(client_id), sequential.account(client_id));
+        }
+    }
+
+    #[test]
+    fn test_apply_batch_duplicate() {
+        let events = [
+            create_event(TransactionType::Deposit, 3, 1, 10.0),
+            create_event(TransactionType::Deposit, 1, 1, 5.0),
+        ];
+        let mut context = TransactionContext::new();
+        let results = context.apply_batch(&events);
+        assert_eq!(results, [Err(TransactionError::Duplicate), Ok(())]);
+        assert_eq!(context.account(1).unwrap().total, Price(50_000));
+        assert_eq!(context.account(3), None);
+    }
+
+    #[test]
+    fn test_state_digest() {
+        let events = [
+            create_event(TransactionType::Deposit, 1, 1, 10.0),
+            create_event(TransactionType::Deposit, 2, 2, 5.0),
+            create_event(TransactionType::Dispute, 2, 2, 0.0),
+            create_event(TransactionType::Withdrawal, 1, 3, 2.5),
+        ];
+        let mut context = TransactionContext::new();
+        for event in &events {
+            context.apply(event).unwrap();
+        }
+        // the order across clients does not matter
+        let mut reordered = TransactionContext::new();
+        for i in [1, 0, 2, 3] {
+            reordered.apply(&events[i]).unwrap();
+        }
+        assert_eq!(context.state_digest(), reordered.state_digest());
+        // and the digest is stable across runs and versions
+        assert_eq!(
+            context.state_digest(),
+            "b134540b04b6fdfdb12753314176c26189ede450d6a63db781401d2928f7de53"
+        );
+
+        reordered
+            .apply(&create_event(TransactionType::Resolve, 2, 2, 0.0))
+            .unwrap();
+        assert_ne!(context.state_digest(), reordered.state_digest());
+
+        // every field that changes what the account can do is covered
+        let digest = |account: Account| {
+            let mut context = TransactionContext::new();
+            context.insert_account(1, account);
+            context.state_digest()
+        };
+        let digests: HashSet<_> = [
+            Account::default(),
+            Account {
+                withdrawals_locked: true,
+                ..Default::default()
+            },
+            Account {
+                shortfall: Price(1),
+                ..Default::default()
+            },
+            Account {
+                meta: AccountMetadata {
+                    overdrawn: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        ]
+        .into_iter()
+        .map(digest)
+        .collect();
+        assert_eq!(digests.len(), 4);
+    }
+
+    #[test]
+    fn test_invalid_transitions() {
+        let mut context = TransactionContext::new();
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 1, 1, 10.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+
+        for ty in [TransactionType::Resolve, TransactionType::Chargeback] {
+            assert_eq!(
+                context.handle_dispute(&create_event(ty, 1, 1, 0.0)),
+                Err(TransactionError::InvalidDispute)
+            );
+        }
+        context
+            .handle_dispute(&create_event(TransactionType::Dispute, 1, 1, 0.0))
+            .unwrap();
+        assert_eq!(
+            context.handle_dispute(&create_event(TransactionType::Dispute, 1, 1, 0.0)),
+            Err(TransactionError::InvalidDispute)
+        );
+        context
+            .handle_dispute(&create_event(TransactionType::Resolve, 1, 1, 0.0))
+            .unwrap();
+        // a resolved transaction can not be disputed again
+        for ty in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert_eq!(
+                context.handle_dispute(&create_event(ty, 1, 1, 0.0)),
+                Err(TransactionError::InvalidDispute)
+            );
+        }
+        assert_eq!(
+            context.transaction(1).map(|(_, flags, _)| flags),
+            Some(TransactionFlags::Resolved)
+        );
+    }
+
+    #[test]
+    fn test_account_metadata() {
+        let mut context = TransactionContext::new();
+
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 1, 1, 10.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 1, 2, 5.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+        // rejected, not counted
+        assert_eq!(
+            context.handle_transaction(
+                &create_event(TransactionType::Withdrawal, 1, 3, 50.0),
+                Account::withdraw,
+                false,
+            ),
+            Err(TransactionError::InsufficientFunds)
+        );
+        context
+            .handle_dispute(&create_event(TransactionType::Dispute, 1, 2, 0.0))
+            .unwrap();
+        let mut chargeback = create_event(TransactionType::Chargeback, 1, 2, 0.0);
+        chargeback.timestamp = Some(1_700_000_000);
+        context.handle_dispute(&chargeback).unwrap();
+
+        let meta = context.account(1).expect("Account not found").meta;
+        assert_eq!(meta.tx_count, 2);
+        assert_eq!(meta.disputes, 1);
+        assert_eq!(meta.chargebacks, 1);
+        assert_eq!(meta.lock_tx, Some(2));
+        assert_eq!(meta.locked_at, Some(1_700_000_000));
+
+        // without a timestamp there is no lock time, rather than the clock
+        let mut context = TransactionContext::new();
+        for event in [
+            create_event(TransactionType::Deposit, 1, 1, 5.0),
+            create_event(TransactionType::Dispute, 1, 1, 0.0),
+            create_event(TransactionType::Chargeback, 1, 1, 0.0),
+        ] {
+            context.apply(&event).unwrap();
+        }
+        let meta = context.account(1).expect("Account not found").meta;
+        assert_eq!(meta.lock_tx, Some(1));
+        assert_eq!(meta.locked_at, None);
+    }
+
+    #[test]
+    fn test_history() {
+        let mut context = TransactionContext::new();
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 1, 1, 10.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+        assert_eq!(context.history(1).count(), 0);
+
+        context.track_history();
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 1, 2, 5.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+        context
+            .handle_transaction(
+                &create_event(TransactionType::Deposit, 2, 3, 5.0),
+                Account::deposit,
+                true,
+            )
+            .unwrap();
+        // rejected, not recorded
+        assert_eq!(
+            context.handle_transaction(
+                &create_event(TransactionType::Withdrawal, 1, 4, 50.0),
+                Account::withdraw,
+                false,
+            ),
+            Err(TransactionError::InsufficientFunds)
+        );
+        context
+            .handle_dispute(&create_event(TransactionType::Dispute, 1, 1, 0.0))
+            .unwrap();
+
+        let history: Vec<_> = context.history(1).collect();
+        assert_eq!(
+            history,
+            [
+                TxRecord {
+                    ty: TransactionType::Deposit,
+                    tx: 2,
+                    amount: 5.0.try_into().unwrap(),
+                    reference: None,
+                },
+                TxRecord {
+                    ty: TransactionType::Dispute,
+                    tx: 1,
+                    amount: 10.0.try_into().unwrap(),
+                    reference: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_account_at() {
+        let mut context = TransactionContext::new();
+        context.track_balances();
+        for (ty, tx, amount) in [
+            (TransactionType::Deposit, 1, 10.0),
+            (TransactionType::Deposit, 2, 5.0),
+            (TransactionType::Withdrawal, 3, 3.0),
+        ] {
+            let store = ty == TransactionType::Deposit;
+            let action = if store {
+                Account::deposit
+            } else {
+                Account::withdraw
+            };
+            context
+                .handle_transaction(&create_event(ty, 1, tx, amount), action, store)
+                .unwrap();
+        }
+        context
+            .handle_dispute(&create_event(TransactionType::Dispute, 1, 1, 0.0))
+            .unwrap();
+        context
+            .handle_dispute(&create_event(TransactionType::Chargeback, 1, 1, 0.0))
+            .unwrap();
+
+        let at_tx = context.account_at(1, HistoryPoint::Tx(2)).unwrap();
+        assert_eq!(at_tx.total, 15.0.try_into().unwrap());
+        assert_eq!(at_tx.meta.tx_count, 2);
+
+        let disputed = context.account_at(1, HistoryPoint::Offset(3)).unwrap();
+        assert_eq!(disputed.held, 10.0.try_into().unwrap());
+        assert_eq!(disputed.available(), 2.0.try_into().unwrap());
+        assert!(!disputed.locked);
+
+        let last = context.account_at(1, HistoryPoint::Offset(4)).unwrap();
+        let current = context.account(1).unwrap();
+        assert_eq!(last.total, current.total);
+        assert_eq!(last.held, current.held);
+        assert!(last.locked);
+        assert_eq!(last.meta.lock_tx, Some(1));
+
+        assert!(context.account_at(1, HistoryPoint::Offset(5)).is_none());
+        assert!(context.account_at(1, HistoryPoint::Tx(42)).is_none());
+        assert!(context.account_at(2, HistoryPoint::Offset(0)).is_none());
+
+        // the recorded running balances match the replay
+        let balances: Vec<_> = context.balances(1).collect();
+        assert_eq!(balances.len(), 5);
+        for (offset, balance) in balances.into_iter().enumerate() {
+            let replayed = context.account_at(1, HistoryPoint::Offset(offset)).unwrap();
+            assert_eq!(
+                (balance.held, balance.total),
+                (replayed.held, replayed.total)
+            );
+        }
+    }
 }
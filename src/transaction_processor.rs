@@ -1,72 +1,705 @@
 use crate::{
-    data_types::{Account, TransactionEvent, TransactionFlags, TransactionType},
+    admin::admin_event,
+    audit_log::AuditLog,
+    control::{Command, CommandError, Control},
+    data_types::{Account, Rejected, TransactionError, TransactionEvent, TransactionType},
+    dispute_expiry::DisputeExpiry,
+    memory_budget::{MemoryBudget, CHECK_EVERY},
+    metrics::{metrics, StageMetrics},
+    pipeline::{Message, Sink},
+    pruning::AccountPruner,
+    pseudonym,
+    replication::Replication,
+    settings::{LiveSettings, Settings},
+    state_view::StateView,
     transaction_context::TransactionContext,
+    watchdog::Watchdog,
 };
 use rtrb::Consumer;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, info, info_span, trace, trace_span, warn};
+
+/// How often a paused processor checks for commands.
+const PAUSE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Where the processor takes its messages from.
+enum Input<'a> {
+    /// the ring buffer behind the stages of a [`crate::pipeline`]
+    Queue(Consumer<Message>),
+    /// messages on the processor thread, the end of the iterator is the end of
+    /// the stream
+    Iter(Box<dyn Iterator<Item = Message> + 'a>),
+}
+
+impl std::fmt::Debug for Input<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Input::Queue(consumer) => f.debug_tuple("Queue").field(consumer).finish(),
+            Input::Iter(_) => f.write_str("Iter"),
+        }
+    }
+}
+
+/// Outcome of waiting for the next message.
+enum Next {
+    Message(Message),
+    /// the source is gone without signalling the end of the stream
+    Gone,
+    /// the [`Control`] has commands waiting or is paused, or followers are
+    /// joining the [`Replication`]
+    Interrupted,
+}
+
+impl Input<'_> {
+    /// Waits for the next message, or until `interrupt` returns true.
+    fn next(&mut self, interrupt: impl Fn() -> bool) -> Next {
+        match self {
+            Input::Queue(consumer) => loop {
+                match consumer.pop() {
+                    Ok(message) => return Next::Message(message),
+                    // Emptiness is checked again as the source could have pushed
+                    // its last messages after the pop above.
+                    Err(_) if consumer.is_abandoned() && consumer.is_empty() => return Next::Gone,
+                    Err(_) if interrupt() => return Next::Interrupted,
+                    Err(_) => {}
+                }
+            },
+            Input::Iter(iter) => Next::Message(iter.next().unwrap_or(Message::EndOfStream)),
+        }
+    }
+
+    /// Messages waiting to be processed.
+    fn queued(&self) -> usize {
+        match self {
+            Input::Queue(consumer) => consumer.slots(),
+            Input::Iter(_) => 0,
+        }
+    }
+}
 
-#[derive(Debug)]
 pub struct TransactionProcessor<'a> {
     context: &'a mut TransactionContext,
-    consumer: Consumer<TransactionEvent>,
+    input: Input<'a>,
+    audit_log: Option<&'a mut AuditLog>,
+    state_view: Option<StateView>,
+    rejects: Option<&'a mut Vec<Rejected>>,
+    sinks: Vec<&'a mut dyn Sink>,
+    memory_budget: Option<MemoryBudget>,
+    pruner: Option<AccountPruner>,
+    dispute_expiry: Option<DisputeExpiry>,
+    exactly_once: bool,
+    watchdog: Option<Watchdog>,
+    /// the settings and the ones last applied to the context
+    settings: Option<(LiveSettings, Arc<Settings>)>,
+    control: Option<Control>,
+    replication: Option<Replication>,
+    /// metrics of the apply and emit stages, see [`crate::pipeline`]
+    stages: [Arc<StageMetrics>; 2],
+}
+
+impl std::fmt::Debug for TransactionProcessor<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransactionProcessor")
+            .field("context", &self.context)
+            .field("input", &self.input)
+            .field("audit_log", &self.audit_log)
+            .field("state_view", &self.state_view)
+            .field("rejects", &self.rejects)
+            .field("sinks", &self.sinks.len())
+            .field("memory_budget", &self.memory_budget)
+            .field("pruner", &self.pruner)
+            .field("dispute_expiry", &self.dispute_expiry)
+            .field("exactly_once", &self.exactly_once)
+            .field("watchdog", &self.watchdog)
+            .field("settings", &self.settings)
+            .field("control", &self.control)
+            .field("replication", &self.replication)
+            .finish()
+    }
 }
 
 impl<'a> TransactionProcessor<'a> {
-    /// Processes Events until the sources are exhausted.
-    /// Returns a Iterator over the processed accounts.
+    /// Processes Events into the given context until the sources are exhausted.
     pub fn exhaust_sources(
-        consumer: Consumer<TransactionEvent>,
-    ) -> impl Iterator<Item = (u16, Account)> {
-        let mut context = TransactionContext::new();
-
+        context: &mut TransactionContext,
+        consumer: Consumer<Message>,
+    ) -> anyhow::Result<()> {
         // here multiple workers could be started, in this case the context needs to be made
         // thread-safe so it will handle interior mutability.
-        TransactionProcessor::new(&mut context, consumer).run();
+        TransactionProcessor::new(context, consumer).run()
+    }
 
-        context.into_iter_accounts()
+    /// Processes the messages a [`crate::pipeline`] pushes into the ring
+    /// buffer.
+    pub fn new(context: &'a mut TransactionContext, consumer: Consumer<Message>) -> Self {
+        Self::with_input(context, Input::Queue(consumer))
     }
 
-    fn new(context: &'a mut TransactionContext, consumer: Consumer<TransactionEvent>) -> Self {
-        TransactionProcessor { context, consumer }
+    /// Processes the events of the iterator on the thread calling
+    /// [`TransactionProcessor::run`], without a ring buffer or other threads.
+    /// Useful for batches that are in memory already.
+    pub fn from_iter(
+        context: &'a mut TransactionContext,
+        events: impl IntoIterator<Item = TransactionEvent> + 'a,
+    ) -> Self {
+        let messages = events.into_iter().map(Message::Event);
+        Self::with_input(context, Input::Iter(Box::new(messages)))
     }
 
-    fn run(mut self) {
+    fn with_input(context: &'a mut TransactionContext, input: Input<'a>) -> Self {
+        TransactionProcessor {
+            context,
+            input,
+            audit_log: None,
+            state_view: None,
+            rejects: None,
+            sinks: Vec::new(),
+            memory_budget: None,
+            pruner: None,
+            dispute_expiry: None,
+            exactly_once: false,
+            watchdog: None,
+            settings: None,
+            control: None,
+            replication: None,
+            stages: [metrics().stage("apply"), metrics().stage("emit")],
+        }
+    }
+
+    /// Record the outcome of every event in the given audit log.
+    pub fn with_audit_log(mut self, audit_log: &'a mut AuditLog) -> Self {
+        self.audit_log = Some(audit_log);
+        self
+    }
+
+    /// Publish every account change to the given view.
+    pub fn with_state_view(mut self, state_view: StateView) -> Self {
+        self.state_view = Some(state_view);
+        self
+    }
+
+    /// Collect every rejected event in the given vec.
+    pub fn with_rejects(mut self, rejects: &'a mut Vec<Rejected>) -> Self {
+        self.rejects = Some(rejects);
+        self
+    }
+
+    /// Report the outcome of every event to the given sink, the sink is
+    /// flushed on every [`Message::Flush`]. Finishing the sink is up to the
+    /// caller.
+    pub fn with_sink(mut self, sink: &'a mut dyn Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Keep the state within the given budget, see [`MemoryBudget`].
+    pub fn with_memory_budget(mut self, budget: MemoryBudget) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Remove dormant accounts every [`CHECK_EVERY`] events, at every flush
+    /// and at the end of the stream, see [`AccountPruner`]. Pruned accounts are reported to the audit
+    /// log and the sinks.
+    pub fn with_pruner(mut self, pruner: AccountPruner) -> Self {
+        self.pruner = Some(pruner);
+        self
+    }
+
+    /// Resolve disputes that stay open too long before every event that moves
+    /// time past their deadline, see [`DisputeExpiry`]. The resolves are
+    /// processed like any other event.
+    pub fn with_dispute_expiry(mut self, expiry: DisputeExpiry) -> Self {
+        self.dispute_expiry = Some(expiry);
+        self
+    }
+
+    /// Commit the state together with the amount of events received at every
+    /// flush and at the end of the stream, see [`TransactionContext::commit`].
+    /// The events up to the position committed by an earlier run are skipped,
+    /// so the input has to be the same, in the same order.
+    pub fn with_exactly_once(mut self) -> Self {
+        self.exactly_once = true;
+        self
+    }
+
+    /// Log the events that take longer than the deadline of the watchdog.
+    pub fn with_watchdog(mut self, watchdog: Watchdog) -> Self {
+        self.watchdog = Some(watchdog);
+        self
+    }
+
+    /// Follow the lock policies of the settings when they are reloaded.
+    pub fn with_settings(mut self, settings: LiveSettings) -> Self {
+        let current = settings.load();
+        self.context
+            .set_client_lock_policies(current.lock_policies.clone());
+        self.settings = Some((settings, current));
+        self
+    }
+
+    /// Run the commands of the admin endpoints in between events.
+    pub fn with_control(mut self, control: Control) -> Self {
+        self.control = Some(control);
+        self
+    }
+
+    /// Ship the processed events and the pruned accounts to the followers of
+    /// the replication.
+    pub fn with_replication(mut self, replication: Replication) -> Self {
+        self.replication = Some(replication);
+        self
+    }
+
+    /// Processes Events until the end of the stream. Fails when flushing a
+    /// sink fails or the state can not be kept within the memory budget.
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let _span = info_span!("process").entered();
+        let mut events = 0u64;
+        let mut position = 0u64;
+        let committed = match self.exactly_once {
+            true => self.context.position().unwrap_or_default(),
+            false => 0,
+        };
+        if committed > 0 {
+            info!(events = committed, "skipping the events committed earlier");
+        }
         loop {
-            if let Ok(mut event) = self.consumer.pop() {
-                // precautionary call to make sure the interface is honored
-                event.amount.make_absolute();
-                self.update_accounts(event);
-            } else if self.consumer.is_abandoned() {
-                // we are done
-                break;
+            if let Some(control) = self.control.clone() {
+                if control.has_pending() {
+                    self.run_commands(&control, position, committed)?;
+                }
+                if control.is_paused() {
+                    std::thread::sleep(PAUSE_INTERVAL);
+                    continue;
+                }
+            }
+            if let Some(replication) = &mut self.replication {
+                if replication.has_joining() {
+                    replication.admit(self.context);
+                }
+            }
+            let (control, replication) = (&self.control, &self.replication);
+            let interrupt = || {
+                control
+                    .as_ref()
+                    .is_some_and(|control| control.has_pending() || control.is_paused())
+                    || replication.as_ref().is_some_and(Replication::has_joining)
+            };
+            let message = match self.input.next(interrupt) {
+                Next::Message(message) => message,
+                Next::Interrupted => continue,
+                Next::Gone => {
+                    warn!("source is gone without signalling the end of the stream");
+                    break;
+                }
+            };
+            match message {
+                Message::Event(_) if position < committed => {
+                    position += 1;
+                    self.stages[0].record(true, Duration::ZERO);
+                }
+                Message::Event(mut event) => {
+                    position += 1;
+                    // precautionary call to make sure the interface is honored,
+                    // only adjustments carry a signed amount
+                    if event.ty != TransactionType::Adjustment {
+                        event.amount.make_absolute();
+                    }
+                    self.expire_disputes(event.timestamp);
+                    // rejects reach the sinks
+                    let _ = self.process_event(event);
+                    events += 1;
+                    if events.is_multiple_of(CHECK_EVERY) {
+                        self.prune();
+                        if let Some(budget) = &mut self.memory_budget {
+                            budget.enforce(self.context)?;
+                        }
+                    }
+                }
+                Message::Rejected(_) if position < committed => {
+                    position += 1;
+                }
+                Message::Rejected(Rejected { event, error }) => {
+                    position += 1;
+                    let account = self.context.account(event.client_id).copied();
+                    self.emit(&event, Err(error), account);
+                }
+                // nothing to commit before the committed position
+                Message::Flush if position < committed => {}
+                Message::Flush => {
+                    self.prune();
+                    self.flush(position)?
+                }
+                Message::EndOfStream => {
+                    self.prune();
+                    if position < committed {
+                        anyhow::bail!(
+                            "the input ends at event {position}, before the committed position {committed}"
+                        );
+                    }
+                    if self.exactly_once {
+                        self.flush(position)?;
+                    }
+                    break;
+                }
             }
         }
+        Ok(())
     }
 
-    fn update_accounts(&mut self, event: TransactionEvent) {
-        match event.ty {
-            TransactionType::Deposit => {
-                self.context
-                    .handle_transaction(&event, Account::deposit, true)
+    /// Runs the commands of the control. A snapshot that fails to be written
+    /// fails the run, like at a batch boundary.
+    fn run_commands(
+        &mut self,
+        control: &Control,
+        position: u64,
+        committed: u64,
+    ) -> anyhow::Result<()> {
+        for (command, reply) in control.take() {
+            info!(?command, "admin command");
+            match command {
+                Command::Snapshot if position < committed => reply.send(Err(CommandError::Failed(
+                    "still skipping the committed events".to_string(),
+                ))),
+                Command::Snapshot => {
+                    let result = self.flush(position);
+                    reply.send(
+                        result
+                            .as_ref()
+                            .map(|_| ())
+                            .map_err(|e| CommandError::Failed(format!("{e:#}"))),
+                    );
+                    result?;
+                }
+                Command::RotateAuditLog => {
+                    let mut result = Ok(());
+                    if let Some(audit_log) = &mut self.audit_log {
+                        result = AuditLog::rotate(audit_log).map_err(anyhow::Error::from);
+                    }
+                    for sink in &mut self.sinks {
+                        result = result.and_then(|_| sink.rotate());
+                    }
+                    reply.send(result.map_err(|e| CommandError::Failed(format!("{e:#}"))));
+                }
+                Command::Unlock(client_id) => {
+                    let event = admin_event(TransactionType::Unlock, client_id, 0);
+                    let result = self.process_event(event);
+                    reply.send(result.map_err(CommandError::Rejected));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn prune(&mut self) {
+        let Some(pruner) = &mut self.pruner else {
+            return;
+        };
+        let pruned = pruner.prune(self.context);
+        if pruned.is_empty() {
+            return;
+        }
+        debug!(accounts = pruned.len(), "pruned dormant accounts");
+        metrics().record_pruned(pruned.len());
+        for pruned in &pruned {
+            if let Some(audit_log) = &mut self.audit_log {
+                audit_log.record_prune(pruned);
+            }
+            for sink in &mut self.sinks {
+                sink.prune(pruned);
+            }
+            if let Some(expiry) = &mut self.dispute_expiry {
+                expiry.prune(pruned);
             }
-            TransactionType::Withdrawal => {
+            if let Some(view) = &self.state_view {
+                view.remove(pruned.client_id);
+            }
+            if let Some(replication) = &mut self.replication {
+                replication.ship_prune(pruned);
+            }
+        }
+    }
+
+    fn expire_disputes(&mut self, timestamp: Option<u64>) {
+        let Some(expiry) = &mut self.dispute_expiry else {
+            return;
+        };
+        let expired = expiry.advance(timestamp);
+        if expired.is_empty() {
+            return;
+        }
+        debug!(disputes = expired.len(), "resolving expired disputes");
+        metrics().record_expired_disputes(expired.len());
+        for event in expired {
+            let _ = self.process_event(event);
+        }
+    }
+
+    fn flush(&mut self, position: u64) -> anyhow::Result<()> {
+        debug!("flushing sinks");
+        match self.exactly_once {
+            true => self.context.commit(position)?,
+            false => self.context.flush()?,
+        }
+        if let Some(audit_log) = &mut self.audit_log {
+            AuditLog::flush(audit_log)?;
+        }
+        for sink in &mut self.sinks {
+            sink.flush(self.context)?;
+        }
+        Ok(())
+    }
+
+    fn process_event(&mut self, event: TransactionEvent) -> Result<(), TransactionError> {
+        let _span =
+            trace_span!("event", ty = %event.ty, client = %pseudonym::client(event.client_id), event.tx).entered();
+
+        if let Some(pruner) = &mut self.pruner {
+            pruner.observe(&event);
+        }
+        if let Some((settings, applied)) = &mut self.settings {
+            let current = settings.load();
+            if !Arc::ptr_eq(&current, applied) {
                 self.context
-                    .handle_transaction(&event, Account::withdraw, false)
+                    .set_client_lock_policies(current.lock_policies.clone());
+                *applied = current;
+            }
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.start(&event);
+        }
+        let apply = &self.stages[0];
+        let start = Instant::now();
+        let (result, account) = match self.context.apply(&event) {
+            Ok(account) => (Ok(()), Some(account)),
+            // a rejected event can still have created the account
+            Err(error) => (Err(error), self.context.account(event.client_id).copied()),
+        };
+        let elapsed = start.elapsed();
+        if let Some(replication) = &mut self.replication {
+            replication.ship(&event);
+        }
+        if let Some(expiry) = &mut self.dispute_expiry {
+            expiry.observe(&event, result);
+        }
+        apply.record(false, elapsed);
+        apply.set_queued(self.input.queued());
+        let metrics = metrics();
+        metrics.record_event(event.ty, elapsed);
+        metrics.set_ring_buffer_occupancy(self.input.queued());
+        metrics.set_tracked(
+            self.context.account_count(),
+            self.context.transaction_count(),
+            self.context.memory_usage(),
+        );
+
+        let start = Instant::now();
+        self.emit(&event, result, account);
+        let emitted = start.elapsed();
+        self.stages[1].record(false, emitted);
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.finish(elapsed, emitted);
+        }
+        result
+    }
+
+    /// Reports the outcome of an event to the rejects, the audit log, the
+    /// sinks and the state view.
+    fn emit(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<Account>,
+    ) {
+        match result {
+            Ok(()) => {
+                trace!(ty = %event.ty, client = %pseudonym::client(event.client_id), event.tx, %event.amount, "applied")
+            }
+            Err(error) => {
+                metrics().record_reject(error);
+                if let Some(rejects) = &mut self.rejects {
+                    rejects.push(Rejected {
+                        event: *event,
+                        error,
+                    });
+                }
+                debug!(%error, ty = %event.ty, client = %pseudonym::client(event.client_id), event.tx, %event.amount, "rejected");
+            }
+        }
+
+        let account = account.as_ref();
+        if let Some(audit_log) = &mut self.audit_log {
+            audit_log.record(event, result, account);
+        }
+        for sink in &mut self.sinks {
+            sink.record(event, result, account);
+        }
+
+        if let (Some(view), Some(account), Ok(())) = (&self.state_view, account, result) {
+            view.update(event.client_id, *account);
+        }
+        if let (Some(view), TransactionType::Chargeback, Ok(())) =
+            (&self.state_view, event.ty, result)
+        {
+            if let Some(suspense) = self.context.suspense_account() {
+                if let Some(account) = self.context.account(suspense) {
+                    view.update(suspense, *account);
+                }
+            }
+        }
+        if let (Some(view), TransactionType::Merge, Ok(()), Some(merged)) =
+            (&self.state_view, event.ty, result, event.merged_client)
+        {
+            match self.context.account(merged) {
+                Some(account) => view.update(merged, *account),
+                None => view.remove(merged),
             }
-            TransactionType::Dispute => self.context.handle_dispute(
-                &event,
-                (TransactionFlags::None, TransactionFlags::Disputed),
-                Account::dispute,
-            ),
-            TransactionType::Resolve => self.context.handle_dispute(
-                &event,
-                (TransactionFlags::Disputed, TransactionFlags::Resolved),
-                Account::resolve,
-            ),
-            TransactionType::Chargeback => self.context.handle_dispute(
-                &event,
-                (TransactionFlags::Disputed, TransactionFlags::Chargeback),
-                Account::chargeback,
-            ),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::Price;
+
+    /// Waits until the condition holds, the processor runs on another thread.
+    fn wait_for(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !condition() {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for the processor"
+            );
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_from_iter() {
+        let event = |ty, tx, amount| TransactionEvent::new(ty, 1, tx, Price(amount));
+        let events = vec![
+            event(TransactionType::Deposit, 1, 10_000),
+            event(TransactionType::Withdrawal, 2, 20_000),
+            event(TransactionType::Withdrawal, 3, -5_000),
+        ];
+
+        let mut context = TransactionContext::new();
+        let mut rejects = Vec::new();
+        TransactionProcessor::from_iter(&mut context, events)
+            .with_rejects(&mut rejects)
+            .run()
+            .unwrap();
+        assert_eq!(context.account(1).unwrap().total, Price(5_000));
+        assert_eq!(rejects.len(), 1);
+        assert_eq!(rejects[0].error, TransactionError::InsufficientFunds);
+    }
+
+    #[test]
+    fn test_merge_updates_view() {
+        let mut merge = admin_event(TransactionType::Merge, 1, 3);
+        merge.merged_client = Some(2);
+        let events = vec![
+            TransactionEvent::new(TransactionType::Deposit, 1, 1, Price(10_000)),
+            TransactionEvent::new(TransactionType::Deposit, 2, 2, Price(5_000)),
+            merge,
+        ];
+
+        let mut context = TransactionContext::new();
+        let view = StateView::new();
+        TransactionProcessor::from_iter(&mut context, events)
+            .with_state_view(view.clone())
+            .run()
+            .unwrap();
+        assert_eq!(view.account(1).unwrap().total, Price(15_000));
+        assert!(view.account(2).is_none());
+        assert_eq!(view.accounts().len(), 1);
+    }
+
+    #[test]
+    fn test_prune_and_expire() {
+        let day = 24 * 60 * 60;
+        let event = |ty, client_id, tx, amount, timestamp| TransactionEvent {
+            timestamp: Some(timestamp),
+            ..TransactionEvent::new(ty, client_id, tx, Price(amount))
+        };
+        let events = vec![
+            event(TransactionType::Deposit, 1, 1, 10_000, 0),
+            event(TransactionType::Withdrawal, 1, 2, 10_000, 0),
+            event(TransactionType::Deposit, 2, 3, 10_000, 0),
+            event(TransactionType::Dispute, 2, 3, 0, 0),
+            event(TransactionType::Deposit, 3, 4, 10_000, 10 * day),
+        ];
+
+        let mut context = TransactionContext::new();
+        let view = StateView::new();
+        TransactionProcessor::from_iter(&mut context, events)
+            .with_state_view(view.clone())
+            .with_pruner(AccountPruner::new(Duration::from_secs(day)))
+            .with_dispute_expiry(DisputeExpiry::new(Duration::from_secs(5 * day)))
+            .run()
+            .unwrap();
+        // the emptied account is pruned, the one holding funds is kept
+        assert!(context.account(1).is_none());
+        assert!(view.account(1).is_none());
+        // the dispute expired before the last event
+        let account = view.account(2).unwrap();
+        assert_eq!(account.held, Price(0));
+        assert_eq!(account.total, Price(10_000));
+        assert_eq!(context.account(2), Some(&account));
+        assert_eq!(view.accounts().len(), 2);
+    }
+
+    #[test]
+    fn test_control() {
+        let (mut producer, consumer) = rtrb::RingBuffer::new(16);
+        let mut context = TransactionContext::new();
+        let view = StateView::new();
+        let control = Control::new("secret".to_string(), None);
+        let event =
+            |ty, tx, amount| Message::Event(TransactionEvent::new(ty, 1, tx, Price(amount)));
+
+        std::thread::scope(|scope| {
+            let processor = scope.spawn(|| {
+                TransactionProcessor::new(&mut context, consumer)
+                    .with_state_view(view.clone())
+                    .with_control(control.clone())
+                    .run()
+            });
+
+            for message in [
+                event(TransactionType::Deposit, 1, 10_000),
+                event(TransactionType::Dispute, 1, 0),
+                event(TransactionType::Chargeback, 1, 0),
+            ] {
+                producer.push(message).unwrap();
+            }
+            wait_for(|| view.account(1).is_some_and(|account| account.locked));
+            assert_eq!(control.run(Command::Unlock(1)), Ok(()));
+            assert!(!view.account(1).unwrap().locked);
+            assert_eq!(
+                control.run(Command::Unlock(9)),
+                Err(CommandError::Rejected(TransactionError::NotFound))
+            );
+
+            // commands still run while paused, once the snapshot is written no
+            // events are taken anymore
+            control.pause();
+            assert_eq!(control.run(Command::Snapshot), Ok(()));
+            producer
+                .push(event(TransactionType::Deposit, 2, 5_000))
+                .unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(view.account(1).unwrap().total, Price(0));
+            control.resume();
+            wait_for(|| view.account(1).unwrap().total == Price(5_000));
+
+            producer.push(Message::EndOfStream).unwrap();
+            processor.join().unwrap().unwrap();
+        });
+    }
+}
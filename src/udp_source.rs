@@ -0,0 +1,213 @@
+//! Fire-and-forget ingestion of events sent as UDP datagrams, for emitters
+//! in the style of syslog. Delivery is best effort: datagrams get lost or
+//! arrive out of order without the sender knowing. The sequence numbers they
+//! carry make such losses visible in the [`metrics`], they are not recovered.
+//!
+//! Every datagram holds a single event, as a csv row in the canonical column
+//! order prefixed by the sequence number of the sender:
+//! `<seq>,<type>,<client>,<tx>,<amount>[,<timestamp>,...[,<signature>]]` with
+//! the optional columns of [`COLUMNS`] in between, admin events need the
+//! signature, see [`crate::admin`]. Sequence numbers count up per sender, a
+//! sender starting over at 0 is taken as a restart.
+use crate::{
+    admin::{self, AdminKey},
+    data_types::TransactionEvent,
+    metrics::metrics,
+    push_source::{push_source, PushSource, QueueMode},
+    schema::COLUMNS,
+    shutdown::Shutdown,
+};
+use csv::StringRecord;
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+use tracing::{debug, info, info_span, warn};
+
+/// Largest payload of a UDP datagram.
+const MAX_DATAGRAM: usize = 65_507;
+
+/// How the sequence number of a datagram relates to the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sequence {
+    /// the first datagram of the sender, or the one after the last
+    InOrder,
+    /// the given amount of datagrams in front of this one did not arrive
+    Gap(u64),
+    /// the datagram arrived after a later one, or twice
+    OutOfOrder,
+}
+
+/// Tracks the last sequence number of every sender.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    last: HashMap<SocketAddr, u64>,
+}
+
+impl SequenceTracker {
+    pub fn observe(&mut self, sender: SocketAddr, seq: u64) -> Sequence {
+        let Some(last) = self.last.get_mut(&sender) else {
+            self.last.insert(sender, seq);
+            return Sequence::InOrder;
+        };
+        if seq == 0 {
+            *last = 0;
+            return Sequence::InOrder;
+        }
+        if seq <= *last {
+            return Sequence::OutOfOrder;
+        }
+        let gap = seq - *last - 1;
+        *last = seq;
+        match gap {
+            0 => Sequence::InOrder,
+            gap => Sequence::Gap(gap),
+        }
+    }
+}
+
+/// Splits a datagram into its sequence number and event.
+pub fn parse_datagram(
+    datagram: &[u8],
+    admin_key: Option<&AdminKey>,
+) -> anyhow::Result<(u64, TransactionEvent)> {
+    let text = std::str::from_utf8(datagram)?.trim_end_matches(['\r', '\n']);
+    let Some((seq, row)) = text.split_once(',') else {
+        anyhow::bail!("expected `<seq>,<type>,<client>,<tx>,<amount>`");
+    };
+    let seq = seq.trim().parse()?;
+    let record: StringRecord = row.split(',').map(str::trim).collect();
+    let headers = StringRecord::from(&COLUMNS[..]);
+    let mut event: TransactionEvent = record.deserialize(Some(&headers))?;
+    // the signature follows the canonical columns
+    let signature = record.get(COLUMNS.len());
+    admin::authenticate(&mut event, signature, admin_key);
+    Ok((seq, event))
+}
+
+/// Receives the datagrams of the socket on a separate thread, and returns the
+/// source of their events. The source ends once shutdown is requested.
+///
+/// With a blocking queue, datagrams arriving while the pipeline is behind
+/// pile up in the receive buffer of the socket, and are counted as lost once
+/// the kernel drops them.
+pub fn run_udp_source(
+    socket: UdpSocket,
+    mode: QueueMode,
+    shutdown: Shutdown,
+) -> anyhow::Result<PushSource> {
+    // wakes up regularly to check for shutdown
+    socket.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let addr = socket.local_addr()?;
+    let admin_key = AdminKey::from_env()?;
+    let (pusher, source) = push_source("udp", mode);
+    let span = info_span!("ingest", %addr);
+
+    std::thread::Builder::new()
+        .name("UDP source".to_string())
+        .spawn(move || {
+            let _span = span.entered();
+            info!(%addr, "listening for UDP datagrams");
+            let mut sequences = SequenceTracker::default();
+            let mut buf = vec![0; MAX_DATAGRAM];
+            while !shutdown.is_requested() {
+                let (len, sender) = match socket.recv_from(&mut buf) {
+                    Ok(received) => received,
+                    Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                        continue
+                    }
+                    Err(error) => {
+                        warn!(%error, "failed to receive datagram");
+                        continue;
+                    }
+                };
+                metrics().record_source_progress(1, len as u64);
+                let (seq, event) = match parse_datagram(&buf[..len], admin_key.as_ref()) {
+                    Ok(parsed) => parsed,
+                    Err(error) => {
+                        debug!(%sender, %error, "skipping datagram");
+                        metrics().record_parse_failure();
+                        continue;
+                    }
+                };
+                let sequence = sequences.observe(sender, seq);
+                if let Sequence::Gap(lost) = sequence {
+                    debug!(%sender, seq, lost, "datagrams lost");
+                }
+                metrics().record_datagram(sequence);
+                if pusher.push(event).is_err() {
+                    break;
+                }
+            }
+            info!("UDP source stopped");
+        })?;
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_types::Price, pipeline::Message};
+
+    #[test]
+    fn test_udp_source() {
+        let (lost, out_of_order) = (
+            metrics().datagrams_lost(),
+            metrics().datagrams_out_of_order(),
+        );
+        let shutdown = Shutdown::default();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let source = run_udp_source(socket, QueueMode::Unbounded, shutdown.clone()).unwrap();
+
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        for datagram in [
+            "0,deposit,1,1,1.0",
+            "1,deposit,1,2,2.0",
+            // 2 and 3 are lost
+            "4,deposit,1,5,5.0\n",
+            "not an event",
+            "3,deposit,1,4,4.0",
+            "5,withdrawal,1,6,0.5,1700000000",
+        ] {
+            sender.send_to(datagram.as_bytes(), addr).unwrap();
+        }
+        let txs: Vec<_> = source
+            .filter_map(Message::into_event)
+            .take(5)
+            .map(|event| (event.tx, event.amount))
+            .collect();
+        shutdown.request();
+
+        assert_eq!(
+            txs,
+            [
+                (1, Price(10_000)),
+                (2, Price(20_000)),
+                (5, Price(50_000)),
+                (4, Price(40_000)),
+                (6, Price(5_000)),
+            ]
+        );
+        assert_eq!(metrics().datagrams_lost() - lost, 2);
+        assert_eq!(metrics().datagrams_out_of_order() - out_of_order, 1);
+    }
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut sequences = SequenceTracker::default();
+        let a: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        assert_eq!(sequences.observe(a, 7), Sequence::InOrder);
+        assert_eq!(sequences.observe(b, 0), Sequence::InOrder);
+        assert_eq!(sequences.observe(a, 8), Sequence::InOrder);
+        assert_eq!(sequences.observe(a, 11), Sequence::Gap(2));
+        assert_eq!(sequences.observe(a, 9), Sequence::OutOfOrder);
+        assert_eq!(sequences.observe(a, 11), Sequence::OutOfOrder);
+        // restart of the sender
+        assert_eq!(sequences.observe(a, 0), Sequence::InOrder);
+        assert_eq!(sequences.observe(a, 1), Sequence::InOrder);
+    }
+}
@@ -0,0 +1,335 @@
+//! Flags events that take longer than a deadline to process, see
+//! `--event-deadline`. A slow event is logged once it is done, with the time
+//! it spent in the accounting rules, in the state store and in the sinks. An
+//! event that is still stuck, e.g. on a stalled disk, is logged by a
+//! background thread as soon as it passes the deadline.
+use crate::{
+    data_types::TransactionEvent,
+    metrics::metrics,
+    pseudonym,
+    state_store::{MemoryUsage, StateStore, StoredTransaction},
+};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use tracing::warn;
+use txe_accounting::{Account, Ledger};
+
+/// Time spent in a [`TimedStore`] since the last [`StoreTimings::take`].
+#[derive(Debug, Default)]
+pub struct StoreTimings {
+    reads: AtomicU64,
+    read_nanos: AtomicU64,
+    writes: AtomicU64,
+    write_nanos: AtomicU64,
+}
+
+/// Calls of a [`TimedStore`] and the time they took.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StoreTiming {
+    pub reads: u64,
+    pub read: Duration,
+    pub writes: u64,
+    pub write: Duration,
+}
+
+impl StoreTimings {
+    fn record(&self, write: bool, start: Instant) {
+        let nanos = start.elapsed().as_nanos() as u64;
+        let (calls, total) = match write {
+            true => (&self.writes, &self.write_nanos),
+            false => (&self.reads, &self.read_nanos),
+        };
+        calls.fetch_add(1, Ordering::Relaxed);
+        total.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    /// The calls since the last time and starts over.
+    pub fn take(&self) -> StoreTiming {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        StoreTiming {
+            reads: take(&self.reads),
+            read: Duration::from_nanos(take(&self.read_nanos)),
+            writes: take(&self.writes),
+            write: Duration::from_nanos(take(&self.write_nanos)),
+        }
+    }
+}
+
+/// Measures the lookups and writes of the accounting rules on top of another
+/// store. Bulk operations like flushes are not measured.
+#[derive(Debug)]
+pub struct TimedStore {
+    inner: Box<dyn StateStore>,
+    timings: Arc<StoreTimings>,
+}
+
+impl TimedStore {
+    pub fn new(inner: Box<dyn StateStore>, timings: Arc<StoreTimings>) -> Self {
+        TimedStore { inner, timings }
+    }
+}
+
+impl Ledger for TimedStore {
+    fn transaction(&self, tx: u32) -> Option<StoredTransaction> {
+        let start = Instant::now();
+        let transaction = self.inner.transaction(tx);
+        self.timings.record(false, start);
+        transaction
+    }
+
+    fn put_transaction(&mut self, tx: u32, transaction: StoredTransaction) {
+        let start = Instant::now();
+        self.inner.put_transaction(tx, transaction);
+        self.timings.record(true, start);
+    }
+
+    fn update_account(&mut self, client_id: u16, update: &mut dyn FnMut(&mut Account)) -> Account {
+        let start = Instant::now();
+        let account = self.inner.update_account(client_id, update);
+        self.timings.record(true, start);
+        account
+    }
+}
+
+impl StateStore for TimedStore {
+    fn account(&self, client_id: u16) -> Option<&Account> {
+        let start = Instant::now();
+        let account = self.inner.account(client_id);
+        self.timings.record(false, start);
+        account
+    }
+
+    fn put_account(&mut self, client_id: u16, account: Account) {
+        let start = Instant::now();
+        self.inner.put_account(client_id, account);
+        self.timings.record(true, start);
+    }
+
+    fn reserve_accounts(&mut self, additional: usize) {
+        self.inner.reserve_accounts(additional)
+    }
+
+    fn accounts(&self) -> Box<dyn Iterator<Item = (u16, &Account)> + '_> {
+        self.inner.accounts()
+    }
+
+    fn account_count(&self) -> usize {
+        self.inner.account_count()
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.inner.transaction_count()
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = (u32, StoredTransaction)> + '_> {
+        self.inner.transactions()
+    }
+
+    fn memory_usage(&self) -> MemoryUsage {
+        self.inner.memory_usage()
+    }
+
+    fn remove_account(&mut self, client_id: u16) -> Option<Account> {
+        self.inner.remove_account(client_id)
+    }
+
+    fn remove_transactions(&mut self, remove: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        self.inner.remove_transactions(remove)
+    }
+
+    fn evict_transactions(&mut self, evict: &dyn Fn(&StoredTransaction) -> bool) -> usize {
+        self.inner.evict_transactions(evict)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.inner.flush()
+    }
+
+    fn position(&self) -> Option<u64> {
+        self.inner.position()
+    }
+
+    fn commit(&mut self, position: u64) -> anyhow::Result<()> {
+        self.inner.commit(position)
+    }
+}
+
+/// The event being processed.
+#[derive(Debug)]
+struct InFlight {
+    event: TransactionEvent,
+    since: Instant,
+    /// already logged by the background thread
+    flagged: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    in_flight: Mutex<Option<InFlight>>,
+    stop: AtomicBool,
+}
+
+/// Logs the events that take longer than the deadline, see the module
+/// documentation. The background thread stops when the watchdog is dropped.
+#[derive(Debug)]
+pub struct Watchdog {
+    deadline: Duration,
+    store: Option<Arc<StoreTimings>>,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    pub fn new(deadline: Duration) -> anyhow::Result<Self> {
+        let shared = Arc::new(Shared::default());
+        let thread = std::thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn({
+                let shared = shared.clone();
+                move || watch(&shared, deadline)
+            })?;
+        Ok(Watchdog {
+            deadline,
+            store: None,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Reports the time spent in the store, measured by a [`TimedStore`]
+    /// sharing the timings.
+    pub fn with_store_timings(mut self, timings: Arc<StoreTimings>) -> Self {
+        self.store = Some(timings);
+        self
+    }
+
+    /// Called before the event is applied.
+    pub fn start(&self, event: &TransactionEvent) {
+        if let Some(store) = &self.store {
+            store.take();
+        }
+        *self
+            .shared
+            .in_flight
+            .lock()
+            .expect("watchdog lock is poisoned") = Some(InFlight {
+            event: *event,
+            since: Instant::now(),
+            flagged: false,
+        });
+    }
+
+    /// Called once the outcome of the event is emitted, with the time it took
+    /// to apply and to emit it. Returns whether the event was slow.
+    pub fn finish(&self, apply: Duration, emit: Duration) -> bool {
+        let Some(in_flight) = self
+            .shared
+            .in_flight
+            .lock()
+            .expect("watchdog lock is poisoned")
+            .take()
+        else {
+            return false;
+        };
+        let elapsed = in_flight.since.elapsed();
+        if elapsed <= self.deadline {
+            return false;
+        }
+        metrics().record_slow_event();
+        let event = in_flight.event;
+        let store = self
+            .store
+            .as_ref()
+            .map(|store| store.take())
+            .unwrap_or_default();
+        warn!(
+            ty = %event.ty,
+            client = %pseudonym::client(event.client_id),
+            event.tx,
+            elapsed = ?elapsed,
+            deadline = ?self.deadline,
+            apply = ?apply,
+            emit = ?emit,
+            store_reads = store.reads,
+            store_read = ?store.read,
+            store_writes = store.writes,
+            store_write = ?store.write,
+            "slow event"
+        );
+        true
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Checks the event in flight a few times per deadline.
+fn watch(shared: &Shared, deadline: Duration) {
+    let interval = (deadline / 4).max(Duration::from_millis(1));
+    while !shared.stop.load(Ordering::Relaxed) {
+        std::thread::park_timeout(interval);
+        let mut in_flight = shared.in_flight.lock().expect("watchdog lock is poisoned");
+        let Some(in_flight) = in_flight.as_mut().filter(|in_flight| !in_flight.flagged) else {
+            continue;
+        };
+        let elapsed = in_flight.since.elapsed();
+        if elapsed > deadline {
+            in_flight.flagged = true;
+            let event = &in_flight.event;
+            warn!(
+                ty = %event.ty,
+                client = %pseudonym::client(event.client_id),
+                event.tx,
+                elapsed = ?elapsed,
+                deadline = ?deadline,
+                "event is still processing past the deadline"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data_types::{Price, TransactionType},
+        state_store::MemoryStore,
+        transaction_context::TransactionContext,
+    };
+
+    #[test]
+    fn test_watchdog() {
+        let timings = Arc::new(StoreTimings::default());
+        let store = TimedStore::new(Box::new(MemoryStore::default()), timings.clone());
+        let mut context = TransactionContext::with_store(Box::new(store));
+        let event = TransactionEvent::new(TransactionType::Deposit, 1, 1, Price(10_000));
+        context.apply(&event).unwrap();
+        let timing = timings.take();
+        assert!(timing.reads > 0 && timing.writes > 0);
+        assert_eq!(timings.take(), StoreTiming::default());
+
+        let watchdog = Watchdog::new(Duration::from_millis(20))
+            .unwrap()
+            .with_store_timings(timings);
+        watchdog.start(&event);
+        assert!(!watchdog.finish(Duration::ZERO, Duration::ZERO));
+        watchdog.start(&event);
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(watchdog.finish(Duration::ZERO, Duration::ZERO));
+        // nothing in flight
+        assert!(!watchdog.finish(Duration::ZERO, Duration::ZERO));
+    }
+}
@@ -0,0 +1,593 @@
+//! Notifies HTTP endpoints of notable outcomes, see `--webhooks`: an account
+//! getting locked, a large change of the total of an account and repeated
+//! rejects for a client. Every notification is POSTed as JSON to the webhooks
+//! subscribed to it, by a background thread that retries failed deliveries
+//! with a doubling backoff and logs every attempt.
+//!
+//! ```toml
+//! # change of the total of an account that counts as large
+//! large_movement = 10000.0
+//! # rejects of a client within reject_window that count as repeated
+//! repeated_rejects = 5
+//! reject_window = "10m"
+//! # delivery attempts per notification and the wait before the first retry
+//! attempts = 5
+//! backoff = "1s"
+//! # every delivery attempt as a json line
+//! log = "webhooks.log"
+//!
+//! [[webhook]]
+//! url = "https://hooks.example.com/txe"
+//! # the notifications to send, all by default
+//! events = ["account_locked", "large_movement", "repeated_rejects"]
+//! # signs the body, sent as `X-Txe-Signature: sha256=<hex>`
+//! secret = "s3cret"
+//! ```
+use crate::{
+    data_types::{Account, Price, TransactionError, TransactionEvent},
+    metrics::metrics,
+    pipeline::Sink,
+    pruning::parse_period,
+    pseudonym::{self, DisplayClient},
+    transaction_context::{PrunedAccount, TransactionContext},
+};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::JoinHandle,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+use ureq::Agent;
+
+/// Notifications waiting for delivery before new ones are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+
+/// How long a single delivery attempt may take.
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// the account, or only its withdrawals, got locked
+    AccountLocked,
+    /// the total of the account changed by at least `large_movement`
+    LargeMovement,
+    /// the client had `repeated_rejects` rejects within `reject_window`
+    RepeatedRejects,
+}
+
+impl NotificationKind {
+    pub const ALL: [NotificationKind; 3] = [
+        NotificationKind::AccountLocked,
+        NotificationKind::LargeMovement,
+        NotificationKind::RepeatedRejects,
+    ];
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default = "all_kinds")]
+    pub events: Vec<NotificationKind>,
+    pub secret: Option<String>,
+}
+
+fn all_kinds() -> Vec<NotificationKind> {
+    NotificationKind::ALL.to_vec()
+}
+
+/// The contents of a `--webhooks` file, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub large_movement: Option<Price>,
+    pub repeated_rejects: Option<usize>,
+    #[serde(
+        default = "default_reject_window",
+        deserialize_with = "deserialize_period"
+    )]
+    pub reject_window: Duration,
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_backoff", deserialize_with = "deserialize_period")]
+    pub backoff: Duration,
+    pub log: Option<PathBuf>,
+    #[serde(default, rename = "webhook")]
+    pub webhooks: Vec<Webhook>,
+}
+
+fn default_reject_window() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_attempts() -> u32 {
+    5
+}
+
+fn default_backoff() -> Duration {
+    Duration::from_secs(1)
+}
+
+pub(crate) fn deserialize_period<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    let period = String::deserialize(deserializer)?;
+    parse_period(&period).map_err(serde::de::Error::custom)
+}
+
+impl WebhookConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let config: WebhookConfig =
+            toml::from_str(&content).map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))?;
+        if config.attempts == 0 {
+            anyhow::bail!("{}: attempts needs to be at least 1", path.display());
+        }
+        if config.repeated_rejects == Some(0) {
+            anyhow::bail!(
+                "{}: repeated_rejects needs to be at least 1",
+                path.display()
+            );
+        }
+        Ok(config)
+    }
+}
+
+/// The body of a webhook request.
+#[derive(Debug, Clone, Serialize)]
+struct Notification {
+    event: NotificationKind,
+    client: DisplayClient,
+    /// the event that caused the notification
+    tx: u32,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    change: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejects: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    available: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    held: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<Price>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locked: Option<bool>,
+    /// unix timestamp in seconds
+    time: u64,
+}
+
+impl Notification {
+    fn new(kind: NotificationKind, event: &TransactionEvent, account: Option<&Account>) -> Self {
+        Notification {
+            event: kind,
+            client: pseudonym::client(event.client_id),
+            tx: event.tx,
+            ty: event.ty.as_str(),
+            change: None,
+            rejects: None,
+            reason: None,
+            available: account.map(Account::available),
+            held: account.map(|account| account.held),
+            total: account.map(|account| account.total),
+            locked: account.map(|account| account.locked),
+            time: now(),
+        }
+    }
+}
+
+/// The wall clock time in unix seconds.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// A line of the delivery log.
+#[derive(Debug, Serialize)]
+struct DeliveryRecord<'a> {
+    time: u64,
+    url: &'a str,
+    event: NotificationKind,
+    client: DisplayClient,
+    tx: u32,
+    attempt: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    outcome: &'static str,
+}
+
+/// [`Sink`] detecting the notable outcomes, see the module documentation.
+/// Finishing it waits for the queued notifications to be delivered.
+#[derive(Debug)]
+pub struct WebhookSink {
+    large_movement: Option<Price>,
+    repeated_rejects: Option<usize>,
+    reject_window: Duration,
+    /// clients with a locked account, or locked withdrawals
+    locked: HashSet<u16>,
+    /// the total per client, to derive the change
+    totals: HashMap<u16, Price>,
+    /// recent rejects per client
+    rejects: HashMap<u16, VecDeque<Instant>>,
+    queue: Option<SyncSender<Notification>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WebhookSink {
+    /// The accounts in the context are the opening state, e.g. after
+    /// restoring a snapshot.
+    pub fn new(config: WebhookConfig, context: &TransactionContext) -> anyhow::Result<Self> {
+        let log = config
+            .log
+            .as_deref()
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()?
+            .map(BufWriter::new);
+        let (queue, notifications) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let dispatcher = Dispatcher {
+            agent: Agent::new_with_config(
+                Agent::config_builder()
+                    .timeout_global(Some(REQUEST_TIMEOUT))
+                    .http_status_as_error(false)
+                    .build(),
+            ),
+            webhooks: config.webhooks,
+            attempts: config.attempts,
+            backoff: config.backoff,
+            log,
+        };
+        let thread = std::thread::Builder::new()
+            .name("webhooks".to_string())
+            .spawn(move || dispatcher.run(notifications))?;
+        Ok(WebhookSink {
+            large_movement: config.large_movement,
+            repeated_rejects: config.repeated_rejects,
+            reject_window: config.reject_window,
+            locked: context
+                .iter_accounts()
+                .filter(|(_, account)| account.locked || account.withdrawals_locked)
+                .map(|(client, _)| client)
+                .collect(),
+            totals: context
+                .iter_accounts()
+                .map(|(client, account)| (client, account.total))
+                .collect(),
+            rejects: HashMap::new(),
+            queue: Some(queue),
+            thread: Some(thread),
+        })
+    }
+
+    fn notify(&mut self, notification: Notification) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        match queue.try_send(notification) {
+            Ok(()) => {}
+            Err(TrySendError::Full(notification)) => {
+                metrics().record_webhook_delivery(false);
+                warn!(event = ?notification.event, client = %notification.client, "webhook queue is full, dropping the notification");
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn reject(&mut self, event: &TransactionEvent, error: TransactionError) {
+        let Some(threshold) = self.repeated_rejects else {
+            return;
+        };
+        let now = Instant::now();
+        let rejects = self.rejects.entry(event.client_id).or_default();
+        rejects.push_back(now);
+        while rejects
+            .front()
+            .is_some_and(|at| now.duration_since(*at) > self.reject_window)
+        {
+            rejects.pop_front();
+        }
+        if rejects.len() < threshold {
+            return;
+        }
+        let count = rejects.len();
+        // one notification per burst
+        rejects.clear();
+        let mut notification = Notification::new(NotificationKind::RepeatedRejects, event, None);
+        notification.rejects = Some(count);
+        notification.reason = Some(error.as_str());
+        self.notify(notification);
+    }
+}
+
+impl Sink for WebhookSink {
+    fn record(
+        &mut self,
+        event: &TransactionEvent,
+        result: Result<(), TransactionError>,
+        account: Option<&Account>,
+    ) {
+        if let Err(error) = result {
+            self.reject(event, error);
+        }
+        if let Some(merged) = event.merged_client.filter(|_| result.is_ok()) {
+            self.totals.remove(&merged);
+            self.locked.remove(&merged);
+        }
+        let Some(account) = account else {
+            return;
+        };
+        let client = event.client_id;
+
+        let before = self
+            .totals
+            .insert(client, account.total)
+            .unwrap_or_default();
+        let change = Price(account.total.0 - before.0);
+        if self
+            .large_movement
+            .is_some_and(|threshold| change.0.abs() >= threshold.0)
+        {
+            let mut notification =
+                Notification::new(NotificationKind::LargeMovement, event, Some(account));
+            notification.change = Some(change);
+            self.notify(notification);
+        }
+
+        let locked = account.locked || account.withdrawals_locked;
+        match (locked, self.locked.contains(&client)) {
+            (true, false) => {
+                self.locked.insert(client);
+                self.notify(Notification::new(
+                    NotificationKind::AccountLocked,
+                    event,
+                    Some(account),
+                ));
+            }
+            (false, true) => {
+                self.locked.remove(&client);
+            }
+            _ => {}
+        }
+    }
+
+    fn prune(&mut self, pruned: &PrunedAccount) {
+        self.totals.remove(&pruned.client_id);
+        self.rejects.remove(&pruned.client_id);
+    }
+
+    fn finish(&mut self, _context: &TransactionContext) -> anyhow::Result<()> {
+        // the dispatcher stops once the queue is drained
+        self.queue.take();
+        if let Some(thread) = self.thread.take() {
+            thread
+                .join()
+                .map_err(|_| anyhow::anyhow!("webhook delivery panicked"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Delivers the notifications on the webhook thread.
+struct Dispatcher {
+    agent: Agent,
+    webhooks: Vec<Webhook>,
+    attempts: u32,
+    backoff: Duration,
+    log: Option<BufWriter<File>>,
+}
+
+impl Dispatcher {
+    fn run(mut self, notifications: Receiver<Notification>) {
+        for notification in notifications {
+            let body = serde_json::to_vec(&notification).expect("notification serializes");
+            for index in 0..self.webhooks.len() {
+                if self.webhooks[index].events.contains(&notification.event) {
+                    self.deliver(index, &notification, &body);
+                }
+            }
+            if let Some(log) = &mut self.log {
+                if let Err(error) = log.flush() {
+                    warn!(%error, "failed to write the webhook delivery log");
+                }
+            }
+        }
+    }
+
+    /// Posts the body until it is accepted or the attempts run out.
+    fn deliver(&mut self, index: usize, notification: &Notification, body: &[u8]) {
+        let webhook = &self.webhooks[index];
+        let signature = webhook
+            .secret
+            .as_ref()
+            .map(|secret| format!("sha256={}", sign(secret, body)));
+        let mut backoff = self.backoff;
+        for attempt in 1..=self.attempts {
+            let mut request = self
+                .agent
+                .post(&webhook.url)
+                .header("Content-Type", "application/json");
+            if let Some(signature) = &signature {
+                request = request.header("X-Txe-Signature", signature);
+            }
+            let (status, error) = match request.send(body) {
+                Ok(response) => (Some(response.status().as_u16()), None),
+                Err(error) => (None, Some(error.to_string())),
+            };
+            let delivered = status.is_some_and(|status| (200..300).contains(&status));
+            let last = attempt == self.attempts;
+            let outcome = match (delivered, last) {
+                (true, _) => "delivered",
+                (false, false) => "retrying",
+                (false, true) => "failed",
+            };
+            if let Some(log) = &mut self.log {
+                let record = DeliveryRecord {
+                    time: now(),
+                    url: &webhook.url,
+                    event: notification.event,
+                    client: notification.client,
+                    tx: notification.tx,
+                    attempt,
+                    status,
+                    error,
+                    outcome,
+                };
+                let written = serde_json::to_writer(&mut *log, &record)
+                    .map_err(std::io::Error::from)
+                    .and_then(|_| log.write_all(b"\n"));
+                if let Err(error) = written {
+                    warn!(%error, "failed to write the webhook delivery log");
+                }
+            }
+            if delivered || last {
+                metrics().record_webhook_delivery(delivered);
+                if !delivered {
+                    warn!(url = webhook.url, event = ?notification.event, attempts = attempt, "failed to deliver a webhook");
+                }
+                return;
+            }
+            std::thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+}
+
+/// HMAC-SHA256 of the body, as hex.
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).expect("any key length");
+    mac.update(body);
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data_types::TransactionType;
+
+    fn event(ty: TransactionType, client_id: u16, tx: u32, amount: i64) -> TransactionEvent {
+        TransactionEvent::new(ty, client_id, tx, Price(amount))
+    }
+
+    #[test]
+    fn test_webhooks() {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let addr = server.server_addr().to_ip().unwrap();
+        let log = std::env::temp_dir().join("txe_test_webhooks.log");
+        let _ = std::fs::remove_file(&log);
+        let config: WebhookConfig = toml::from_str(&format!(
+            r#"
+            large_movement = 100.0
+            repeated_rejects = 2
+            attempts = 2
+            backoff = "10ms"
+            log = "{}"
+
+            [[webhook]]
+            url = "http://{addr}/hook"
+            events = ["account_locked", "large_movement"]
+            secret = "s3cret"
+
+            [[webhook]]
+            url = "http://{addr}/rejects"
+            events = ["repeated_rejects"]
+            "#,
+            log.display()
+        ))
+        .unwrap();
+
+        let mut context = TransactionContext::new();
+        let mut sink = WebhookSink::new(config, &context).unwrap();
+        for event in [
+            event(TransactionType::Deposit, 1, 1, 2_000_000),
+            event(TransactionType::Deposit, 1, 2, 10_000),
+            event(TransactionType::Dispute, 1, 1, 0),
+            event(TransactionType::Chargeback, 1, 1, 0),
+            event(TransactionType::Withdrawal, 1, 3, 10_000),
+            event(TransactionType::Withdrawal, 1, 4, 10_000),
+        ] {
+            let (result, account) = match context.apply(&event) {
+                Ok(account) => (Ok(()), Some(account)),
+                Err(error) => (Err(error), context.account(1).copied()),
+            };
+            sink.record(&event, result, account.as_ref());
+        }
+
+        // the deposit of 200.0, its chargeback and the lock, the first
+        // delivery is retried
+        let mut bodies = Vec::new();
+        for status in [500, 200, 200, 200, 200] {
+            let mut request = server.recv().unwrap();
+            let mut body = String::new();
+            request.as_reader().read_to_string(&mut body).unwrap();
+            let signature = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("X-Txe-Signature"))
+                .map(|header| header.value.to_string());
+            if request.url() == "/hook" {
+                assert_eq!(
+                    signature,
+                    Some(format!("sha256={}", sign("s3cret", body.as_bytes())))
+                );
+            } else {
+                assert_eq!(signature, None);
+            }
+            request.respond(tiny_http::Response::empty(status)).unwrap();
+            if status == 200 {
+                bodies.push(serde_json::from_str::<serde_json::Value>(&body).unwrap());
+            }
+        }
+        sink.finish(&context).unwrap();
+
+        let events: Vec<_> = bodies
+            .iter()
+            .map(|body| body["event"].as_str().unwrap())
+            .collect();
+        assert_eq!(
+            events,
+            [
+                "large_movement",
+                "large_movement",
+                "account_locked",
+                "repeated_rejects"
+            ]
+        );
+        assert_eq!(bodies[1]["change"], "-200.0");
+        assert_eq!(bodies[3]["rejects"], 2);
+        assert_eq!(bodies[3]["reason"], "locked");
+
+        let log = std::fs::read_to_string(&log).unwrap();
+        let outcomes: Vec<_> = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["outcome"].clone())
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                "retrying",
+                "delivered",
+                "delivered",
+                "delivered",
+                "delivered"
+            ]
+        );
+    }
+}
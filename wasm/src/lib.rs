@@ -0,0 +1,256 @@
+//! WebAssembly build of the accounting rules, so a browser can compute the
+//! accounts of a set of transactions without a round trip to the engine.
+//! Build it with `wasm-pack build wasm --target web`.
+//!
+//! The state lives in a [`BTreeLedger`], nothing is persisted. Events that are
+//! rejected by the rules are skipped, like the binary does.
+use serde::{Deserialize, Serialize};
+use txe_accounting::{
+    apply_dispute, apply_transaction, Account, BTreeLedger, HoldPolicy, Ledger, LockPolicy, Price,
+    TransactionError, TransactionFlags, TransactionType,
+};
+use wasm_bindgen::prelude::*;
+
+/// A row of the csv input, extra columns are ignored.
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    ty: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(default)]
+    amount: Price,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountResponse {
+    client: u16,
+    available: Price,
+    held: Price,
+    total: Price,
+    locked: bool,
+}
+
+/// Processes a csv of transactions, with the same columns the binary reads,
+/// and returns the resulting accounts as a json array.
+#[wasm_bindgen]
+pub fn process_csv(text: &str) -> Result<String, JsError> {
+    let mut engine = Engine::new();
+    engine.push_csv(text)?;
+    Ok(engine.accounts_json())
+}
+
+/// State that events can be pushed into one by one.
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct Engine {
+    ledger: BTreeLedger,
+    rejected: u32,
+}
+
+#[wasm_bindgen]
+impl Engine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Engine {
+        Engine::default()
+    }
+
+    /// Applies a single event. `amount` is only read for deposits,
+    /// withdrawals and recoveries. Fails with the reason when the event is rejected.
+    #[wasm_bindgen(js_name = pushEvent)]
+    pub fn push_event(
+        &mut self,
+        ty: &str,
+        client: u16,
+        tx: u32,
+        amount: Option<String>,
+    ) -> Result<(), JsError> {
+        let ty: TransactionType = ty.parse().map_err(|e: String| JsError::new(&e))?;
+        let amount = match amount {
+            Some(amount) => amount
+                .parse()
+                .map_err(|_| JsError::new(&format!("invalid amount `{amount}`")))?,
+            None => Price::default(),
+        };
+        self.apply(&Event {
+            ty,
+            client,
+            tx,
+            amount,
+        })
+        .map_err(|e| JsError::new(e.as_str()))
+    }
+
+    /// Applies the events of a csv, see [`process_csv`].
+    #[wasm_bindgen(js_name = pushCsv)]
+    pub fn push_csv(&mut self, text: &str) -> Result<(), JsError> {
+        self.apply_csv(text)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// The accounts so far as a json array, ordered by client.
+    #[wasm_bindgen(js_name = accountsJson)]
+    pub fn accounts_json(&self) -> String {
+        let accounts: Vec<_> = self
+            .ledger
+            .accounts
+            .iter()
+            .map(|(client, account)| AccountResponse {
+                client: *client,
+                available: account.available(),
+                held: account.held,
+                total: account.total,
+                locked: account.locked,
+            })
+            .collect();
+        serde_json::to_string(&accounts).expect("accounts serialize to json")
+    }
+
+    /// Amount of events that were rejected by the rules.
+    #[wasm_bindgen(getter)]
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+}
+
+impl Engine {
+    fn apply(&mut self, event: &Event) -> Result<(), TransactionError> {
+        let ledger = &mut self.ledger;
+        let result = match event.ty {
+            TransactionType::Deposit => apply_transaction(
+                ledger,
+                event.client,
+                event.tx,
+                event.amount,
+                Account::deposit,
+                true,
+            ),
+            TransactionType::Withdrawal => apply_transaction(
+                ledger,
+                event.client,
+                event.tx,
+                event.amount,
+                Account::withdraw,
+                false,
+            ),
+            TransactionType::Recovery => recover(ledger, event),
+            // there is no key to authenticate admin events with
+            ty if ty.is_admin() => Err(TransactionError::Unauthorized),
+            // the events have no timestamp, the lock time is not part of the
+            // output anyway
+            _ => apply_dispute(
+                ledger,
+                event.ty,
+                event.client,
+                event.tx,
+                LockPolicy::Lock,
+                HoldPolicy::Full,
+                None,
+            )
+            .map(|(account, _)| account),
+        };
+        if result.is_err() {
+            self.rejected += 1;
+        }
+        result.map(|_| ())
+    }
+
+    fn apply_csv(&mut self, text: &str) -> Result<(), csv::Error> {
+        let mut rdr = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(text.as_bytes());
+        for event in rdr.deserialize() {
+            let _ = self.apply(&event?);
+        }
+        Ok(())
+    }
+}
+
+/// Repays a negative balance like the binary does, the tx id is stored so it
+/// can not be reused but the recovery can not be disputed.
+fn recover(ledger: &mut BTreeLedger, event: &Event) -> Result<Account, TransactionError> {
+    if !ledger.accounts.contains_key(&event.client) {
+        return Err(TransactionError::NotFound);
+    }
+    if ledger.transaction(event.tx).is_some() {
+        return Err(TransactionError::Duplicate);
+    }
+    let mut result = Ok(());
+    let account = ledger.update_account(event.client, &mut |account| {
+        result = account.recover(event.amount);
+    });
+    result?;
+    ledger.put_transaction(
+        event.tx,
+        (event.amount, TransactionFlags::Final, event.client),
+    );
+    Ok(account)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_csv() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+deposit, 1, 3, 2.0
+withdrawal, 1, 4, 1.5
+withdrawal, 2, 5, 3.0
+dispute, 1, 1,
+chargeback, 1, 1,
+";
+        let mut engine = Engine::new();
+        engine.apply_csv(input).unwrap();
+        assert_eq!(engine.rejected(), 1);
+        assert_eq!(
+            engine.accounts_json(),
+            r#"[{"client":1,"available":"0.5","held":"0.0","total":"0.5","locked":true},{"client":2,"available":"2.0","held":"0.0","total":"2.0","locked":false}]"#
+        );
+
+        assert!(Engine::new()
+            .apply_csv("type,client,tx,amount\nfoo,1,1,1.0\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_recovery() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 5.0
+withdrawal, 1, 2, 4.0
+dispute, 1, 1,
+chargeback, 1, 1,
+recovery, 1, 3, 2.0
+recovery, 1, 3, 2.0
+dispute, 1, 3,
+recovery, 1, 4, 5.0
+recovery, 2, 5, 1.0
+";
+        let mut engine = Engine::new();
+        engine.apply_csv(input).unwrap();
+        // the replayed recovery, its dispute, the one above zero and the one
+        // without an account
+        assert_eq!(engine.rejected(), 4);
+        assert_eq!(
+            engine.accounts_json(),
+            r#"[{"client":1,"available":"-2.0","held":"0.0","total":"-2.0","locked":true}]"#
+        );
+    }
+
+    #[test]
+    fn test_apply_event() {
+        let mut engine = Engine::new();
+        let deposit = Event {
+            ty: TransactionType::Deposit,
+            client: 1,
+            tx: 1,
+            amount: Price(10_000),
+        };
+        assert_eq!(engine.apply(&deposit), Ok(()));
+        assert_eq!(engine.apply(&deposit), Err(TransactionError::Duplicate));
+        assert_eq!(engine.ledger.accounts[&1].total, Price(10_000));
+    }
+}